@@ -0,0 +1,195 @@
+struct AnimationNode {
+  translation: vec4<f32>,
+  rotation: vec4<f32>,
+  scale: vec4<f32>,
+  parent: i32,
+  kind: u32,
+  output_index: u32,
+  channel_table: u32,
+}
+
+struct AnimationChannel {
+  times_offset: u32,
+  values_offset: u32,
+  key_count: u32,
+  property: u32,
+}
+
+struct AnimationJob {
+  node_offset: u32,
+  node_count: u32,
+  animation_index: u32,
+  lt_offset: u32,
+  time: f32,
+  is_skeletal: u32,
+  _padding_0: u32,
+  _padding_1: u32,
+}
+
+const NO_CHANNELS: u32 = 0xffffffffu;
+const NODE_KIND_MESH: u32 = 1u;
+const NODE_KIND_JOINT: u32 = 2u;
+const PROPERTY_ROTATION: u32 = 0u;
+const PROPERTY_TRANSLATION: u32 = 1u;
+// a LocalTransform is a mat4x4 followed by the u32 model index
+const LOCAL_TRANSFORM_STRIDE: u32 = 17u;
+
+@group(0) @binding(0)
+var<storage, read> nodes: array<AnimationNode>;
+@group(0) @binding(1)
+var<storage, read> channel_ranges: array<vec2<u32>>;
+@group(0) @binding(2)
+var<storage, read> channels: array<AnimationChannel>;
+@group(0) @binding(3)
+var<storage, read> keyframe_data: array<f32>;
+@group(0) @binding(4)
+var<storage, read> inverse_bind_matrices: array<mat4x4<f32>>;
+
+@group(1) @binding(0)
+var<storage, read> jobs: array<AnimationJob>;
+@group(1) @binding(1)
+var<storage, read_write> local_transforms: array<f32>;
+@group(1) @binding(2)
+var<storage, read_write> joint_transforms: array<mat4x4<f32>>;
+
+struct Trs {
+  translation: vec3<f32>,
+  rotation: vec4<f32>,
+  scale: vec3<f32>,
+}
+
+fn keyframe(channel: AnimationChannel, index: u32) -> vec4<f32> {
+	let base = channel.values_offset + index * 4u;
+	return vec4<f32>(keyframe_data[base], keyframe_data[base + 1u], keyframe_data[base + 2u], keyframe_data[base + 3u]);
+}
+
+fn nlerp(a: vec4<f32>, b: vec4<f32>, amount: f32) -> vec4<f32> {
+	var other = b;
+	if (dot(a, b) < 0.0) {
+		other = -b;
+	}
+	return normalize(a * (1.0 - amount) + other * amount);
+}
+
+// returns the keyframe value at time, and whether the channel has started yet
+fn sample_channel(channel: AnimationChannel, time: f32, started: ptr<function, bool>) -> vec4<f32> {
+	let last = channel.key_count - 1u;
+	if (time < keyframe_data[channel.times_offset]) {
+		*started = false;
+		return vec4<f32>(0.0);
+	}
+	*started = true;
+	if (time >= keyframe_data[channel.times_offset + last]) {
+		return keyframe(channel, last);
+	}
+	// find the first keyframe at or after time
+	var low = 1u;
+	var high = last;
+	while (low < high) {
+		let mid = (low + high) / 2u;
+		if (keyframe_data[channel.times_offset + mid] < time) {
+			low = mid + 1u;
+		} else {
+			high = mid;
+		}
+	}
+	let start_time = keyframe_data[channel.times_offset + low - 1u];
+	let end_time = keyframe_data[channel.times_offset + low];
+	let amount = (time - start_time) / (end_time - start_time);
+	let first = keyframe(channel, low - 1u);
+	let second = keyframe(channel, low);
+	if (channel.property == PROPERTY_ROTATION) {
+		return nlerp(first, second, amount);
+	}
+	return first + (second - first) * amount;
+}
+
+fn sample_node(node: AnimationNode, animation_index: u32, time: f32) -> Trs {
+	var trs = Trs(node.translation.xyz, node.rotation, node.scale.xyz);
+	if (node.channel_table == NO_CHANNELS) {
+		return trs;
+	}
+	let range = channel_ranges[node.channel_table + animation_index];
+	for (var i = range.x; i < range.x + range.y; i++) {
+		let channel = channels[i];
+		var started = false;
+		let value = sample_channel(channel, time, &started);
+		if (!started) {
+			continue;
+		}
+		if (channel.property == PROPERTY_ROTATION) {
+			trs.rotation = value;
+		} else if (channel.property == PROPERTY_TRANSLATION) {
+			trs.translation = value.xyz;
+		} else {
+			trs.scale = value.xyz;
+		}
+	}
+	return trs;
+}
+
+fn trs_matrix(trs: Trs) -> mat4x4<f32> {
+	let q = trs.rotation;
+	let x2 = q.x + q.x;
+	let y2 = q.y + q.y;
+	let z2 = q.z + q.z;
+	let xx2 = x2 * q.x;
+	let xy2 = x2 * q.y;
+	let xz2 = x2 * q.z;
+	let yy2 = y2 * q.y;
+	let yz2 = y2 * q.z;
+	let zz2 = z2 * q.z;
+	let sy2 = y2 * q.w;
+	let sz2 = z2 * q.w;
+	let sx2 = x2 * q.w;
+	let s = trs.scale;
+	return mat4x4<f32>(
+		vec4<f32>(1.0 - yy2 - zz2, xy2 + sz2, xz2 - sy2, 0.0) * s.x,
+		vec4<f32>(xy2 - sz2, 1.0 - xx2 - zz2, yz2 + sx2, 0.0) * s.y,
+		vec4<f32>(xz2 + sy2, yz2 - sx2, 1.0 - xx2 - yy2, 0.0) * s.z,
+		vec4<f32>(trs.translation, 1.0),
+	);
+}
+
+// one invocation per (node, job). Each node walks up its own ancestors instead of
+// waiting on its parent, so there is no synchronization between invocations
+@compute @workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+	let job = jobs[id.y];
+	if (id.x >= job.node_count) {
+		return;
+	}
+	let node_index = job.node_offset + id.x;
+	let node = nodes[node_index];
+	if (node.kind != NODE_KIND_MESH && node.kind != NODE_KIND_JOINT) {
+		return;
+	}
+
+	var global = trs_matrix(sample_node(node, job.animation_index, job.time));
+	var parent = node.parent;
+	while (parent >= 0) {
+		let parent_node = nodes[u32(parent)];
+		global = trs_matrix(sample_node(parent_node, job.animation_index, job.time)) * global;
+		parent = parent_node.parent;
+	}
+
+	if (node.kind == NODE_KIND_MESH) {
+		if (job.is_skeletal != 0u) {
+			global = mat4x4<f32>(
+				vec4<f32>(1.0, 0.0, 0.0, 0.0),
+				vec4<f32>(0.0, 1.0, 0.0, 0.0),
+				vec4<f32>(0.0, 0.0, 1.0, 0.0),
+				vec4<f32>(0.0, 0.0, 0.0, 1.0),
+			);
+		}
+		// leave the model index that follows the matrix untouched
+		let base = (job.lt_offset + node.output_index) * LOCAL_TRANSFORM_STRIDE;
+		for (var c = 0u; c < 4u; c++) {
+			for (var r = 0u; r < 4u; r++) {
+				local_transforms[base + c * 4u + r] = global[c][r];
+			}
+		}
+	} else {
+		joint_transforms[node.output_index] = global * inverse_bind_matrices[node.output_index];
+	}
+}
@@ -110,7 +110,7 @@ impl ApplicationHandler for App<'_> {
                 }
                 self.window.as_ref().unwrap().request_redraw();
                 self.update_state();
-                match self.app_state.as_ref().unwrap().draw() {
+                match self.app_state.as_mut().unwrap().draw() {
                     Ok(_) => {}
                     Err(_) => {
                         event_loop.exit();
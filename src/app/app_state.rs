@@ -1,11 +1,20 @@
 use super::app_config::AppConfig;
+use super::baked::BakedPosePipeline;
+use super::compute::{AnimationBackend, AnimationComputePipeline};
 use super::util;
-use crate::app::util::{create_diffuse_bgl, setup_config, setup_global_instance_bind_group};
+use crate::app::util::{
+    create_diffuse_bgl, create_global_instance_bind_group, setup_config,
+    setup_global_instance_bind_group,
+};
+use crate::model::animation::animation_state_machine::AnimationParameter;
 use crate::model::materials::material::{GMaterial, MaterialDefinition};
 use crate::model::materials::texture::GTexture;
-use crate::model::model::{GDrawModel, LocalTransform};
+use crate::model::model::LocalTransform;
 use crate::model::vertex::*;
 use crate::scene::camera::get_camera_color_bg;
+use crate::scene::indirect::{DrawIndirect, IndirectDrawMode, IndirectDraws};
+use crate::scene::instances::InstanceHandle;
+use crate::scene::render_queue::{RenderQueue, RenderStats};
 use crate::scene::scene::GScene;
 use std::sync::Arc;
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
@@ -37,6 +46,9 @@ impl InputController {
     }
 }
 
+/// big enough for a frame of transform changes of a typical scene in one chunk
+const TRANSFORM_STAGING_CHUNK_SIZE: wgpu::BufferAddress = 64 * 1024;
+
 pub enum UpdateResult {
     UpdateError,
 }
@@ -46,10 +58,23 @@ pub struct AppState<'a> {
     render_pipeline: wgpu::RenderPipeline,
     pub gscene: GScene<'a>,
     bind_groups: Vec<wgpu::BindGroup>,
+    /// kept to rebind the global transform buffer when instances outgrow it
+    global_instance_bind_group_layout: wgpu::BindGroupLayout,
     joint_bind_group: wgpu::BindGroup,
     pub input_controller: InputController,
     pub materials: Vec<GMaterial>,
     depth_texture: GTexture,
+    animation_compute: Option<AnimationComputePipeline>,
+    baked_poses: Option<BakedPosePipeline>,
+    /// the instance the number keys drive the state machine of
+    animated_instance: InstanceHandle,
+    /// stages the changed transforms on their way to the gpu
+    staging_belt: wgpu::util::StagingBelt,
+    /// the draws of the visible instances in the order they are encoded, rebuilt every update
+    render_queue: RenderQueue,
+    indirect_draws: IndirectDraws,
+    /// what the last frame cost to encode
+    render_stats: RenderStats,
 }
 
 impl<'a> AppState<'a> {
@@ -64,12 +89,44 @@ impl<'a> AppState<'a> {
         let aspect_ratio = (app_config.size.width / app_config.size.height) as f32;
         let sampler_texture_bgl = create_diffuse_bgl(&app_config);
         let mut gscene = util::get_scene(&app_config.device, aspect_ratio);
+        if let Some(stats) = gscene.get_index_optimization_stats() {
+            println!(
+                "reordered the indices of {} primitives, acmr {:.3} -> {:.3}",
+                stats.primitives,
+                stats.acmr_before(),
+                stats.acmr_after()
+            );
+        }
+        let animated_instance = gscene.get_instance_handle(0, 0);
+        if let Some(animation_data) = gscene.models[0].animation_data.as_ref() {
+            let state_machine = util::get_state_machine(animation_data.animation_count);
+            gscene
+                .set_animation_state_machine(animated_instance, state_machine)
+                .expect("the first instance should exist")
+                .expect("the state machine should only refer to animations of the model");
+        }
         let camera_color_bind_group_layout = gscene.get_camera_bind_group(&app_config.device);
 
         let (global_instance_bind_group_layout, global_instance_bind_group) =
             setup_global_instance_bind_group(&app_config, &gscene);
 
         let (joint_bgl, joint_bind_group) = AppState::setup_joint_bind_group(&app_config, &gscene);
+        // baked poses are bound in place of the joint transforms, and played by their own
+        // vertex shader entry point
+        let baked_poses = match util::ANIMATION_BACKEND {
+            AnimationBackend::Baked => match BakedPosePipeline::new(&app_config.device, &gscene) {
+                Ok(baked_poses) => Some(baked_poses),
+                Err(error) => {
+                    eprintln!("{}, animating on the cpu instead", error);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let (joint_bgl, vertex_entry_point) = match baked_poses.as_ref() {
+            Some(baked_poses) => (baked_poses.bind_group_layout(), "vs_baked"),
+            None => (&joint_bgl, "vs_main"),
+        };
 
         let render_pipeline_layout =
             app_config
@@ -79,7 +136,7 @@ impl<'a> AppState<'a> {
                     bind_group_layouts: &[
                         &camera_color_bind_group_layout,
                         &global_instance_bind_group_layout,
-                        &joint_bgl,
+                        joint_bgl,
                         &sampler_texture_bgl,
                     ],
                     push_constant_ranges: &[],
@@ -92,7 +149,7 @@ impl<'a> AppState<'a> {
                     layout: Some(&render_pipeline_layout),
                     vertex: wgpu::VertexState {
                         module: &shader,
-                        entry_point: Some("vs_main"),
+                        entry_point: Some(vertex_entry_point),
                         buffers: &[ModelVertex::desc(), LocalTransform::desc()],
                         compilation_options: Default::default(),
                     },
@@ -175,13 +232,29 @@ impl<'a> AppState<'a> {
         let depth_texture = GTexture::create_depth_texture(&app_config.device, &app_config.config);
 
         let bind_groups = vec![camera_color_bind_group, global_instance_bind_group];
+        let animation_compute = match util::ANIMATION_BACKEND {
+            AnimationBackend::Cpu | AnimationBackend::Baked => None,
+            AnimationBackend::Compute => {
+                Some(AnimationComputePipeline::new(&app_config.device, &gscene))
+            }
+        };
         Self {
+            animation_compute,
+            baked_poses,
+            animated_instance,
+            staging_belt: wgpu::util::StagingBelt::new(TRANSFORM_STAGING_CHUNK_SIZE),
+            render_queue: RenderQueue::default(),
+            indirect_draws: IndirectDraws::new(IndirectDrawMode::from_features(
+                app_config.device.features(),
+            )),
+            render_stats: RenderStats::default(),
             materials,
             app_config,
             render_pipeline,
             gscene,
             depth_texture,
             bind_groups,
+            global_instance_bind_group_layout,
             joint_bind_group,
             input_controller: InputController::new(),
         }
@@ -239,11 +312,23 @@ impl<'a> AppState<'a> {
             self.gscene.update_camera_pos(0.0, 0.0, -speed);
         }
         if self.input_controller.key_1_down {
-            self.gscene.initialize_animation(0, 0, 0);
+            self.gscene
+                .set_animation_parameter(
+                    self.animated_instance,
+                    "key_1",
+                    AnimationParameter::Trigger,
+                )
+                .expect("the animated instance is never removed");
             self.input_controller.key_1_down = false;
         }
         if self.input_controller.key_2_down {
-            self.gscene.initialize_animation(0, 0, 1);
+            self.gscene
+                .set_animation_parameter(
+                    self.animated_instance,
+                    "key_2",
+                    AnimationParameter::Trigger,
+                )
+                .expect("the animated instance is never removed");
             self.input_controller.key_2_down = false;
         }
         // if self.input_controller.key_q_down {
@@ -265,25 +350,52 @@ impl<'a> AppState<'a> {
         self.process_input();
         let time = std::time::SystemTime::now();
         let timestamp = time.duration_since(std::time::UNIX_EPOCH).unwrap();
-        if self.gscene.get_animation_frame(timestamp) {
-            unsafe {
-                self.app_config.queue.write_buffer(
-                    self.gscene
-                        .get_local_transform_buffer()
-                        .as_ref()
-                        .unwrap_unchecked(),
-                    0,
-                    bytemuck::cast_slice(self.gscene.get_local_transform_data()),
-                );
-                self.app_config.queue.write_buffer(
-                    self.gscene.get_joint_buf_unchecked(),
-                    0,
-                    bytemuck::cast_slice(self.gscene.get_joint_transform_data()),
-                );
+        self.sync_instances();
+        if let Some(baked_poses) = self.baked_poses.as_mut() {
+            baked_poses.sync_instances(
+                &self.app_config.device,
+                &self.app_config.queue,
+                &self.gscene,
+            );
+            baked_poses.update(&self.app_config.queue, &mut self.gscene, timestamp);
+        } else if let Some(animation_compute) = self.animation_compute.as_mut() {
+            match self
+                .gscene
+                .get_animation_compute_jobs(timestamp, animation_compute.animation_data())
+            {
+                Ok(Some(jobs)) => animation_compute.dispatch(
+                    &self.app_config.device,
+                    &self.app_config.queue,
+                    &self.gscene,
+                    &jobs,
+                ),
+                Ok(None) => {}
+                Err(error) => {
+                    // the cpu path can do everything, so it takes over for good
+                    eprintln!("{}, animating on the cpu from now on", error);
+                    self.animation_compute = None;
+                    self.gscene.get_animation_frame(timestamp);
+                }
             }
+        } else {
+            self.gscene.get_animation_frame(timestamp);
+        }
+        // attached instances follow their parents, which may have moved without any animation
+        self.gscene.propagate_transforms();
+        // the compute shader animates the local transforms on the gpu only, so the cpu copy
+        // the bounds are placed with can't be trusted
+        if self.animation_compute.is_none() {
+            self.gscene.cull_instances();
         }
+        self.upload_transform_changes();
+        self.render_queue.clear();
+        self.render_queue.push_scene(&self.gscene, 0);
+        self.render_queue.sort();
+        self.indirect_draws.build(&self.render_queue);
+        self.indirect_draws
+            .upload(&self.app_config.device, &self.app_config.queue);
         // let rot = cgmath::Matrix4::from_angle_y(cgmath::Deg(0.4));
-        // self.gscene.update_global_transform_x(0, rot.into());
+        // self.gscene.update_global_transform(self.gscene.get_instance_handle(0, 0), rot.into()).unwrap();
         // unsafe {
         //     self.app_config.queue.write_buffer(
         //         self.gscene
@@ -297,7 +409,48 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
-    pub(super) fn draw(&self) -> Result<(), wgpu::SurfaceError> {
+    /// copy only the transforms changed this frame to the gpu. Animations and attachments touch
+    /// many small, scattered ranges, so they go through the staging belt rather than one
+    /// queue write each
+    fn upload_transform_changes(&mut self) {
+        let device = &self.app_config.device;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Transform upload encoder"),
+        });
+        if !self
+            .gscene
+            .upload_transform_changes(device, &mut encoder, &mut self.staging_belt)
+        {
+            return;
+        }
+        self.staging_belt.finish();
+        self.app_config
+            .queue
+            .submit(std::iter::once(encoder.finish()));
+        self.staging_belt.recall();
+    }
+
+    /// upload spawned and despawned instances, rebinding the transform buffers if they were
+    /// replaced
+    fn sync_instances(&mut self) {
+        let device = &self.app_config.device;
+        if !self
+            .gscene
+            .sync_instance_buffers(device, &self.app_config.queue)
+        {
+            return;
+        }
+        self.bind_groups[1] = create_global_instance_bind_group(
+            device,
+            &self.global_instance_bind_group_layout,
+            &self.gscene,
+        );
+        if let Some(animation_compute) = self.animation_compute.as_mut() {
+            animation_compute.rebind(device, &self.gscene);
+        }
+    }
+
+    pub(super) fn draw(&mut self) -> Result<(), wgpu::SurfaceError> {
         let output = self.app_config.surface.get_current_texture()?;
         let view = output
             .texture
@@ -341,8 +494,10 @@ impl<'a> AppState<'a> {
             for (idx, bind_group) in self.bind_groups.iter().enumerate() {
                 render_pass.set_bind_group(idx as u32, Some(bind_group), &[]);
             }
-            render_pass.set_bind_group(2, &self.joint_bind_group, &[]);
-            render_pass.set_bind_group(3, &self.materials[0].bind_group, &[]);
+            match self.baked_poses.as_ref() {
+                Some(baked_poses) => render_pass.set_bind_group(2, baked_poses.bind_group(), &[]),
+                None => render_pass.set_bind_group(2, &self.joint_bind_group, &[]),
+            }
 
             render_pass.set_vertex_buffer(
                 0,
@@ -362,8 +517,11 @@ impl<'a> AppState<'a> {
                     .expect("local transform data should be initialized")
                     .slice(..),
             );
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.draw_scene(&self.gscene, &self.materials);
+            self.render_stats = render_pass.draw_scene_indirect(
+                &self.indirect_draws,
+                &[&self.render_pipeline],
+                &self.materials,
+            );
         }
         self.app_config
             .queue
@@ -373,6 +531,11 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    /// the draw calls, pipeline and material bind group changes of the last frame
+    pub fn get_render_stats(&self) -> RenderStats {
+        self.render_stats
+    }
+
     pub(super) fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.app_config.resize(new_size);
         self.depth_texture =
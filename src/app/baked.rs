@@ -0,0 +1,323 @@
+use std::{fmt::Display, ops::Range, time::Duration};
+
+use wgpu::util::DeviceExt;
+
+use crate::{
+    app::compute::storage_buffer_init,
+    model::animation::baked_poses::{BakedPoseInstance, BakedPoses},
+    scene::scene::GScene,
+};
+
+/// the rate every animation is baked at
+const BAKED_FRAME_RATE: f32 = 30.0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct BakedPoseUniform {
+    time: f32,
+    joint_count: u32,
+    _padding: [u32; 2],
+}
+
+/// why a scene can't be played from baked poses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BakeError {
+    /// none of the models has a skeletal animation to bake
+    NoSkeletalAnimations,
+}
+
+impl Display for BakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSkeletalAnimations => write!(f, "no model has skeletal animations to bake"),
+        }
+    }
+}
+
+/// Owns the baked poses of every skeletal model and the bind group the vertex shader plays
+/// them back from, in place of the joint transform buffer.
+pub struct BakedPosePipeline {
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pose_buffer: wgpu::Buffer,
+    clip_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    uniform_buffer: wgpu::Buffer,
+    /// kept to sample the poses of the instances other instances are attached to
+    poses: BakedPoses,
+    /// the clips baked from each model's animations
+    model_clips: Vec<Range<usize>>,
+    /// what the instance at each global transform index plays, as last uploaded
+    instances: Vec<BakedPoseInstance>,
+    joint_count: u32,
+    /// the timestamp of the first update, clips are played from there
+    start_time: Option<Duration>,
+}
+
+impl BakedPosePipeline {
+    /// Bake every animation of every skeletal model, see [baked_instances] for which
+    /// instance plays what
+    pub fn new(device: &wgpu::Device, scene: &GScene) -> Result<Self, BakeError> {
+        let (poses, model_clips) = bake_scene(scene)?;
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("baked pose bgl"),
+            entries: &[
+                vertex_entry(3, wgpu::BufferBindingType::Storage { read_only: true }),
+                vertex_entry(4, wgpu::BufferBindingType::Storage { read_only: true }),
+                vertex_entry(5, wgpu::BufferBindingType::Storage { read_only: true }),
+                vertex_entry(6, wgpu::BufferBindingType::Uniform),
+            ],
+        });
+        let pose_buffer = storage_buffer_init(device, "baked poses", &poses.joint_matrices);
+        let clip_buffer = storage_buffer_init(device, "baked clips", &poses.gpu_clips());
+        let instances = baked_instances(scene, &model_clips, poses.clips.len());
+        let instance_buffer = create_instance_buffer(device, instances.len());
+        let joint_count = poses.joint_count as u32;
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("baked pose uniform"),
+            contents: bytemuck::bytes_of(&BakedPoseUniform {
+                time: 0.0,
+                joint_count,
+                _padding: [0; 2],
+            }),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            [
+                &pose_buffer,
+                &clip_buffer,
+                &instance_buffer,
+                &uniform_buffer,
+            ],
+        );
+        Ok(Self {
+            bind_group_layout,
+            bind_group,
+            pose_buffer,
+            clip_buffer,
+            instance_buffer,
+            uniform_buffer,
+            poses,
+            model_clips,
+            // uploaded by the first sync
+            instances: Vec::new(),
+            joint_count,
+            start_time: None,
+        })
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Rebuild the instance table if instances were spawned or despawned since the last call,
+    /// replacing its buffer and the bind group once it no longer fits
+    pub fn sync_instances(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, scene: &GScene) {
+        let instances = baked_instances(scene, &self.model_clips, self.poses.clips.len());
+        if instances == self.instances {
+            return;
+        }
+        let size = std::mem::size_of_val(&instances[..]) as u64;
+        if size > self.instance_buffer.size() {
+            self.instance_buffer = create_instance_buffer(device, instances.len());
+            self.bind_group = create_bind_group(
+                device,
+                &self.bind_group_layout,
+                [
+                    &self.pose_buffer,
+                    &self.clip_buffer,
+                    &self.instance_buffer,
+                    &self.uniform_buffer,
+                ],
+            );
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+        self.instances = instances;
+    }
+
+    /// Advance the clock the vertex shader samples the baked clips at. The instances attached
+    /// to joints follow the pose their parent is drawn with, sampled here on the cpu
+    pub fn update(&mut self, queue: &wgpu::Queue, scene: &mut GScene, timestamp: Duration) {
+        let start_time = *self.start_time.get_or_insert(timestamp);
+        let time = timestamp.saturating_sub(start_time).as_secs_f32();
+        let uniform = BakedPoseUniform {
+            time,
+            joint_count: self.joint_count,
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+        for parent in scene.get_joint_parents() {
+            let Ok((model_id, instance_idx)) = scene.get_instance_location(parent) else {
+                continue;
+            };
+            let global_index = scene.get_instance_global_index(instance_idx, model_id);
+            let Some(instance) = self.instances.get(global_index) else {
+                continue;
+            };
+            let pose = self.poses.sample(
+                instance.clip as usize,
+                time * instance.speed + instance.time_offset,
+            );
+            scene.set_joint_pose(parent, &pose);
+        }
+    }
+}
+
+/// The poses of every animation of every skeletal model, one after the other, and the range of
+/// clips baked from each model
+fn bake_scene(scene: &GScene) -> Result<(BakedPoses, Vec<Range<usize>>), BakeError> {
+    let mut poses: Option<BakedPoses> = None;
+    let mut model_clips = Vec::with_capacity(scene.models.len());
+    for (model_id, model) in scene.models.iter().enumerate() {
+        let first_clip = poses.as_ref().map_or(0, |poses| poses.clips.len());
+        if let Some(animation_data) = model
+            .animation_data
+            .as_ref()
+            .filter(|data| data.is_skeletal)
+        {
+            let mut animation_indices: Vec<usize> = animation_data
+                .animation_node
+                .animation_indices()
+                .into_iter()
+                .collect();
+            animation_indices.sort_unstable();
+            let model_poses =
+                scene.bake_animation_poses(model_id, &animation_indices, BAKED_FRAME_RATE);
+            match poses.as_mut() {
+                Some(poses) => poses.append(model_poses),
+                None => poses = Some(model_poses),
+            }
+        }
+        let clip_count = poses.as_ref().map_or(0, |poses| poses.clips.len());
+        model_clips.push(first_clip..clip_count);
+    }
+    poses
+        .filter(|poses| !poses.clips.is_empty())
+        .map(|poses| (poses, model_clips))
+        .ok_or(BakeError::NoSkeletalAnimations)
+}
+
+/// What every instance plays, by global transform index. Instance i of a model plays clip
+/// i % n of the model's n clips, each a little further along than the one before it. Models
+/// without clips of their own, e.g. a skinned mesh next to its skeleton, pick from every clip
+fn baked_instances(
+    scene: &GScene,
+    model_clips: &[Range<usize>],
+    clip_count: usize,
+) -> Vec<BakedPoseInstance> {
+    let mut instances =
+        vec![BakedPoseInstance::new(0, 0.0, 1.0); scene.get_global_transform_data().len()];
+    for (model_id, instance_count) in scene.get_model_instances().iter().enumerate() {
+        let clips = match model_clips[model_id].is_empty() {
+            true => 0..clip_count,
+            false => model_clips[model_id].clone(),
+        };
+        for instance_idx in 0..*instance_count {
+            let global_index = scene.get_instance_global_index(instance_idx, model_id);
+            let clip = clips.start + instance_idx % clips.len();
+            instances[global_index] = BakedPoseInstance::new(clip, global_index as f32 * 0.37, 1.0);
+        }
+    }
+    instances
+}
+
+/// room for at least instance_count instances, there is always room for one
+fn create_instance_buffer(device: &wgpu::Device, instance_count: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("baked pose instances"),
+        size: (instance_count.max(1).next_power_of_two() * std::mem::size_of::<BakedPoseInstance>())
+            as u64,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffers: [&wgpu::Buffer; 4],
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("baked pose bind group"),
+        layout,
+        entries: &buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32 + 3,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn vertex_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::VERTEX,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::loader::loader::GltfLoader, scene::scene::GSceneData, transforms};
+
+    #[test]
+    fn test_every_skeletal_model_is_baked() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let skeleton_id = scene.find_model("root").unwrap();
+        let fox_id = scene.find_model("fox").unwrap();
+        let (poses, model_clips) = bake_scene(&scene).unwrap();
+        let animation_count = scene.models[skeleton_id]
+            .animation_data
+            .as_ref()
+            .unwrap()
+            .animation_node
+            .animation_indices()
+            .len();
+        assert_eq!(poses.clips.len(), animation_count);
+        assert_eq!(model_clips[skeleton_id], 0..animation_count);
+        assert!(model_clips[fox_id].is_empty());
+
+        // spawned instances get a clip of their own model, and despawns don't leave gaps
+        let spawned: Vec<_> = (0..4)
+            .map(|_| {
+                scene
+                    .spawn_instance(skeleton_id, transforms::identity())
+                    .unwrap()
+            })
+            .collect();
+        scene.despawn_instance(spawned[1]).unwrap();
+        let instances = baked_instances(&scene, &model_clips, poses.clips.len());
+        assert_eq!(instances.len(), scene.get_global_transform_data().len());
+        for instance_idx in 0..scene.get_model_instances()[skeleton_id] {
+            let global_index = scene.get_instance_global_index(instance_idx, skeleton_id);
+            let expected = instance_idx % animation_count;
+            assert_eq!(instances[global_index].clip as usize, expected);
+        }
+    }
+
+    #[test]
+    fn test_scenes_without_skeletons_are_not_baked() {
+        let gltf_data = GltfLoader::load_gltf("box-animated").unwrap();
+        let scene = GSceneData::new(gltf_data).build_scene_uninit();
+        assert_eq!(
+            bake_scene(&scene).err(),
+            Some(BakeError::NoSkeletalAnimations)
+        );
+    }
+}
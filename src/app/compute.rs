@@ -0,0 +1,406 @@
+use wgpu::util::DeviceExt;
+
+use crate::{
+    model::animation::animation_compute::{AnimationComputeData, AnimationComputeJob},
+    scene::scene::GScene,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationBackend {
+    /// sample and propagate every animation on the cpu, then upload the full transform buffers
+    Cpu,
+    /// upload only the per instance clocks, and let the animation compute shader write the
+    /// local and joint transform buffers directly
+    Compute,
+    /// sample every animation of the first model once at startup, and let the vertex shader
+    /// look up each instance's pose by clip and time. Nothing is evaluated per frame, so state
+    /// machines, layers and events don't run
+    Baked,
+}
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Owns the animation compute shader and the static animation data of the scene.
+pub struct AnimationComputePipeline {
+    pipeline: wgpu::ComputePipeline,
+    animation_data: AnimationComputeData,
+    data_bind_group: wgpu::BindGroup,
+    job_bind_group_layout: wgpu::BindGroupLayout,
+    job_bind_group: wgpu::BindGroup,
+    job_buffer: wgpu::Buffer,
+    job_capacity: usize,
+}
+
+impl AnimationComputePipeline {
+    pub fn new(device: &wgpu::Device, scene: &GScene) -> Self {
+        let animation_data = scene.get_animation_compute_data();
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Animation compute shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../animation_compute.wgsl").into()),
+        });
+        let data_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("animation data bgl"),
+                entries: &(0..5)
+                    .map(|binding| storage_entry(binding, true))
+                    .collect::<Vec<_>>(),
+            });
+        let job_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("animation job bgl"),
+                entries: &[
+                    storage_entry(0, true),
+                    storage_entry(1, false),
+                    storage_entry(2, false),
+                ],
+            });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Animation compute pipeline layout"),
+            bind_group_layouts: &[&data_bind_group_layout, &job_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Animation compute pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: Some("cs_main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        // storage bindings can't be empty, so pad every array with a zeroed element
+        let node_buffer = storage_buffer_init(device, "animation nodes", &animation_data.nodes);
+        let range_buffer = storage_buffer_init(
+            device,
+            "animation channel ranges",
+            &animation_data.channel_ranges,
+        );
+        let channel_buffer =
+            storage_buffer_init(device, "animation channels", &animation_data.channels);
+        let keyframe_buffer =
+            storage_buffer_init(device, "animation keyframes", &animation_data.keyframe_data);
+        let ibm_buffer = storage_buffer_init(
+            device,
+            "inverse bind matrices",
+            &animation_data.inverse_bind_matrices,
+        );
+        let data_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("animation data bind group"),
+            layout: &data_bind_group_layout,
+            entries: &[
+                &node_buffer,
+                &range_buffer,
+                &channel_buffer,
+                &keyframe_buffer,
+                &ibm_buffer,
+            ]
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect::<Vec<_>>(),
+        });
+
+        let job_capacity = 16;
+        let job_buffer = Self::create_job_buffer(device, job_capacity);
+        let job_bind_group =
+            Self::create_job_bind_group(device, &job_bind_group_layout, &job_buffer, scene);
+        Self {
+            pipeline,
+            animation_data,
+            data_bind_group,
+            job_bind_group_layout,
+            job_bind_group,
+            job_buffer,
+            job_capacity,
+        }
+    }
+
+    pub fn animation_data(&self) -> &AnimationComputeData {
+        &self.animation_data
+    }
+
+    /// point the jobs at the scene's current local transform buffer, after it was replaced
+    pub fn rebind(&mut self, device: &wgpu::Device, scene: &GScene) {
+        self.job_bind_group = Self::create_job_bind_group(
+            device,
+            &self.job_bind_group_layout,
+            &self.job_buffer,
+            scene,
+        );
+    }
+
+    /// upload this frame's jobs and evaluate them into the scene's transform buffers
+    pub fn dispatch(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &GScene,
+        jobs: &[AnimationComputeJob],
+    ) {
+        if jobs.is_empty() || self.animation_data.max_node_count == 0 {
+            return;
+        }
+        if jobs.len() > self.job_capacity {
+            self.job_capacity = jobs.len().next_power_of_two();
+            self.job_buffer = Self::create_job_buffer(device, self.job_capacity);
+            self.job_bind_group = Self::create_job_bind_group(
+                device,
+                &self.job_bind_group_layout,
+                &self.job_buffer,
+                scene,
+            );
+        }
+        queue.write_buffer(&self.job_buffer, 0, bytemuck::cast_slice(jobs));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Animation compute encoder"),
+        });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Animation compute pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_pipeline(&self.pipeline);
+            compute_pass.set_bind_group(0, &self.data_bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.job_bind_group, &[]);
+            compute_pass.dispatch_workgroups(
+                self.animation_data.max_node_count.div_ceil(WORKGROUP_SIZE),
+                jobs.len() as u32,
+                1,
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    fn create_job_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("animation job buffer"),
+            size: (capacity * std::mem::size_of::<AnimationComputeJob>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_job_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        job_buffer: &wgpu::Buffer,
+        scene: &GScene,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("animation job bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: job_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: scene
+                        .get_local_transform_buffer()
+                        .as_ref()
+                        .expect("local transform data should be initialized")
+                        .as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: scene
+                        .get_joint_buf()
+                        .expect("should be initialized")
+                        .as_entire_binding(),
+                },
+            ],
+        })
+    }
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+pub(super) fn storage_buffer_init<T: bytemuck::Pod + bytemuck::Zeroable>(
+    device: &wgpu::Device,
+    label: &str,
+    data: &[T],
+) -> wgpu::Buffer {
+    let zeroed = [T::zeroed()];
+    let contents = if data.is_empty() { &zeroed[..] } else { data };
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: bytemuck::cast_slice(contents),
+        usage: wgpu::BufferUsages::STORAGE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::{
+        model::{
+            animation::{animation::PlaybackOptions, animation_compute::NODE_KIND_MESH},
+            loader::loader::GltfLoader,
+            model::LocalTransform,
+        },
+        scene::scene::GSceneData,
+    };
+
+    /// a device on whichever adapter is around, None if nothing can run compute shaders
+    fn test_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+                .ok()?;
+        if !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+        {
+            return None;
+        }
+        pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: adapter.limits(),
+            memory_hints: Default::default(),
+            trace: wgpu::Trace::Off,
+        }))
+        .ok()
+    }
+
+    fn read_buffer<T: bytemuck::Pod>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        buffer: &wgpu::Buffer,
+    ) -> Vec<T> {
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback buffer"),
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("readback encoder"),
+        });
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        queue.submit(std::iter::once(encoder.finish()));
+        staging
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::PollType::Wait).unwrap();
+        let data = bytemuck::cast_slice(&staging.slice(..).get_mapped_range()).to_vec();
+        data
+    }
+
+    fn assert_matrices_close(gpu: &[[[f32; 4]; 4]], cpu: &[[[f32; 4]; 4]], time: f32) {
+        assert_eq!(gpu.len(), cpu.len());
+        for (m, n) in gpu.iter().zip(cpu.iter()) {
+            for c in 0..4 {
+                for r in 0..4 {
+                    assert!(
+                        (m[c][r] - n[c][r]).abs() < 1e-4,
+                        "at {time}s: {:?} != {:?}",
+                        m,
+                        n
+                    );
+                }
+            }
+        }
+    }
+
+    /// run animation_compute.wgsl on the first animated model and compare what it writes
+    /// with the cpu path at the same timestamps
+    fn compare_shader_with_cpu(dir_name: &str, times: &[f32]) {
+        let Some((device, queue)) = test_device() else {
+            eprintln!("no adapter can run the animation compute shader, skipping");
+            return;
+        };
+        let gltf_data = GltfLoader::load_gltf(dir_name).unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_init(&device, 1.0);
+        let model_id = scene
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let instance = scene.get_instance_handle(0, model_id);
+        let options = PlaybackOptions {
+            looping: true,
+            ..Default::default()
+        };
+        scene.initialize_animation(instance, 0, options).unwrap();
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut pipeline = AnimationComputePipeline::new(&device, &scene);
+
+        for time in times {
+            let timestamp = start + Duration::from_secs_f32(*time);
+            let jobs = scene
+                .get_animation_compute_jobs(timestamp, pipeline.animation_data())
+                .unwrap()
+                .unwrap();
+            // every matrix the jobs should write starts out as nans, so anything the shader
+            // misses shows up
+            let nodes = &pipeline.animation_data().nodes;
+            let mut local_transforms = scene.get_local_transform_data().clone();
+            for job in jobs.iter() {
+                let job_nodes = &nodes[job.node_offset as usize..][..job.node_count as usize];
+                for node in job_nodes.iter().filter(|node| node.kind == NODE_KIND_MESH) {
+                    local_transforms[(job.lt_offset + node.output_index) as usize]
+                        .transform_matrix = [[f32::NAN; 4]; 4];
+                }
+            }
+            let local_buffer = scene.get_local_transform_buffer().as_ref().unwrap();
+            queue.write_buffer(local_buffer, 0, bytemuck::cast_slice(&local_transforms));
+            let joint_buffer = scene.get_joint_buf().unwrap();
+            let joint_count = scene.get_joint_transform_data().len();
+            queue.write_buffer(
+                joint_buffer,
+                0,
+                bytemuck::cast_slice(&vec![[[f32::NAN; 4]; 4]; joint_count]),
+            );
+            pipeline.dispatch(&device, &queue, &scene, &jobs);
+            let gpu_local: Vec<LocalTransform> = read_buffer(&device, &queue, local_buffer);
+            let gpu_joints: Vec<[[f32; 4]; 4]> = read_buffer(&device, &queue, joint_buffer);
+
+            assert!(scene.get_animation_frame(timestamp));
+            let cpu_local: Vec<[[f32; 4]; 4]> = scene
+                .get_local_transform_data()
+                .iter()
+                .map(|local_transform| local_transform.transform_matrix)
+                .collect();
+            let gpu_local: Vec<[[f32; 4]; 4]> = gpu_local
+                .iter()
+                .map(|local_transform| local_transform.transform_matrix)
+                .collect();
+            assert_matrices_close(&gpu_local, &cpu_local, *time);
+            assert_matrices_close(
+                &gpu_joints[..joint_count],
+                scene.get_joint_transform_data(),
+                *time,
+            );
+        }
+    }
+
+    #[test]
+    fn test_shader_matches_cpu_mesh_animation() {
+        compare_shader_with_cpu("box-animated", &[0.0, 0.3, 1.1, 1.25, 2.0, 3.3]);
+    }
+
+    #[test]
+    fn test_shader_matches_cpu_skeletal_animation() {
+        compare_shader_with_cpu("cesium-man", &[0.0, 0.1, 0.45, 0.9, 1.5]);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod app;
 mod app_config;
 pub mod app_state;
+pub mod baked;
+pub mod compute;
 mod util;
@@ -2,6 +2,11 @@ use std::sync::Arc;
 use wgpu::{BindGroupEntry, BindGroupLayoutEntry};
 use winit::window::Window;
 
+use crate::app::compute::AnimationBackend;
+use crate::model::animation::animation_state_machine::{
+    AnimationRef, AnimationState, AnimationStateMachine, AnimationTransition, TransitionCondition,
+};
+use crate::scene::indirect::INDIRECT_DRAW_FEATURES;
 #[allow(unused_imports)]
 use crate::scene::scene_scaffolds::{BOX_ANIMATED, BUGGY, FLEXY_BOX, FOX, MONKEY, POLLY};
 #[allow(unused_imports)]
@@ -59,25 +64,35 @@ pub(super) fn setup_global_instance_bind_group(
                     count: None,
                 }],
             });
-    let global_instance_bind_group =
-        app_config
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &global_instance_bind_group_layout,
-                entries: &[BindGroupEntry {
-                    binding: 1,
-                    resource: scene
-                        .get_global_buf()
-                        .expect("should be initialized")
-                        .as_entire_binding(),
-                }],
-                label: Some("Global bind group"),
-            });
+    let global_instance_bind_group = create_global_instance_bind_group(
+        &app_config.device,
+        &global_instance_bind_group_layout,
+        scene,
+    );
     (
         global_instance_bind_group_layout,
         global_instance_bind_group,
     )
 }
+/// the bind group of the scene's global transform buffer, which has to be recreated whenever
+/// the buffer is
+pub(super) fn create_global_instance_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    scene: &GScene,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[BindGroupEntry {
+            binding: 1,
+            resource: scene
+                .get_global_buf()
+                .expect("should be initialized")
+                .as_entire_binding(),
+        }],
+        label: Some("Global bind group"),
+    })
+}
 pub(super) async fn setup_config<'a>(window: Arc<Window>) -> AppConfig<'a> {
     let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
         ..Default::default()
@@ -97,7 +112,7 @@ pub(super) async fn setup_config<'a>(window: Arc<Window>) -> AppConfig<'a> {
     let (device, queue) = adapter
         .request_device(&wgpu::DeviceDescriptor {
             label: None,
-            required_features: wgpu::Features::empty(),
+            required_features: adapter.features() & INDIRECT_DRAW_FEATURES,
             required_limits: wgpu::Limits::default(),
             memory_hints: Default::default(),
             trace: wgpu::Trace::Off,
@@ -137,3 +152,25 @@ pub(super) async fn setup_config<'a>(window: Arc<Window>) -> AppConfig<'a> {
 pub(super) fn get_scene<'a>(device: &wgpu::Device, aspect_ratio: f32) -> GScene<'a> {
     BRAIN.create(device, aspect_ratio).unwrap()
 }
+
+/// The state machine driving the first instance of the first model. Setting the "key_1" or
+/// "key_2" trigger fades into the model's first or second animation
+pub(super) fn get_state_machine(animation_count: usize) -> AnimationStateMachine {
+    let mut state_machine = AnimationStateMachine::new();
+    let triggers = ["key_1", "key_2"];
+    for (animation_index, trigger) in triggers.into_iter().enumerate().take(animation_count) {
+        let state = state_machine.add_state(AnimationState::new(
+            trigger,
+            AnimationRef::Index(animation_index),
+        ));
+        state_machine.add_transition(
+            AnimationTransition::new(None, state)
+                .when(TransitionCondition::Trigger(trigger.to_string()))
+                .with_cross_fade(0.2),
+        );
+    }
+    state_machine
+}
+
+/// which path evaluates animations each frame
+pub(super) const ANIMATION_BACKEND: AnimationBackend = AnimationBackend::Cpu;
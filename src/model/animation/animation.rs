@@ -1,23 +1,49 @@
-use std::{collections::HashMap, fmt::Debug, rc::Rc, time::Duration};
+use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
 
 use cgmath::SquareMatrix;
 
 use crate::model::{
-    animation::{animation_controller::AnimationSample, animation_node::AnimationNode},
+    animation::{
+        animation_compute::ComputeUnsupported,
+        animation_controller::AnimationSample,
+        animation_events::{for_each_fired_event, FiredAnimationEvent},
+        animation_layers::AnimationLayer,
+        animation_node::AnimationNode,
+        joint_overrides::{apply_joint_overrides, JointOverride},
+        root_motion::{RootMotion, RootMotionState},
+    },
     model::ModelAnimationData,
 };
+use crate::scene::instances::InstanceHandle;
 
 pub(super) struct AnimationProcessingResult<'a> {
     pub(super) mesh_transforms: &'a [[[f32; 4]; 4]],
     pub(super) joint_transforms: &'a [[[f32; 4]; 4]],
     pub(super) joint_indices: &'a [usize],
+    pub(super) fired_events: Vec<FiredAnimationEvent>,
+    pub(super) root_motion_delta: Option<[[f32; 4]; 4]>,
     pub(super) is_done: bool,
 }
 pub struct AnimationFrame<'a> {
+    /// the instance each of the slices below belongs to
+    pub instances: Vec<InstanceHandle>,
     pub lt_offsets: Vec<usize>,
     pub mesh_transform_slices: Vec<&'a [[[f32; 4]; 4]]>,
     pub joint_ids: Vec<&'a [usize]>,
     pub joint_transform_slices: Vec<&'a [[[f32; 4]; 4]]>,
+    /// the events every instance passed this frame, in instance order
+    pub events: Vec<FiredAnimationEvent>,
+    /// (instance, transform) of the motion extracted from each instance's root this frame,
+    /// to be applied on the right of that instance's global transform
+    pub root_motion: Vec<(InstanceHandle, [[f32; 4]; 4])>,
+}
+
+/// How a newly started animation instance is played
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlaybackOptions {
+    /// restart from the beginning instead of finishing
+    pub looping: bool,
+    pub root_motion: RootMotion,
 }
 
 pub struct MeshAnimationInstance;
@@ -25,20 +51,40 @@ pub struct JointAnimationInstance;
 
 pub(super) struct AnimationInstance {
     /// the node tree for the model
-    pub(super) animation_node: Rc<AnimationNode>,
+    pub(super) animation_node: Arc<AnimationNode>,
+    /// the model instance playing the animation
+    pub(super) instance: InstanceHandle,
     /// the offset in the local transform buffer that this instance affects
     pub(super) model_instance_offset: usize,
     pub(super) start_time: Duration,
     pub(super) time_elapsed: Duration,
+    /// the time elapsed at the previous frame, used to find the events passed since then
+    pub(super) previous_time_elapsed: Option<f32>,
+    /// the time within the animation sampled this frame, wrapped around for looping instances
+    pub(super) sample_time: f32,
+    /// looping instances restart from the beginning instead of finishing
+    pub(super) looping: bool,
+    /// animations blended on top of this one, in order
+    pub(super) layers: Vec<AnimationLayer>,
+    /// procedural changes applied to the joints after sampling
+    pub(super) joint_overrides: Vec<JointOverride>,
+    /// the root motion extracted from this instance, if enabled
+    pub(super) root_motion: Option<RootMotionState>,
+    /// set once a non looping instance has played its last keyframe
+    pub(super) is_finished: bool,
     /// global index of the animation as defined in the gltf file
     pub(super) animation_index: usize,
+    /// the time of the last keyframe in this animation
+    pub(super) duration: f32,
     /// the set of transforms affected by the samplers
     /// of this instances node tree
     pub(super) mesh_transforms: Vec<[[f32; 4]; 4]>,
     pub(super) joint_transforms: Vec<[[f32; 4]; 4]>,
-    /// a map of sampler id -> sample
-    /// used to keep track of the last frames data
-    pub(super) current_samples: HashMap<usize, Option<AnimationSample>>,
+    /// the model space transform of every joint, before the inverse bind matrix is applied
+    pub(super) joint_globals: Vec<cgmath::Matrix4<f32>>,
+    /// a map of sampler id -> the keyframe interval sampled last frame.
+    /// only used as a starting point, sampling doesn't depend on it
+    pub(super) current_samples: HashMap<usize, AnimationSample>,
 }
 impl Debug for AnimationInstance {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -51,36 +97,119 @@ impl Debug for AnimationInstance {
 
 impl AnimationInstance {
     pub fn new(
-        animation_node: Rc<AnimationNode>,
+        animation_node: Arc<AnimationNode>,
+        instance: InstanceHandle,
         model_instance_offset: usize,
         start_time: Duration,
-        time_elapsed: Duration,
         animation_index: usize,
+        options: PlaybackOptions,
         mesh_transforms: Vec<[[f32; 4]; 4]>,
         joint_transforms: Vec<[[f32; 4]; 4]>,
-        current_samples: HashMap<usize, Option<AnimationSample>>,
     ) -> Self {
+        let mut current_samples = HashMap::new();
+        animation_node.get_default_samples(animation_index, &mut current_samples);
+        let duration = animation_node.get_animation_duration(animation_index);
+        let root_motion = match options.root_motion {
+            RootMotion::Off => None,
+            mode => animation_node.root_motion_node().map(|node_id| {
+                let sample = |time| {
+                    animation_node
+                        .sample_model_space_transform(
+                            node_id,
+                            animation_index,
+                            time,
+                            cgmath::Matrix4::identity(),
+                        )
+                        .unwrap()
+                };
+                RootMotionState::new(mode, node_id, sample(0.0), sample(duration))
+            }),
+        };
         Self {
             animation_node,
+            instance,
             model_instance_offset,
             start_time,
-            time_elapsed,
+            time_elapsed: Duration::ZERO,
+            previous_time_elapsed: None,
+            sample_time: 0.0,
+            looping: options.looping,
+            layers: Vec::new(),
+            joint_overrides: Vec::new(),
+            root_motion,
+            is_finished: false,
             animation_index,
+            duration,
             mesh_transforms,
+            joint_globals: vec![cgmath::Matrix4::identity(); joint_transforms.len()],
             joint_transforms,
             current_samples,
         }
     }
 
+    /// what keeps this instance from being animated by the compute shader, if anything
+    pub(super) fn compute_unsupported(&self) -> Option<ComputeUnsupported> {
+        if self.root_motion.is_some() {
+            Some(ComputeUnsupported::RootMotion(self.instance))
+        } else if !self.joint_overrides.is_empty() {
+            Some(ComputeUnsupported::JointOverrides(self.instance))
+        } else if !self.layers.is_empty() {
+            Some(ComputeUnsupported::Layers(self.instance))
+        } else {
+            None
+        }
+    }
+
+    /// Advance the clock of this instance to timestamp, pushing the events passed since the
+    /// last frame onto fired_events. Returns the time within the animation to sample
+    pub(super) fn advance(
+        &mut self,
+        timestamp: Duration,
+        animation_data: &ModelAnimationData,
+        fired_events: &mut Vec<FiredAnimationEvent>,
+    ) -> f32 {
+        self.time_elapsed = timestamp - self.start_time;
+        let elapsed = self.time_elapsed.as_secs_f32();
+        if let Some(events) = animation_data.animation_events.get(&self.animation_index) {
+            for_each_fired_event(
+                events,
+                self.previous_time_elapsed,
+                elapsed,
+                self.duration,
+                self.looping,
+                |event| {
+                    fired_events.push(FiredAnimationEvent {
+                        model_index: animation_data.model_index,
+                        instance: self.instance,
+                        animation_index: self.animation_index,
+                        name: event.name.clone(),
+                        time: event.time,
+                    })
+                },
+            );
+        }
+        self.previous_time_elapsed = Some(elapsed);
+        for layer in self.layers.iter_mut() {
+            layer.advance(timestamp);
+        }
+        self.sample_time = if self.looping && self.duration > 0.0 {
+            elapsed % self.duration
+        } else {
+            elapsed
+        };
+        self.sample_time
+    }
+
     /// given the current timestamp, mutate this instance's mesh transforms,
     /// and return it as a slice
     pub(super) fn process_animation_frame<'a>(
         &'a mut self,
         timestamp: Duration,
         animation_data: &'a ModelAnimationData,
-        skin_ibms: &HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+        inverse_bind_matrices: &[cgmath::Matrix4<f32>],
     ) -> AnimationProcessingResult<'a> {
-        self.time_elapsed = timestamp - self.start_time;
+        let mut fired_events = Vec::new();
+        self.advance(timestamp, animation_data, &mut fired_events);
         // im not sure if there a good way to do this without cloning the node RC
         // i dont think its a big problem, but its annoying.
         let node = self.animation_node.clone();
@@ -88,12 +217,37 @@ impl AnimationInstance {
             self,
             cgmath::Matrix4::<f32>::identity(),
             animation_data,
-            skin_ibms,
-        );
+            inverse_bind_matrices,
+        ) && !self.looping;
+        self.is_finished = done;
+        if !self.joint_overrides.is_empty() {
+            apply_joint_overrides(
+                &self.joint_overrides,
+                &node,
+                animation_data,
+                &mut self.joint_globals,
+            );
+            for (joint_index, global) in self.joint_globals.iter().enumerate() {
+                self.joint_transforms[joint_index] =
+                    (global * inverse_bind_matrices[joint_index]).into();
+            }
+        }
+        let loop_index = if self.looping && self.duration > 0.0 {
+            (self.time_elapsed.as_secs_f32() / self.duration).floor() as u32
+        } else {
+            0
+        };
+        let root_motion_delta = self
+            .root_motion
+            .as_mut()
+            .and_then(|root_motion| root_motion.take_delta(loop_index))
+            .map(|delta| delta.into());
         return AnimationProcessingResult {
             mesh_transforms: &self.mesh_transforms[..],
             joint_transforms: &self.joint_transforms[..],
             joint_indices: &animation_data.joint_animation_data.joint_indices[..],
+            fired_events,
+            root_motion_delta,
             is_done: done,
         };
     }
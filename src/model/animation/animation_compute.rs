@@ -0,0 +1,269 @@
+use std::fmt::Display;
+
+use crate::model::{
+    animation::{
+        animation_controller::AnimationTransforms,
+        animation_node::{AnimationNode, NodeType},
+        compression::dequantize_rotations,
+    },
+    model::{GModel, ModelAnimationData},
+};
+use crate::scene::instances::InstanceHandle;
+
+/// marks a node which has no samplers for any animation
+pub(super) const NO_CHANNELS: u32 = u32::MAX;
+pub const NODE_KIND_NODE: u32 = 0;
+pub const NODE_KIND_MESH: u32 = 1;
+pub const NODE_KIND_JOINT: u32 = 2;
+pub(super) const PROPERTY_ROTATION: u32 = 0;
+pub(super) const PROPERTY_TRANSLATION: u32 = 1;
+pub(super) const PROPERTY_SCALE: u32 = 2;
+
+/// What the animation compute shader can't evaluate. The cpu path handles all of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeUnsupported {
+    /// the instance extracts root motion, which would have to be taken out of the pose the
+    /// shader writes
+    RootMotion(InstanceHandle),
+    /// the instance has procedural joint overrides, which are solved on the cpu
+    JointOverrides(InstanceHandle),
+    /// the instance blends layers, which includes the cross fades of state machines
+    Layers(InstanceHandle),
+    /// instances are attached to the joints of the instance, which follow its own pose. The
+    /// shader writes the poses of every instance of a model to the same joint transforms
+    JointAttachments(InstanceHandle),
+}
+
+impl Display for ComputeUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RootMotion(instance) => write!(
+                f,
+                "{:?} extracts root motion, which the compute shader doesn't support",
+                instance
+            ),
+            Self::JointOverrides(instance) => write!(
+                f,
+                "{:?} has joint overrides, which the compute shader doesn't support",
+                instance
+            ),
+            Self::Layers(instance) => write!(
+                f,
+                "{:?} blends animation layers, which the compute shader doesn't support",
+                instance
+            ),
+            Self::JointAttachments(instance) => write!(
+                f,
+                "{:?} has instances attached to its joints, which the compute shader doesn't support",
+                instance
+            ),
+        }
+    }
+}
+
+/// An [AnimationNode] flattened for the compute shader.
+/// Nodes are stored depth first, so a parent always comes before its children
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuAnimationNode {
+    pub translation: [f32; 4],
+    /// x, y, z, w
+    pub rotation: [f32; 4],
+    pub scale: [f32; 4],
+    /// absolute index of the parent node, or -1 for the root of a model
+    pub parent: i32,
+    pub kind: u32,
+    /// the mesh slot (relative to the instance's local transform offset) or the joint index
+    pub output_index: u32,
+    /// offset into the channel range table. The range for animation a is at
+    /// channel_table + a, or NO_CHANNELS if the node is never animated
+    pub channel_table: u32,
+}
+
+/// A single [AnimationSampler](super::animation_controller::AnimationSampler), with its
+/// times and keyframes stored in the shared keyframe data array
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuAnimationChannel {
+    pub times_offset: u32,
+    /// keyframes are always 4 floats wide, translation and scale leave w unused
+    pub values_offset: u32,
+    pub key_count: u32,
+    pub property: u32,
+}
+
+/// One animation instance to evaluate this frame
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct AnimationComputeJob {
+    pub node_offset: u32,
+    pub node_count: u32,
+    pub animation_index: u32,
+    pub lt_offset: u32,
+    pub time: f32,
+    pub is_skeletal: u32,
+    _padding: [u32; 2],
+}
+impl AnimationComputeJob {
+    pub fn new(
+        node_range: (u32, u32),
+        animation_index: usize,
+        lt_offset: usize,
+        time: f32,
+        is_skeletal: bool,
+    ) -> Self {
+        Self {
+            node_offset: node_range.0,
+            node_count: node_range.1,
+            animation_index: animation_index as u32,
+            lt_offset: lt_offset as u32,
+            time,
+            is_skeletal: is_skeletal as u32,
+            _padding: [0; 2],
+        }
+    }
+}
+
+/// All of the animation data in a scene, laid out so it can be uploaded once
+/// into storage buffers and evaluated by the animation compute shader.
+pub struct AnimationComputeData {
+    pub nodes: Vec<GpuAnimationNode>,
+    /// (channel offset, channel count) for every animated node and animation
+    pub channel_ranges: Vec<[u32; 2]>,
+    pub channels: Vec<GpuAnimationChannel>,
+    /// sampler times and keyframes, addressed in floats by each channel
+    pub keyframe_data: Vec<f32>,
+    pub inverse_bind_matrices: Vec<[[f32; 4]; 4]>,
+    /// (node offset, node count) for each model, None if the model isn't animated
+    pub model_node_ranges: Vec<Option<(u32, u32)>>,
+    pub max_node_count: u32,
+}
+
+impl AnimationComputeData {
+    pub fn from_models(models: &[GModel], inverse_bind_matrices: &[cgmath::Matrix4<f32>]) -> Self {
+        let mut data = Self {
+            nodes: Vec::new(),
+            channel_ranges: Vec::new(),
+            channels: Vec::new(),
+            keyframe_data: Vec::new(),
+            inverse_bind_matrices: inverse_bind_matrices
+                .iter()
+                .map(|ibm| (*ibm).into())
+                .collect(),
+            model_node_ranges: Vec::with_capacity(models.len()),
+            max_node_count: 0,
+        };
+        // every node gets a slot for every animation index in the file, so that the
+        // shader can index the table directly
+        let animation_slots = models
+            .iter()
+            .filter_map(|model| model.animation_data.as_ref())
+            .map(|animation_data| animation_data.animation_node.max_animation_index() + 1)
+            .max()
+            .unwrap_or(0);
+
+        for model in models.iter() {
+            match &model.animation_data {
+                Some(animation_data) => {
+                    let node_offset = data.nodes.len() as u32;
+                    data.flatten_node(
+                        &animation_data.animation_node,
+                        -1,
+                        animation_data,
+                        animation_slots,
+                    );
+                    let node_count = data.nodes.len() as u32 - node_offset;
+                    data.max_node_count = data.max_node_count.max(node_count);
+                    data.model_node_ranges.push(Some((node_offset, node_count)));
+                }
+                None => data.model_node_ranges.push(None),
+            }
+        }
+        data
+    }
+
+    fn flatten_node(
+        &mut self,
+        node: &AnimationNode,
+        parent: i32,
+        animation_data: &ModelAnimationData,
+        animation_slots: usize,
+    ) {
+        let (kind, output_index) = match node.node_type {
+            NodeType::Node => (NODE_KIND_NODE, 0),
+            NodeType::Mesh => (
+                NODE_KIND_MESH,
+                *animation_data
+                    .mesh_animation_data
+                    .node_to_lt_index
+                    .get(&node.node_id)
+                    .unwrap() as u32,
+            ),
+            NodeType::Joint(ibm_idx) => (NODE_KIND_JOINT, ibm_idx as u32),
+        };
+        let channel_table = match &node.samplers {
+            Some(sampler_map) => {
+                let table = self.channel_ranges.len() as u32;
+                for animation_index in 0..animation_slots {
+                    let channel_offset = self.channels.len() as u32;
+                    if let Some(samplers) = sampler_map.get(&animation_index) {
+                        for sampler in samplers {
+                            let times_offset = self.keyframe_data.len() as u32;
+                            self.keyframe_data.extend(sampler.times.iter());
+                            let values_offset = self.keyframe_data.len() as u32;
+                            let property = match &sampler.transforms {
+                                AnimationTransforms::Rotation(quats) => {
+                                    for q in quats {
+                                        self.keyframe_data.extend([q.v.x, q.v.y, q.v.z, q.s]);
+                                    }
+                                    PROPERTY_ROTATION
+                                }
+                                AnimationTransforms::QuantizedRotation(quats) => {
+                                    for q in dequantize_rotations(quats) {
+                                        self.keyframe_data.extend([q.v.x, q.v.y, q.v.z, q.s]);
+                                    }
+                                    PROPERTY_ROTATION
+                                }
+                                AnimationTransforms::Translation(vecs) => {
+                                    for v in vecs {
+                                        self.keyframe_data.extend([v.x, v.y, v.z, 0.0]);
+                                    }
+                                    PROPERTY_TRANSLATION
+                                }
+                                AnimationTransforms::Scale(vecs) => {
+                                    for v in vecs {
+                                        self.keyframe_data.extend([v.x, v.y, v.z, 0.0]);
+                                    }
+                                    PROPERTY_SCALE
+                                }
+                            };
+                            self.channels.push(GpuAnimationChannel {
+                                times_offset,
+                                values_offset,
+                                key_count: sampler.times.len() as u32,
+                                property,
+                            });
+                        }
+                    }
+                    self.channel_ranges
+                        .push([channel_offset, self.channels.len() as u32 - channel_offset]);
+                }
+                table
+            }
+            None => NO_CHANNELS,
+        };
+        let index = self.nodes.len() as i32;
+        self.nodes.push(GpuAnimationNode {
+            translation: [node.trans.x, node.trans.y, node.trans.z, 0.0],
+            rotation: [node.rot.v.x, node.rot.v.y, node.rot.v.z, node.rot.s],
+            scale: [node.scale.x, node.scale.y, node.scale.z, 0.0],
+            parent,
+            kind,
+            output_index,
+            channel_table,
+        });
+        for child in node.children.iter() {
+            self.flatten_node(child, index, animation_data, animation_slots);
+        }
+    }
+}
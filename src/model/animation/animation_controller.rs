@@ -3,16 +3,32 @@ use std::{
     time::{Duration, UNIX_EPOCH},
 };
 
+use cgmath::InnerSpace;
 use gltf::animation::Channel;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 use crate::model::{
     animation::{
         animation::*,
+        animation_compute::{AnimationComputeData, AnimationComputeJob, ComputeUnsupported},
+        animation_events::FiredAnimationEvent,
+        animation_layers::{AnimationLayer, LayerBlend},
+        animation_state_machine::{
+            AnimationParameter, AnimationStateMachine, CrossFade, StateMachineError,
+            StateMachineInstance,
+        },
+        compression::QuantizedQuaternion,
+        joint_overrides::JointOverride,
         util::{AnimationType, InterpolationType},
     },
     model::{GModel, ModelAnimationData},
     util::{copy_binary_data_from_gltf, AttributeType},
 };
+use crate::scene::instances::InstanceHandle;
+
+/// below this many active instances, the cost of handing work to the thread pool
+/// outweighs sampling everything on the calling thread
+const PARALLEL_ANIMATION_THRESHOLD: usize = 4;
 
 /// Keeps track of which animations are currently playing.
 /// The controllers functions are
@@ -23,7 +39,10 @@ pub struct SceneAnimationController {
     dead_animations: Vec<usize>,
     pub(super) active_animations: Vec<VecDeque<AnimationInstance>>,
     pub(super) active_animation_count: usize,
-    pub(super) skin_ibms: HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+    /// the inverse bind matrix of every joint, by joint index
+    pub(super) inverse_bind_matrices: Vec<cgmath::Matrix4<f32>>,
+    /// for every model, the state machine driving each of its instances that has one
+    state_machines: Vec<HashMap<InstanceHandle, StateMachineInstance>>,
 }
 
 impl SceneAnimationController {
@@ -36,112 +55,553 @@ impl SceneAnimationController {
             dead_animations: vec![0; model_no],
             active_animations,
             active_animation_count: 0,
-            skin_ibms,
+            inverse_bind_matrices: joint_inverse_bind_matrices(&skin_ibms),
+            state_machines: (0..model_no).map(|_| HashMap::new()).collect(),
         }
     }
 
     pub fn initialize_animation(
         &mut self,
         animation_data: &ModelAnimationData,
+        instance: InstanceHandle,
         animation_index: usize,
         model_instance_offset: usize,
         model_mesh_instance_count: usize,
-        model_joint_instance_count: usize,
+        options: PlaybackOptions,
+    ) {
+        let start_time = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        self.start_animation(
+            animation_data,
+            instance,
+            animation_index,
+            (model_instance_offset, model_mesh_instance_count),
+            options,
+            start_time,
+        );
+    }
+
+    /// target is the local transform offset and mesh count the instance writes to
+    fn start_animation(
+        &mut self,
+        animation_data: &ModelAnimationData,
+        instance: InstanceHandle,
+        animation_index: usize,
+        target: (usize, usize),
+        options: PlaybackOptions,
+        start_time: Duration,
     ) {
+        let (model_instance_offset, model_mesh_instance_count) = target;
         let animation_node = animation_data.animation_node.clone();
         let mut mesh_transforms: Vec<[[f32; 4]; 4]> = Vec::with_capacity(model_mesh_instance_count);
         let mut joint_transforms: Vec<[[f32; 4]; 4]> =
-            Vec::with_capacity(model_joint_instance_count);
-        let mut sample_map = HashMap::<usize, Option<AnimationSample>>::new();
-        animation_node.get_default_samples(animation_index, &mut sample_map);
+            Vec::with_capacity(animation_data.joint_animation_data.joint_count);
         animation_node.initialize_sampled_transforms(&mut mesh_transforms, &mut joint_transforms);
-        let start_time = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap();
 
         let animation_instance = AnimationInstance::new(
             animation_node,
+            instance,
             model_instance_offset,
             start_time,
-            Duration::ZERO,
             animation_index,
+            options,
             mesh_transforms,
             joint_transforms,
-            sample_map,
         );
         self.active_animations[animation_data.model_index].push_back(animation_instance);
         self.active_animation_count += 1;
     }
 
+    /// mark every animation playing on the given model instance as finished
+    pub fn stop_animations(&mut self, model_index: usize, handle: InstanceHandle) {
+        for instance in self.active_animations[model_index].iter_mut() {
+            if instance.instance == handle && !instance.is_finished {
+                instance.is_finished = true;
+                self.dead_animations[model_index] += 1;
+            }
+        }
+    }
+
+    /// an instance of the model other than the given one that is still playing something, or
+    /// is driven by a state machine
+    pub fn other_animated_instance(
+        &self,
+        model_index: usize,
+        instance: InstanceHandle,
+    ) -> Option<InstanceHandle> {
+        self.active_animations[model_index]
+            .iter()
+            .filter(|animation| !animation.is_finished)
+            .map(|animation| animation.instance)
+            .chain(self.state_machines[model_index].keys().copied())
+            .find(|other| *other != instance)
+    }
+
+    /// Let a state machine decide what the given model instance plays from the next frame on,
+    /// replacing any previous state machine of that instance. target_of gives the local
+    /// transform offset and mesh count an animation index writes to
+    pub fn set_state_machine(
+        &mut self,
+        animation_data: &ModelAnimationData,
+        instance: InstanceHandle,
+        state_machine: AnimationStateMachine,
+        target_of: impl Fn(usize) -> (usize, usize),
+    ) -> Result<(), StateMachineError> {
+        let state_machine = StateMachineInstance::new(state_machine, animation_data, target_of)?;
+        self.state_machines[animation_data.model_index].insert(instance, state_machine);
+        Ok(())
+    }
+
+    pub fn remove_state_machine(&mut self, model_index: usize, instance: InstanceHandle) {
+        self.state_machines[model_index].remove(&instance);
+    }
+
+    /// Follow model instances that moved in the local transform buffer. new_offset maps the
+    /// old local transform offset of an instance to its new one.
+    /// Skeletal animations write at offset 0, which stays the first instance's
+    pub fn relocate_instances(&mut self, new_offset: impl Fn(usize) -> usize) {
+        // finished animations may belong to a removed instance, which has no new offset
+        for instance in self
+            .active_animations
+            .iter_mut()
+            .flatten()
+            .filter(|instance| !instance.is_finished)
+        {
+            instance.model_instance_offset = new_offset(instance.model_instance_offset);
+        }
+        for state_machine in self
+            .state_machines
+            .iter_mut()
+            .flat_map(|state_machines| state_machines.values_mut())
+        {
+            for target in state_machine.targets.iter_mut() {
+                target.model_instance_offset = new_offset(target.model_instance_offset);
+            }
+        }
+    }
+
+    /// set a parameter of the state machine driving the given model instance,
+    /// returns false if there is no such state machine
+    pub fn set_animation_parameter(
+        &mut self,
+        model_index: usize,
+        instance: InstanceHandle,
+        name: &str,
+        value: AnimationParameter,
+    ) -> bool {
+        let Some(state_machine) = self.state_machines[model_index].get_mut(&instance) else {
+            return false;
+        };
+        state_machine.parameters.insert(name.to_string(), value);
+        true
+    }
+
+    /// the name of the state the given model instance is in, or is fading into
+    pub fn get_animation_state(
+        &self,
+        model_index: usize,
+        instance: InstanceHandle,
+    ) -> Option<&str> {
+        let state_machine = self.state_machines[model_index].get(&instance)?;
+        let state = match state_machine.cross_fade {
+            Some(cross_fade) => cross_fade.to,
+            None => state_machine.current_state?,
+        };
+        Some(&state_machine.machine.states[state].name)
+    }
+
+    /// take the transitions of every state machine, starting and stopping animations and
+    /// advancing cross fades as needed
+    fn update_state_machines(&mut self, timestamp: Duration, models: &[GModel]) {
+        for (model_index, model) in models.iter().enumerate() {
+            if self.state_machines[model_index].is_empty() {
+                continue;
+            }
+            let animation_data = model.animation_data.as_ref().unwrap();
+            let mut state_machines = std::mem::take(&mut self.state_machines[model_index]);
+            for (instance, state_machine) in state_machines.iter_mut() {
+                self.update_state_machine(*instance, state_machine, timestamp, animation_data);
+            }
+            self.state_machines[model_index] = state_machines;
+        }
+    }
+
+    fn update_state_machine(
+        &mut self,
+        instance: InstanceHandle,
+        state_machine: &mut StateMachineInstance,
+        timestamp: Duration,
+        animation_data: &ModelAnimationData,
+    ) {
+        if !state_machine.is_started {
+            state_machine.is_started = true;
+            if let Some(initial_state) = state_machine.machine.initial_state {
+                self.enter_state(
+                    instance,
+                    state_machine,
+                    initial_state,
+                    timestamp,
+                    animation_data,
+                );
+            }
+        }
+        if let Some(cross_fade) = state_machine.cross_fade {
+            // transitions wait for the fade to finish
+            let progress = timestamp
+                .saturating_sub(cross_fade.start_time)
+                .as_secs_f32()
+                / cross_fade.duration;
+            let to_animation = state_machine.targets[cross_fade.to].animation_index;
+            let layer = self
+                .playing_instance(instance, state_machine, animation_data.model_index)
+                .and_then(|playing| {
+                    playing.layers.iter_mut().find(|layer| {
+                        layer.animation_index == to_animation
+                            && layer.start_time == cross_fade.start_time
+                    })
+                });
+            match layer {
+                Some(layer) if progress < 1.0 => layer.weight = progress,
+                // the fade is over, or the state faded from finished before it was
+                _ => self.enter_state(
+                    instance,
+                    state_machine,
+                    cross_fade.to,
+                    cross_fade.start_time,
+                    animation_data,
+                ),
+            }
+            return;
+        }
+
+        let state_duration = state_machine.current_state.map_or(0.0, |state| {
+            animation_data
+                .animation_node
+                .get_animation_duration(state_machine.targets[state].animation_index)
+        });
+        let Some(transition) = state_machine.take_transition(timestamp, state_duration) else {
+            return;
+        };
+        let to_target = state_machine.targets[transition.to];
+        let options = state_machine.machine.states[transition.to].options;
+        // the next state is blended on top of the current one as a layer, which only works
+        // while both write to the same transforms
+        let shares_target = state_machine.current_state.is_some_and(|state| {
+            state_machine.targets[state].model_instance_offset == to_target.model_instance_offset
+        });
+        let playing_instance = match transition.cross_fade > 0.0 && shares_target {
+            true => self.playing_instance(instance, state_machine, animation_data.model_index),
+            false => None,
+        };
+        match playing_instance {
+            Some(playing) => {
+                let mut layer =
+                    AnimationLayer::new(to_target.animation_index, LayerBlend::Override)
+                        .with_weight(0.0)
+                        .with_looping(options.looping);
+                layer.start_time = timestamp;
+                layer.duration = playing
+                    .animation_node
+                    .get_animation_duration(to_target.animation_index);
+                playing.layers.push(layer);
+                state_machine.cross_fade = Some(CrossFade {
+                    to: transition.to,
+                    start_time: timestamp,
+                    duration: transition.cross_fade,
+                });
+            }
+            None => self.enter_state(
+                instance,
+                state_machine,
+                transition.to,
+                timestamp,
+                animation_data,
+            ),
+        }
+    }
+
+    /// the instance playing the current state of state_machine, if it hasn't finished
+    fn playing_instance(
+        &mut self,
+        handle: InstanceHandle,
+        state_machine: &StateMachineInstance,
+        model_index: usize,
+    ) -> Option<&mut AnimationInstance> {
+        let target = state_machine.targets[state_machine.current_state?];
+        self.active_animations[model_index]
+            .iter_mut()
+            .rev()
+            .find(|instance| {
+                instance.instance == handle
+                    && instance.animation_index == target.animation_index
+                    && !instance.is_finished
+            })
+    }
+
+    /// stop the current state's animation and start playing state from start_time
+    fn enter_state(
+        &mut self,
+        instance: InstanceHandle,
+        state_machine: &mut StateMachineInstance,
+        state: usize,
+        start_time: Duration,
+        animation_data: &ModelAnimationData,
+    ) {
+        let target = state_machine.targets[state];
+        if state_machine.current_state.is_some() {
+            self.stop_animations(animation_data.model_index, instance);
+        }
+        self.start_animation(
+            animation_data,
+            instance,
+            target.animation_index,
+            (target.model_instance_offset, target.mesh_count),
+            state_machine.machine.states[state].options,
+            start_time,
+        );
+        state_machine.current_state = Some(state);
+        state_machine.state_start_time = start_time;
+        state_machine.cross_fade = None;
+    }
+
+    /// Blend a layer on top of the most recently started animation of the given instance.
+    /// Returns false if that instance isn't playing anything
+    pub fn add_animation_layer(
+        &mut self,
+        model_index: usize,
+        handle: InstanceHandle,
+        mut layer: AnimationLayer,
+    ) -> bool {
+        let Some(instance) = self.active_animations[model_index]
+            .iter_mut()
+            .rev()
+            .find(|instance| instance.instance == handle)
+        else {
+            return false;
+        };
+        layer.start_time = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        layer.duration = instance
+            .animation_node
+            .get_animation_duration(layer.animation_index);
+        instance.layers.push(layer);
+        true
+    }
+
+    /// remove every layer from the animations of the given instance
+    pub fn clear_animation_layers(&mut self, model_index: usize, handle: InstanceHandle) {
+        for instance in self.active_animations[model_index].iter_mut() {
+            if instance.instance == handle {
+                instance.layers.clear();
+            }
+        }
+    }
+
+    /// Replace the procedural joint overrides of the most recently started animation of the
+    /// given instance. Returns false if that instance isn't playing anything. Overrides of nodes
+    /// that aren't joints are skipped, [super::joint_overrides::find_unknown_joint] finds them
+    /// beforehand
+    pub fn set_joint_overrides(
+        &mut self,
+        model_index: usize,
+        handle: InstanceHandle,
+        joint_overrides: Vec<JointOverride>,
+    ) -> bool {
+        let Some(instance) = self.active_animations[model_index]
+            .iter_mut()
+            .rev()
+            .find(|instance| instance.instance == handle)
+        else {
+            return false;
+        };
+        instance.joint_overrides = joint_overrides;
+        true
+    }
+
+    pub fn inverse_bind_matrices(&self) -> &[cgmath::Matrix4<f32>] {
+        &self.inverse_bind_matrices
+    }
+
+    /// process any animations that were marked as done last frame.
+    /// looping instances never finish, so the finished ones aren't necessarily at the front
+    fn remove_dead_animations(&mut self) {
+        for (idx, dead_animation_count) in self.dead_animations.iter_mut().enumerate() {
+            if *dead_animation_count == 0 {
+                continue;
+            }
+            self.active_animations[idx].retain(|instance| !instance.is_finished);
+            self.active_animation_count -= *dead_animation_count;
+            *dead_animation_count = 0;
+        }
+    }
+
+    /// The compute shader counterpart to [Self::do_animations]. Advances the clock of every
+    /// active animation and describes the work for the gpu, without sampling anything here.
+    /// Fails without advancing anything if an instance needs what the shader can't do.
+    pub fn get_compute_jobs(
+        &mut self,
+        timestamp: Duration,
+        models: &[GModel],
+        compute_data: &AnimationComputeData,
+        fired_events: &mut Vec<FiredAnimationEvent>,
+    ) -> Result<Option<Vec<AnimationComputeJob>>, ComputeUnsupported> {
+        self.update_state_machines(timestamp, models);
+        self.remove_dead_animations();
+        if self.active_animation_count == 0 {
+            return Ok(None);
+        }
+        if let Some(unsupported) = self
+            .active_animations
+            .iter()
+            .flatten()
+            .find_map(AnimationInstance::compute_unsupported)
+        {
+            return Err(unsupported);
+        }
+        let mut jobs = Vec::with_capacity(self.active_animation_count);
+        for (idx, bucket) in self.active_animations.iter_mut().enumerate() {
+            if bucket.is_empty() {
+                continue;
+            }
+            let animation_data = models[idx].animation_data.as_ref().unwrap();
+            let node_range = compute_data.model_node_ranges[idx]
+                .expect("animated models should have a node range");
+            for animation_instance in bucket.iter_mut() {
+                let time = animation_instance.advance(timestamp, animation_data, fired_events);
+                jobs.push(AnimationComputeJob::new(
+                    node_range,
+                    animation_instance.animation_index,
+                    animation_instance.model_instance_offset,
+                    time,
+                    animation_data.is_skeletal,
+                ));
+                // like the cpu path, the final pose is written once before the instance is removed
+                if !animation_instance.looping && time >= animation_instance.duration {
+                    animation_instance.is_finished = true;
+                    self.dead_animations[idx] += 1;
+                }
+            }
+        }
+        Ok(Some(jobs))
+    }
+
     pub fn do_animations<'a>(
         &'a mut self,
         timestamp: Duration,
         models: &'a Vec<GModel>,
     ) -> Option<AnimationFrame<'a>> {
-        // process any animations that were marked as done last frame
-        for (idx, dead_animation_count) in self.dead_animations.iter_mut().enumerate() {
-            let count = dead_animation_count.clone();
-            for _ in 0..count {
-                self.active_animations[idx].pop_front();
-                *dead_animation_count -= 1;
-                self.active_animation_count -= 1;
-            }
-        }
+        let parallel = self.active_animation_count >= PARALLEL_ANIMATION_THRESHOLD;
+        self.process_animations(timestamp, models, parallel)
+    }
+
+    /// Samples every active instance, either on the rayon thread pool or on this thread.
+    /// Instances only ever write to their own transforms, and the results are collected
+    /// in bucket order, so both paths produce the same frame.
+    pub(super) fn process_animations<'a>(
+        &'a mut self,
+        timestamp: Duration,
+        models: &'a [GModel],
+        parallel: bool,
+    ) -> Option<AnimationFrame<'a>> {
+        self.update_state_machines(timestamp, models);
+        self.remove_dead_animations();
 
         // if there are no active animations, do nothing
         if self.active_animation_count == 0 {
             return None;
         }
-        let len = self.active_animations.len();
+        let Self {
+            dead_animations,
+            active_animations,
+            inverse_bind_matrices,
+            ..
+        } = self;
+        let inverse_bind_matrices = &inverse_bind_matrices[..];
 
+        let instances: Vec<(usize, &'a mut AnimationInstance)> = active_animations
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(idx, bucket)| bucket.iter_mut().map(move |instance| (idx, instance)))
+            .collect();
+        let process = |(idx, animation_instance): (usize, &'a mut AnimationInstance)| {
+            let animation_data = models[idx].animation_data.as_ref().unwrap();
+            let lt_offset = animation_instance.model_instance_offset;
+            let instance = animation_instance.instance;
+            let result = animation_instance.process_animation_frame(
+                timestamp,
+                animation_data,
+                inverse_bind_matrices,
+            );
+            (idx, lt_offset, instance, result)
+        };
+        let results: Vec<_> = if parallel {
+            instances.into_par_iter().map(process).collect()
+        } else {
+            instances.into_iter().map(process).collect()
+        };
+
+        let len = results.len();
         let mut frame = AnimationFrame {
+            instances: Vec::with_capacity(len),
             mesh_transform_slices: Vec::with_capacity(len),
             joint_transform_slices: Vec::with_capacity(len),
-            joint_ids: Vec::new(),
+            joint_ids: Vec::with_capacity(len),
             lt_offsets: Vec::with_capacity(len),
+            events: Vec::new(),
+            root_motion: Vec::new(),
         };
-
-        for (idx, bucket) in self.active_animations.iter_mut().enumerate() {
-            if bucket.len() == 0 {
-                continue;
+        for (idx, lt_offset, instance, animation_processing_result) in results {
+            frame.instances.push(instance);
+            frame.lt_offsets.push(lt_offset);
+            frame
+                .mesh_transform_slices
+                .push(animation_processing_result.mesh_transforms);
+            frame
+                .joint_transform_slices
+                .push(animation_processing_result.joint_transforms);
+            frame
+                .joint_ids
+                .push(animation_processing_result.joint_indices);
+            frame
+                .events
+                .extend(animation_processing_result.fired_events);
+            if let Some(delta) = animation_processing_result.root_motion_delta {
+                frame.root_motion.push((instance, delta));
             }
-            let animation_data = &models[idx].animation_data.as_ref().unwrap();
-            for animation_instance in bucket.iter_mut() {
-                frame
-                    .lt_offsets
-                    .push(animation_instance.model_instance_offset);
-                let animation_processing_result = animation_instance.process_animation_frame(
-                    timestamp,
-                    animation_data,
-                    &self.skin_ibms,
-                );
-                frame
-                    .mesh_transform_slices
-                    .push(animation_processing_result.mesh_transforms);
-                frame
-                    .joint_transform_slices
-                    .push(animation_processing_result.joint_transforms);
-                frame
-                    .joint_ids
-                    .push(animation_processing_result.joint_indices);
-                if animation_processing_result.is_done {
-                    self.dead_animations[idx] += 1;
-                }
+            if animation_processing_result.is_done {
+                dead_animations[idx] += 1;
             }
         }
         Some(frame)
     }
 }
 
+/// the loader numbers joints across every skin in skin order, so the matrices of each skin go
+/// one after another in that same order
+fn joint_inverse_bind_matrices(
+    skin_ibms: &HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+) -> Vec<cgmath::Matrix4<f32>> {
+    let mut skins: Vec<_> = skin_ibms.iter().collect();
+    skins.sort_by_key(|(skin_index, _)| **skin_index);
+    skins
+        .into_iter()
+        .flat_map(|(_, ibms)| ibms.iter().copied())
+        .collect()
+}
+
 #[derive(Copy, Clone, Debug)]
 pub(super) enum SampleResult {
     Active(AnimationSample),
     Done(usize),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AnimationTransforms {
     Rotation(Vec<cgmath::Quaternion<f32>>),
+    /// rotations compressed to 48 bits each, see [compress_sampler](super::compression::compress_sampler)
+    QuantizedRotation(Vec<QuantizedQuaternion>),
     Translation(Vec<cgmath::Vector3<f32>>),
     Scale(Vec<cgmath::Vector3<f32>>),
 }
@@ -149,6 +609,7 @@ impl AnimationTransforms {
     pub(super) fn len(&self) -> usize {
         match self {
             Self::Rotation(r) => r.len(),
+            Self::QuantizedRotation(r) => r.len(),
             Self::Translation(t) => t.len(),
             Self::Scale(s) => s.len(),
         }
@@ -197,13 +658,21 @@ impl AnimationTransforms {
         }
     }
 }
-#[derive(Debug, Clone, Copy)]
+/// a single sampled (and interpolated) keyframe value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimationValue {
+    Rotation(cgmath::Quaternion<f32>),
+    Translation(cgmath::Vector3<f32>),
+    Scale(cgmath::Vector3<f32>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(super) struct AnimationSample {
     pub(super) end_time: f32,
     pub(super) transform_index: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AnimationSampler {
     pub(super) id: usize,
     pub interpolation: InterpolationType,
@@ -267,32 +736,84 @@ impl AnimationSampler {
         }
     }
 
-    pub(super) fn sample(
+    /// Finds the keyframe interval containing time, starting from the cached cursor.
+    /// The cursor is only an optimization, when time is outside of it (seeking
+    /// backwards or jumping ahead) the interval is found with a binary search
+    pub(super) fn sample(&self, cursor: AnimationSample, time: f32) -> SampleResult {
+        let start_time = match cursor.transform_index {
+            -1 => f32::NEG_INFINITY,
+            i => self.times[i as usize],
+        };
+        if time >= start_time && time < cursor.end_time {
+            return SampleResult::Active(cursor);
+        }
+        self.locate(time)
+    }
+
+    /// stateless lookup of the keyframe interval containing time
+    pub(super) fn locate(&self, time: f32) -> SampleResult {
+        let last_index = self.times.len() - 1;
+        if time >= self.times[last_index] {
+            return SampleResult::Done(last_index);
+        }
+        // the number of keyframes at or before time, the interval starts at the last of them
+        let next = self.times.partition_point(|t| *t <= time);
+        SampleResult::Active(AnimationSample {
+            end_time: self.times[next],
+            transform_index: next as i32 - 1,
+        })
+    }
+
+    /// The interpolated value of this sampler at time, or None if its first keyframe
+    /// is still in the future. Does not depend on any previously sampled time,
+    /// so it can be used for scrubbing and reverse playback
+    pub fn sample_at(&self, time: f32) -> Option<AnimationValue> {
+        self.value_of(self.locate(time), time)
+    }
+
+    pub(super) fn value_of(
         &self,
-        current_sample: AnimationSample,
-        time_elapsed: Duration,
-    ) -> SampleResult {
-        // if the current time elapsed has surpassed the threshold time for this sample
-        // we need to calculate a new sample
-        if time_elapsed.as_secs_f32() >= current_sample.end_time {
-            let idx = (current_sample.transform_index + 1) as usize;
-            // loop through the times after the current time
-            // skipping the first time, as that is already the end time
-            // if we hit a time that is greater than the time elapsed, at times[i]
-            // we know that times[i] is our new end time, and i - 1 is our new t index
-            // if we reach the end of the times, this sampler is done, return None
-            for i in (idx..self.times.len()).skip(1) {
-                if time_elapsed.as_secs_f32() > self.times[i] {
-                    continue;
-                } else {
-                    return SampleResult::Active(AnimationSample {
-                        end_time: self.times[i],
-                        transform_index: (i as i32 - 1),
-                    });
-                }
+        sample_result: SampleResult,
+        time: f32,
+    ) -> Option<AnimationValue> {
+        match sample_result {
+            SampleResult::Active(AnimationSample {
+                transform_index: -1,
+                ..
+            }) => None,
+            SampleResult::Active(sample) => {
+                let i = sample.transform_index as usize;
+                let amount = (time - self.times[i]) / (self.times[i + 1] - self.times[i]);
+                Some(match &self.transforms {
+                    AnimationTransforms::Rotation(quats) => {
+                        AnimationValue::Rotation(quats[i].nlerp(quats[i + 1], amount))
+                    }
+                    AnimationTransforms::QuantizedRotation(quats) => {
+                        // decoded in place, keeping the pair in the same hemisphere like
+                        // dequantize_rotations does
+                        let from = quats[i].to_quaternion();
+                        let to = quats[i + 1].to_quaternion();
+                        let to = if from.dot(to) < 0.0 { -to } else { to };
+                        AnimationValue::Rotation(from.nlerp(to, amount))
+                    }
+                    AnimationTransforms::Translation(vecs) => {
+                        AnimationValue::Translation(vecs[i] + (vecs[i + 1] - vecs[i]) * amount)
+                    }
+                    AnimationTransforms::Scale(vecs) => {
+                        AnimationValue::Scale(vecs[i] + (vecs[i + 1] - vecs[i]) * amount)
+                    }
+                })
             }
-            return SampleResult::Done(self.times.len() - 1);
+            SampleResult::Done(last_index) => Some(match &self.transforms {
+                AnimationTransforms::Rotation(quats) => AnimationValue::Rotation(quats[last_index]),
+                AnimationTransforms::QuantizedRotation(quats) => {
+                    AnimationValue::Rotation(quats[last_index].to_quaternion())
+                }
+                AnimationTransforms::Translation(vecs) => {
+                    AnimationValue::Translation(vecs[last_index])
+                }
+                AnimationTransforms::Scale(vecs) => AnimationValue::Scale(vecs[last_index]),
+            }),
         }
-        return SampleResult::Active(current_sample);
     }
 }
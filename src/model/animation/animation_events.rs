@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use crate::scene::instances::InstanceHandle;
+
+/// A named point on an animation's timeline, e.g. a footstep at 0.4 seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationEvent {
+    pub name: String,
+    /// seconds from the start of the animation
+    pub time: f32,
+}
+
+/// An [AnimationEvent] which an animation instance passed during the last frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiredAnimationEvent {
+    pub model_index: usize,
+    /// the instance that fired the event
+    pub instance: InstanceHandle,
+    /// global index of the animation as defined in the gltf file
+    pub animation_index: usize,
+    pub name: String,
+    pub time: f32,
+}
+
+/// animation index -> the events of that animation, sorted by time
+pub type AnimationEventMap = HashMap<usize, Vec<AnimationEvent>>;
+
+/// Reads the events stored in the extras of each animation, in the form
+/// `{ "events": [{ "name": "footstep", "time": 0.4 }] }`.
+/// Malformed entries are skipped rather than failing the whole file.
+pub fn load_animation_events(animations: gltf::iter::Animations) -> AnimationEventMap {
+    let mut event_map = AnimationEventMap::new();
+    for animation in animations {
+        let Some(extras) = animation.extras() else {
+            continue;
+        };
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(extras.get()) else {
+            continue;
+        };
+        let Some(events) = value.get("events").and_then(|events| events.as_array()) else {
+            continue;
+        };
+        for event in events {
+            let name = event.get("name").and_then(|name| name.as_str());
+            let time = event.get("time").and_then(|time| time.as_f64());
+            if let (Some(name), Some(time)) = (name, time) {
+                insert_event(
+                    event_map.entry(animation.index()).or_default(),
+                    AnimationEvent {
+                        name: name.to_string(),
+                        time: time as f32,
+                    },
+                );
+            }
+        }
+    }
+    event_map
+}
+
+/// insert an event, keeping the events sorted by time
+pub(crate) fn insert_event(events: &mut Vec<AnimationEvent>, event: AnimationEvent) {
+    let index = events.partition_point(|e| e.time <= event.time);
+    events.insert(index, event);
+}
+
+/// Calls fire for every event between from (exclusive) and to (inclusive), where both times
+/// count from the start of the first playthrough. A looping animation fires its events again
+/// on every loop, so a single frame that wraps around fires the end of one loop and the start
+/// of the next. If from is None, the instance just started and events at time 0 fire too.
+pub(super) fn for_each_fired_event(
+    events: &[AnimationEvent],
+    from: Option<f32>,
+    to: f32,
+    duration: f32,
+    looping: bool,
+    mut fire: impl FnMut(&AnimationEvent),
+) {
+    let (first_loop, last_loop) = if looping && duration > 0.0 {
+        (
+            from.map_or(0.0, |from| (from / duration).floor()),
+            (to / duration).floor(),
+        )
+    } else {
+        (0.0, 0.0)
+    };
+    let mut loop_index = first_loop;
+    while loop_index <= last_loop {
+        for event in events {
+            let time = loop_index * duration + event.time;
+            let after_from = match from {
+                Some(from) => time > from,
+                None => time >= 0.0,
+            };
+            if after_from && time <= to {
+                fire(event);
+            }
+        }
+        loop_index += 1.0;
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashSet, time::Duration};
+
+use cgmath::{InnerSpace, One};
+
+use crate::model::animation::{
+    animation_controller::{AnimationSampler, AnimationValue},
+    animation_node::AnimationNode,
+};
+
+/// How a layer combines with the pose below it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerBlend {
+    /// replace the pose below, blended by the layer weight
+    Override,
+    /// add the difference between the layer's sampled pose and its pose at reference_time
+    Additive { reference_time: f32 },
+}
+
+/// The set of nodes a layer affects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JointMask {
+    node_ids: HashSet<usize>,
+}
+
+impl JointMask {
+    pub fn from_node_ids(node_ids: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            node_ids: node_ids.into_iter().collect(),
+        }
+    }
+    /// the node with node_id and all of its descendants, e.g. the spine for an upper body clip
+    pub fn subtree(animation_node: &AnimationNode, node_id: usize) -> Self {
+        let mut node_ids = HashSet::new();
+        animation_node.collect_subtree_ids(node_id, false, &mut node_ids);
+        Self { node_ids }
+    }
+    pub fn contains(&self, node_id: usize) -> bool {
+        self.node_ids.contains(&node_id)
+    }
+    pub fn len(&self) -> usize {
+        self.node_ids.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.node_ids.is_empty()
+    }
+}
+
+/// An animation sampled on top of an [AnimationInstance](super::animation::AnimationInstance).
+/// Layers are applied in the order they were added, after the instance's own animation
+#[derive(Debug, Clone)]
+pub struct AnimationLayer {
+    pub animation_index: usize,
+    pub blend: LayerBlend,
+    /// 0 leaves the pose below untouched, 1 applies the layer fully
+    pub weight: f32,
+    /// the nodes this layer affects, all of them if None
+    pub mask: Option<JointMask>,
+    pub looping: bool,
+    pub(super) start_time: Duration,
+    pub(super) duration: f32,
+    pub(super) sample_time: f32,
+}
+
+impl AnimationLayer {
+    pub fn new(animation_index: usize, blend: LayerBlend) -> Self {
+        Self {
+            animation_index,
+            blend,
+            weight: 1.0,
+            mask: None,
+            looping: true,
+            start_time: Duration::ZERO,
+            duration: 0.0,
+            sample_time: 0.0,
+        }
+    }
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+    pub fn with_mask(mut self, mask: JointMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub(super) fn advance(&mut self, timestamp: Duration) {
+        let elapsed = timestamp.saturating_sub(self.start_time).as_secs_f32();
+        self.sample_time = if self.looping && self.duration > 0.0 {
+            elapsed % self.duration
+        } else {
+            elapsed
+        };
+    }
+
+    /// blend this layer's samplers for a node into pose
+    pub(super) fn apply(&self, node_id: usize, samplers: &[AnimationSampler], pose: &mut Trs) {
+        let is_masked = self
+            .mask
+            .as_ref()
+            .is_some_and(|mask| !mask.contains(node_id));
+        if is_masked || self.weight <= 0.0 {
+            return;
+        }
+        for sampler in samplers {
+            let Some(value) = sampler.sample_at(self.sample_time) else {
+                continue;
+            };
+            match self.blend {
+                LayerBlend::Override => pose.blend(value, self.weight),
+                LayerBlend::Additive { reference_time } => {
+                    // before its first keyframe, a sampler's reference is that keyframe
+                    let reference = sampler
+                        .sample_at(reference_time.max(sampler.times[0]))
+                        .unwrap();
+                    pose.add(value, reference, self.weight);
+                }
+            }
+        }
+    }
+}
+
+/// the local transform of a node, split into its components so that it can be blended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Trs {
+    pub(super) translation: cgmath::Vector3<f32>,
+    pub(super) rotation: cgmath::Quaternion<f32>,
+    pub(super) scale: cgmath::Vector3<f32>,
+}
+
+impl Trs {
+    pub(super) fn matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.translation)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    pub(super) fn set(&mut self, value: AnimationValue) {
+        match value {
+            AnimationValue::Rotation(r) => self.rotation = r,
+            AnimationValue::Translation(t) => self.translation = t,
+            AnimationValue::Scale(s) => self.scale = s,
+        }
+    }
+
+    fn blend(&mut self, value: AnimationValue, weight: f32) {
+        match value {
+            AnimationValue::Rotation(r) => self.rotation = shortest_nlerp(self.rotation, r, weight),
+            AnimationValue::Translation(t) => self.translation += (t - self.translation) * weight,
+            AnimationValue::Scale(s) => self.scale += (s - self.scale) * weight,
+        }
+    }
+
+    fn add(&mut self, value: AnimationValue, reference: AnimationValue, weight: f32) {
+        match (value, reference) {
+            (AnimationValue::Rotation(r), AnimationValue::Rotation(reference)) => {
+                let delta = r * reference.conjugate();
+                let delta = shortest_nlerp(cgmath::Quaternion::one(), delta, weight);
+                self.rotation = (delta * self.rotation).normalize();
+            }
+            (AnimationValue::Translation(t), AnimationValue::Translation(reference)) => {
+                self.translation += (t - reference) * weight
+            }
+            (AnimationValue::Scale(s), AnimationValue::Scale(reference)) => {
+                let ratio = |v: f32, r: f32| if r == 0.0 { 1.0 } else { v / r };
+                let factor = cgmath::Vector3::new(
+                    ratio(s.x, reference.x),
+                    ratio(s.y, reference.y),
+                    ratio(s.z, reference.z),
+                );
+                let one = cgmath::Vector3::new(1.0, 1.0, 1.0);
+                let factor = one + (factor - one) * weight;
+                self.scale = cgmath::Vector3::new(
+                    self.scale.x * factor.x,
+                    self.scale.y * factor.y,
+                    self.scale.z * factor.z,
+                );
+            }
+            _ => unreachable!("a sampler always produces the same kind of value"),
+        }
+    }
+}
+
+/// nlerp along the shorter arc, so that blending never takes the long way around
+fn shortest_nlerp(
+    from: cgmath::Quaternion<f32>,
+    to: cgmath::Quaternion<f32>,
+    amount: f32,
+) -> cgmath::Quaternion<f32> {
+    let to = if from.dot(to) < 0.0 { -to } else { to };
+    from.nlerp(to, amount)
+}
@@ -1,26 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use cgmath::{InnerSpace, SquareMatrix};
+use cgmath::SquareMatrix;
 use gltf::{animation::Channel, Node};
 
 use crate::model::{
     animation::{
         animation::AnimationInstance,
-        animation_controller::{
-            AnimationSample, AnimationSampler, AnimationTransforms, SampleResult,
-        },
+        animation_controller::{AnimationSample, AnimationSampler, AnimationValue, SampleResult},
+        animation_layers::Trs,
         util::{IDENTITY, NO_ROTATION, NO_TRANSLATION},
     },
     model::ModelAnimationData,
 };
 
-#[derive(Debug, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
 pub enum NodeType {
     Node,
     Mesh,
     Joint(usize),
 }
 type ModelAnimationMap = HashMap<usize, Vec<AnimationSampler>>;
+#[derive(Clone)]
 pub struct AnimationNode {
     pub children: Vec<AnimationNode>,
     pub rot: cgmath::Quaternion<f32>,
@@ -34,7 +34,7 @@ impl AnimationNode {
     pub(super) fn get_default_samples(
         &self,
         animation_index: usize,
-        map: &mut HashMap<usize, Option<AnimationSample>>,
+        map: &mut HashMap<usize, AnimationSample>,
     ) {
         if let Some(sampler_map) = &self.samplers {
             if let Some(samplers) = sampler_map.get(&animation_index) {
@@ -43,7 +43,7 @@ impl AnimationNode {
                         end_time: sampler.times[0],
                         transform_index: -1,
                     };
-                    map.insert(sampler.id, Some(default));
+                    map.insert(sampler.id, default);
                 }
             }
         }
@@ -51,6 +51,136 @@ impl AnimationNode {
             child_node.get_default_samples(animation_index, map);
         }
     }
+    /// the time of the last keyframe of any sampler in this tree for the given animation
+    pub(super) fn get_animation_duration(&self, animation_index: usize) -> f32 {
+        let mut duration: f32 = 0.0;
+        if let Some(sampler_map) = &self.samplers {
+            if let Some(samplers) = sampler_map.get(&animation_index) {
+                for sampler in samplers {
+                    duration = duration.max(*sampler.times.last().unwrap_or(&0.0));
+                }
+            }
+        }
+        for child_node in &self.children {
+            duration = duration.max(child_node.get_animation_duration(animation_index));
+        }
+        duration
+    }
+    /// the largest animation index that any node in this tree has samplers for
+    pub(super) fn max_animation_index(&self) -> usize {
+        let own = self
+            .samplers
+            .as_ref()
+            .and_then(|sampler_map| sampler_map.keys().max().copied())
+            .unwrap_or(0);
+        self.children
+            .iter()
+            .map(|child_node| child_node.max_animation_index())
+            .fold(own, usize::max)
+    }
+    /// every animation index that some node in this tree has samplers for
+    pub(crate) fn animation_indices(&self) -> HashSet<usize> {
+        let mut indices: HashSet<usize> = self
+            .samplers
+            .as_ref()
+            .map(|sampler_map| sampler_map.keys().copied().collect())
+            .unwrap_or_default();
+        for child_node in self.children.iter() {
+            indices.extend(child_node.animation_indices());
+        }
+        indices
+    }
+    /// the node that root motion is extracted from: the topmost joint of a skeleton,
+    /// otherwise the topmost animated node
+    pub(super) fn root_motion_node(&self) -> Option<usize> {
+        self.find_node(&|node| matches!(node.node_type, NodeType::Joint(_)))
+            .or_else(|| self.find_node(&|node| node.samplers.is_some()))
+    }
+    fn find_node(&self, predicate: &impl Fn(&AnimationNode) -> bool) -> Option<usize> {
+        if predicate(self) {
+            return Some(self.node_id);
+        }
+        self.children
+            .iter()
+            .find_map(|child_node| child_node.find_node(predicate))
+    }
+    pub(super) fn find_node_mut(&mut self, node_id: usize) -> Option<&mut AnimationNode> {
+        if self.node_id == node_id {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child_node| child_node.find_node_mut(node_id))
+    }
+    /// collect the ids of the node with node_id and all of its descendants
+    pub(super) fn collect_subtree_ids(
+        &self,
+        node_id: usize,
+        in_subtree: bool,
+        node_ids: &mut HashSet<usize>,
+    ) {
+        let in_subtree = in_subtree || self.node_id == node_id;
+        if in_subtree {
+            node_ids.insert(self.node_id);
+        }
+        for child_node in &self.children {
+            child_node.collect_subtree_ids(node_id, in_subtree, node_ids);
+        }
+    }
+    /// the type of the node with node_id, and its model space transform in the rest pose
+    pub fn rest_pose(
+        &self,
+        node_id: usize,
+        parent: cgmath::Matrix4<f32>,
+    ) -> Option<(NodeType, cgmath::Matrix4<f32>)> {
+        let global = parent
+            * cgmath::Matrix4::from_translation(self.trans)
+            * cgmath::Matrix4::from(self.rot)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+        if self.node_id == node_id {
+            return Some((self.node_type.clone(), global));
+        }
+        self.children
+            .iter()
+            .find_map(|child_node| child_node.rest_pose(node_id, global))
+    }
+    /// the model space transform of the node with node_id at time, without touching
+    /// any instance state
+    pub(super) fn sample_model_space_transform(
+        &self,
+        node_id: usize,
+        animation_index: usize,
+        time: f32,
+        base_translation: cgmath::Matrix4<f32>,
+    ) -> Option<cgmath::Matrix4<f32>> {
+        let mut translation = self.trans;
+        let mut rotation = self.rot;
+        let mut scale = self.scale;
+        if let Some(samplers) = self
+            .samplers
+            .as_ref()
+            .and_then(|sampler_map| sampler_map.get(&animation_index))
+        {
+            for sampler in samplers {
+                match sampler.sample_at(time) {
+                    Some(AnimationValue::Rotation(r)) => rotation = r,
+                    Some(AnimationValue::Translation(t)) => translation = t,
+                    Some(AnimationValue::Scale(s)) => scale = s,
+                    None => {}
+                }
+            }
+        }
+        let global = base_translation
+            * cgmath::Matrix4::from_translation(translation)
+            * cgmath::Matrix4::from(rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
+        if self.node_id == node_id {
+            return Some(global);
+        }
+        self.children.iter().find_map(|child_node| {
+            child_node.sample_model_space_transform(node_id, animation_index, time, global)
+        })
+    }
     pub(super) fn initialize_sampled_transforms(
         &self,
         mesh_transforms: &mut Vec<[[f32; 4]; 4]>,
@@ -124,7 +254,7 @@ impl AnimationNode {
         instance: &mut AnimationInstance,
         base_translation: cgmath::Matrix4<f32>,
         animation_data: &ModelAnimationData,
-        skin_ibms: &HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+        inverse_bind_matrices: &[cgmath::Matrix4<f32>],
     ) -> bool {
         let mut node_is_done: bool = true;
 
@@ -133,94 +263,51 @@ impl AnimationNode {
         let mut current_frame_transform: Option<cgmath::Matrix4<f32>> = None;
 
         if let Some(sample_map) = &self.samplers {
+            // start from the rest pose, then apply the instance's animation and its layers
+            let mut pose = Trs {
+                translation: self.trans,
+                rotation: self.rot,
+                scale: self.scale,
+            };
+            let mut is_sampled = false;
             if let Some(sampler_set) = sample_map.get(&instance.animation_index) {
-                let mut rotation: Option<cgmath::Quaternion<f32>> = None;
-                let mut translation: Option<cgmath::Vector3<f32>> = None;
-                let mut scale: Option<cgmath::Vector3<f32>> = None;
+                is_sampled = true;
+                let time = instance.sample_time;
                 for sampler in sampler_set {
-                    let corresponding_sample = instance.current_samples.get(&sampler.id).unwrap();
-                    // if the sampler is finished, skip it.
-                    if corresponding_sample.is_none() {
-                        continue;
+                    let cursor = instance.current_samples.get_mut(&sampler.id).unwrap();
+                    // Active samples are still playing, finished samplers keep holding their
+                    // final keyframe
+                    let sample_result = sampler.sample(*cursor, time);
+                    if let SampleResult::Active(current_sample) = sample_result {
+                        node_is_done = false;
+                        *cursor = current_sample;
                     }
-                    // otherwise, get the new sample, which may be Active or Done
-                    let maybe_current_sample =
-                        sampler.sample((*corresponding_sample).unwrap(), instance.time_elapsed);
-                    // if it is Active, do the transforms, unless it hasnt started yet (idx is -1)
-                    // if its Done apply the final transform and set the sample to None in the
-                    // instace map
-
-                    match maybe_current_sample {
-                        SampleResult::Active(current_sample) => {
-                            node_is_done = false;
-                            if current_sample.transform_index == -1 {
-                                continue;
-                            }
-                            let i = current_sample.transform_index as usize;
-
-                            let amount: f32 = (instance.time_elapsed.as_secs_f32()
-                                - sampler.times[i])
-                                / (sampler.times[i + 1] - sampler.times[i]);
-                            match &sampler.transforms {
-                                AnimationTransforms::Rotation(quaternion_vec) => {
-                                    let first_quat = quaternion_vec[i];
-                                    let second_quat = quaternion_vec[i + 1];
-                                    rotation = Some(first_quat.nlerp(second_quat, amount));
-                                }
-                                AnimationTransforms::Translation(translation_vec) => {
-                                    let first_trans = &translation_vec[i];
-                                    let second_trans = &translation_vec[i + 1];
-
-                                    let t_diff = second_trans - first_trans;
-                                    let t_interp = t_diff * amount;
-                                    translation = Some(first_trans + t_interp);
-                                }
-                                AnimationTransforms::Scale(scale_vec) => {
-                                    let first_scale = &scale_vec[i];
-                                    let second_scale = &scale_vec[i + 1];
-
-                                    let t_diff = first_scale - second_scale;
-                                    let t_interp = t_diff * amount;
-                                    scale = Some(first_scale + t_interp);
-                                }
-                            }
-                            *instance.current_samples.get_mut(&sampler.id).unwrap() =
-                                Some(current_sample);
-                        }
-                        SampleResult::Done(last_index) => {
-                            match &sampler.transforms {
-                                AnimationTransforms::Rotation(quats) => {
-                                    rotation = Some(quats[last_index]);
-                                }
-                                AnimationTransforms::Translation(vecs) => {
-                                    translation = Some(vecs[last_index]);
-                                }
-                                AnimationTransforms::Scale(s_vecs) => {
-                                    scale = Some(s_vecs[last_index]);
-                                }
-                            }
-                            *instance.current_samples.get_mut(&sampler.id).unwrap() = None;
-                        }
+                    // samplers that haven't reached their first keyframe leave the rest pose
+                    if let Some(value) = sampler.value_of(sample_result, time) {
+                        pose.set(value);
                     }
                 }
-                current_frame_transform = Some(
-                    cgmath::Matrix4::from_translation(translation.unwrap_or(self.trans))
-                        * cgmath::Matrix4::from(rotation.unwrap_or(self.rot))
-                        * match scale {
-                            Some(s) => cgmath::Matrix4::<f32>::from_nonuniform_scale(s.x, s.y, s.z),
-                            None => cgmath::Matrix4::<f32>::from_nonuniform_scale(
-                                self.scale[0],
-                                self.scale[1],
-                                self.scale[2],
-                            ),
-                        },
-                );
+            }
+            for layer in instance.layers.iter() {
+                if let Some(layer_samplers) = sample_map.get(&layer.animation_index) {
+                    is_sampled = true;
+                    layer.apply(self.node_id, layer_samplers, &mut pose);
+                }
+            }
+            if is_sampled {
+                current_frame_transform = Some(pose.matrix());
             }
         }
 
         let animation_transform = current_frame_transform.unwrap_or(self.static_transform());
 
-        let global = base_translation * animation_transform;
+        let mut global = base_translation * animation_transform;
+        // strip the extracted root motion, which moves the whole instance instead
+        if let Some(root_motion) = instance.root_motion.as_mut() {
+            if root_motion.node_id == self.node_id {
+                global = root_motion.extract(global);
+            }
+        }
         match self.node_type {
             NodeType::Mesh => {
                 let mesh_id = animation_data
@@ -235,9 +322,10 @@ impl AnimationNode {
                 }
             }
             NodeType::Joint(ibm_idx) => {
-                let inverse_bind_matrix: cgmath::Matrix4<f32> = skin_ibms.get(&0).unwrap()[ibm_idx];
+                let inverse_bind_matrix: cgmath::Matrix4<f32> = inverse_bind_matrices[ibm_idx];
                 // get the index of this joint within the joint transforms buffer
                 instance.joint_transforms[ibm_idx] = (global * inverse_bind_matrix).into();
+                instance.joint_globals[ibm_idx] = global;
             }
             NodeType::Node => {}
         }
@@ -245,7 +333,12 @@ impl AnimationNode {
         // assign the mesh transform to the proper slot for this in.stance
         // if any one of the child nodes is still processing, set done to false
         for child_node in &self.children {
-            if !child_node.update_node_transforms(instance, global, animation_data, skin_ibms) {
+            if !child_node.update_node_transforms(
+                instance,
+                global,
+                animation_data,
+                inverse_bind_matrices,
+            ) {
                 node_is_done = false;
             }
         }
@@ -0,0 +1,295 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use crate::model::{animation::animation::PlaybackOptions, model::ModelAnimationData};
+
+/// An animation of the model, by its position in the gltf file or by its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnimationRef {
+    Index(usize),
+    Name(String),
+}
+
+impl AnimationRef {
+    fn resolve(&self, animation_data: &ModelAnimationData) -> Result<usize, StateMachineError> {
+        match self {
+            Self::Index(index) if *index < animation_data.animation_count => Ok(*index),
+            Self::Index(index) => Err(StateMachineError::AnimationOutOfRange(*index)),
+            Self::Name(name) => animation_data
+                .find_animation(name)
+                .ok_or_else(|| StateMachineError::UnknownAnimation(name.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateMachineError {
+    UnknownAnimation(String),
+    AnimationOutOfRange(usize),
+    /// a transition or the initial state refers to a state that doesn't exist
+    UnknownState(usize),
+}
+
+impl Display for StateMachineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownAnimation(name) => write!(f, "no animation is named {}", name),
+            Self::AnimationOutOfRange(index) => write!(f, "there is no animation {}", index),
+            Self::UnknownState(index) => write!(f, "there is no state {}", index),
+        }
+    }
+}
+
+/// The value of a named parameter the transitions of a state machine are conditioned on.
+/// Parameters that were never set are false, 0 and not triggered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimationParameter {
+    Bool(bool),
+    Float(f32),
+    /// set until a transition conditioned on it is taken
+    Trigger,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransitionCondition {
+    /// the bool parameter has the given value
+    Bool(String, bool),
+    /// the float parameter is greater than the threshold
+    Greater(String, f32),
+    /// the float parameter is less than the threshold
+    Less(String, f32),
+    /// the trigger parameter is set, taking the transition resets it
+    Trigger(String),
+}
+
+impl TransitionCondition {
+    fn holds(&self, parameters: &HashMap<String, AnimationParameter>) -> bool {
+        let float = |name: &String| match parameters.get(name) {
+            Some(AnimationParameter::Float(value)) => *value,
+            _ => 0.0,
+        };
+        match self {
+            Self::Bool(name, value) => {
+                let set = matches!(parameters.get(name), Some(AnimationParameter::Bool(true)));
+                set == *value
+            }
+            Self::Greater(name, threshold) => float(name) > *threshold,
+            Self::Less(name, threshold) => float(name) < *threshold,
+            Self::Trigger(name) => {
+                matches!(parameters.get(name), Some(AnimationParameter::Trigger))
+            }
+        }
+    }
+}
+
+/// A state of the machine, playing a single animation
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationState {
+    pub name: String,
+    pub animation: AnimationRef,
+    pub options: PlaybackOptions,
+}
+
+impl AnimationState {
+    pub fn new(name: &str, animation: AnimationRef) -> Self {
+        Self {
+            name: name.to_string(),
+            animation,
+            options: PlaybackOptions::default(),
+        }
+    }
+    pub fn with_options(mut self, options: PlaybackOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// A move from one state to another, taken once all of its conditions hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationTransition {
+    /// the state this transition leaves, None to leave any state (including the current one,
+    /// which restarts it)
+    pub from: Option<usize>,
+    pub to: usize,
+    /// all of these have to hold, a transition without conditions is taken as soon as it can be
+    pub conditions: Vec<TransitionCondition>,
+    /// seconds spent blending from the current state into the next one
+    pub cross_fade: f32,
+    /// the fraction of the current state's animation that has to have played before the
+    /// transition can be taken, e.g. 1.0 to wait for it to finish
+    pub exit_time: Option<f32>,
+}
+
+impl AnimationTransition {
+    pub fn new(from: Option<usize>, to: usize) -> Self {
+        Self {
+            from,
+            to,
+            conditions: Vec::new(),
+            cross_fade: 0.0,
+            exit_time: None,
+        }
+    }
+    pub fn when(mut self, condition: TransitionCondition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+    pub fn with_cross_fade(mut self, cross_fade: f32) -> Self {
+        self.cross_fade = cross_fade;
+        self
+    }
+    pub fn with_exit_time(mut self, exit_time: f32) -> Self {
+        self.exit_time = Some(exit_time);
+        self
+    }
+}
+
+/// A declarative description of which animation a model instance plays, and when it moves
+/// on to the next one. Transitions are checked in the order they were added, and at most one
+/// is taken per frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnimationStateMachine {
+    pub states: Vec<AnimationState>,
+    pub transitions: Vec<AnimationTransition>,
+    /// the state entered when the machine starts, if None nothing plays until a transition
+    /// from any state is taken
+    pub initial_state: Option<usize>,
+}
+
+impl AnimationStateMachine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// add a state, returning its index
+    pub fn add_state(&mut self, state: AnimationState) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+    pub fn add_transition(&mut self, transition: AnimationTransition) {
+        self.transitions.push(transition);
+    }
+    pub fn with_initial_state(mut self, state: usize) -> Self {
+        self.initial_state = Some(state);
+        self
+    }
+    pub fn state_index(&self, name: &str) -> Option<usize> {
+        self.states.iter().position(|state| state.name == name)
+    }
+
+    /// the animation index of every state
+    fn resolve(
+        &self,
+        animation_data: &ModelAnimationData,
+    ) -> Result<Vec<usize>, StateMachineError> {
+        let state_count = self.states.len();
+        let referenced_states = self
+            .transitions
+            .iter()
+            .flat_map(|transition| transition.from.into_iter().chain([transition.to]))
+            .chain(self.initial_state);
+        for state in referenced_states {
+            if state >= state_count {
+                return Err(StateMachineError::UnknownState(state));
+            }
+        }
+        self.states
+            .iter()
+            .map(|state| state.animation.resolve(animation_data))
+            .collect()
+    }
+}
+
+/// Where the animation of a state is played, see [StateMachineInstance::new]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct StateTarget {
+    pub(super) animation_index: usize,
+    /// the offset in the local transform buffer the animation writes to
+    pub(super) model_instance_offset: usize,
+    pub(super) mesh_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct CrossFade {
+    pub(super) to: usize,
+    pub(super) start_time: Duration,
+    pub(super) duration: f32,
+}
+
+/// A state machine driving one model instance
+#[derive(Debug)]
+pub(super) struct StateMachineInstance {
+    pub(super) machine: AnimationStateMachine,
+    pub(super) targets: Vec<StateTarget>,
+    pub(super) parameters: HashMap<String, AnimationParameter>,
+    pub(super) current_state: Option<usize>,
+    pub(super) state_start_time: Duration,
+    pub(super) cross_fade: Option<CrossFade>,
+    pub(super) is_started: bool,
+}
+
+impl StateMachineInstance {
+    /// target_of gives the local transform offset and mesh count of an animation index
+    pub(super) fn new(
+        machine: AnimationStateMachine,
+        animation_data: &ModelAnimationData,
+        target_of: impl Fn(usize) -> (usize, usize),
+    ) -> Result<Self, StateMachineError> {
+        let targets = machine
+            .resolve(animation_data)?
+            .into_iter()
+            .map(|animation_index| {
+                let (model_instance_offset, mesh_count) = target_of(animation_index);
+                StateTarget {
+                    animation_index,
+                    model_instance_offset,
+                    mesh_count,
+                }
+            })
+            .collect();
+        Ok(Self {
+            machine,
+            targets,
+            parameters: HashMap::new(),
+            current_state: None,
+            state_start_time: Duration::ZERO,
+            cross_fade: None,
+            is_started: false,
+        })
+    }
+
+    /// The first transition that can be taken at timestamp, consuming its triggers.
+    /// state_duration is the duration of the current state's animation
+    pub(super) fn take_transition(
+        &mut self,
+        timestamp: Duration,
+        state_duration: f32,
+    ) -> Option<AnimationTransition> {
+        let state_time = timestamp
+            .saturating_sub(self.state_start_time)
+            .as_secs_f32();
+        let transition = self
+            .machine
+            .transitions
+            .iter()
+            .find(|transition| {
+                let from_matches =
+                    transition.from.is_none() || transition.from == self.current_state;
+                let exit_time_passed = match (transition.exit_time, self.current_state) {
+                    (Some(exit_time), Some(_)) => state_time >= exit_time * state_duration,
+                    _ => true,
+                };
+                from_matches
+                    && exit_time_passed
+                    && transition
+                        .conditions
+                        .iter()
+                        .all(|condition| condition.holds(&self.parameters))
+            })?
+            .clone();
+        for condition in transition.conditions.iter() {
+            if let TransitionCondition::Trigger(name) = condition {
+                self.parameters.remove(name);
+            }
+        }
+        Some(transition)
+    }
+}
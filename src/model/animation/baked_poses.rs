@@ -0,0 +1,236 @@
+use cgmath::SquareMatrix;
+
+use crate::model::{
+    animation::{
+        animation_controller::AnimationValue,
+        animation_node::{AnimationNode, NodeType},
+    },
+    model::ModelAnimationData,
+};
+
+/// Where the frames of one baked animation are in [BakedPoses::joint_matrices]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BakedClip {
+    /// global index of the animation as defined in the gltf file
+    pub animation_index: usize,
+    /// the index of the clip's first frame
+    pub frame_offset: usize,
+    pub frame_count: usize,
+    pub duration: f32,
+}
+
+/// The skinning matrices of a model's joints, sampled at a fixed rate for a set of animations.
+/// Once uploaded, the vertex shader can look up the pose of any number of instances by
+/// clip and time, without evaluating the animations each frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct BakedPoses {
+    /// frames per second the clips were sampled at. The frames of a clip are spread evenly
+    /// over its duration, so the actual rate can be slightly higher
+    pub frame_rate: f32,
+    pub joint_count: usize,
+    pub clips: Vec<BakedClip>,
+    /// frame major, frame f of a clip starts at (clip.frame_offset + f) * joint_count
+    pub joint_matrices: Vec<[[f32; 4]; 4]>,
+}
+
+impl BakedPoses {
+    /// Sample every animation in animation_indices at frame_rate. An index the model has no
+    /// animation for is baked as a single frame of the rest pose
+    pub fn bake(
+        animation_data: &ModelAnimationData,
+        inverse_bind_matrices: &[cgmath::Matrix4<f32>],
+        animation_indices: &[usize],
+        frame_rate: f32,
+    ) -> Self {
+        assert!(
+            frame_rate > 0.0,
+            "poses have to be baked at a positive rate"
+        );
+        let joint_count = inverse_bind_matrices.len();
+        let mut clips = Vec::with_capacity(animation_indices.len());
+        let mut joint_matrices = Vec::new();
+        let mut frame_offset = 0;
+        for animation_index in animation_indices.iter().copied() {
+            let duration = animation_data
+                .animation_node
+                .get_animation_duration(animation_index);
+            // the first and last frames land exactly on the start and end of the clip
+            let frame_count = (duration * frame_rate).ceil() as usize + 1;
+            for frame in 0..frame_count {
+                let time = match frame_count {
+                    1 => 0.0,
+                    _ => duration * frame as f32 / (frame_count - 1) as f32,
+                };
+                let start = joint_matrices.len();
+                joint_matrices.resize(start + joint_count, cgmath::Matrix4::identity().into());
+                bake_node(
+                    &animation_data.animation_node,
+                    animation_index,
+                    time,
+                    cgmath::Matrix4::identity(),
+                    inverse_bind_matrices,
+                    &mut joint_matrices[start..],
+                );
+            }
+            clips.push(BakedClip {
+                animation_index,
+                frame_offset,
+                frame_count,
+                duration,
+            });
+            frame_offset += frame_count;
+        }
+        Self {
+            frame_rate,
+            joint_count,
+            clips,
+            joint_matrices,
+        }
+    }
+
+    /// add the clips of other after these, both have to be baked for the same joints
+    pub fn append(&mut self, other: BakedPoses) {
+        assert_eq!(
+            self.joint_count, other.joint_count,
+            "appended poses have to be baked for the same joints"
+        );
+        let frame_offset = self
+            .clips
+            .last()
+            .map_or(0, |clip| clip.frame_offset + clip.frame_count);
+        self.clips
+            .extend(other.clips.into_iter().map(|clip| BakedClip {
+                frame_offset: clip.frame_offset + frame_offset,
+                ..clip
+            }));
+        self.joint_matrices.extend(other.joint_matrices);
+    }
+
+    /// the index of the clip baked from the given animation
+    pub fn clip_index(&self, animation_index: usize) -> Option<usize> {
+        self.clips
+            .iter()
+            .position(|clip| clip.animation_index == animation_index)
+    }
+
+    /// the joint matrices of one frame of a clip
+    pub fn frame(&self, clip: usize, frame: usize) -> &[[[f32; 4]; 4]] {
+        let start = (self.clips[clip].frame_offset + frame) * self.joint_count;
+        &self.joint_matrices[start..start + self.joint_count]
+    }
+
+    /// The pose of a clip at time, looping, blended between the two nearest frames.
+    /// This is what the vertex shader does, see vs_baked in shader.wgsl
+    pub fn sample(&self, clip: usize, time: f32) -> Vec<[[f32; 4]; 4]> {
+        let BakedClip {
+            frame_count,
+            duration,
+            ..
+        } = self.clips[clip];
+        let frame = if duration > 0.0 && frame_count > 1 {
+            time.rem_euclid(duration) / duration * (frame_count - 1) as f32
+        } else {
+            0.0
+        };
+        let first = (frame.floor() as usize).min(frame_count - 1);
+        let second = (first + 1).min(frame_count - 1);
+        let amount = frame - frame.floor();
+        self.frame(clip, first)
+            .iter()
+            .zip(self.frame(clip, second))
+            .map(|(a, b)| {
+                let a = cgmath::Matrix4::from(*a);
+                let b = cgmath::Matrix4::from(*b);
+                (a * (1.0 - amount) + b * amount).into()
+            })
+            .collect()
+    }
+
+    pub fn gpu_clips(&self) -> Vec<GpuBakedClip> {
+        self.clips
+            .iter()
+            .map(|clip| GpuBakedClip {
+                frame_offset: clip.frame_offset as u32,
+                frame_count: clip.frame_count as u32,
+                duration: clip.duration,
+                _padding: 0,
+            })
+            .collect()
+    }
+}
+
+fn bake_node(
+    node: &AnimationNode,
+    animation_index: usize,
+    time: f32,
+    parent: cgmath::Matrix4<f32>,
+    inverse_bind_matrices: &[cgmath::Matrix4<f32>],
+    joint_matrices: &mut [[[f32; 4]; 4]],
+) {
+    let mut translation = node.trans;
+    let mut rotation = node.rot;
+    let mut scale = node.scale;
+    if let Some(samplers) = node
+        .samplers
+        .as_ref()
+        .and_then(|sampler_map| sampler_map.get(&animation_index))
+    {
+        for sampler in samplers {
+            match sampler.sample_at(time) {
+                Some(AnimationValue::Rotation(r)) => rotation = r,
+                Some(AnimationValue::Translation(t)) => translation = t,
+                Some(AnimationValue::Scale(s)) => scale = s,
+                None => {}
+            }
+        }
+    }
+    let global = parent
+        * cgmath::Matrix4::from_translation(translation)
+        * cgmath::Matrix4::from(rotation)
+        * cgmath::Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
+    if let NodeType::Joint(ibm_idx) = node.node_type {
+        joint_matrices[ibm_idx] = (global * inverse_bind_matrices[ibm_idx]).into();
+    }
+    for child_node in &node.children {
+        bake_node(
+            child_node,
+            animation_index,
+            time,
+            global,
+            inverse_bind_matrices,
+            joint_matrices,
+        );
+    }
+}
+
+/// A [BakedClip] as the vertex shader reads it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuBakedClip {
+    pub frame_offset: u32,
+    pub frame_count: u32,
+    pub duration: f32,
+    _padding: u32,
+}
+
+/// What a single instance plays from the baked poses. Indexed by the instance's global
+/// transform index
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BakedPoseInstance {
+    pub clip: u32,
+    /// seconds added to the clock, so instances playing the same clip don't move in lockstep
+    pub time_offset: f32,
+    pub speed: f32,
+    _padding: u32,
+}
+impl BakedPoseInstance {
+    pub fn new(clip: usize, time_offset: f32, speed: f32) -> Self {
+        Self {
+            clip: clip as u32,
+            time_offset,
+            speed,
+            _padding: 0,
+        }
+    }
+}
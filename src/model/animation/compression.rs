@@ -0,0 +1,322 @@
+use std::sync::Arc;
+
+use cgmath::InnerSpace;
+
+use crate::model::{
+    animation::{
+        animation_controller::{AnimationSampler, AnimationTransforms, AnimationValue},
+        animation_node::AnimationNode,
+    },
+    model::ModelAnimationData,
+};
+
+/// the components a quantized quaternion keeps are at most this large
+const QUANTIZED_RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// a component is stored as this many steps either side of zero, so that zero is exact
+const QUANTIZED_STEPS: f32 = 16383.0;
+
+/// A unit quaternion in 48 bits instead of 128. The largest component is dropped, since it
+/// can be recovered from the other three, and made positive (q and -q are the same rotation).
+/// The other three are stored in 15 bits each, and the index of the dropped one in the two
+/// bits left over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizedQuaternion([u16; 3]);
+
+impl QuantizedQuaternion {
+    pub fn from_quaternion(rotation: cgmath::Quaternion<f32>) -> Self {
+        let rotation = rotation.normalize();
+        let components = [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s];
+        let largest = (0..4)
+            .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+            .unwrap();
+        let sign = components[largest].signum();
+        let mut packed = [0u16; 3];
+        let kept = (0..4).filter(|i| *i != largest);
+        for (slot, i) in kept.enumerate() {
+            let normalized = (components[i] * sign / QUANTIZED_RANGE).clamp(-1.0, 1.0);
+            packed[slot] = ((normalized * QUANTIZED_STEPS).round() + QUANTIZED_STEPS) as u16;
+        }
+        packed[0] |= ((largest & 1) as u16) << 15;
+        packed[1] |= ((largest >> 1) as u16) << 15;
+        Self(packed)
+    }
+
+    pub fn to_quaternion(self) -> cgmath::Quaternion<f32> {
+        let largest = ((self.0[0] >> 15) | ((self.0[1] >> 15) << 1)) as usize;
+        let mut components = [0.0f32; 4];
+        let kept = (0..4).filter(|i| *i != largest);
+        for (slot, i) in kept.enumerate() {
+            let steps = (self.0[slot] & 0x7fff) as f32 - QUANTIZED_STEPS;
+            components[i] = steps / QUANTIZED_STEPS * QUANTIZED_RANGE;
+        }
+        let sum: f32 = components.iter().map(|c| c * c).sum();
+        components[largest] = (1.0 - sum).max(0.0).sqrt();
+        cgmath::Quaternion::new(components[3], components[0], components[1], components[2])
+    }
+}
+
+/// Decode a run of quantized keyframes. Quantizing can flip the sign of a keyframe, so each
+/// one is kept in the same hemisphere as the one before it, otherwise interpolating between
+/// them would take the long way around
+pub(super) fn dequantize_rotations(
+    rotations: &[QuantizedQuaternion],
+) -> Vec<cgmath::Quaternion<f32>> {
+    let mut decoded: Vec<cgmath::Quaternion<f32>> = Vec::with_capacity(rotations.len());
+    for rotation in rotations {
+        let rotation = rotation.to_quaternion();
+        match decoded.last() {
+            Some(previous) if previous.dot(rotation) < 0.0 => decoded.push(-rotation),
+            _ => decoded.push(rotation),
+        }
+    }
+    decoded
+}
+
+/// How far the compressed animation may stray from the original keyframes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionSettings {
+    /// in model units
+    pub translation_tolerance: f32,
+    /// in radians
+    pub rotation_tolerance: f32,
+    pub scale_tolerance: f32,
+    /// store rotations in 48 bits, see [QuantizedQuaternion]
+    pub quantize_rotations: bool,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            translation_tolerance: 1e-4,
+            rotation_tolerance: 1e-3,
+            scale_tolerance: 1e-4,
+            quantize_rotations: false,
+        }
+    }
+}
+
+/// What compressing some animations saved, and what it cost
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    pub keyframes_before: usize,
+    pub keyframes_after: usize,
+    /// the size of the keyframe times and values
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    /// the largest distance between an original keyframe and the compressed animation at the
+    /// time of that keyframe
+    pub max_translation_error: f32,
+    /// in radians
+    pub max_rotation_error: f32,
+    pub max_scale_error: f32,
+}
+
+impl CompressionStats {
+    pub fn bytes_saved(&self) -> usize {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+
+    pub fn merge(&mut self, other: &CompressionStats) {
+        self.keyframes_before += other.keyframes_before;
+        self.keyframes_after += other.keyframes_after;
+        self.bytes_before += other.bytes_before;
+        self.bytes_after += other.bytes_after;
+        self.max_translation_error = self.max_translation_error.max(other.max_translation_error);
+        self.max_rotation_error = self.max_rotation_error.max(other.max_rotation_error);
+        self.max_scale_error = self.max_scale_error.max(other.max_scale_error);
+    }
+}
+
+fn byte_size(sampler: &AnimationSampler) -> usize {
+    let values = match &sampler.transforms {
+        AnimationTransforms::Rotation(r) => std::mem::size_of_val(r.as_slice()),
+        AnimationTransforms::QuantizedRotation(r) => std::mem::size_of_val(r.as_slice()),
+        AnimationTransforms::Translation(t) => std::mem::size_of_val(t.as_slice()),
+        AnimationTransforms::Scale(s) => std::mem::size_of_val(s.as_slice()),
+    };
+    std::mem::size_of_val(sampler.times.as_slice()) + values
+}
+
+/// The angle of the rotation between a and b. Measured from the distance between them rather
+/// than acos of their dot product, which loses too much precision near 1 to see small errors
+pub(super) fn rotation_error(a: cgmath::Quaternion<f32>, b: cgmath::Quaternion<f32>) -> f32 {
+    let (a, b) = (a.normalize(), b.normalize());
+    let b = if a.dot(b) < 0.0 { -b } else { b };
+    4.0 * ((a - b).magnitude() * 0.5).min(1.0).asin()
+}
+
+/// The indices of the keyframes to keep. A keyframe is dropped when interpolating between
+/// its neighbours that are kept reproduces it, and every keyframe in between, within tolerance.
+/// The first and last keyframes are always kept so that the animation's span doesn't change
+fn reduce_keyframes<T: Copy>(
+    times: &[f32],
+    values: &[T],
+    lerp: impl Fn(T, T, f32) -> T,
+    error: impl Fn(T, T) -> f32,
+    tolerance: f32,
+) -> Vec<usize> {
+    let count = times.len();
+    if count <= 2 {
+        return (0..count).collect();
+    }
+    let mut kept = vec![0];
+    let mut anchor = 0;
+    for end in 2..count {
+        let span = times[end] - times[anchor];
+        let fits = (anchor + 1..end).all(|i| {
+            let amount = if span > 0.0 {
+                (times[i] - times[anchor]) / span
+            } else {
+                0.0
+            };
+            error(lerp(values[anchor], values[end], amount), values[i]) <= tolerance
+        });
+        if !fits {
+            anchor = end - 1;
+            kept.push(anchor);
+        }
+    }
+    kept.push(count - 1);
+    kept
+}
+
+fn select<T: Copy>(values: &[T], kept: &[usize]) -> Vec<T> {
+    kept.iter().map(|i| values[*i]).collect()
+}
+
+/// Drop the keyframes of sampler that interpolation can recreate, and optionally quantize
+/// its rotations. The stats measure the result against every original keyframe
+pub fn compress_sampler(
+    sampler: &AnimationSampler,
+    settings: &CompressionSettings,
+) -> (AnimationSampler, CompressionStats) {
+    let times = &sampler.times;
+    let vector_error = |a: cgmath::Vector3<f32>, b: cgmath::Vector3<f32>| (a - b).magnitude();
+    let vector_lerp =
+        |a: cgmath::Vector3<f32>, b: cgmath::Vector3<f32>, amount: f32| a + (b - a) * amount;
+    let (kept, transforms) = match &sampler.transforms {
+        AnimationTransforms::Rotation(rotations) => {
+            let kept = reduce_keyframes(
+                times,
+                rotations,
+                |a, b, amount| a.nlerp(b, amount),
+                rotation_error,
+                settings.rotation_tolerance,
+            );
+            let rotations = select(rotations, &kept);
+            let transforms = match settings.quantize_rotations {
+                true => AnimationTransforms::QuantizedRotation(
+                    rotations
+                        .into_iter()
+                        .map(QuantizedQuaternion::from_quaternion)
+                        .collect(),
+                ),
+                false => AnimationTransforms::Rotation(rotations),
+            };
+            (kept, transforms)
+        }
+        AnimationTransforms::QuantizedRotation(rotations) => {
+            let decoded = dequantize_rotations(rotations);
+            let kept = reduce_keyframes(
+                times,
+                &decoded,
+                |a, b, amount| a.nlerp(b, amount),
+                rotation_error,
+                settings.rotation_tolerance,
+            );
+            let transforms = AnimationTransforms::QuantizedRotation(select(rotations, &kept));
+            (kept, transforms)
+        }
+        AnimationTransforms::Translation(translations) => {
+            let kept = reduce_keyframes(
+                times,
+                translations,
+                vector_lerp,
+                vector_error,
+                settings.translation_tolerance,
+            );
+            let transforms = AnimationTransforms::Translation(select(translations, &kept));
+            (kept, transforms)
+        }
+        AnimationTransforms::Scale(scales) => {
+            let kept = reduce_keyframes(
+                times,
+                scales,
+                vector_lerp,
+                vector_error,
+                settings.scale_tolerance,
+            );
+            let transforms = AnimationTransforms::Scale(select(scales, &kept));
+            (kept, transforms)
+        }
+    };
+    let compressed = AnimationSampler {
+        id: sampler.id,
+        interpolation: sampler.interpolation,
+        times: select(times, &kept),
+        transforms,
+    };
+
+    let mut stats = CompressionStats {
+        keyframes_before: times.len(),
+        keyframes_after: compressed.times.len(),
+        bytes_before: byte_size(sampler),
+        bytes_after: byte_size(&compressed),
+        ..Default::default()
+    };
+    for time in times {
+        let (Some(original), Some(value)) = (sampler.sample_at(*time), compressed.sample_at(*time))
+        else {
+            continue;
+        };
+        match (original, value) {
+            (AnimationValue::Rotation(a), AnimationValue::Rotation(b)) => {
+                stats.max_rotation_error = stats.max_rotation_error.max(rotation_error(a, b))
+            }
+            (AnimationValue::Translation(a), AnimationValue::Translation(b)) => {
+                stats.max_translation_error = stats.max_translation_error.max(vector_error(a, b))
+            }
+            (AnimationValue::Scale(a), AnimationValue::Scale(b)) => {
+                stats.max_scale_error = stats.max_scale_error.max(vector_error(a, b))
+            }
+            _ => unreachable!("compressing a sampler doesn't change what it animates"),
+        }
+    }
+    (compressed, stats)
+}
+
+fn compress_node(
+    node: &mut AnimationNode,
+    settings: &CompressionSettings,
+    stats: &mut CompressionStats,
+) {
+    for samplers in node
+        .samplers
+        .iter_mut()
+        .flat_map(|sampler_map| sampler_map.values_mut())
+    {
+        for sampler in samplers.iter_mut() {
+            let (compressed, sampler_stats) = compress_sampler(sampler, settings);
+            *sampler = compressed;
+            stats.merge(&sampler_stats);
+        }
+    }
+    for child_node in node.children.iter_mut() {
+        compress_node(child_node, settings, stats);
+    }
+}
+
+/// Compress every animation of a model. Instances that are already playing keep the
+/// uncompressed animation
+pub fn compress_animations(
+    animation_data: &mut ModelAnimationData,
+    settings: &CompressionSettings,
+) -> CompressionStats {
+    let mut stats = CompressionStats::default();
+    compress_node(
+        Arc::make_mut(&mut animation_data.animation_node),
+        settings,
+        &mut stats,
+    );
+    stats
+}
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+
+use cgmath::{EuclideanSpace, InnerSpace, One, Rotation};
+
+use crate::model::{animation::animation_node::AnimationNode, model::ModelAnimationData};
+
+/// A procedural change to a joint, applied after the animation has been sampled and before
+/// the joint matrices are written. Joints are identified by their gltf node index, and
+/// positions are in the model space of the instance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JointOverride {
+    /// rotate the joint (and everything below it) in its own space
+    Rotate {
+        joint_node: usize,
+        rotation: cgmath::Quaternion<f32>,
+    },
+    /// turn the joint so that its aim axis (in joint space) points at target, e.g. a head
+    LookAt {
+        joint_node: usize,
+        aim_axis: cgmath::Vector3<f32>,
+        target: cgmath::Point3<f32>,
+        weight: f32,
+    },
+    /// bend a root, middle, end chain (e.g. hip, knee, foot) so that the end reaches target.
+    /// The middle joint bends towards pole
+    TwoBoneIk {
+        root_node: usize,
+        middle_node: usize,
+        end_node: usize,
+        target: cgmath::Point3<f32>,
+        pole: cgmath::Point3<f32>,
+        weight: f32,
+    },
+}
+
+impl JointOverride {
+    /// the gltf node index of every joint this override moves or reads
+    pub fn joint_nodes(&self) -> Vec<usize> {
+        match *self {
+            Self::Rotate { joint_node, .. } | Self::LookAt { joint_node, .. } => vec![joint_node],
+            Self::TwoBoneIk {
+                root_node,
+                middle_node,
+                end_node,
+                ..
+            } => vec![root_node, middle_node, end_node],
+        }
+    }
+}
+
+/// the first node the overrides refer to that isn't one of the model's joints, if any
+pub fn find_unknown_joint(
+    overrides: &[JointOverride],
+    animation_data: &ModelAnimationData,
+) -> Option<usize> {
+    overrides
+        .iter()
+        .flat_map(JointOverride::joint_nodes)
+        .find(|node_id| {
+            !animation_data
+                .joint_animation_data
+                .joint_to_joint_index
+                .contains_key(node_id)
+        })
+}
+
+/// Apply the overrides, in order, to the model space transforms of every joint. Overrides
+/// referring to nodes that aren't joints are skipped, see [find_unknown_joint]
+pub(super) fn apply_joint_overrides(
+    overrides: &[JointOverride],
+    animation_node: &AnimationNode,
+    animation_data: &ModelAnimationData,
+    joint_globals: &mut [cgmath::Matrix4<f32>],
+) {
+    let joint_index = |node_id: usize| {
+        animation_data
+            .joint_animation_data
+            .joint_to_joint_index
+            .get(&node_id)
+            .copied()
+    };
+    // rotate a joint and all of the joints below it about the joint's position
+    let rotate_subtree = |joint_globals: &mut [cgmath::Matrix4<f32>],
+                          (node_id, index): (usize, usize),
+                          rotation: cgmath::Quaternion<f32>| {
+        let pivot = joint_globals[index].w.truncate();
+        let correction = cgmath::Matrix4::from_translation(pivot)
+            * cgmath::Matrix4::from(rotation)
+            * cgmath::Matrix4::from_translation(-pivot);
+        let mut subtree = HashSet::new();
+        animation_node.collect_subtree_ids(node_id, false, &mut subtree);
+        for (node_id, index) in animation_data
+            .joint_animation_data
+            .joint_to_joint_index
+            .iter()
+        {
+            if subtree.contains(node_id) {
+                joint_globals[*index] = correction * joint_globals[*index];
+            }
+        }
+    };
+    let position = |joint_globals: &[cgmath::Matrix4<f32>], index: usize| {
+        cgmath::Point3::from_vec(joint_globals[index].w.truncate())
+    };
+    // the node and its joint index
+    let joint = |node_id: usize| joint_index(node_id).map(|index| (node_id, index));
+
+    for joint_override in overrides {
+        match *joint_override {
+            JointOverride::Rotate {
+                joint_node,
+                rotation,
+            } => {
+                let Some(joint_node) = joint(joint_node) else {
+                    continue;
+                };
+                // a rotation in joint space, expressed in model space
+                let global = joint_globals[joint_node.1];
+                let axes = cgmath::Matrix3::from_cols(
+                    global.x.truncate().normalize(),
+                    global.y.truncate().normalize(),
+                    global.z.truncate().normalize(),
+                );
+                let orientation = cgmath::Quaternion::from(axes);
+                rotate_subtree(
+                    joint_globals,
+                    joint_node,
+                    orientation * rotation * orientation.invert(),
+                );
+            }
+            JointOverride::LookAt {
+                joint_node,
+                aim_axis,
+                target,
+                weight,
+            } => {
+                let Some(joint_node) = joint(joint_node) else {
+                    continue;
+                };
+                let global = joint_globals[joint_node.1];
+                let aim = (global * aim_axis.extend(0.0)).truncate();
+                let to_target = target - position(joint_globals, joint_node.1);
+                if let Some(rotation) = arc_between(aim, to_target) {
+                    rotate_subtree(
+                        joint_globals,
+                        joint_node,
+                        cgmath::Quaternion::one().nlerp(rotation, weight),
+                    );
+                }
+            }
+            JointOverride::TwoBoneIk {
+                root_node,
+                middle_node,
+                end_node,
+                target,
+                pole,
+                weight,
+            } => {
+                let (Some(root_node), Some(middle_node), Some(end_node)) =
+                    (joint(root_node), joint(middle_node), joint(end_node))
+                else {
+                    continue;
+                };
+                let root = position(joint_globals, root_node.1);
+                let middle = position(joint_globals, middle_node.1);
+                let end = position(joint_globals, end_node.1);
+                let target = end + (target - end) * weight;
+                let upper_length = (middle - root).magnitude();
+                let lower_length = (end - middle).magnitude();
+                let to_target = target - root;
+                if to_target.magnitude2() < f32::EPSILON {
+                    continue;
+                }
+                // out of reach targets straighten the chain towards them
+                let distance = to_target.magnitude().clamp(
+                    (upper_length - lower_length).abs() + 1e-4,
+                    upper_length + lower_length - 1e-4,
+                );
+                let direction = to_target.normalize();
+                // the direction the middle joint bends in, perpendicular to the chain
+                let bend = [pole - root, middle - root]
+                    .into_iter()
+                    .map(|hint| hint - direction * hint.dot(direction))
+                    .find(|bend| bend.magnitude2() > f32::EPSILON)
+                    .map(|bend| bend.normalize());
+                let Some(bend) = bend else {
+                    continue;
+                };
+                // law of cosines for the angle at the root
+                let cos_root = ((upper_length * upper_length + distance * distance
+                    - lower_length * lower_length)
+                    / (2.0 * upper_length * distance))
+                    .clamp(-1.0, 1.0);
+                let sin_root = (1.0 - cos_root * cos_root).sqrt();
+                let new_middle = root + (direction * cos_root + bend * sin_root) * upper_length;
+                let new_end = root + direction * distance;
+
+                if let Some(rotation) = arc_between(middle - root, new_middle - root) {
+                    rotate_subtree(joint_globals, root_node, rotation);
+                }
+                let middle = position(joint_globals, middle_node.1);
+                let end = position(joint_globals, end_node.1);
+                if let Some(rotation) = arc_between(end - middle, new_end - middle) {
+                    rotate_subtree(joint_globals, middle_node, rotation);
+                }
+            }
+        }
+    }
+}
+
+/// the shortest rotation taking from onto to, None if either is zero length
+fn arc_between(
+    from: cgmath::Vector3<f32>,
+    to: cgmath::Vector3<f32>,
+) -> Option<cgmath::Quaternion<f32>> {
+    if from.magnitude2() < f32::EPSILON || to.magnitude2() < f32::EPSILON {
+        return None;
+    }
+    Some(cgmath::Quaternion::from_arc(
+        from.normalize(),
+        to.normalize(),
+        None,
+    ))
+}
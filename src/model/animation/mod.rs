@@ -1,5 +1,14 @@
 pub mod animation;
+pub mod animation_compute;
 pub mod animation_controller;
+pub mod animation_events;
+pub mod animation_layers;
 pub mod animation_node;
+pub mod animation_state_machine;
+pub mod baked_poses;
+pub mod compression;
+pub mod joint_overrides;
+pub mod retarget;
+pub mod root_motion;
 mod test;
 mod util;
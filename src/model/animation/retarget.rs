@@ -0,0 +1,343 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use cgmath::{InnerSpace, Rotation, SquareMatrix};
+
+use crate::model::{
+    animation::{
+        animation_controller::{AnimationSampler, AnimationTransforms},
+        animation_node::{AnimationNode, NodeType},
+        compression::dequantize_rotations,
+    },
+    model::GModel,
+};
+
+/// source joint name -> the name of the target joint it drives
+pub type JointNameMap = HashMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetargetError {
+    /// the source or the target model isn't animated
+    NoAnimationData,
+    /// the source has no animation with this index
+    UnknownAnimation(usize),
+    /// a name in the joint map isn't a joint of its skeleton
+    UnknownJoint(String),
+    /// an animation can't be retargeted onto the model it came from
+    SameModel,
+    /// the scene has no model with this index
+    UnknownModel(usize),
+}
+
+impl Display for RetargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoAnimationData => write!(f, "both models need animation data"),
+            Self::UnknownAnimation(index) => write!(f, "the source has no animation {}", index),
+            Self::UnknownJoint(name) => write!(f, "no joint is named {}", name),
+            Self::SameModel => write!(f, "a model can't be retargeted onto itself"),
+            Self::UnknownModel(model_id) => write!(f, "there is no model {}", model_id),
+        }
+    }
+}
+
+/// The rest pose of a node, which retargeting takes as the bind pose of its skeleton
+struct RestPose {
+    translation: cgmath::Vector3<f32>,
+    rotation: cgmath::Quaternion<f32>,
+    scale: cgmath::Vector3<f32>,
+    global: cgmath::Matrix4<f32>,
+    parent_global: cgmath::Matrix4<f32>,
+    depth: usize,
+}
+
+fn collect_rest_poses(
+    node: &AnimationNode,
+    parent_global: cgmath::Matrix4<f32>,
+    depth: usize,
+    rest_poses: &mut HashMap<usize, RestPose>,
+) {
+    let global = parent_global
+        * cgmath::Matrix4::from_translation(node.trans)
+        * cgmath::Matrix4::from(node.rot)
+        * cgmath::Matrix4::from_nonuniform_scale(node.scale.x, node.scale.y, node.scale.z);
+    rest_poses.insert(
+        node.node_id,
+        RestPose {
+            translation: node.trans,
+            rotation: node.rot,
+            scale: node.scale,
+            global,
+            parent_global,
+            depth,
+        },
+    );
+    for child_node in &node.children {
+        collect_rest_poses(child_node, global, depth + 1, rest_poses);
+    }
+}
+
+fn collect_joint_ids(node: &AnimationNode, joint_ids: &mut Vec<usize>) {
+    if matches!(node.node_type, NodeType::Joint(_)) {
+        joint_ids.push(node.node_id);
+    }
+    for child_node in &node.children {
+        collect_joint_ids(child_node, joint_ids);
+    }
+}
+
+/// name -> node id of every named joint in the model's skeleton
+fn joint_ids_by_name(model: &GModel) -> HashMap<&str, usize> {
+    let mut joint_ids = Vec::new();
+    if let Some(animation_data) = &model.animation_data {
+        collect_joint_ids(&animation_data.animation_node, &mut joint_ids);
+    }
+    model
+        .node_names
+        .iter()
+        .filter(|(_, node_id)| joint_ids.contains(node_id))
+        .map(|(name, node_id)| (name.as_str(), *node_id))
+        .collect()
+}
+
+/// Pair every joint of source with the joint of target that has the same name
+pub fn matching_joint_names(source: &GModel, target: &GModel) -> JointNameMap {
+    let target_joints = joint_ids_by_name(target);
+    joint_ids_by_name(source)
+        .into_keys()
+        .filter(|name| target_joints.contains_key(name))
+        .map(|name| (name.to_string(), name.to_string()))
+        .collect()
+}
+
+/// Copy an animation of source onto the skeleton of target, adding it to target as a new
+/// animation and returning its index.
+///
+/// Rotations are carried over as the change from the source joint's rest pose, expressed in
+/// model space, so joints with differently oriented axes still turn the same way. Translations
+/// are offsets from the rest pose, scaled by the ratio of bone lengths, or for the topmost
+/// mapped joint by the ratio of its distance from the model origin (e.g. hip height).
+/// Target joints without a mapping hold their rest pose
+pub fn retarget_animation(
+    source: &GModel,
+    animation_index: usize,
+    target: &mut GModel,
+    joint_names: &JointNameMap,
+    name: Option<&str>,
+) -> Result<usize, RetargetError> {
+    let (Some(source_data), Some(target_data)) = (&source.animation_data, &target.animation_data)
+    else {
+        return Err(RetargetError::NoAnimationData);
+    };
+    if source_data
+        .animation_node
+        .get_animation_duration(animation_index)
+        <= 0.0
+    {
+        return Err(RetargetError::UnknownAnimation(animation_index));
+    }
+    // only joints are in the rest poses and sampler trees below
+    let (source_joints, target_joints) = (joint_ids_by_name(source), joint_ids_by_name(target));
+    let joint_id = |joints: &HashMap<&str, usize>, name: &String| {
+        joints
+            .get(name.as_str())
+            .copied()
+            .ok_or_else(|| RetargetError::UnknownJoint(name.clone()))
+    };
+    let joint_pairs = joint_names
+        .iter()
+        .map(|(source_name, target_name)| {
+            Ok((
+                joint_id(&source_joints, source_name)?,
+                joint_id(&target_joints, target_name)?,
+            ))
+        })
+        .collect::<Result<Vec<(usize, usize)>, RetargetError>>()?;
+
+    let mut source_rest = HashMap::new();
+    collect_rest_poses(
+        &source_data.animation_node,
+        cgmath::Matrix4::identity(),
+        0,
+        &mut source_rest,
+    );
+    let mut target_rest = HashMap::new();
+    collect_rest_poses(
+        &target_data.animation_node,
+        cgmath::Matrix4::identity(),
+        0,
+        &mut target_rest,
+    );
+    // the topmost mapped joint moves the whole skeleton, so it is scaled by the overall size
+    let root_pair = joint_pairs
+        .iter()
+        .min_by_key(|(source_id, _)| source_rest.get(source_id).map(|rest| rest.depth))
+        .copied();
+
+    // target joint id -> its samplers for the new animation
+    let mut retargeted: HashMap<usize, Vec<AnimationSampler>> = HashMap::new();
+    let mut next_sampler_id = 0;
+    for (source_id, target_id) in joint_pairs.iter().copied() {
+        let Some(samplers) = find_samplers(&source_data.animation_node, source_id, animation_index)
+        else {
+            continue;
+        };
+        // several source joints may drive the same target joint, only one of them is used
+        if retargeted.contains_key(&target_id) {
+            continue;
+        }
+        let (Some(from), Some(to)) = (source_rest.get(&source_id), target_rest.get(&target_id))
+        else {
+            continue;
+        };
+        let translation_ratio = if Some((source_id, target_id)) == root_pair {
+            length_ratio(from.global.w.truncate(), to.global.w.truncate())
+        } else {
+            length_ratio(from.translation, to.translation)
+        };
+        let samplers = samplers
+            .iter()
+            .map(|sampler| {
+                next_sampler_id += 1;
+                AnimationSampler {
+                    id: next_sampler_id - 1,
+                    interpolation: sampler.interpolation,
+                    times: sampler.times.clone(),
+                    transforms: retarget_transforms(
+                        &sampler.transforms,
+                        from,
+                        to,
+                        translation_ratio,
+                    ),
+                }
+            })
+            .collect();
+        retargeted.insert(target_id, samplers);
+    }
+
+    let target_data = target.animation_data.as_mut().unwrap();
+    let new_index = target_data
+        .animation_count
+        .max(target_data.animation_node.max_animation_index() + 1);
+    let animation_node = Arc::make_mut(&mut target_data.animation_node);
+    for (target_id, samplers) in retargeted {
+        // joints were collected from this tree, so they are always found
+        if let Some(node) = animation_node.find_node_mut(target_id) {
+            node.add_sampler_set(new_index, samplers);
+        }
+    }
+    target_data.animation_count = new_index + 1;
+    if let Some(name) = name {
+        target_data
+            .animation_names
+            .insert(name.to_string(), new_index);
+    }
+    if let Some(events) = source_data.animation_events.get(&animation_index) {
+        for event in events {
+            target_data.add_animation_event(new_index, &event.name, event.time);
+        }
+    }
+    Ok(new_index)
+}
+
+fn find_samplers(
+    animation_node: &AnimationNode,
+    node_id: usize,
+    animation_index: usize,
+) -> Option<&Vec<AnimationSampler>> {
+    if animation_node.node_id == node_id {
+        return animation_node.samplers.as_ref()?.get(&animation_index);
+    }
+    animation_node
+        .children
+        .iter()
+        .find_map(|child_node| find_samplers(child_node, node_id, animation_index))
+}
+
+/// how much longer to is than from, 1 if from has no length
+fn length_ratio(from: cgmath::Vector3<f32>, to: cgmath::Vector3<f32>) -> f32 {
+    if from.magnitude2() < 1e-12 {
+        1.0
+    } else {
+        to.magnitude() / from.magnitude()
+    }
+}
+
+/// the rotation of a transform, without its scale
+fn rotation_of(transform: &cgmath::Matrix4<f32>) -> cgmath::Quaternion<f32> {
+    cgmath::Quaternion::from(cgmath::Matrix3::from_cols(
+        transform.x.truncate().normalize(),
+        transform.y.truncate().normalize(),
+        transform.z.truncate().normalize(),
+    ))
+    .normalize()
+}
+
+fn linear_part(transform: &cgmath::Matrix4<f32>) -> cgmath::Matrix3<f32> {
+    cgmath::Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    )
+}
+
+fn retarget_transforms(
+    transforms: &AnimationTransforms,
+    from: &RestPose,
+    to: &RestPose,
+    translation_ratio: f32,
+) -> AnimationTransforms {
+    match transforms {
+        AnimationTransforms::QuantizedRotation(rotations) => retarget_transforms(
+            &AnimationTransforms::Rotation(dequantize_rotations(rotations)),
+            from,
+            to,
+            translation_ratio,
+        ),
+        AnimationTransforms::Rotation(rotations) => {
+            // the change from the rest pose is moved from the source joint's axes into the
+            // target joint's through model space
+            let axes = rotation_of(&to.global).invert() * rotation_of(&from.global);
+            let rest_inverse = from.rotation.invert();
+            AnimationTransforms::Rotation(
+                rotations
+                    .iter()
+                    .map(|rotation| {
+                        let change = axes * (rest_inverse * *rotation) * axes.invert();
+                        (to.rotation * change).normalize()
+                    })
+                    .collect(),
+            )
+        }
+        AnimationTransforms::Translation(translations) => {
+            // offsets are moved from the source parent's space into the target parent's
+            let parent_space = linear_part(&to.parent_global)
+                .invert()
+                .unwrap_or(cgmath::Matrix3::identity())
+                * linear_part(&from.parent_global);
+            AnimationTransforms::Translation(
+                translations
+                    .iter()
+                    .map(|translation| {
+                        let offset = parent_space * (translation - from.translation);
+                        to.translation + offset * translation_ratio
+                    })
+                    .collect(),
+            )
+        }
+        AnimationTransforms::Scale(scales) => {
+            let ratio = |value: f32, rest: f32| if rest == 0.0 { 1.0 } else { value / rest };
+            AnimationTransforms::Scale(
+                scales
+                    .iter()
+                    .map(|scale| {
+                        cgmath::Vector3::new(
+                            to.scale.x * ratio(scale.x, from.scale.x),
+                            to.scale.y * ratio(scale.y, from.scale.y),
+                            to.scale.z * ratio(scale.z, from.scale.z),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
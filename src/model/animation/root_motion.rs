@@ -0,0 +1,148 @@
+use cgmath::SquareMatrix;
+
+/// What to strip from the root of a model's animation and deliver as motion of the instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RootMotion {
+    /// the animation moves the root within the model, as authored
+    #[default]
+    Off,
+    /// the horizontal (xz) translation of the root is extracted
+    Translation,
+    /// the horizontal translation and the rotation about the up (y) axis are extracted
+    TranslationAndYaw,
+}
+
+/// The horizontal placement of the root in model space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct RootPose {
+    pub(super) translation: cgmath::Vector3<f32>,
+    /// radians about the y axis, relative to the orientation at the start of the animation
+    pub(super) yaw: f32,
+}
+
+impl RootPose {
+    fn matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.translation)
+            * cgmath::Matrix4::from_angle_y(cgmath::Rad(self.yaw))
+    }
+}
+
+/// Per instance root motion bookkeeping
+#[derive(Debug)]
+pub(super) struct RootMotionState {
+    pub(super) mode: RootMotion,
+    /// the node whose motion is extracted
+    pub(super) node_id: usize,
+    /// the inverse of the root's model space rotation at the start of the animation,
+    /// yaw is measured relative to it
+    start_rotation_inverse: cgmath::Matrix3<f32>,
+    pub(super) start: RootPose,
+    pub(super) end: RootPose,
+    /// the pose extracted this frame
+    current: Option<RootPose>,
+    /// the pose and loop index of the previous frame
+    previous: Option<(RootPose, u32)>,
+}
+
+impl RootMotionState {
+    /// start and end are the model space transforms of the root node at the first and last
+    /// keyframe of the animation
+    pub(super) fn new(
+        mode: RootMotion,
+        node_id: usize,
+        start: cgmath::Matrix4<f32>,
+        end: cgmath::Matrix4<f32>,
+    ) -> Self {
+        let start_rotation_inverse = rotation_part(&start)
+            .invert()
+            .unwrap_or(cgmath::Matrix3::identity());
+        let mut state = Self {
+            mode,
+            node_id,
+            start_rotation_inverse,
+            start: RootPose {
+                translation: cgmath::Vector3::new(0.0, 0.0, 0.0),
+                yaw: 0.0,
+            },
+            end: RootPose {
+                translation: cgmath::Vector3::new(0.0, 0.0, 0.0),
+                yaw: 0.0,
+            },
+            current: None,
+            previous: None,
+        };
+        state.start = state.pose_of(&start);
+        state.end = state.pose_of(&end);
+        state
+    }
+
+    pub(super) fn pose_of(&self, transform: &cgmath::Matrix4<f32>) -> RootPose {
+        let yaw = match self.mode {
+            RootMotion::TranslationAndYaw => {
+                // how far the start orientation's x axis has turned about y
+                let x_axis = rotation_part(transform)
+                    * self.start_rotation_inverse
+                    * cgmath::Vector3::unit_x();
+                (-x_axis.z).atan2(x_axis.x)
+            }
+            _ => 0.0,
+        };
+        RootPose {
+            translation: cgmath::Vector3::new(transform.w.x, 0.0, transform.w.z),
+            yaw,
+        }
+    }
+
+    /// Record the root's sampled model space transform for this frame, and return it with
+    /// the extracted motion removed, so that the root stays at its starting placement
+    pub(super) fn extract(&mut self, global: cgmath::Matrix4<f32>) -> cgmath::Matrix4<f32> {
+        if self.mode == RootMotion::Off {
+            return global;
+        }
+        let pose = self.pose_of(&global);
+        self.current = Some(pose);
+        let root_position = global.w.truncate();
+        cgmath::Matrix4::from_translation(self.start.translation - pose.translation)
+            * cgmath::Matrix4::from_translation(root_position)
+            * cgmath::Matrix4::from_angle_y(cgmath::Rad(self.start.yaw - pose.yaw))
+            * cgmath::Matrix4::from_translation(-root_position)
+            * global
+    }
+
+    /// The motion extracted since the previous frame, as a transform to apply on the right
+    /// of the instance's global transform. loop_index is the number of completed loops
+    pub(super) fn take_delta(&mut self, loop_index: u32) -> Option<cgmath::Matrix4<f32>> {
+        let current = self.current.take()?;
+        let delta = match self.previous {
+            None => cgmath::Matrix4::identity(),
+            Some((previous, previous_loop)) => {
+                let inverse = |pose: &RootPose| pose.matrix().invert().unwrap();
+                // the motion of the root within its own frame
+                let mut motion = inverse(&previous);
+                if loop_index > previous_loop {
+                    // finish the previous loop, play any skipped loops whole, then start over
+                    let full_loop = inverse(&self.start) * self.end.matrix();
+                    motion = motion * self.end.matrix();
+                    for _ in 1..loop_index - previous_loop {
+                        motion = motion * full_loop;
+                    }
+                    motion = motion * inverse(&self.start);
+                }
+                motion = motion * current.matrix();
+                // the root is displayed at its start pose, so move the instance such that the
+                // root moves by motion
+                self.start.matrix() * motion * inverse(&self.start)
+            }
+        };
+        self.previous = Some((current, loop_index));
+        Some(delta)
+    }
+}
+
+fn rotation_part(transform: &cgmath::Matrix4<f32>) -> cgmath::Matrix3<f32> {
+    cgmath::Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    )
+}
@@ -1,5 +1,1587 @@
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+
+    use cgmath::{EuclideanSpace, InnerSpace, Rotation, Rotation3, SquareMatrix};
+
+    use crate::model::{
+        animation::{
+            animation::PlaybackOptions,
+            animation_compute::*,
+            animation_controller::{
+                AnimationSample, AnimationSampler, AnimationTransforms, AnimationValue,
+                SampleResult, SceneAnimationController,
+            },
+            animation_events::{for_each_fired_event, AnimationEvent},
+            animation_layers::{AnimationLayer, JointMask, LayerBlend, Trs},
+            animation_node::{AnimationNode, NodeType},
+            animation_state_machine::{
+                AnimationParameter, AnimationRef, AnimationState, AnimationStateMachine,
+                AnimationTransition, StateMachineError, TransitionCondition,
+            },
+            baked_poses::BakedPoses,
+            compression::{
+                compress_sampler, rotation_error, CompressionSettings, QuantizedQuaternion,
+            },
+            joint_overrides::JointOverride,
+            retarget::{matching_joint_names, retarget_animation, RetargetError},
+            root_motion::{RootMotion, RootMotionState},
+            util::InterpolationType,
+        },
+        loader::loader::GltfLoader,
+        model::GModel,
+    };
+    use crate::scene::instances::InstanceHandle;
+
+    /// the local transform of a flattened node, with every channel rebuilt into a cpu
+    /// [AnimationSampler] so that the flattened keyframes are sampled like the originals
+    fn sample_flattened(
+        data: &AnimationComputeData,
+        node: &GpuAnimationNode,
+        animation_index: u32,
+        time: f32,
+    ) -> cgmath::Matrix4<f32> {
+        let mut translation = cgmath::Vector3::new(
+            node.translation[0],
+            node.translation[1],
+            node.translation[2],
+        );
+        let mut rotation = cgmath::Quaternion::new(
+            node.rotation[3],
+            node.rotation[0],
+            node.rotation[1],
+            node.rotation[2],
+        );
+        let mut scale = cgmath::Vector3::new(node.scale[0], node.scale[1], node.scale[2]);
+        if node.channel_table != NO_CHANNELS {
+            let [offset, count] =
+                data.channel_ranges[(node.channel_table + animation_index) as usize];
+            for channel in &data.channels[offset as usize..(offset + count) as usize] {
+                let times = data.keyframe_data[channel.times_offset as usize
+                    ..(channel.times_offset + channel.key_count) as usize]
+                    .to_vec();
+                let keys = (0..channel.key_count as usize).map(|i| {
+                    let base = channel.values_offset as usize + i * 4;
+                    let v = &data.keyframe_data[base..base + 4];
+                    cgmath::Vector4::new(v[0], v[1], v[2], v[3])
+                });
+                let transforms = match channel.property {
+                    PROPERTY_ROTATION => AnimationTransforms::Rotation(
+                        keys.map(|v| cgmath::Quaternion::new(v.w, v.x, v.y, v.z))
+                            .collect(),
+                    ),
+                    PROPERTY_TRANSLATION => {
+                        AnimationTransforms::Translation(keys.map(|v| v.truncate()).collect())
+                    }
+                    _ => AnimationTransforms::Scale(keys.map(|v| v.truncate()).collect()),
+                };
+                let sampler = AnimationSampler {
+                    id: 0,
+                    interpolation: InterpolationType::Linear,
+                    times,
+                    transforms,
+                };
+                match sampler.sample_at(time) {
+                    Some(AnimationValue::Rotation(value)) => rotation = value,
+                    Some(AnimationValue::Translation(value)) => translation = value,
+                    Some(AnimationValue::Scale(value)) => scale = value,
+                    None => {}
+                }
+            }
+        }
+        cgmath::Matrix4::from_translation(translation)
+            * cgmath::Matrix4::from(rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+    }
+
+    fn evaluate_flattened(
+        data: &AnimationComputeData,
+        job: &AnimationComputeJob,
+        mesh_transforms: &mut [[[f32; 4]; 4]],
+        joint_transforms: &mut [[[f32; 4]; 4]],
+    ) {
+        for node_index in job.node_offset..job.node_offset + job.node_count {
+            let node = &data.nodes[node_index as usize];
+            let mut global = sample_flattened(data, node, job.animation_index, job.time);
+            let mut parent = node.parent;
+            while parent >= 0 {
+                let parent_node = &data.nodes[parent as usize];
+                global =
+                    sample_flattened(data, parent_node, job.animation_index, job.time) * global;
+                parent = parent_node.parent;
+            }
+            let output = node.output_index as usize;
+            match node.kind {
+                NODE_KIND_MESH if job.is_skeletal != 0 => {
+                    mesh_transforms[output] = cgmath::Matrix4::<f32>::identity().into()
+                }
+                NODE_KIND_MESH => mesh_transforms[output] = global.into(),
+                NODE_KIND_JOINT => {
+                    let ibm = cgmath::Matrix4::from(data.inverse_bind_matrices[output]);
+                    joint_transforms[output] = (global * ibm).into();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn assert_matrices_close(a: &[[[f32; 4]; 4]], b: &[[[f32; 4]; 4]], time: f32) {
+        assert_eq!(a.len(), b.len());
+        for (m, n) in a.iter().zip(b.iter()) {
+            for c in 0..4 {
+                for r in 0..4 {
+                    assert!(
+                        (m[c][r] - n[c][r]).abs() < 1e-4,
+                        "at {time}s: {:?} != {:?}",
+                        m,
+                        n
+                    );
+                }
+            }
+        }
+    }
+
+    /// the flattened compute data must produce the same transforms as the cpu node tree.
+    /// The shader itself is checked against the cpu in app::compute
+    fn compare_compute_with_cpu(dir_name: &str, times: &[f32]) {
+        let gltf_data = GltfLoader::load_gltf(dir_name).unwrap();
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        let compute_data = AnimationComputeData::from_models(
+            &gltf_data.models,
+            controller.inverse_bind_matrices(),
+        );
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        controller.initialize_animation(
+            animation_data,
+            InstanceHandle::for_slot(0),
+            0,
+            0,
+            mesh_count,
+            PlaybackOptions::default(),
+        );
+        controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+
+        for time in times {
+            let instance = &mut controller.active_animations[model_idx][0];
+            let cpu = instance.process_animation_frame(
+                Duration::from_secs_f32(*time),
+                animation_data,
+                &controller.inverse_bind_matrices,
+            );
+            let job = AnimationComputeJob::new(
+                compute_data.model_node_ranges[model_idx].unwrap(),
+                0,
+                0,
+                *time,
+                animation_data.is_skeletal,
+            );
+            // every output has to be written by the flattened nodes, or the nans show up
+            let mut mesh_transforms = vec![[[f32::NAN; 4]; 4]; cpu.mesh_transforms.len()];
+            let mut joint_transforms = vec![[[f32::NAN; 4]; 4]; cpu.joint_transforms.len()];
+            evaluate_flattened(
+                &compute_data,
+                &job,
+                &mut mesh_transforms,
+                &mut joint_transforms,
+            );
+            assert_matrices_close(cpu.mesh_transforms, &mesh_transforms, *time);
+            assert_matrices_close(cpu.joint_transforms, &joint_transforms, *time);
+        }
+    }
+
+    fn test_sampler() -> AnimationSampler {
+        AnimationSampler {
+            id: 0,
+            interpolation: InterpolationType::Linear,
+            times: vec![0.5, 1.0, 2.0, 4.0],
+            transforms: AnimationTransforms::Translation(vec![
+                cgmath::Vector3::new(0.0, 0.0, 0.0),
+                cgmath::Vector3::new(1.0, 0.0, 0.0),
+                cgmath::Vector3::new(1.0, 2.0, 0.0),
+                cgmath::Vector3::new(1.0, 2.0, 4.0),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_sample_at_is_random_access() {
+        let sampler = test_sampler();
+        assert_eq!(sampler.sample_at(0.25), None);
+        assert_eq!(
+            sampler.sample_at(0.5),
+            Some(AnimationValue::Translation(cgmath::Vector3::new(
+                0.0, 0.0, 0.0
+            )))
+        );
+        assert_eq!(
+            sampler.sample_at(3.0),
+            Some(AnimationValue::Translation(cgmath::Vector3::new(
+                1.0, 2.0, 2.0
+            )))
+        );
+        // going backwards gives the same answer as going forwards
+        assert_eq!(
+            sampler.sample_at(0.75),
+            Some(AnimationValue::Translation(cgmath::Vector3::new(
+                0.5, 0.0, 0.0
+            )))
+        );
+        // past the end the last keyframe is held
+        assert_eq!(
+            sampler.sample_at(10.0),
+            Some(AnimationValue::Translation(cgmath::Vector3::new(
+                1.0, 2.0, 4.0
+            )))
+        );
+    }
+
+    #[test]
+    fn test_scale_interpolates_from_the_first_keyframe() {
+        let sampler = AnimationSampler {
+            id: 0,
+            interpolation: InterpolationType::Linear,
+            times: vec![0.0, 1.0],
+            transforms: AnimationTransforms::Scale(vec![
+                cgmath::Vector3::new(1.0, 1.0, 1.0),
+                cgmath::Vector3::new(3.0, 1.0, 0.0),
+            ]),
+        };
+        assert_eq!(
+            sampler.sample_at(0.25),
+            Some(AnimationValue::Scale(cgmath::Vector3::new(1.5, 1.0, 0.75)))
+        );
+    }
+
+    #[test]
+    fn test_cursor_sampling_matches_sample_at() {
+        let sampler = test_sampler();
+        let mut cursor = AnimationSample {
+            end_time: sampler.times[0],
+            transform_index: -1,
+        };
+        // forwards, backwards, large jumps and past the end
+        for time in [0.0, 0.6, 0.7, 1.5, 3.9, 0.9, 0.2, 2.0, 5.0, 1.2, 4.0, 0.5] {
+            let sample_result = sampler.sample(cursor, time);
+            if let SampleResult::Active(sample) = sample_result {
+                cursor = sample;
+            }
+            assert_eq!(
+                sampler.value_of(sample_result, time),
+                sampler.sample_at(time)
+            );
+        }
+    }
+
+    fn fired_names(from: Option<f32>, to: f32, looping: bool) -> Vec<String> {
+        let events = vec![
+            AnimationEvent {
+                name: "start".to_string(),
+                time: 0.0,
+            },
+            AnimationEvent {
+                name: "footstep".to_string(),
+                time: 0.4,
+            },
+            AnimationEvent {
+                name: "land".to_string(),
+                time: 0.9,
+            },
+        ];
+        let mut fired = Vec::new();
+        for_each_fired_event(&events, from, to, 1.0, looping, |event| {
+            fired.push(event.name.clone())
+        });
+        fired
+    }
+
+    #[test]
+    fn test_animation_events_fire_once_per_crossing() {
+        assert_eq!(fired_names(None, 0.1, false), ["start"]);
+        assert_eq!(fired_names(Some(0.1), 0.4, false), ["footstep"]);
+        assert_eq!(fired_names(Some(0.4), 0.5, false), Vec::<String>::new());
+        // a non looping animation doesn't start over
+        assert_eq!(fired_names(Some(0.5), 1.5, false), ["land"]);
+        // wrapping fires the end of one loop and the start of the next
+        assert_eq!(
+            fired_names(Some(0.5), 1.5, true),
+            ["land", "start", "footstep"]
+        );
+        // a long frame can pass several loops
+        assert_eq!(
+            fired_names(Some(0.95), 2.1, true),
+            ["start", "footstep", "land", "start"]
+        );
+    }
+
+    #[test]
+    fn test_looping_animation_reports_events_each_loop() {
+        let mut gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let model_idx = gltf_data
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let model = &mut gltf_data.models[model_idx];
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let animation_data = model.animation_data.as_mut().unwrap();
+        animation_data.add_animation_event(0, "footstep", 0.5);
+        let duration = animation_data.animation_node.get_animation_duration(0);
+
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        let animation_data = gltf_data.models[model_idx].animation_data.as_ref().unwrap();
+        controller.initialize_animation(
+            animation_data,
+            InstanceHandle::for_slot(0),
+            0,
+            0,
+            mesh_count,
+            PlaybackOptions {
+                looping: true,
+                ..Default::default()
+            },
+        );
+        controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+
+        let mut footsteps = 0;
+        let frames = 200;
+        for frame in 0..=frames {
+            let time = duration * 3.0 * frame as f32 / frames as f32;
+            let animation_frame = controller
+                .do_animations(Duration::from_secs_f32(time), &gltf_data.models)
+                .expect("looping animations never finish");
+            for event in animation_frame.events {
+                assert_eq!(event.name, "footstep");
+                assert_eq!(event.model_index, model_idx);
+                footsteps += 1;
+            }
+        }
+        assert_eq!(footsteps, 3);
+        assert_eq!(controller.active_animation_count, 1);
+    }
+
+    fn check_root_motion(mode: RootMotion) {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        controller.initialize_animation(
+            animation_data,
+            InstanceHandle::for_slot(0),
+            0,
+            0,
+            mesh_count,
+            PlaybackOptions {
+                looping: true,
+                root_motion: mode,
+            },
+        );
+        controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+        let node = &animation_data.animation_node;
+        let root_id = node.root_motion_node().unwrap();
+        let root_joint = animation_data.joint_animation_data.joint_to_joint_index[&root_id];
+        let root_ibm = gltf_data.skin_ibms[&0][root_joint];
+        let duration = node.get_animation_duration(0);
+        let model_space = |time| {
+            node.sample_model_space_transform(root_id, 0, time, cgmath::Matrix4::identity())
+                .unwrap()
+        };
+        // a copy of the instance's state, to measure poses with
+        let state = RootMotionState::new(mode, root_id, model_space(0.0), model_space(duration));
+        let pose = |time| {
+            let pose = state.pose_of(&model_space(time));
+            cgmath::Matrix4::from_translation(pose.translation)
+                * cgmath::Matrix4::from_angle_y(cgmath::Rad(pose.yaw))
+        };
+        let start = pose(0.0);
+        let start_inverse = start.invert().unwrap();
+        let full_loop = start_inverse * pose(duration);
+
+        let mut accumulated = cgmath::Matrix4::<f32>::identity();
+        let frames = 150;
+        for frame in 0..=frames {
+            let time = duration * 2.5 * frame as f32 / frames as f32;
+            let animation_frame = controller
+                .do_animations(Duration::from_secs_f32(time), &gltf_data.models)
+                .unwrap();
+            assert_eq!(animation_frame.root_motion.len(), 1);
+            accumulated = accumulated * cgmath::Matrix4::from(animation_frame.root_motion[0].1);
+
+            // the root stays where it started within the model
+            let root = cgmath::Matrix4::from(animation_frame.joint_transform_slices[0][root_joint])
+                * root_ibm.invert().unwrap();
+            let displayed = state.pose_of(&root);
+            assert!((displayed.translation - start.w.truncate()).magnitude() < 1e-3);
+            assert!(displayed.yaw.abs() < 1e-3);
+
+            // and the instance has moved by everything that was extracted
+            let loops = (time / duration).floor() as i32;
+            let local_time = time - loops as f32 * duration;
+            let mut expected = start;
+            for _ in 0..loops {
+                expected = expected * full_loop;
+            }
+            expected = expected * start_inverse * pose(local_time) * start_inverse;
+            for c in 0..4 {
+                assert!(
+                    (accumulated[c] - expected[c]).magnitude() < 1e-3,
+                    "at {time}s: {:?} != {:?}",
+                    accumulated,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_root_motion_translation() {
+        check_root_motion(RootMotion::Translation);
+    }
+
+    #[test]
+    fn test_root_motion_translation_and_yaw() {
+        check_root_motion(RootMotion::TranslationAndYaw);
+    }
+
+    #[test]
+    fn test_layer_blending() {
+        let sampler = test_sampler();
+        let rest = Trs {
+            translation: cgmath::Vector3::new(0.0, 0.0, 1.0),
+            rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        };
+        let apply = |layer: &AnimationLayer, node_id| {
+            let mut pose = rest;
+            layer.apply(node_id, std::slice::from_ref(&sampler), &mut pose);
+            pose.translation
+        };
+        // sampled translation at 3s is (1, 2, 2)
+        let mut layer = AnimationLayer::new(0, LayerBlend::Override).with_weight(0.5);
+        layer.sample_time = 3.0;
+        assert_eq!(apply(&layer, 7), cgmath::Vector3::new(0.5, 1.0, 1.5));
+        let layer = layer.with_mask(JointMask::from_node_ids([3]));
+        assert_eq!(apply(&layer, 7), rest.translation);
+        assert_eq!(apply(&layer, 3), cgmath::Vector3::new(0.5, 1.0, 1.5));
+        // relative to the pose at 1s, (1, 0, 0)
+        let mut layer = AnimationLayer::new(
+            0,
+            LayerBlend::Additive {
+                reference_time: 1.0,
+            },
+        );
+        layer.sample_time = 3.0;
+        assert_eq!(apply(&layer, 7), cgmath::Vector3::new(0.0, 2.0, 3.0));
+        // sampled at its reference, an additive layer changes nothing
+        layer.sample_time = 1.0;
+        assert_eq!(apply(&layer, 7), rest.translation);
+    }
+
+    #[test]
+    fn test_masked_layer_only_affects_subtree() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let joint_data = &animation_data.joint_animation_data;
+        let node = &animation_data.animation_node;
+        // mask the first joint below the root joint that has joints of its own
+        let root_joint = node.root_motion_node().unwrap();
+        let mut root_joint_mask = HashSet::new();
+        node.collect_subtree_ids(root_joint, false, &mut root_joint_mask);
+        let masked_node = *joint_data
+            .joint_to_joint_index
+            .keys()
+            .filter(|id| **id != root_joint && root_joint_mask.contains(id))
+            .find(|id| JointMask::subtree(node, **id).len() > 2)
+            .unwrap();
+        let mask = JointMask::subtree(node, masked_node);
+
+        let play = |layer: Option<AnimationLayer>| {
+            let mut controller =
+                SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+            controller.initialize_animation(
+                animation_data,
+                InstanceHandle::for_slot(0),
+                0,
+                0,
+                mesh_count,
+                PlaybackOptions {
+                    looping: true,
+                    ..Default::default()
+                },
+            );
+            controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+            if let Some(layer) = layer {
+                assert!(controller.add_animation_layer(
+                    model_idx,
+                    InstanceHandle::for_slot(0),
+                    layer
+                ));
+                controller.active_animations[model_idx][0].layers[0].start_time =
+                    Duration::from_millis(300);
+            }
+            let frame = controller
+                .do_animations(Duration::from_millis(1000), &gltf_data.models)
+                .unwrap();
+            frame.joint_transform_slices[0].to_vec()
+        };
+        let base = play(None);
+        let layered = play(Some(
+            AnimationLayer::new(0, LayerBlend::Override).with_mask(mask.clone()),
+        ));
+        let unweighted = play(Some(
+            AnimationLayer::new(0, LayerBlend::Override)
+                .with_mask(mask.clone())
+                .with_weight(0.0),
+        ));
+        assert_eq!(base, unweighted);
+        let mut changed = 0;
+        for (node_id, joint_index) in joint_data.joint_to_joint_index.iter() {
+            if mask.contains(*node_id) {
+                changed += (base[*joint_index] != layered[*joint_index]) as usize;
+            } else {
+                assert_eq!(base[*joint_index], layered[*joint_index]);
+            }
+        }
+        assert!(changed > 0);
+    }
+
+    #[test]
+    fn test_layers_stay_on_their_own_instance() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let model_idx = gltf_data
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = gltf_data.models[model_idx].animation_data.as_ref().unwrap();
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        // skeletal instances all write at offset 0, only their handles tell them apart
+        let (first, second) = (InstanceHandle::for_slot(0), InstanceHandle::for_slot(1));
+        for instance in [first, second] {
+            controller.initialize_animation(
+                animation_data,
+                instance,
+                0,
+                0,
+                0,
+                PlaybackOptions::default(),
+            );
+        }
+        let layer_counts = |controller: &SceneAnimationController| {
+            controller.active_animations[model_idx]
+                .iter()
+                .map(|animation| (animation.instance, animation.layers.len()))
+                .collect::<Vec<_>>()
+        };
+
+        let layer = AnimationLayer::new(2, LayerBlend::Override);
+        assert!(controller.add_animation_layer(model_idx, second, layer));
+        assert_eq!(layer_counts(&controller), vec![(first, 0), (second, 1)]);
+        controller.clear_animation_layers(model_idx, first);
+        assert_eq!(layer_counts(&controller), vec![(first, 0), (second, 1)]);
+        controller.clear_animation_layers(model_idx, second);
+        assert_eq!(layer_counts(&controller), vec![(first, 0), (second, 0)]);
+    }
+
+    #[test]
+    fn test_joint_overrides_stay_on_their_own_instance() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let model_idx = gltf_data
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = gltf_data.models[model_idx].animation_data.as_ref().unwrap();
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        let compute_data = AnimationComputeData::from_models(
+            &gltf_data.models,
+            controller.inverse_bind_matrices(),
+        );
+        let (first, second) = (InstanceHandle::for_slot(0), InstanceHandle::for_slot(1));
+        let joint_node = *animation_data
+            .joint_animation_data
+            .joint_to_joint_index
+            .keys()
+            .next()
+            .unwrap();
+        let overrides = vec![JointOverride::Rotate {
+            joint_node,
+            rotation: cgmath::Quaternion::from_angle_y(cgmath::Deg(30.0)),
+        }];
+        // nothing is playing on the instance yet
+        assert!(!controller.set_joint_overrides(model_idx, second, overrides.clone()));
+        for instance in [first, second] {
+            controller.initialize_animation(
+                animation_data,
+                instance,
+                0,
+                0,
+                0,
+                PlaybackOptions::default(),
+            );
+        }
+
+        assert!(controller.set_joint_overrides(model_idx, second, overrides.clone()));
+        let override_counts: Vec<_> = controller.active_animations[model_idx]
+            .iter()
+            .map(|animation| (animation.instance, animation.joint_overrides.len()))
+            .collect();
+        assert_eq!(override_counts, vec![(first, 0), (second, 1)]);
+        let jobs = controller.get_compute_jobs(
+            Duration::from_millis(100),
+            &gltf_data.models,
+            &compute_data,
+            &mut vec![],
+        );
+        assert_eq!(
+            jobs.unwrap_err(),
+            ComputeUnsupported::JointOverrides(second)
+        );
+    }
+
+    #[test]
+    fn test_inverse_bind_matrices_follow_the_joint_numbering_of_every_skin() {
+        // the loader numbers the joints of skin 1 after those of skin 0
+        let ibm = |x: f32| cgmath::Matrix4::from_translation(cgmath::Vector3::new(x, 0.0, 0.0));
+        let skin_ibms = [(1, vec![ibm(2.0)]), (0, vec![ibm(0.0), ibm(1.0)])]
+            .into_iter()
+            .collect();
+        let controller = SceneAnimationController::new(0, skin_ibms);
+        assert_eq!(
+            controller.inverse_bind_matrices(),
+            &[ibm(0.0), ibm(1.0), ibm(2.0)]
+        );
+    }
+
+    /// a joint, one of its child joints and one of that joint's child joints
+    fn find_joint_chain(node: &AnimationNode) -> Option<(usize, usize, usize)> {
+        let is_joint = |node: &AnimationNode| matches!(node.node_type, NodeType::Joint(_));
+        if is_joint(node) {
+            for middle in node.children.iter().filter(|child| is_joint(child)) {
+                if let Some(end) = middle.children.iter().find(|child| is_joint(child)) {
+                    if end.children.iter().any(is_joint) {
+                        return Some((node.node_id, middle.node_id, end.node_id));
+                    }
+                }
+            }
+        }
+        node.children.iter().find_map(find_joint_chain)
+    }
+
+    #[test]
+    fn test_joint_overrides() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let joint_data = &animation_data.joint_animation_data;
+        let (root, middle, end) = find_joint_chain(&animation_data.animation_node).unwrap();
+        let joint = |node_id: usize| joint_data.joint_to_joint_index[&node_id];
+
+        let play = |joint_overrides: Vec<JointOverride>| {
+            let mut controller =
+                SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+            controller.initialize_animation(
+                animation_data,
+                InstanceHandle::for_slot(0),
+                0,
+                0,
+                mesh_count,
+                PlaybackOptions::default(),
+            );
+            controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+            assert!(controller.set_joint_overrides(
+                model_idx,
+                InstanceHandle::for_slot(0),
+                joint_overrides
+            ));
+            let frame = controller
+                .do_animations(Duration::from_millis(700), &gltf_data.models)
+                .unwrap();
+            let joint_transforms = frame.joint_transform_slices[0].to_vec();
+            let instance = &controller.active_animations[model_idx][0];
+            let ibms = &gltf_data.skin_ibms[&0];
+            for (i, global) in instance.joint_globals.iter().enumerate() {
+                let expected: [[f32; 4]; 4] = (global * ibms[i]).into();
+                assert_eq!(joint_transforms[i], expected);
+            }
+            instance.joint_globals.clone()
+        };
+        let position = |globals: &[cgmath::Matrix4<f32>], node_id| {
+            cgmath::Point3::from_vec(globals[joint(node_id)].w.truncate())
+        };
+        let base = play(vec![]);
+
+        // pull the end of the chain a little towards the root, and sideways
+        let root_position = position(&base, root);
+        let end_position = position(&base, end);
+        let target = end_position
+            + (root_position - end_position) * 0.2
+            + cgmath::Vector3::new(0.02, 0.0, 0.02);
+        let pole = position(&base, middle) + (position(&base, middle) - root_position);
+        let solved = play(vec![JointOverride::TwoBoneIk {
+            root_node: root,
+            middle_node: middle,
+            end_node: end,
+            target,
+            pole,
+            weight: 1.0,
+        }]);
+        assert!((position(&solved, end) - target).magnitude() < 1e-3);
+        assert!((position(&solved, root) - root_position).magnitude() < 1e-5);
+        let bone = |globals: &[cgmath::Matrix4<f32>], a, b| {
+            (position(globals, a) - position(globals, b)).magnitude()
+        };
+        assert!((bone(&solved, root, middle) - bone(&base, root, middle)).abs() < 1e-4);
+        assert!((bone(&solved, middle, end) - bone(&base, middle, end)).abs() < 1e-4);
+        // nothing outside of the chain moves
+        let mut chain = HashSet::new();
+        animation_data
+            .animation_node
+            .collect_subtree_ids(root, false, &mut chain);
+        for (node_id, joint_index) in joint_data.joint_to_joint_index.iter() {
+            if !chain.contains(node_id) {
+                assert_eq!(base[*joint_index], solved[*joint_index]);
+            }
+        }
+
+        let look_target = position(&base, end) + cgmath::Vector3::new(1.0, 1.0, 0.0);
+        let aim_axis = cgmath::Vector3::unit_y();
+        let looked = play(vec![JointOverride::LookAt {
+            joint_node: end,
+            aim_axis,
+            target: look_target,
+            weight: 1.0,
+        }]);
+        let aim = (looked[joint(end)] * aim_axis.extend(0.0))
+            .truncate()
+            .normalize();
+        let expected = (look_target - position(&looked, end)).normalize();
+        assert!((aim - expected).magnitude() < 1e-4);
+    }
+
+    #[test]
+    fn test_state_machine() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let looping = PlaybackOptions {
+            looping: true,
+            ..Default::default()
+        };
+        let mut state_machine = AnimationStateMachine::new();
+        let survey = state_machine.add_state(
+            AnimationState::new("survey", AnimationRef::Name("Survey".to_string()))
+                .with_options(looping),
+        );
+        let walk = state_machine.add_state(
+            AnimationState::new("walk", AnimationRef::Name("Walk".to_string()))
+                .with_options(looping),
+        );
+        let run = state_machine.add_state(AnimationState::new("run", AnimationRef::Index(2)));
+        state_machine.add_transition(
+            AnimationTransition::new(Some(survey), walk)
+                .when(TransitionCondition::Bool("moving".to_string(), true))
+                .with_cross_fade(0.5),
+        );
+        state_machine.add_transition(
+            AnimationTransition::new(Some(walk), run)
+                .when(TransitionCondition::Greater("speed".to_string(), 2.0)),
+        );
+        state_machine
+            .add_transition(AnimationTransition::new(Some(run), survey).with_exit_time(1.0));
+        let state_machine = state_machine.with_initial_state(survey);
+
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        let mut bad_state_machine = state_machine.clone();
+        bad_state_machine.states[walk].animation = AnimationRef::Name("Fly".to_string());
+        assert_eq!(
+            controller.set_state_machine(
+                animation_data,
+                InstanceHandle::for_slot(0),
+                bad_state_machine,
+                |_| (0, 0)
+            ),
+            Err(StateMachineError::UnknownAnimation("Fly".to_string()))
+        );
+        controller
+            .set_state_machine(
+                animation_data,
+                InstanceHandle::for_slot(0),
+                state_machine,
+                |_| (0, 0),
+            )
+            .unwrap();
+        let frame = |controller: &mut SceneAnimationController, millis: u64| {
+            controller
+                .process_animations(Duration::from_millis(millis), &gltf_data.models, false)
+                .is_some()
+        };
+        let playing = |controller: &SceneAnimationController| {
+            controller.active_animations[model_idx]
+                .iter()
+                .map(|instance| instance.animation_index)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(frame(&mut controller, 0));
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("survey")
+        );
+        assert_eq!(playing(&controller), vec![0]);
+
+        // conditions that don't hold leave the state alone
+        controller.set_animation_parameter(
+            model_idx,
+            InstanceHandle::for_slot(0),
+            "moving",
+            AnimationParameter::Bool(false),
+        );
+        frame(&mut controller, 500);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("survey")
+        );
+
+        // walk fades in as a layer on top of survey
+        controller.set_animation_parameter(
+            model_idx,
+            InstanceHandle::for_slot(0),
+            "moving",
+            AnimationParameter::Bool(true),
+        );
+        frame(&mut controller, 1000);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("walk")
+        );
+        frame(&mut controller, 1250);
+        let survey_instance = &controller.active_animations[model_idx][0];
+        assert_eq!(survey_instance.animation_index, 0);
+        assert_eq!(survey_instance.layers[0].animation_index, 1);
+        assert!((survey_instance.layers[0].weight - 0.5).abs() < 1e-4);
+        // transitions wait for the fade
+        controller.set_animation_parameter(
+            model_idx,
+            InstanceHandle::for_slot(0),
+            "speed",
+            AnimationParameter::Float(3.0),
+        );
+        frame(&mut controller, 1400);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("walk")
+        );
+
+        // once the fade is over walk plays on its own, as if it had started with the fade
+        frame(&mut controller, 1500);
+        assert_eq!(playing(&controller), vec![1]);
+        let walk_instance = &controller.active_animations[model_idx][0];
+        assert_eq!(walk_instance.start_time, Duration::from_millis(1000));
+        assert!(walk_instance.layers.is_empty());
+
+        // run isn't faded, and returns to survey once it has played through
+        frame(&mut controller, 1600);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("run")
+        );
+        assert_eq!(playing(&controller), vec![2]);
+        let run_duration = animation_data.animation_node.get_animation_duration(2);
+        let run_end = 1600 + (run_duration * 1000.0).ceil() as u64;
+        frame(&mut controller, run_end - 10);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("run")
+        );
+        frame(&mut controller, run_end);
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("survey")
+        );
+        assert_eq!(playing(&controller), vec![0]);
+    }
+
+    #[test]
+    fn test_state_machine_only_stops_its_own_instance() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let model_idx = gltf_data
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = gltf_data.models[model_idx].animation_data.as_ref().unwrap();
+        let compute_data = AnimationComputeData::from_models(&gltf_data.models, &[]);
+        let mut state_machine = AnimationStateMachine::new();
+        let survey = state_machine.add_state(AnimationState::new("survey", AnimationRef::Index(0)));
+        let run = state_machine.add_state(AnimationState::new("run", AnimationRef::Index(2)));
+        state_machine.add_transition(
+            AnimationTransition::new(Some(survey), run)
+                .when(TransitionCondition::Bool("running".to_string(), true)),
+        );
+        state_machine.add_transition(
+            AnimationTransition::new(Some(run), survey)
+                .when(TransitionCondition::Bool("running".to_string(), false))
+                .with_cross_fade(0.5),
+        );
+        let state_machine = state_machine.with_initial_state(survey);
+
+        // skeletal instances all write at offset 0, only their handles tell them apart
+        let (machine, bystander) = (InstanceHandle::for_slot(0), InstanceHandle::for_slot(1));
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        controller.initialize_animation(
+            animation_data,
+            bystander,
+            1,
+            0,
+            0,
+            PlaybackOptions {
+                looping: true,
+                ..Default::default()
+            },
+        );
+        controller.active_animations[model_idx][0].start_time = Duration::ZERO;
+        controller
+            .set_state_machine(animation_data, machine, state_machine, |_| (0, 0))
+            .unwrap();
+        let playing = |controller: &mut SceneAnimationController, millis: u64| {
+            controller.process_animations(Duration::from_millis(millis), &gltf_data.models, false);
+            controller.active_animations[model_idx]
+                .iter()
+                .map(|animation| (animation.instance, animation.animation_index))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            playing(&mut controller, 0),
+            vec![(bystander, 1), (machine, 0)]
+        );
+        controller.set_animation_parameter(
+            model_idx,
+            machine,
+            "running",
+            AnimationParameter::Bool(true),
+        );
+        assert_eq!(
+            playing(&mut controller, 100),
+            vec![(bystander, 1), (machine, 2)]
+        );
+
+        // the fade back to survey is a layer, which the compute shader can't blend
+        controller.set_animation_parameter(
+            model_idx,
+            machine,
+            "running",
+            AnimationParameter::Bool(false),
+        );
+        let jobs = controller.get_compute_jobs(
+            Duration::from_millis(200),
+            &gltf_data.models,
+            &compute_data,
+            &mut vec![],
+        );
+        assert_eq!(jobs.unwrap_err(), ComputeUnsupported::Layers(machine));
+        // like a despawn
+        controller.remove_state_machine(model_idx, machine);
+        controller.stop_animations(model_idx, machine);
+        assert_eq!(playing(&mut controller, 300), vec![(bystander, 1)]);
+    }
+
+    #[test]
+    fn test_state_machine_triggers() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let model_idx = gltf_data
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = gltf_data.models[model_idx].animation_data.as_ref().unwrap();
+        // no initial state, like the number keys of the app
+        let mut state_machine = AnimationStateMachine::new();
+        let walk = state_machine.add_state(AnimationState::new("walk", AnimationRef::Index(1)));
+        state_machine.add_transition(
+            AnimationTransition::new(None, walk)
+                .when(TransitionCondition::Trigger("go".to_string())),
+        );
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        controller
+            .set_state_machine(
+                animation_data,
+                InstanceHandle::for_slot(0),
+                state_machine,
+                |_| (0, 0),
+            )
+            .unwrap();
+        let models = &gltf_data.models;
+        assert!(controller
+            .process_animations(Duration::ZERO, models, false)
+            .is_none());
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            None
+        );
+
+        controller.set_animation_parameter(
+            model_idx,
+            InstanceHandle::for_slot(0),
+            "go",
+            AnimationParameter::Trigger,
+        );
+        assert!(controller
+            .process_animations(Duration::from_millis(100), models, false)
+            .is_some());
+        assert_eq!(
+            controller.get_animation_state(model_idx, InstanceHandle::for_slot(0)),
+            Some("walk")
+        );
+        // the trigger was consumed, so the state isn't restarted
+        controller.process_animations(Duration::from_millis(200), models, false);
+        assert_eq!(controller.active_animations[model_idx].len(), 1);
+        assert_eq!(
+            controller.active_animations[model_idx][0].start_time,
+            Duration::from_millis(100)
+        );
+        // setting it again restarts the state from any state, replacing the old instance
+        controller.set_animation_parameter(
+            model_idx,
+            InstanceHandle::for_slot(0),
+            "go",
+            AnimationParameter::Trigger,
+        );
+        controller.process_animations(Duration::from_millis(300), models, false);
+        assert_eq!(controller.active_animations[model_idx].len(), 1);
+        assert_eq!(
+            controller.active_animations[model_idx][0].start_time,
+            Duration::from_millis(300)
+        );
+    }
+
+    fn keep_only_rotations(node: &mut AnimationNode) {
+        for samplers in node
+            .samplers
+            .iter_mut()
+            .flat_map(|sampler_map| sampler_map.values_mut())
+        {
+            samplers
+                .retain(|sampler| matches!(sampler.transforms, AnimationTransforms::Rotation(_)));
+        }
+        for child_node in node.children.iter_mut() {
+            keep_only_rotations(child_node);
+        }
+    }
+
+    fn scale_rest_translations(node: &mut AnimationNode, factor: f32) {
+        node.trans *= factor;
+        for child_node in node.children.iter_mut() {
+            scale_rest_translations(child_node, factor);
+        }
+    }
+
+    /// the model space position of every joint of the model at time
+    fn joint_positions(
+        model: &GModel,
+        animation_index: usize,
+        time: f32,
+    ) -> Vec<(String, cgmath::Vector3<f32>)> {
+        let animation_node = &model.animation_data.as_ref().unwrap().animation_node;
+        let mut positions: Vec<_> = model
+            .node_names
+            .iter()
+            .filter_map(|(name, node_id)| {
+                let global = animation_node.sample_model_space_transform(
+                    *node_id,
+                    animation_index,
+                    time,
+                    cgmath::Matrix4::identity(),
+                )?;
+                Some((name.clone(), global.w.truncate()))
+            })
+            .collect();
+        positions.sort_by(|a, b| a.0.cmp(&b.0));
+        positions
+    }
+
+    #[test]
+    fn test_retarget_scales_bone_lengths() {
+        let source = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut target = GltfLoader::load_gltf("cesium-man").unwrap();
+        let idx = source
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let source = &source.models[idx];
+        let target = &mut target.models[idx];
+        // a skeleton twice the size of the source
+        let target_data = target.animation_data.as_mut().unwrap();
+        scale_rest_translations(Arc::get_mut(&mut target_data.animation_node).unwrap(), 2.0);
+
+        let joint_names = matching_joint_names(source, target);
+        assert_eq!(
+            joint_names.len(),
+            source
+                .animation_data
+                .as_ref()
+                .unwrap()
+                .joint_animation_data
+                .joint_count
+        );
+        let animation_index =
+            retarget_animation(source, 0, target, &joint_names, Some("walk")).unwrap();
+        let target_data = target.animation_data.as_ref().unwrap();
+        assert_eq!(animation_index, 1);
+        assert_eq!(target_data.find_animation("walk"), Some(1));
+        assert_eq!(
+            target_data.animation_node.get_animation_duration(1),
+            source
+                .animation_data
+                .as_ref()
+                .unwrap()
+                .animation_node
+                .get_animation_duration(0)
+        );
+        for time in [0.0, 0.4, 1.1, 1.9] {
+            let expected = joint_positions(source, 0, time);
+            let retargeted = joint_positions(target, animation_index, time);
+            for ((name, expected), (_, retargeted)) in expected.iter().zip(retargeted.iter()) {
+                assert!(
+                    (expected * 2.0 - retargeted).magnitude() < 1e-4,
+                    "{} at {}: {:?} {:?}",
+                    name,
+                    time,
+                    expected,
+                    retargeted
+                );
+            }
+        }
+
+        let mut unknown_joint = joint_names.clone();
+        unknown_joint.insert("tail".to_string(), "tail".to_string());
+        assert_eq!(
+            retarget_animation(source, 0, target, &unknown_joint, None),
+            Err(RetargetError::UnknownJoint("tail".to_string()))
+        );
+        // named nodes that aren't joints can't be mapped either
+        let joint_ids = &target
+            .animation_data
+            .as_ref()
+            .unwrap()
+            .joint_animation_data
+            .joint_to_joint_index;
+        let not_a_joint = target
+            .node_names
+            .iter()
+            .find(|(_, node_id)| !joint_ids.contains_key(node_id))
+            .map(|(name, _)| name.clone())
+            .unwrap();
+        let mut non_joint = joint_names.clone();
+        non_joint.insert(not_a_joint.clone(), not_a_joint.clone());
+        assert_eq!(
+            retarget_animation(source, 0, target, &non_joint, None),
+            Err(RetargetError::UnknownJoint(not_a_joint))
+        );
+    }
+
+    #[test]
+    fn test_retarget_compensates_joint_orientation() {
+        let mut source = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut target = GltfLoader::load_gltf("cesium-man").unwrap();
+        let idx = source
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        // with every translation animated, the rest translations would never be seen
+        let source_data = source.models[idx].animation_data.as_mut().unwrap();
+        keep_only_rotations(Arc::get_mut(&mut source_data.animation_node).unwrap());
+        let source = &source.models[idx];
+        let target = &mut target.models[idx];
+        // turn the axes of a leg joint without changing the rest pose, by turning its
+        // children back the other way
+        let leg_joint = target.node_names["leg_joint_R_1"];
+        let turn = cgmath::Quaternion::from_axis_angle(
+            cgmath::Vector3::new(0.3, 1.0, -0.5).normalize(),
+            cgmath::Deg(70.0),
+        );
+        let target_data = target.animation_data.as_mut().unwrap();
+        let node = Arc::get_mut(&mut target_data.animation_node)
+            .unwrap()
+            .find_node_mut(leg_joint)
+            .unwrap();
+        node.rot = node.rot * turn;
+        for child_node in node.children.iter_mut() {
+            child_node.rot = turn.invert() * child_node.rot;
+            child_node.trans = turn.invert().rotate_vector(child_node.trans);
+        }
+        assert_ne!(
+            joint_positions(source, 0, 0.5),
+            joint_positions(&*target, 0, 0.5),
+            "the turned joint should change the pose"
+        );
+
+        let joint_names = matching_joint_names(source, target);
+        let animation_index = retarget_animation(source, 0, target, &joint_names, None).unwrap();
+        for time in [0.0, 0.7, 1.5] {
+            let expected = joint_positions(source, 0, time);
+            let retargeted = joint_positions(target, animation_index, time);
+            for ((name, expected), (_, retargeted)) in expected.iter().zip(retargeted.iter()) {
+                assert!(
+                    (expected - retargeted).magnitude() < 1e-4,
+                    "{} at {}: {:?} {:?}",
+                    name,
+                    time,
+                    expected,
+                    retargeted
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_keyframe_reduction_drops_linear_keys() {
+        // a straight line with a single kink in the middle
+        let times: Vec<f32> = (0..=20).map(|i| i as f32 * 0.1).collect();
+        let translations = times
+            .iter()
+            .map(|time| {
+                let x = if *time <= 1.0 { *time } else { 2.0 - *time };
+                cgmath::Vector3::new(x, *time * 0.5, 0.0)
+            })
+            .collect();
+        let sampler = AnimationSampler {
+            id: 0,
+            interpolation: InterpolationType::Linear,
+            times: times.clone(),
+            transforms: AnimationTransforms::Translation(translations),
+        };
+        let (compressed, stats) = compress_sampler(&sampler, &CompressionSettings::default());
+        assert_eq!(compressed.times.len(), 3);
+        assert_eq!(compressed.times[0], 0.0);
+        assert_eq!(compressed.times[2], 2.0);
+        assert!((compressed.times[1] - 1.0).abs() < 1e-6);
+        assert_eq!(stats.keyframes_before, 21);
+        assert_eq!(stats.keyframes_after, 3);
+        assert!(stats.bytes_saved() > 0);
+        assert!(stats.max_translation_error < 1e-5);
+        for time in times.iter().chain([0.05, 1.37].iter()) {
+            let (Some(AnimationValue::Translation(a)), Some(AnimationValue::Translation(b))) =
+                (sampler.sample_at(*time), compressed.sample_at(*time))
+            else {
+                panic!("both samplers are translations");
+            };
+            assert!((a - b).magnitude() < 1e-5, "{}: {:?} {:?}", time, a, b);
+        }
+    }
+
+    #[test]
+    fn test_quantized_quaternion_round_trip() {
+        let axes = [
+            cgmath::Vector3::new(1.0, 0.0, 0.0),
+            cgmath::Vector3::new(0.3, -1.0, 0.2),
+            cgmath::Vector3::new(-0.5, 0.4, -1.0),
+        ];
+        for axis in axes {
+            for degrees in [-350.0, -170.0, -45.0, 0.0, 10.0, 90.0, 179.0, 270.0] {
+                let rotation =
+                    cgmath::Quaternion::from_axis_angle(axis.normalize(), cgmath::Deg(degrees));
+                for rotation in [rotation, -rotation] {
+                    let decoded = QuantizedQuaternion::from_quaternion(rotation).to_quaternion();
+                    let error = rotation_error(rotation, decoded);
+                    assert!(error < 2e-4, "{:?} {:?} {}", rotation, decoded, error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_compressed_animation_stays_within_tolerance() {
+        let original = GltfLoader::load_gltf("cesium-man").unwrap();
+        let idx = original
+            .models
+            .iter()
+            .position(|model| model.animation_data.is_some())
+            .unwrap();
+        let settings = CompressionSettings::default();
+        let quantized_settings = CompressionSettings {
+            quantize_rotations: true,
+            ..settings
+        };
+        let (reduced, reduced_stats) =
+            GltfLoader::load_gltf_compressed("cesium-man", &settings).unwrap();
+        let (quantized, quantized_stats) =
+            GltfLoader::load_gltf_compressed("cesium-man", &quantized_settings).unwrap();
+
+        for stats in [reduced_stats, quantized_stats] {
+            assert!(stats.keyframes_after < stats.keyframes_before);
+            assert!(stats.bytes_after < stats.bytes_before);
+            assert!(stats.max_translation_error <= settings.translation_tolerance);
+            assert!(stats.max_scale_error <= settings.scale_tolerance);
+        }
+        assert!(reduced_stats.max_rotation_error <= settings.rotation_tolerance);
+        // quantizing adds its own error on top of the keyframes that were dropped
+        assert!(quantized_stats.max_rotation_error <= settings.rotation_tolerance + 2e-4);
+        assert!(quantized_stats.bytes_after < reduced_stats.bytes_after);
+        assert_eq!(
+            quantized_stats.keyframes_after,
+            reduced_stats.keyframes_after
+        );
+
+        for time in [0.0, 0.35, 1.0, 1.62] {
+            let expected = joint_positions(&original.models[idx], 0, time);
+            for compressed in [&reduced.models[idx], &quantized.models[idx]] {
+                let positions = joint_positions(compressed, 0, time);
+                for ((name, expected), (_, position)) in expected.iter().zip(positions.iter()) {
+                    assert!(
+                        (expected - position).magnitude() < 5e-3,
+                        "{} at {}: {:?} {:?}",
+                        name,
+                        time,
+                        expected,
+                        position
+                    );
+                }
+            }
+        }
+    }
+
+    fn assert_poses_close(a: &[[[f32; 4]; 4]], b: &[[[f32; 4]; 4]], tolerance: f32, what: &str) {
+        assert_eq!(a.len(), b.len());
+        for (joint, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+            let difference = cgmath::Matrix4::from(*a) - cgmath::Matrix4::from(*b);
+            let largest = [difference.x, difference.y, difference.z, difference.w]
+                .iter()
+                .flat_map(|column| [column.x, column.y, column.z, column.w])
+                .fold(0.0f32, |largest, value| largest.max(value.abs()));
+            assert!(
+                largest < tolerance,
+                "{} joint {}: {:?} {:?}",
+                what,
+                joint,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn test_baked_poses_match_cpu_animation() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let joint_count = animation_data.joint_animation_data.joint_count;
+        let baked = BakedPoses::bake(animation_data, &gltf_data.skin_ibms[&0], &[0], 30.0);
+        let clip = baked.clips[0];
+        assert_eq!(baked.clip_index(0), Some(0));
+        assert_eq!(baked.clip_index(1), None);
+        assert_eq!(clip.frame_count, (clip.duration * 30.0).ceil() as usize + 1);
+        assert_eq!(baked.joint_matrices.len(), clip.frame_count * joint_count);
+
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        controller.initialize_animation(
+            animation_data,
+            InstanceHandle::for_slot(0),
+            0,
+            0,
+            model.mesh_instances.iter().sum::<u32>() as usize,
+            PlaybackOptions {
+                looping: true,
+                ..Default::default()
+            },
+        );
+        let start_time = Duration::from_secs(100);
+        controller.active_animations[model_idx][0].start_time = start_time;
+        let mut cpu_pose = |time: f32| {
+            let timestamp = start_time + Duration::from_secs_f32(time);
+            let frame = controller
+                .process_animations(timestamp, &gltf_data.models, false)
+                .unwrap();
+            frame.joint_transform_slices[0].to_vec()
+        };
+
+        let frame_time =
+            |frame: usize| clip.duration * frame as f32 / (clip.frame_count - 1) as f32;
+        for frame in [0, 7, clip.frame_count / 2, clip.frame_count - 2] {
+            let time = frame_time(frame);
+            let expected = cpu_pose(time);
+            assert_poses_close(baked.frame(0, frame), &expected, 1e-4, "baked frame");
+            assert_poses_close(&baked.sample(0, time), &expected, 1e-4, "sampled frame");
+            // clips loop
+            let looped = baked.sample(0, time + clip.duration * 2.0);
+            assert_poses_close(&looped, &expected, 1e-3, "looped frame");
+        }
+        // between frames the pose is blended, which stays close to the real one at 30 fps
+        for time in [0.0123, 0.51, 1.234] {
+            assert_poses_close(
+                &baked.sample(0, time),
+                &cpu_pose(time),
+                2e-2,
+                "between frames",
+            );
+        }
+    }
+
+    #[test]
+    fn test_appended_poses_keep_their_frames() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let animation_data = gltf_data
+            .models
+            .iter()
+            .find_map(|model| model.animation_data.as_ref())
+            .unwrap();
+        let ibms = &gltf_data.skin_ibms[&0];
+        let mut baked = BakedPoses::bake(animation_data, ibms, &[0], 10.0);
+        let run = BakedPoses::bake(animation_data, ibms, &[2], 10.0);
+        baked.append(run.clone());
+
+        assert_eq!(baked.clips.len(), 2);
+        assert_eq!(baked.clip_index(2), Some(1));
+        assert_eq!(
+            baked.clips[1].frame_offset,
+            baked.clips[0].frame_offset + baked.clips[0].frame_count
+        );
+        for frame in [0, run.clips[0].frame_count - 1] {
+            assert_eq!(baked.frame(1, frame), run.frame(0, frame));
+        }
+    }
+
+    #[test]
+    fn test_parallel_animations_match_serial() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let (model_idx, model) = gltf_data
+            .models
+            .iter()
+            .enumerate()
+            .find(|(_, model)| model.animation_data.is_some())
+            .unwrap();
+        let animation_data = model.animation_data.as_ref().unwrap();
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let new_controller = || {
+            let mut controller =
+                SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+            // stagger the instances so that they sample different keyframes
+            for i in 0..16 {
+                controller.initialize_animation(
+                    animation_data,
+                    InstanceHandle::for_slot(i as u32),
+                    0,
+                    i,
+                    mesh_count,
+                    PlaybackOptions::default(),
+                );
+                controller.active_animations[model_idx][i].start_time =
+                    Duration::from_millis(i as u64 * 70);
+            }
+            controller
+        };
+        let mut serial = new_controller();
+        let mut parallel = new_controller();
+
+        for frame in 0..40 {
+            let timestamp = Duration::from_millis(1200 + frame * 50);
+            let serial_frame = serial.process_animations(timestamp, &gltf_data.models, false);
+            let parallel_frame = parallel.process_animations(timestamp, &gltf_data.models, true);
+            match (serial_frame, parallel_frame) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.lt_offsets, b.lt_offsets);
+                    assert_eq!(a.mesh_transform_slices, b.mesh_transform_slices);
+                    assert_eq!(a.joint_transform_slices, b.joint_transform_slices);
+                    assert_eq!(a.joint_ids, b.joint_ids);
+                }
+                (None, None) => {}
+                _ => panic!("serial and parallel disagree on active animations"),
+            }
+            assert_eq!(
+                serial.active_animation_count,
+                parallel.active_animation_count
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_matches_cpu_mesh_animation() {
+        compare_compute_with_cpu("box-animated", &[0.0, 0.3, 1.1, 1.25, 2.0, 3.3]);
+    }
+
+    #[test]
+    fn test_compute_matches_cpu_skeletal_animation() {
+        compare_compute_with_cpu("cesium-man", &[0.0, 0.1, 0.45, 0.9, 1.5]);
+    }
+
+    #[test]
+    fn test_compute_refuses_root_motion() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut controller =
+            SceneAnimationController::new(gltf_data.models.len(), gltf_data.skin_ibms.clone());
+        let compute_data = AnimationComputeData::from_models(
+            &gltf_data.models,
+            controller.inverse_bind_matrices(),
+        );
+        let model = &gltf_data.models[0];
+        let mesh_count = model.mesh_instances.iter().sum::<u32>() as usize;
+        let handle = InstanceHandle::for_slot(0);
+        controller.initialize_animation(
+            model.animation_data.as_ref().unwrap(),
+            handle,
+            0,
+            0,
+            mesh_count,
+            PlaybackOptions {
+                looping: true,
+                root_motion: RootMotion::Translation,
+            },
+        );
+
+        let jobs = controller.get_compute_jobs(
+            Duration::from_millis(100),
+            &gltf_data.models,
+            &compute_data,
+            &mut vec![],
+        );
+        assert_eq!(jobs.unwrap_err(), ComputeUnsupported::RootMotion(handle));
+    }
 
     #[test]
     fn test_box() {
@@ -0,0 +1,219 @@
+use std::ops::Range;
+
+/// A set of byte ranges, kept sorted and merged so that no two of them overlap or touch.
+/// Used to copy only the index data primitives use out of the binary data, back to back
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntervalSet {
+    ranges: Vec<Range<usize>>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add range to the set, merging it with every range it overlaps or touches. Empty ranges
+    /// add nothing
+    pub fn insert(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        // the ranges ending before range starts are left alone, as are the ones starting
+        // after it ends, everything in between is merged into it
+        let first = self.ranges.partition_point(|other| other.end < range.start);
+        let last = self
+            .ranges
+            .partition_point(|other| other.start <= range.end);
+        let merged = match (
+            self.ranges[first..last].first(),
+            self.ranges[first..last].last(),
+        ) {
+            (Some(first), Some(last)) => first.start.min(range.start)..last.end.max(range.end),
+            _ => range,
+        };
+        self.ranges.splice(first..last, std::iter::once(merged));
+    }
+
+    /// the merged ranges, in order
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    /// the number of bytes covered
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(ExactSizeIterator::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Where range starts once the set's ranges are packed back to back, see [Self::gather].
+    /// None if range isn't covered by one of them, empty ranges are never covered
+    pub fn packed_offset(&self, range: &Range<usize>) -> Option<usize> {
+        let containing = self
+            .ranges
+            .partition_point(|other| other.end <= range.start);
+        let container = self.ranges.get(containing)?;
+        if range.is_empty() || container.start > range.start || container.end < range.end {
+            return None;
+        }
+        let packed_before: usize = self.ranges[..containing]
+            .iter()
+            .map(ExactSizeIterator::len)
+            .sum();
+        Some(packed_before + range.start - container.start)
+    }
+
+    /// copy the bytes of every range out of data, back to back
+    pub fn gather(&self, data: &[u8]) -> Vec<u8> {
+        let mut packed = Vec::with_capacity(self.len());
+        for range in self.ranges.iter() {
+            packed.extend_from_slice(&data[range.clone()]);
+        }
+        packed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn set_of(ranges: &[Range<usize>]) -> IntervalSet {
+        let mut set = IntervalSet::new();
+        for range in ranges {
+            set.insert(range.clone());
+        }
+        set
+    }
+
+    #[test]
+    fn test_insert_after_the_last() {
+        let set = set_of(&[0..4, 7..10, 12..15]);
+        assert_eq!(set.ranges(), vec![0..4, 7..10, 12..15]);
+    }
+
+    #[test]
+    fn test_insert_overlapping_and_touching_the_right() {
+        assert_eq!(set_of(&[0..4, 7..10, 8..15]).ranges(), vec![0..4, 7..15]);
+        assert_eq!(set_of(&[0..4, 7..10, 10..15]).ranges(), vec![0..4, 7..15]);
+    }
+
+    #[test]
+    fn test_insert_overlapping_the_left() {
+        let set = set_of(&[2..4, 8..10, 12..15, 6..9]);
+        assert_eq!(set.ranges(), vec![2..4, 6..10, 12..15]);
+    }
+
+    #[test]
+    fn test_insert_spanning_several() {
+        let spanned = 2..15;
+        assert_eq!(
+            set_of(&[2..4, 8..10, 12..15, 3..13]).ranges(),
+            vec![spanned]
+        );
+        let spanned = 0..15;
+        assert_eq!(set_of(&[2..4, 7..10, 0..15]).ranges(), vec![spanned]);
+        let set = set_of(&[2..4, 10..18, 22..25, 6..20]);
+        assert_eq!(set.ranges(), vec![2..4, 6..20, 22..25]);
+    }
+
+    #[test]
+    fn test_insert_in_between_and_before() {
+        assert_eq!(
+            set_of(&[2..4, 12..20, 6..10]).ranges(),
+            vec![2..4, 6..10, 12..20]
+        );
+        assert_eq!(set_of(&[6..10, 0..3]).ranges(), vec![0..3, 6..10]);
+    }
+
+    #[test]
+    fn test_insert_equal_or_contained_changes_nothing() {
+        // the same range twice, and one sharing only its start, which used to count as equal
+        let short = 4..8;
+        let long = 4..12;
+        assert_eq!(set_of(&[4..8, 4..8]).ranges(), vec![short]);
+        assert_eq!(set_of(&[4..8, 4..12]).ranges(), vec![long.clone()]);
+        assert_eq!(set_of(&[4..12, 4..8]).ranges(), vec![long.clone()]);
+        assert_eq!(set_of(&[4..12, 6..8]).ranges(), vec![long.clone()]);
+        assert_eq!(set_of(&[4..12, 6..6]).ranges(), vec![long]);
+    }
+
+    #[test]
+    fn test_packed_offsets() {
+        let set = set_of(&[2..4, 6..10, 12..20]);
+        assert_eq!(set.packed_offset(&(2..4)), Some(0));
+        assert_eq!(set.packed_offset(&(6..10)), Some(2));
+        assert_eq!(set.packed_offset(&(8..10)), Some(4));
+        assert_eq!(set.packed_offset(&(14..16)), Some(8));
+        // straddling a gap, or outside of every range
+        assert_eq!(set.packed_offset(&(8..13)), None);
+        assert_eq!(set.packed_offset(&(20..22)), None);
+        assert_eq!(set.packed_offset(&(6..6)), None);
+    }
+
+    /// ranges within the first 64 bytes, small enough that they often overlap and touch
+    fn ranges() -> impl Strategy<Value = Vec<Range<usize>>> {
+        prop::collection::vec((0usize..64, 0usize..16), 0..12).prop_map(|ranges| {
+            ranges
+                .into_iter()
+                .map(|(start, len)| start..start + len)
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_covers_exactly_the_union(inserted in ranges()) {
+            let set = set_of(&inserted);
+            let mut covered = [false; 80];
+            for range in inserted.iter() {
+                covered[range.clone()].iter_mut().for_each(|byte| *byte = true);
+            }
+            for (byte, is_covered) in covered.iter().enumerate() {
+                let in_set = set.ranges().iter().any(|range| range.contains(&byte));
+                prop_assert_eq!(in_set, *is_covered);
+            }
+            prop_assert_eq!(set.len(), covered.iter().filter(|byte| **byte).count());
+            // sorted, and merged wherever they overlap or touch
+            prop_assert!(set.ranges().iter().all(|range| !range.is_empty()));
+            for pair in set.ranges().windows(2) {
+                prop_assert!(pair[0].end < pair[1].start);
+            }
+        }
+
+        #[test]
+        fn test_insert_order_doesnt_matter(inserted in ranges()) {
+            let mut reversed = inserted.clone();
+            reversed.reverse();
+            prop_assert_eq!(set_of(&inserted), set_of(&reversed));
+        }
+
+        #[test]
+        fn test_contained_ranges_change_nothing(inserted in ranges(), start in 0usize..80, len in 0usize..16) {
+            let mut set = set_of(&inserted);
+            let before = set.clone();
+            let range = start..start + len;
+            let contained = set.ranges().iter().any(|other| other.start <= range.start && range.end <= other.end);
+            set.insert(range);
+            if contained {
+                prop_assert_eq!(set, before);
+            }
+        }
+
+        #[test]
+        fn test_packed_ranges_keep_their_bytes(inserted in ranges()) {
+            let set = set_of(&inserted);
+            let data: Vec<u8> = (0..80).collect();
+            let packed = set.gather(&data);
+            prop_assert_eq!(packed.len(), set.len());
+            for range in inserted.iter().filter(|range| !range.is_empty()) {
+                let offset = set.packed_offset(range);
+                prop_assert!(offset.is_some());
+                let offset = offset.unwrap();
+                prop_assert_eq!(&packed[offset..offset + range.len()], &data[range.clone()]);
+            }
+        }
+    }
+}
@@ -2,12 +2,14 @@ use std::{collections::HashMap, path::PathBuf};
 
 use crate::{
     model::{
+        animation::compression::{compress_animations, CompressionSettings, CompressionStats},
         loader::util::{
             decode_gltf_data_uri, get_data_files, get_material_definitions, get_root_nodes,
             load_models_from_gltf,
         },
         materials::material::MaterialDefinition,
         model::{GModel, LocalTransform},
+        simplify::{generate_lods, SimplifySettings},
     },
     scene::scene::PrimitiveData,
 };
@@ -65,6 +67,37 @@ impl GltfLoader {
 
         Ok(gltf_data)
     }
+
+    /// [Self::load_gltf], with every animation compressed as it is loaded.
+    /// The stats cover all of the file's animations
+    pub fn load_gltf_compressed<'a>(
+        dir_name: &'a str,
+        settings: &CompressionSettings,
+    ) -> Result<(GltfData<'a>, CompressionStats), GltfFileLoadError> {
+        let mut gltf_data = Self::load_gltf(dir_name)?;
+        let mut stats = CompressionStats::default();
+        for animation_data in gltf_data
+            .models
+            .iter_mut()
+            .filter_map(|model| model.animation_data.as_mut())
+        {
+            stats.merge(&compress_animations(animation_data, settings));
+        }
+        Ok((gltf_data, stats))
+    }
+
+    /// [Self::load_gltf], with levels of detail generated for the meshes that have none, see
+    /// [generate_lods]
+    pub fn load_gltf_with_lods<'a>(
+        dir_name: &'a str,
+        levels: &[SimplifySettings],
+    ) -> Result<GltfData<'a>, GltfFileLoadError> {
+        let mut gltf_data = Self::load_gltf(dir_name)?;
+        if !levels.is_empty() {
+            generate_lods(&mut gltf_data, levels);
+        }
+        Ok(gltf_data)
+    }
 }
 
 pub struct ModelPrimitiveData {
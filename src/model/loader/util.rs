@@ -1,8 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, ReadDir},
     path::PathBuf,
-    rc::Rc,
+    sync::Arc,
 };
 
 use base64::Engine;
@@ -10,8 +10,12 @@ use cgmath::SquareMatrix;
 use gltf::{animation::Channel, Gltf};
 
 use crate::model::{
-    animation::animation_node::{AnimationNode, NodeType},
+    animation::{
+        animation_events::load_animation_events,
+        animation_node::{AnimationNode, NodeType},
+    },
     loader::loader::{GltfData, GltfFileLoadError, ModelPrimitiveData},
+    lod::lod_node_ids,
     materials::material::MaterialDefinition,
     model::{GModel, JointAnimationData, LocalTransform, MeshAnimationData, ModelAnimationData},
     util::{copy_binary_data_from_gltf, get_model_meshes, AttributeType},
@@ -109,6 +113,16 @@ fn build_animation_node_tree(
     node
 }
 
+/// node name -> node index for the node and all of its named descendants
+fn collect_node_names(node: &gltf::Node, node_names: &mut HashMap<String, usize>) {
+    if let Some(name) = node.name() {
+        node_names.insert(name.to_string(), node.index());
+    }
+    for child in node.children() {
+        collect_node_names(&child, node_names);
+    }
+}
+
 fn get_inverse_bind_matrices(
     skin: &gltf::Skin,
     buffer_offsets: &Vec<u64>,
@@ -150,6 +164,13 @@ pub(super) fn load_models_from_gltf<'a>(
     let mut skin_ibms: HashMap<usize, Vec<cgmath::Matrix4<f32>>> =
         HashMap::with_capacity(gltf.skins().len());
     let buffer_offsets: Vec<u64> = get_buffer_offsets(&gltf.buffers());
+    // simplified meshes are drawn in place of the mesh they simplify, never on their own
+    let lod_nodes = lod_node_ids(gltf);
+    let animation_events = load_animation_events(gltf.animations());
+    let animation_names: HashMap<String, usize> = gltf
+        .animations()
+        .filter_map(|animation| Some((animation.name()?.to_string(), animation.index())))
+        .collect();
     for skin in gltf.skins().clone().into_iter() {
         let (skin_idx, ibms) = get_inverse_bind_matrices(&skin, &buffer_offsets, &main_buffer_data);
         skin_ibms.insert(skin_idx, ibms);
@@ -164,7 +185,7 @@ pub(super) fn load_models_from_gltf<'a>(
         };
 
         let root_node = &nodes[*rid];
-        if root_node.camera().is_some() {
+        if root_node.camera().is_some() || lod_nodes.contains(rid) {
             continue;
         }
 
@@ -174,6 +195,7 @@ pub(super) fn load_models_from_gltf<'a>(
             model_data,
             &joint_ids,
             &skin_ibms,
+            &lod_nodes,
         );
 
         // get a animation node trees
@@ -189,6 +211,7 @@ pub(super) fn load_models_from_gltf<'a>(
         let (meshes, primitive_data) = get_model_meshes(
             &model_data.mesh_data.mesh_ids,
             &nodes,
+            gltf,
             &buffer_offsets,
             primitive_material_map,
             &main_buffer_data,
@@ -208,11 +231,25 @@ pub(super) fn load_models_from_gltf<'a>(
                     .clone()
                     .into_values()
                     .collect();
+                // only the names and events of the animations that move this model
+                let own_animations = animation_node.animation_indices();
+                let model_animation_names = animation_names
+                    .iter()
+                    .filter(|(_, animation_index)| own_animations.contains(animation_index))
+                    .map(|(name, animation_index)| (name.clone(), *animation_index))
+                    .collect();
+                let model_animation_events = animation_events
+                    .iter()
+                    .filter(|(animation_index, _)| own_animations.contains(animation_index))
+                    .map(|(animation_index, events)| (*animation_index, events.clone()))
+                    .collect();
                 Some(ModelAnimationData {
                     animation_count,
+                    animation_names: model_animation_names,
                     model_index: models.len(),
-                    animation_node: Rc::new(animation_node),
+                    animation_node: Arc::new(animation_node),
                     is_skeletal: joint_count > 0,
+                    animation_events: model_animation_events,
                     mesh_animation_data: MeshAnimationData {
                         mesh_animations,
                         node_to_lt_index: model_data.mesh_data.node_to_lt_index_map,
@@ -227,8 +264,12 @@ pub(super) fn load_models_from_gltf<'a>(
 
             None => None,
         };
+        let mut node_names = HashMap::new();
+        collect_node_names(root_node, &mut node_names);
         let g_model = GModel::new(
             *rid,
+            root_node.name().map(str::to_string),
+            node_names,
             meshes,
             model_data.mesh_data.mesh_instances,
             gmodel_animation_data,
@@ -317,10 +358,15 @@ fn get_model_data(
     mut model_data: ModelData,
     joint_ids: &Vec<usize>,
     skin_ibms: &HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+    lod_nodes: &HashSet<usize>,
 ) -> ModelData {
     let cg_trans = cgmath::Matrix4::<f32>::from(root_node.transform().matrix());
     let new_trans = base_translation * cg_trans;
-    if let Some(mesh) = root_node.mesh() {
+    // a simplified mesh is drawn through the mesh it simplifies
+    let mesh = root_node
+        .mesh()
+        .filter(|_| !lod_nodes.contains(&root_node.index()));
+    if let Some(mesh) = mesh {
         let mut transform_index = 0;
         'block: {
             // create the transform
@@ -375,7 +421,14 @@ fn get_model_data(
     }
 
     for child_node in root_node.children() {
-        model_data = get_model_data(&child_node, new_trans, model_data, joint_ids, skin_ibms);
+        model_data = get_model_data(
+            &child_node,
+            new_trans,
+            model_data,
+            joint_ids,
+            skin_ibms,
+            lod_nodes,
+        );
     }
     model_data
 }
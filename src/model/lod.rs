@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+
+use gltf::Gltf;
+
+/// the screen size below which the first simplified level is drawn, when the file doesn't say.
+/// Each further level halves it
+pub const DEFAULT_LOD_SCREEN_SIZE: f32 = 0.25;
+
+/// The level of detail of a mesh name, e.g. 2 for "rock_LOD2", along with the name without
+/// the suffix. Names without a suffix are level 0
+pub fn parse_lod_name(name: &str) -> (&str, usize) {
+    name.rsplit_once("_LOD")
+        .and_then(|(base, level)| Some((base, level.parse().ok()?)))
+        .unwrap_or((name, 0))
+}
+
+/// the screen size of each simplified level when none are given
+pub(crate) fn default_screen_sizes(level_count: usize) -> Vec<f32> {
+    (0..level_count)
+        .map(|level| DEFAULT_LOD_SCREEN_SIZE / 2f32.powi(level as i32))
+        .collect()
+}
+
+/// Every node that only stands in for a simplified version of another node's mesh, and
+/// should not be drawn on its own: the ids listed in MSFT_lod, and the nodes of meshes
+/// named like "rock_LOD1"
+pub(crate) fn lod_node_ids(gltf: &Gltf) -> HashSet<usize> {
+    let mut lod_nodes = HashSet::new();
+    for node in gltf.nodes() {
+        lod_nodes.extend(msft_lod_ids(&node));
+        if let Some(name) = node.mesh().as_ref().and_then(gltf::Mesh::name) {
+            if parse_lod_name(name).1 > 0 {
+                lod_nodes.insert(node.index());
+            }
+        }
+    }
+    lod_nodes
+}
+
+fn msft_lod_ids(node: &gltf::Node) -> Vec<usize> {
+    node.extension_value("MSFT_lod")
+        .and_then(|lod| lod.get("ids")?.as_array().cloned())
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| Some(id.as_u64()? as usize))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// MSFT_screencoverage in the node's extras, the smallest screen size each level, starting with
+/// the full detail one, is drawn at
+fn msft_screen_coverage(node: &gltf::Node) -> Option<Vec<f32>> {
+    let extras: serde_json::Value = serde_json::from_str(node.extras().as_ref()?.get()).ok()?;
+    extras
+        .get("MSFT_screencoverage")?
+        .as_array()?
+        .iter()
+        .map(|coverage| Some(coverage.as_f64()? as f32))
+        .collect()
+}
+
+/// The simplified versions of the mesh of node, from the most to the least detailed, each with
+/// the screen size it is drawn below. MSFT_lod takes precedence over mesh names
+pub(crate) fn mesh_lods<'a>(node: &gltf::Node<'a>, gltf: &'a Gltf) -> Vec<(gltf::Mesh<'a>, f32)> {
+    let Some(mesh) = node.mesh() else {
+        return Vec::new();
+    };
+    let lod_meshes: Vec<gltf::Mesh> = match msft_lod_ids(node) {
+        ids if !ids.is_empty() => ids
+            .into_iter()
+            .filter_map(|id| gltf.nodes().nth(id)?.mesh())
+            .collect(),
+        _ => {
+            let Some((base_name, 0)) = mesh.name().map(parse_lod_name) else {
+                return Vec::new();
+            };
+            let mut named_lods: Vec<(usize, gltf::Mesh)> = gltf
+                .meshes()
+                .filter_map(|lod_mesh| match parse_lod_name(lod_mesh.name()?) {
+                    (name, level) if name == base_name && level > 0 => Some((level, lod_mesh)),
+                    _ => None,
+                })
+                .collect();
+            named_lods.sort_by_key(|(level, _)| *level);
+            named_lods
+                .into_iter()
+                .map(|(_, lod_mesh)| lod_mesh)
+                .collect()
+        }
+    };
+    // the first coverage belongs to the full detail level
+    let screen_sizes = msft_screen_coverage(node)
+        .filter(|coverage| coverage.len() >= lod_meshes.len())
+        .unwrap_or_else(|| default_screen_sizes(lod_meshes.len()));
+    lod_meshes.into_iter().zip(screen_sizes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lod_name() {
+        assert_eq!(parse_lod_name("rock_LOD2"), ("rock", 2));
+        assert_eq!(parse_lod_name("rock_LOD0"), ("rock", 0));
+        assert_eq!(parse_lod_name("rock"), ("rock", 0));
+        assert_eq!(parse_lod_name("rock_LODs"), ("rock_LODs", 0));
+    }
+
+    #[test]
+    fn test_lods_are_found_by_name_and_by_extension() {
+        let gltf_data = crate::model::loader::loader::GltfLoader::load_gltf("box-lod").unwrap();
+        // the nodes of simplified meshes aren't models or mesh instances of their own
+        assert_eq!(gltf_data.models.len(), 2);
+        assert_eq!(gltf_data.local_transforms.len(), 2);
+        assert!(gltf_data
+            .models
+            .iter()
+            .all(|model| model.mesh_instances == vec![1] && model.lod_count() == 1));
+    }
+
+    #[test]
+    fn test_default_screen_sizes_halve() {
+        assert_eq!(default_screen_sizes(3), vec![0.25, 0.125, 0.0625]);
+    }
+}
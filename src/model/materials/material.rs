@@ -21,6 +21,8 @@ pub struct MaterialDefinition<'a> {
     pub index: u32,
     /// The GLTF id of this material, stored so that we can avoid duplication of materials
     pub id: usize,
+    /// the name of the material in the gltf file
+    pub name: Option<String>,
     pub image_source: Option<PathBuf>,
     buffer_bytes: Option<Vec<u8>>,
     pub base_color_factors: [f32; 4],
@@ -33,8 +35,8 @@ impl<'a> Debug for MaterialDefinition<'a> {
         fmt::write(
             f,
             format_args!(
-                "index: {}, id: {}, image_source: {:?}, base_colors: {:?}",
-                self.index, self.id, self.image_source, self.base_color_factors
+                "index: {}, id: {}, name: {:?}, image_source: {:?}, base_colors: {:?}",
+                self.index, self.id, self.name, self.image_source, self.base_color_factors
             ),
         )
     }
@@ -65,6 +67,7 @@ impl<'a> MaterialDefinition<'a> {
         Self {
             index: 0,
             id: 9999,
+            name: None,
             image_source: None,
             buffer_bytes: None,
             base_color_factors: [1.0, 1.0, 1.0, 1.0],
@@ -137,6 +140,7 @@ impl<'a> MaterialDefinition<'a> {
         let m = MaterialDefinition {
             index: material_index as u32,
             id: material.index().unwrap_or(0),
+            name: material.name().map(str::to_string),
             image_source: image_path,
             buffer_bytes: image_bytes,
             sampler_descriptor,
@@ -1,8 +1,11 @@
 pub mod animation;
+pub mod interval_set;
 pub mod loader;
+pub mod lod;
 pub mod materials;
 pub mod model;
+pub mod optimize;
 mod primitive;
-mod range_splicer;
+pub mod simplify;
 pub mod util;
 pub mod vertex;
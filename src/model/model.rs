@@ -1,13 +1,15 @@
 use super::util::GltfErrors;
-use crate::model::materials::material::GMaterial;
+use crate::model::animation::animation_events::{insert_event, AnimationEvent, AnimationEventMap};
+use crate::model::interval_set::IntervalSet;
 use crate::model::vertex::ModelVertex;
 use crate::model::{animation::animation_node::AnimationNode, primitive::GPrimitive};
-use crate::scene::scene::{GScene, PrimitiveData};
+use crate::scene::culling::Aabb;
+use crate::scene::scene::{PrimitiveData, VertexAccessors};
 use gltf::Mesh;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::ops::{self, Range};
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AccessorDataType {
@@ -27,12 +29,33 @@ pub struct JointAnimationData {
 }
 
 pub struct ModelAnimationData {
-    pub animation_node: Rc<AnimationNode>,
+    pub animation_node: Arc<AnimationNode>,
     pub model_index: usize,
     pub animation_count: usize,
+    /// animation name -> global index, for the named animations that move this model
+    pub(crate) animation_names: HashMap<String, usize>,
     pub mesh_animation_data: MeshAnimationData,
     pub joint_animation_data: JointAnimationData,
     pub is_skeletal: bool,
+    pub animation_events: AnimationEventMap,
+}
+
+impl ModelAnimationData {
+    /// the global index of this model's animation with the given name
+    pub fn find_animation(&self, name: &str) -> Option<usize> {
+        self.animation_names.get(name).copied()
+    }
+
+    /// attach a named event to a point on the timeline of the given animation
+    pub fn add_animation_event(&mut self, animation_index: usize, name: &str, time: f32) {
+        insert_event(
+            self.animation_events.entry(animation_index).or_default(),
+            AnimationEvent {
+                name: name.to_string(),
+                time,
+            },
+        );
+    }
 }
 
 impl Debug for ModelAnimationData {
@@ -58,6 +81,10 @@ impl Debug for ModelAnimationData {
 // modesls independently later
 pub struct GModel {
     pub model_id: usize,
+    /// the name of the model's root node
+    pub name: Option<String>,
+    /// node name -> gltf node index, for the named nodes of this model
+    pub node_names: HashMap<String, usize>,
     meshes: Vec<GMesh>,
     pub mesh_instances: Vec<u32>,
     pub animation_data: Option<ModelAnimationData>,
@@ -72,34 +99,190 @@ impl GModel {
     }
     pub(super) fn new(
         model_id: usize,
+        name: Option<String>,
+        node_names: HashMap<String, usize>,
         meshes: Vec<GMesh>,
         mesh_instances: Vec<u32>,
         animation_data: Option<ModelAnimationData>,
     ) -> Self {
         Self {
             model_id,
+            name,
+            node_names,
             meshes,
             mesh_instances,
             animation_data,
         }
     }
 
+    /// the gltf index of the first of this model's meshes with the given name
+    pub fn find_mesh(&self, name: &str) -> Option<usize> {
+        self.meshes
+            .iter()
+            .find(|mesh| mesh.name.as_deref() == Some(name))
+            .map(|mesh| mesh.mesh_id)
+    }
+
+    /// The model space bounds of one instance of this model, given the local transforms of its
+    /// mesh instances. None if a mesh has no bounds, then the instance can't be culled
+    pub fn instance_bounds(&self, local_transforms: &[LocalTransform]) -> Option<Aabb> {
+        let mut local_transforms = local_transforms.iter();
+        let mut instance_bounds: Option<Aabb> = None;
+        for (mesh, mesh_instance_count) in self.meshes.iter().zip(&self.mesh_instances) {
+            let mesh_bounds = mesh.bounds()?;
+            for local_transform in local_transforms
+                .by_ref()
+                .take(*mesh_instance_count as usize)
+            {
+                let bounds =
+                    mesh_bounds.transform(&cgmath::Matrix4::from(local_transform.transform_matrix));
+                instance_bounds = Some(match instance_bounds {
+                    Some(instance_bounds) => instance_bounds.union(&bounds),
+                    None => bounds,
+                });
+            }
+        }
+        instance_bounds
+    }
+
+    /// The range of local transforms to draw each mesh with, along with the primitives of the
+    /// level of detail picked from the screen size of each instance, if there are any. The local
+    /// transforms are stored one instance after the other, so the transforms of a mesh are only
+    /// contiguous across instances when the model has a single mesh
+    fn mesh_draws(
+        &self,
+        model_offset: u32,
+        visible_instances: &[Range<u32>],
+        screen_sizes: Option<&[f32]>,
+    ) -> Vec<(&[GPrimitive], Range<u32>)> {
+        let lod_level = |mesh: &GMesh, instance: u32| {
+            mesh.lod_level(screen_sizes.map(|screen_sizes| screen_sizes[instance as usize]))
+        };
+        let instance_stride: u32 = self.mesh_instances.iter().sum();
+        let mut mesh_draws = Vec::new();
+        if self.meshes.len() == 1 {
+            let mesh = &self.meshes[0];
+            // split the visible ranges where the level of detail changes
+            for instances in visible_instances {
+                let mut start = instances.start;
+                while start < instances.end {
+                    let level = lod_level(mesh, start);
+                    let end = (start + 1..instances.end)
+                        .find(|instance| lod_level(mesh, *instance) != level)
+                        .unwrap_or(instances.end);
+                    mesh_draws.push((
+                        mesh.level_primitives(level),
+                        model_offset + instance_stride * start
+                            ..model_offset + instance_stride * end,
+                    ));
+                    start = end;
+                }
+            }
+            return mesh_draws;
+        }
+        for instance in visible_instances.iter().cloned().flatten() {
+            let mut mesh_offset = model_offset + instance * instance_stride;
+            for (mesh, num_mesh_instances) in self.meshes.iter().zip(&self.mesh_instances) {
+                mesh_draws.push((
+                    mesh.level_primitives(lod_level(mesh, instance)),
+                    mesh_offset..mesh_offset + num_mesh_instances,
+                ));
+                mesh_offset += num_mesh_instances;
+            }
+        }
+        mesh_draws
+    }
+
+    /// every primitive draw of the visible instances of this model, in drawing order.
+    /// screen_sizes holds the screen size of every instance, to pick its level of detail with
+    pub fn primitive_draws(
+        &self,
+        model_offset: u32,
+        visible_instances: &[Range<u32>],
+        screen_sizes: Option<&[f32]>,
+        draws: &mut Vec<PrimitiveDraw>,
+    ) {
+        for (primitives, instances) in
+            self.mesh_draws(model_offset, visible_instances, screen_sizes)
+        {
+            for primitive in primitives {
+                let (Some((first_index, index_count)), Some((first_vertex, vertex_count))) = (
+                    primitive.initialized_index_offset_len,
+                    primitive.initialized_vertex_offset_len,
+                ) else {
+                    continue;
+                };
+                draws.push(PrimitiveDraw {
+                    material_index: primitive.material_index,
+                    indices: first_index..first_index + index_count,
+                    vertices: first_vertex..first_vertex + vertex_count,
+                    instances: instances.clone(),
+                });
+            }
+        }
+    }
+
+    pub(super) fn meshes_mut(&mut self) -> &mut [GMesh] {
+        &mut self.meshes
+    }
+
+    /// the index and vertex ranges in the scene's buffers of every primitive, at every level
+    /// of detail. Unindexed primitives have empty index ranges
+    pub fn primitive_buffer_ranges(&self) -> Vec<(Range<u32>, Range<u32>)> {
+        self.meshes
+            .iter()
+            .flat_map(GMesh::levels)
+            .flatten()
+            .filter_map(|primitive| {
+                let (first_vertex, vertex_count) = primitive.initialized_vertex_offset_len?;
+                let (first_index, index_count) =
+                    primitive.initialized_index_offset_len.unwrap_or((0, 0));
+                Some((
+                    first_index..first_index + index_count,
+                    first_vertex..first_vertex + vertex_count,
+                ))
+            })
+            .collect()
+    }
+
+    /// the number of levels of detail below the full one, of the mesh with the most
+    pub fn lod_count(&self) -> usize {
+        self.meshes.iter().map(GMesh::lod_count).max().unwrap_or(0)
+    }
+
+    /// The vertices of this model's primitives, placed from buffer_offset_val on. Primitives
+    /// reading the same accessors with the same material, in this model or in one before it,
+    /// share the vertex range shared_vertices has for them instead of adding their own
     pub fn get_model_vertex_data(
         &mut self,
         primitive_data: &Vec<PrimitiveData>,
         buffer_offset_val: &mut u32,
+        shared_vertices: &mut HashMap<(VertexAccessors, usize), (u32, u32)>,
     ) -> Vec<ModelVertex> {
         let mut vertex_buffer_data = Vec::<ModelVertex>::new();
         // for each piece of data associated with a primitive in this model
         // add data to the vertex buffer.
-        for mesh in self.meshes.iter_mut() {
+        for (mesh_id, primitives) in self.meshes.iter_mut().flat_map(GMesh::levels_mut) {
             let mesh_primitive_data_vec = primitive_data
                 .iter()
-                .filter(|primitive_data| primitive_data.mesh_id == mesh.mesh_id);
-            for (primitive, data) in mesh.primitives.iter_mut().zip(mesh_primitive_data_vec) {
+                .filter(|primitive_data| primitive_data.mesh_id == mesh_id);
+            for (primitive, data) in primitives.iter_mut().zip(mesh_primitive_data_vec) {
+                primitive.bounds = data.bounds.filter(|_| data.joints.is_none());
+                // the material is part of every vertex, so it has to match as well
+                let key = data
+                    .vertex_accessors
+                    .clone()
+                    .map(|accessors| (accessors, primitive.material_index));
+                if let Some(offset_len) = key.as_ref().and_then(|key| shared_vertices.get(key)) {
+                    primitive.initialized_vertex_offset_len = Some(*offset_len);
+                    continue;
+                }
                 let primitive_vertex_data = data.get_vertex_data(primitive.material_index);
-                primitive.initialized_vertex_offset_len =
-                    Some((*buffer_offset_val, primitive_vertex_data.len() as u32));
+                let offset_len = (*buffer_offset_val, primitive_vertex_data.len() as u32);
+                primitive.initialized_vertex_offset_len = Some(offset_len);
+                if let Some(key) = key {
+                    shared_vertices.insert(key, offset_len);
+                }
                 *buffer_offset_val += primitive_vertex_data.len() as u32;
                 vertex_buffer_data.extend(primitive_vertex_data);
             }
@@ -107,50 +290,142 @@ impl GModel {
         vertex_buffer_data
     }
 
-    pub fn build_range_vec(
+    /// add the byte range of every primitive's indices in the binary data to index_ranges
+    pub fn build_index_ranges(
         &self,
-        range_vec: &mut Vec<std::ops::Range<usize>>,
+        index_ranges: &mut IntervalSet,
         primitive_data: &Vec<PrimitiveData>,
     ) {
         for data in primitive_data.iter() {
-            let offset = data.indices_offset;
-            let len = data.indices_len;
-            let primitive_range = offset..offset + len;
-            crate::model::range_splicer::define_index_ranges(range_vec, &primitive_range);
+            index_ranges.insert(data.indices_offset..data.indices_offset + data.indices_len);
         }
     }
 
-    pub fn get_model_index_data(
-        main_buffer_data: &Vec<u8>,
-        range_vec: &Vec<std::ops::Range<usize>>,
-    ) -> Vec<u16> {
-        GPrimitive::get_index_data(main_buffer_data, &range_vec)
+    pub fn get_model_index_data(main_buffer_data: &[u8], index_ranges: &IntervalSet) -> Vec<u16> {
+        GPrimitive::get_index_data(main_buffer_data, index_ranges)
     }
     pub fn set_model_primitive_offsets(
         &mut self,
-        range_vec: &Vec<std::ops::Range<usize>>,
+        index_ranges: &IntervalSet,
         primitive_data: &Vec<PrimitiveData>,
     ) {
-        for mesh in self.meshes.iter_mut() {
-            let mesh_primitive_data = primitive_data
-                .iter()
-                .filter(|data| data.mesh_id == mesh.mesh_id);
-            for (primitive, data) in mesh.primitives.iter_mut().zip(mesh_primitive_data) {
+        for (mesh_id, primitives) in self.meshes.iter_mut().flat_map(GMesh::levels_mut) {
+            let mesh_primitive_data = primitive_data.iter().filter(|data| data.mesh_id == mesh_id);
+            for (primitive, data) in primitives.iter_mut().zip(mesh_primitive_data) {
                 primitive
-                    .set_relative_indices_offset(data, &range_vec)
+                    .set_relative_indices_offset(data, index_ranges)
                     .expect("set primitive indices offset");
             }
         }
     }
 }
 
+/// One instanced draw of a primitive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimitiveDraw {
+    pub material_index: usize,
+    /// empty if the primitive isn't indexed
+    pub indices: Range<u32>,
+    pub vertices: Range<u32>,
+    pub instances: Range<u32>,
+}
+
+/// A simplified version of a mesh
+#[derive(Debug, Clone)]
+pub(super) struct MeshLod {
+    /// the gltf index of the simplified mesh
+    pub mesh_id: usize,
+    /// the fraction of the screen height an instance covers, below which this level is drawn
+    pub screen_size: f32,
+    primitives: Vec<GPrimitive>,
+}
+
 #[derive(Debug, Clone)]
 pub(super) struct GMesh {
     pub mesh_id: usize,
+    pub name: Option<String>,
     primitives: Vec<GPrimitive>,
+    /// from the most to the least detailed, with decreasing screen sizes
+    lods: Vec<MeshLod>,
 }
 
 impl GMesh {
+    /// add a level of detail, less detailed than the ones already added
+    pub(super) fn add_lod(
+        &mut self,
+        lod_mesh: &Mesh,
+        screen_size: f32,
+        primitive_material_map: &HashMap<usize, usize>,
+    ) -> Result<(), GltfErrors> {
+        self.lods.push(MeshLod {
+            mesh_id: lod_mesh.index(),
+            screen_size,
+            primitives: Self::new(lod_mesh, primitive_material_map)?.primitives,
+        });
+        Ok(())
+    }
+
+    /// add a level of detail generated from this mesh, with the same materials
+    pub(super) fn add_simplified_lod(&mut self, mesh_id: usize, screen_size: f32) {
+        let primitives = self
+            .primitives
+            .iter()
+            .map(|primitive| GPrimitive::new(primitive.material_index))
+            .collect();
+        self.lods.push(MeshLod {
+            mesh_id,
+            screen_size,
+            primitives,
+        });
+    }
+
+    pub(super) fn lod_count(&self) -> usize {
+        self.lods.len()
+    }
+
+    /// the primitives of every level of detail, starting with the full one
+    fn levels(&self) -> impl Iterator<Item = &[GPrimitive]> {
+        std::iter::once(self.primitives.as_slice())
+            .chain(self.lods.iter().map(|lod| lod.primitives.as_slice()))
+    }
+
+    /// the gltf mesh index and the primitives of every level of detail, starting with the full one
+    fn levels_mut(&mut self) -> impl Iterator<Item = (usize, &mut Vec<GPrimitive>)> {
+        std::iter::once((self.mesh_id, &mut self.primitives)).chain(
+            self.lods
+                .iter_mut()
+                .map(|lod| (lod.mesh_id, &mut lod.primitives)),
+        )
+    }
+
+    /// the level of detail to draw an instance covering screen_size of the screen height with,
+    /// the full one if it is unknown
+    fn lod_level(&self, screen_size: Option<f32>) -> usize {
+        match screen_size {
+            Some(screen_size) => self
+                .lods
+                .iter()
+                .take_while(|lod| screen_size < lod.screen_size)
+                .count(),
+            None => 0,
+        }
+    }
+
+    fn level_primitives(&self, level: usize) -> &[GPrimitive] {
+        match level {
+            0 => &self.primitives,
+            level => &self.lods[level - 1].primitives,
+        }
+    }
+
+    /// the bounds of all primitives, None if one of them has none
+    fn bounds(&self) -> Option<Aabb> {
+        let mut primitives = self.primitives.iter();
+        let first = primitives.next()?.bounds?;
+        primitives.try_fold(first, |bounds, primitive| {
+            Some(bounds.union(&primitive.bounds?))
+        })
+    }
     pub fn get_primitive_data(
         mesh: &Mesh,
         buffer_offsets: &Vec<u64>,
@@ -177,100 +452,13 @@ impl GMesh {
         }
         Ok(Self {
             mesh_id: mesh.index(),
+            name: mesh.name().map(str::to_string),
             primitives: g_primitives,
+            lods: Vec::new(),
         })
     }
 }
 
-pub trait GDrawModel<'a> {
-    fn draw_scene(&mut self, scene: &'a GScene, materials: &Vec<GMaterial>);
-}
-trait RenderPassUtil<'a> {
-    fn draw_gmesh_instanced(
-        &mut self,
-        mesh: &'a GMesh,
-        instances: Range<u32>,
-        materials: &Vec<GMaterial>,
-    );
-    fn draw_gmodel(
-        &mut self,
-        model: &'a GModel,
-        instances: u32,
-        num_mesh_instances: u32,
-        materials: &Vec<GMaterial>,
-    ) -> u32;
-}
-
-impl<'a> RenderPassUtil<'a> for wgpu::RenderPass<'a> {
-    fn draw_gmesh_instanced(
-        &mut self,
-        mesh: &'a GMesh,
-        instances: Range<u32>,
-        materials: &Vec<GMaterial>,
-    ) {
-        let mut material_id = 0;
-        for primitive in mesh.primitives.iter() {
-            if primitive.material_index != material_id {
-                material_id = primitive.material_index;
-                self.set_bind_group(3, &materials[material_id].bind_group, &[]);
-            }
-            unsafe {
-                let (indices_offset, indices_length) =
-                    primitive.initialized_index_offset_len.unwrap_unchecked();
-                let (vertices_offset, vertices_length) =
-                    primitive.initialized_vertex_offset_len.unwrap_unchecked();
-                if indices_length > 0 {
-                    self.draw_indexed(
-                        indices_offset..(indices_length + indices_offset),
-                        vertices_offset as i32,
-                        instances.clone(),
-                    );
-                } else {
-                    self.draw(
-                        vertices_offset..(vertices_offset + vertices_length),
-                        instances.clone(),
-                    );
-                }
-            }
-        }
-    }
-    fn draw_gmodel(
-        &mut self,
-        model: &'a GModel,
-        model_offset: u32,
-        model_instance_count: u32,
-        materials: &Vec<GMaterial>,
-    ) -> u32 {
-        let mut mesh_offset = model_offset;
-        for (idx, mesh) in model.meshes.iter().enumerate() {
-            let num_mesh_instances = model.mesh_instances[idx] * model_instance_count;
-            self.draw_gmesh_instanced(
-                mesh,
-                mesh_offset..mesh_offset + num_mesh_instances,
-                materials,
-            );
-            mesh_offset += num_mesh_instances;
-        }
-        mesh_offset
-    }
-}
-
-impl<'a, 'b> GDrawModel<'b> for wgpu::RenderPass<'a>
-where
-    'b: 'a,
-{
-    fn draw_scene(&mut self, scene: &'b GScene, materials: &Vec<GMaterial>) {
-        let mut offset: u32 = 0;
-        for (idx, model) in scene.models.iter().enumerate() {
-            offset += self.draw_gmodel(
-                model,
-                offset,
-                scene.get_model_instances()[idx] as u32,
-                materials,
-            );
-        }
-    }
-}
 pub trait ToRawMatrix {
     fn as_raw_matrix(&self) -> [[f32; 4]; 4];
 }
@@ -0,0 +1,409 @@
+use std::ops::Range;
+
+use cgmath::InnerSpace;
+
+use crate::model::vertex::ModelVertex;
+
+/// the size of the lru cache the triangle order is optimized for
+const OPTIMIZED_CACHE_SIZE: usize = 32;
+/// the size of the fifo cache misses are counted with, about what most gpus have
+const ACMR_CACHE_SIZE: usize = 16;
+/// how much worse than the cache order a cluster's misses may get when clusters are split
+/// up further for overdraw
+const OVERDRAW_THRESHOLD: f32 = 1.05;
+
+/// What reordering the indices of a set of primitives did to the vertex cache.
+/// ACMR, the average cache miss ratio, is the number of vertices transformed per triangle:
+/// 3 at worst, around 0.5 for a well ordered regular mesh
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IndexOptimizationStats {
+    pub primitives: usize,
+    pub triangles: usize,
+    pub cache_misses_before: usize,
+    pub cache_misses_after: usize,
+}
+
+impl IndexOptimizationStats {
+    pub fn acmr_before(&self) -> f32 {
+        self.cache_misses_before as f32 / self.triangles.max(1) as f32
+    }
+
+    pub fn acmr_after(&self) -> f32 {
+        self.cache_misses_after as f32 / self.triangles.max(1) as f32
+    }
+
+    pub fn merge(&mut self, other: &IndexOptimizationStats) {
+        self.primitives += other.primitives;
+        self.triangles += other.triangles;
+        self.cache_misses_before += other.cache_misses_before;
+        self.cache_misses_after += other.cache_misses_after;
+    }
+}
+
+/// The vertices a fifo cache of cache_size would transform drawing a triangle list
+pub fn cache_misses(indices: &[u16], cache_size: usize) -> usize {
+    let mut cache: std::collections::VecDeque<u16> = std::collections::VecDeque::new();
+    let mut misses = 0;
+    for index in indices {
+        if !cache.contains(index) {
+            misses += 1;
+            cache.push_back(*index);
+            if cache.len() > cache_size {
+                cache.pop_front();
+            }
+        }
+    }
+    misses
+}
+
+/// Reorder the triangles of a primitive for the vertex cache and then for overdraw, and its
+/// vertices in the order they are first used. The triangles keep their winding, and indices
+/// stay relative to the start of vertices
+pub fn optimize_primitive(
+    indices: &mut [u16],
+    vertices: &mut [ModelVertex],
+) -> IndexOptimizationStats {
+    let cache_misses_before = cache_misses(indices, ACMR_CACHE_SIZE);
+    optimize_vertex_cache(indices, vertices.len());
+    optimize_overdraw(indices, vertices, OVERDRAW_THRESHOLD);
+    optimize_vertex_fetch(indices, vertices);
+    IndexOptimizationStats {
+        primitives: 1,
+        triangles: indices.len() / 3,
+        cache_misses_before,
+        cache_misses_after: cache_misses(indices, ACMR_CACHE_SIZE),
+    }
+}
+
+/// the score of a vertex in Tom Forsyth's linear speed vertex cache optimization: higher the
+/// more recently it was used, and the fewer triangles it has left
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        // the triangle just drawn, which any order gets for free
+        Some(position) if position < 3 => 0.75,
+        Some(position) => {
+            let scaled = (position - 3) as f32 / (OPTIMIZED_CACHE_SIZE - 3) as f32;
+            (1.0 - scaled).powf(1.5)
+        }
+        None => 0.0,
+    };
+    // finishing off vertices with few triangles left gets them out of the way
+    cache_score + 2.0 * (remaining_triangles as f32).powf(-0.5)
+}
+
+/// Reorder triangles so that they reuse the vertices of the ones drawn just before them,
+/// greedily drawing the triangle whose vertices score highest next
+pub fn optimize_vertex_cache(indices: &mut [u16], vertex_count: usize) {
+    let triangle_count = indices.len() / 3;
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); vertex_count];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for corner in corners {
+            vertex_triangles[*corner as usize].push(triangle);
+        }
+    }
+    let mut vertex_scores: Vec<f32> = vertex_triangles
+        .iter()
+        .map(|triangles| vertex_score(None, triangles.len()))
+        .collect();
+    let triangle_score = |triangle: usize, vertex_scores: &[f32]| -> f32 {
+        indices[triangle * 3..triangle * 3 + 3]
+            .iter()
+            .map(|corner| vertex_scores[*corner as usize])
+            .sum()
+    };
+    let mut triangle_scores: Vec<f32> = (0..triangle_count)
+        .map(|triangle| triangle_score(triangle, &vertex_scores))
+        .collect();
+    let mut drawn = vec![false; triangle_count];
+    let mut cache: Vec<u16> = Vec::with_capacity(OPTIMIZED_CACHE_SIZE + 3);
+    let mut ordered: Vec<u16> = Vec::with_capacity(indices.len());
+    // where to look for a triangle when none around the cache are left
+    let mut next_undrawn = 0;
+    let mut best =
+        (0..triangle_count).max_by(|a, b| triangle_scores[*a].total_cmp(&triangle_scores[*b]));
+
+    while let Some(triangle) = best {
+        drawn[triangle] = true;
+        let corners = [0, 1, 2].map(|corner| indices[triangle * 3 + corner]);
+        ordered.extend(corners);
+        for corner in corners {
+            vertex_triangles[corner as usize].retain(|other| *other != triangle);
+        }
+        // the triangle's vertices move to the front, the rest shift back and some fall out
+        let evicted: Vec<u16> = cache
+            .iter()
+            .filter(|vertex| !corners.contains(vertex))
+            .copied()
+            .collect();
+        cache = corners.to_vec();
+        cache.extend(evicted);
+        for vertex in cache.drain(OPTIMIZED_CACHE_SIZE.min(cache.len())..) {
+            vertex_scores[vertex as usize] =
+                vertex_score(None, vertex_triangles[vertex as usize].len());
+        }
+        for (position, vertex) in cache.iter().enumerate() {
+            vertex_scores[*vertex as usize] =
+                vertex_score(Some(position), vertex_triangles[*vertex as usize].len());
+        }
+
+        best = None;
+        let mut best_score = f32::MIN;
+        for vertex in cache.iter() {
+            for other in &vertex_triangles[*vertex as usize] {
+                triangle_scores[*other] = triangle_score(*other, &vertex_scores);
+                if triangle_scores[*other] > best_score {
+                    best = Some(*other);
+                    best_score = triangle_scores[*other];
+                }
+            }
+        }
+        if best.is_none() {
+            while next_undrawn < triangle_count && drawn[next_undrawn] {
+                next_undrawn += 1;
+            }
+            best = (next_undrawn < triangle_count).then_some(next_undrawn);
+        }
+    }
+    indices[..ordered.len()].copy_from_slice(&ordered);
+}
+
+/// Reorder runs of triangles, keeping the order within each, so that runs facing away from
+/// the middle of the mesh are drawn first and hide what is behind them. A run ends wherever the
+/// cache would start over, or where splitting it costs less than threshold times its misses
+pub fn optimize_overdraw(indices: &mut [u16], vertices: &[ModelVertex], threshold: f32) {
+    let clusters = overdraw_clusters(indices, threshold);
+    if clusters.len() < 2 {
+        return;
+    }
+    let position =
+        |index: u16| -> cgmath::Vector3<f32> { vertices[index as usize].position.into() };
+    let triangle_normal = |corners: &[u16]| -> cgmath::Vector3<f32> {
+        let [a, b, c] = [0, 1, 2].map(|corner| position(corners[corner]));
+        (b - a).cross(c - a)
+    };
+    let triangle_centroid = |corners: &[u16]| -> cgmath::Vector3<f32> {
+        corners
+            .iter()
+            .map(|corner| position(*corner))
+            .sum::<cgmath::Vector3<f32>>()
+            / 3.0
+    };
+    let mut mesh_centroid = cgmath::Vector3::new(0.0, 0.0, 0.0);
+    let mut mesh_area = 0.0;
+    for corners in indices.chunks_exact(3) {
+        let area = triangle_normal(corners).magnitude();
+        mesh_centroid += triangle_centroid(corners) * area;
+        mesh_area += area;
+    }
+    if mesh_area <= 0.0 {
+        return;
+    }
+    mesh_centroid /= mesh_area;
+
+    let mut sort_keys: Vec<(f32, Range<usize>)> = clusters
+        .into_iter()
+        .map(|cluster| {
+            let mut centroid = cgmath::Vector3::new(0.0, 0.0, 0.0);
+            let mut normal = cgmath::Vector3::new(0.0, 0.0, 0.0);
+            let mut area = 0.0;
+            for corners in indices[cluster.start * 3..cluster.end * 3].chunks_exact(3) {
+                let triangle_normal = triangle_normal(corners);
+                let triangle_area = triangle_normal.magnitude();
+                centroid += triangle_centroid(corners) * triangle_area;
+                normal += triangle_normal;
+                area += triangle_area;
+            }
+            if area <= 0.0 || normal.magnitude2() <= 0.0 {
+                return (0.0, cluster);
+            }
+            let outwards = (centroid / area - mesh_centroid).dot(normal.normalize());
+            (outwards, cluster)
+        })
+        .collect();
+    sort_keys.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let ordered: Vec<u16> = sort_keys
+        .iter()
+        .flat_map(|(_, cluster)| indices[cluster.start * 3..cluster.end * 3].iter().copied())
+        .collect();
+    indices.copy_from_slice(&ordered);
+}
+
+/// the triangle ranges the cache ordered indices split into, see [optimize_overdraw]
+fn overdraw_clusters(indices: &[u16], threshold: f32) -> Vec<Range<usize>> {
+    let triangle_count = indices.len() / 3;
+    let mut cache: std::collections::VecDeque<u16> = std::collections::VecDeque::new();
+    let mut triangle_misses: Vec<usize> = Vec::with_capacity(triangle_count);
+    for corners in indices.chunks_exact(3) {
+        let mut misses = 0;
+        for corner in corners {
+            if !cache.contains(corner) {
+                misses += 1;
+                cache.push_back(*corner);
+                if cache.len() > ACMR_CACHE_SIZE {
+                    cache.pop_front();
+                }
+            }
+        }
+        triangle_misses.push(misses);
+    }
+    // a triangle missing all three of its vertices starts over, nothing is lost splitting there
+    let mut hard_starts: Vec<usize> = (1..triangle_count)
+        .filter(|triangle| triangle_misses[*triangle] == 3)
+        .collect();
+    hard_starts.insert(0, 0);
+    hard_starts.push(triangle_count);
+
+    let mut clusters = Vec::new();
+    for hard in hard_starts.windows(2) {
+        let (start, end) = (hard[0], hard[1]);
+        if start == end {
+            continue;
+        }
+        let cluster_misses: usize = triangle_misses[start..end].iter().sum();
+        let cluster_threshold = threshold * cluster_misses as f32 / (end - start) as f32;
+        let mut soft_start = start;
+        let mut running_misses = 0;
+        for (triangle, misses) in triangle_misses.iter().enumerate().take(end).skip(start) {
+            running_misses += misses;
+            let running_acmr = running_misses as f32 / (triangle + 1 - soft_start) as f32;
+            if triangle + 1 < end && running_acmr <= cluster_threshold {
+                clusters.push(soft_start..triangle + 1);
+                soft_start = triangle + 1;
+                running_misses = 0;
+            }
+        }
+        clusters.push(soft_start..end);
+    }
+    clusters
+}
+
+/// Reorder vertices in the order the indices first use them, and point the indices at where
+/// they moved. Vertices no index uses are left at the end
+pub fn optimize_vertex_fetch(indices: &mut [u16], vertices: &mut [ModelVertex]) {
+    let mut new_index: Vec<Option<u16>> = vec![None; vertices.len()];
+    let mut order: Vec<usize> = Vec::with_capacity(vertices.len());
+    for index in indices.iter_mut() {
+        let moved = new_index[*index as usize].get_or_insert_with(|| {
+            order.push(*index as usize);
+            (order.len() - 1) as u16
+        });
+        *index = *moved;
+    }
+    order.extend((0..vertices.len()).filter(|vertex| new_index[*vertex].is_none()));
+    let reordered: Vec<ModelVertex> = order.iter().map(|vertex| vertices[*vertex]).collect();
+    vertices.copy_from_slice(&reordered);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a size x size grid of quads, with its triangles in a scrambled order
+    fn scrambled_grid(size: u16) -> (Vec<u16>, Vec<ModelVertex>) {
+        let row = size + 1;
+        let vertices: Vec<ModelVertex> = (0..row * row)
+            .map(|vertex| ModelVertex {
+                position: [(vertex % row) as f32, (vertex / row) as f32, 0.0],
+                normal: [0.0, 0.0, 1.0],
+                tex_coords: [0.0, 0.0],
+                joints: [0; 8],
+                weights: [255, 0, 0, 0, 0, 0, 0, 0],
+                base_color_index: 0,
+            })
+            .collect();
+        let mut triangles: Vec<[u16; 3]> = Vec::new();
+        for y in 0..size {
+            for x in 0..size {
+                let corner = y * row + x;
+                triangles.push([corner, corner + 1, corner + row + 1]);
+                triangles.push([corner, corner + row + 1, corner + row]);
+            }
+        }
+        // a fixed stride through the triangles, coprime with their count
+        let count = triangles.len();
+        let indices = (0..count)
+            .flat_map(|i| triangles[(i * 37) % count])
+            .collect();
+        (indices, vertices)
+    }
+
+    /// the triangles as positions, each rotated to start at its smallest corner, so that two
+    /// lists draw the same thing if they are equal after sorting
+    fn drawn_triangles(indices: &[u16], vertices: &[ModelVertex]) -> Vec<[[u32; 3]; 3]> {
+        let mut triangles: Vec<[[u32; 3]; 3]> = indices
+            .chunks_exact(3)
+            .map(|corners| {
+                let mut corners =
+                    [0, 1, 2].map(|c| vertices[corners[c] as usize].position.map(f32::to_bits));
+                let smallest = (0..3).min_by_key(|c| corners[*c]).unwrap();
+                corners.rotate_left(smallest);
+                corners
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn test_cache_misses_of_a_strip() {
+        // every triangle after the first shares two vertices with the one before
+        let strip = [0, 1, 2, 2, 1, 3, 2, 3, 4, 4, 3, 5];
+        assert_eq!(cache_misses(&strip, 16), 6);
+        // a cache of 3 loses vertex 0 and 1 before they are needed again
+        assert_eq!(cache_misses(&[0, 1, 2, 3, 4, 5, 0, 1, 2], 3), 9);
+    }
+
+    #[test]
+    fn test_optimized_grid_misses_less_and_draws_the_same() {
+        let (mut indices, mut vertices) = scrambled_grid(16);
+        let original = drawn_triangles(&indices, &vertices);
+        let stats = optimize_primitive(&mut indices, &mut vertices);
+        assert_eq!(stats.triangles, 512);
+        assert!(stats.acmr_before() > 1.5);
+        assert!(stats.acmr_after() < 0.8);
+        assert_eq!(drawn_triangles(&indices, &vertices), original);
+    }
+
+    #[test]
+    fn test_vertices_are_fetched_in_order() {
+        let (mut indices, mut vertices) = scrambled_grid(4);
+        let original = drawn_triangles(&indices, &vertices);
+        optimize_vertex_fetch(&mut indices, &mut vertices);
+        let mut next = 0;
+        for index in indices.iter() {
+            assert!(*index <= next);
+            next = next.max(*index + 1);
+        }
+        assert_eq!(drawn_triangles(&indices, &vertices), original);
+    }
+
+    #[test]
+    fn test_outward_facing_clusters_are_drawn_first() {
+        // two separate triangles at x = 0 and x = 1: the one at 0 faces -x, away from the
+        // middle of the mesh, the one at 1 faces -x too, into the middle
+        let vertex = |position: [f32; 3]| ModelVertex {
+            position,
+            normal: [0.0; 3],
+            tex_coords: [0.0; 2],
+            joints: [0; 8],
+            weights: [0; 8],
+            base_color_index: 0,
+        };
+        let vertices: Vec<ModelVertex> = [0.0, 1.0]
+            .into_iter()
+            .flat_map(|x| {
+                [
+                    vertex([x, 0.0, 0.0]),
+                    vertex([x, 0.0, 1.0]),
+                    vertex([x, 1.0, 0.0]),
+                ]
+            })
+            .collect();
+        let mut indices = vec![3, 4, 5, 0, 1, 2];
+        optimize_overdraw(&mut indices, &vertices, OVERDRAW_THRESHOLD);
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+    }
+}
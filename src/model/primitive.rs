@@ -1,16 +1,18 @@
-use std::ops::Range;
-
 use gltf::Primitive;
 
 use crate::{
     model::{
+        interval_set::IntervalSet,
         util::{
-            copy_binary_data_from_gltf, get_index_offset_len, AttributeType, GltfErrors,
-            InitializationError,
+            copy_binary_data_from_gltf, get_index_offset_len, get_joint_indices, get_joint_weights,
+            AttributeType, GltfErrors, InitializationError,
         },
-        vertex::ModelVertex,
+        vertex::{ModelVertex, MAX_JOINT_INFLUENCES},
+    },
+    scene::{
+        culling::Aabb,
+        scene::{PrimitiveData, VertexAccessors},
     },
-    scene::scene::PrimitiveData,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +20,8 @@ pub(super) struct GPrimitive {
     pub material_index: usize,
     pub initialized_vertex_offset_len: Option<(u32, u32)>,
     pub initialized_index_offset_len: Option<(u32, u32)>,
+    /// None if the primitive is skinned, its bind pose says little about where it is drawn
+    pub bounds: Option<Aabb>,
 }
 impl GPrimitive {
     pub fn new(material_index: usize) -> Self {
@@ -25,48 +29,31 @@ impl GPrimitive {
             material_index,
             initialized_vertex_offset_len: None,
             initialized_index_offset_len: None,
+            bounds: None,
         }
     }
-    pub(super) fn get_index_data(
-        main_buffer_data: &Vec<u8>,
-        indices_ranges: &Vec<std::ops::Range<usize>>,
-    ) -> Vec<u16> {
-        let mut index_vec: Vec<u16> = Vec::new();
-        for range in indices_ranges.iter() {
-            let indices_bytes: &[u8] = &main_buffer_data[range.start..range.end];
-            let indices_u16: &[u16] = bytemuck::cast_slice::<u8, u16>(indices_bytes);
-            index_vec.extend(indices_u16.to_vec());
-        }
-        index_vec
+    /// the indices in every range of index_ranges, back to back
+    pub(super) fn get_index_data(main_buffer_data: &[u8], index_ranges: &IntervalSet) -> Vec<u16> {
+        bytemuck::pod_collect_to_vec(&index_ranges.gather(main_buffer_data))
     }
+    /// Point the primitive at its indices in the scene's index buffer, which holds the ranges
+    /// of index_ranges back to back
     pub(super) fn set_relative_indices_offset(
         &mut self,
         data: &PrimitiveData,
-        index_ranges: &Vec<Range<usize>>,
+        index_ranges: &IntervalSet,
     ) -> Result<(), InitializationError> {
-        // upon creation, this primitive will have stored its offset and length relative to the
-        // main byte buffer. Also at this stage, scene_buffer_data has stored a list of ranges that
-        // need to be composed into the final index buffer. We need to translate the indices
-        // relative to the main buffer to indices relative to a buffer which would contain only the
-        // ranges specified in scene_buffer_data.
-        let mut relative_buffer_offset = 0;
-        let offset = data.indices_offset;
-        let len = data.indices_len;
-        for index_range in index_ranges.iter() {
-            if offset > index_range.end {
-                relative_buffer_offset += index_range.len();
-            } else {
-                relative_buffer_offset += offset - index_range.start;
-                // paranoid?
-                if (offset + len) as usize > index_range.end {
-                    return Err(InitializationError::SceneInitializationError);
-                }
-                break;
-            }
+        if data.indices_len == 0 {
+            self.initialized_index_offset_len = Some((0, 0));
+            return Ok(());
         }
-
-        self.initialized_index_offset_len =
-            Some(((relative_buffer_offset / 2) as u32, len as u32 / 2));
+        let relative_buffer_offset = index_ranges
+            .packed_offset(&(data.indices_offset..data.indices_offset + data.indices_len))
+            .ok_or(InitializationError::SceneInitializationError)?;
+        self.initialized_index_offset_len = Some((
+            (relative_buffer_offset / 2) as u32,
+            data.indices_len as u32 / 2,
+        ));
         Ok(())
     }
 }
@@ -98,20 +85,6 @@ impl PrimitiveData {
             Some(tex_coords) => (tex_coords.0, Some(tex_coords.1)),
             None => (gltf::Semantic::TexCoords(0), None),
         };
-        let (_, maybe_joints0_accessor) = match primitive
-            .attributes()
-            .find(|a| a.0 == gltf::Semantic::Joints(0))
-        {
-            Some(joints) => (joints.0, Some(joints.1)),
-            None => (gltf::Semantic::Joints(0), None),
-        };
-        let (_, maybe_weights_accessor) = match primitive
-            .attributes()
-            .find(|a| a.0 == gltf::Semantic::Weights(0))
-        {
-            Some(weights) => (weights.0, Some(weights.1)),
-            None => (gltf::Semantic::Weights(0), None),
-        };
         let positions = copy_binary_data_from_gltf(
             &position_accessor,
             AttributeType::Position,
@@ -123,40 +96,69 @@ impl PrimitiveData {
                 .unwrap_or((0, 0));
         let mut normals = None;
         let mut tex_coords = None;
-        let mut joints = None;
-        let mut weights = None;
-        if let Some(normals_accesor) = maybe_normals_accessor {
+        if let Some(normals_accesor) = &maybe_normals_accessor {
             normals = Some(copy_binary_data_from_gltf(
-                &normals_accesor,
+                normals_accesor,
                 AttributeType::Normal,
                 buffer_offsets,
                 binary_data,
             )?);
         }
-        if let Some(tex_coords_accesor) = maybe_tex_coords_accessor {
+        if let Some(tex_coords_accesor) = &maybe_tex_coords_accessor {
             tex_coords = Some(copy_binary_data_from_gltf(
-                &tex_coords_accesor,
+                tex_coords_accesor,
                 AttributeType::TexCoords,
                 buffer_offsets,
                 binary_data,
             )?);
         }
-        if let Some(joints_accesor) = maybe_joints0_accessor {
-            joints = Some(copy_binary_data_from_gltf(
-                &joints_accesor,
-                AttributeType::Joints,
+        // every JOINTS_n set must be paired with a WEIGHTS_n set
+        let mut joint_sets: Vec<Vec<u16>> = Vec::new();
+        let mut weight_sets: Vec<Vec<f32>> = Vec::new();
+        let mut joint_accessors: Vec<usize> = Vec::new();
+        let mut weight_accessors: Vec<usize> = Vec::new();
+        let mut set_index = 0;
+        while let (Some((_, joints_accessor)), Some((_, weights_accessor))) = (
+            primitive
+                .attributes()
+                .find(|a| a.0 == gltf::Semantic::Joints(set_index)),
+            primitive
+                .attributes()
+                .find(|a| a.0 == gltf::Semantic::Weights(set_index)),
+        ) {
+            joint_sets.push(get_joint_indices(
+                &joints_accessor,
                 buffer_offsets,
                 binary_data,
             )?);
-        }
-        if let Some(weights_accesor) = maybe_weights_accessor {
-            weights = Some(copy_binary_data_from_gltf(
-                &weights_accesor,
-                AttributeType::Weights,
+            weight_sets.push(get_joint_weights(
+                &weights_accessor,
                 buffer_offsets,
                 binary_data,
             )?);
+            joint_accessors.push(joints_accessor.index());
+            weight_accessors.push(weights_accessor.index());
+            set_index += 1;
         }
+        let vertex_accessors = VertexAccessors {
+            positions: position_accessor.index(),
+            normals: maybe_normals_accessor.as_ref().map(gltf::Accessor::index),
+            tex_coords: maybe_tex_coords_accessor
+                .as_ref()
+                .map(gltf::Accessor::index),
+            joints: joint_accessors,
+            weights: weight_accessors,
+        };
+        let bounds = Self::accessor_bounds(&position_accessor)
+            .or_else(|| Aabb::from_positions(&bytemuck::pod_collect_to_vec::<u8, f32>(&positions)));
+        let (joints, weights) = if joint_sets.is_empty() {
+            (None, None)
+        } else {
+            let vertex_count = positions.len() / 12;
+            let (joints, weights) =
+                Self::select_joint_influences(&joint_sets, &weight_sets, vertex_count);
+            (Some(joints), Some(weights))
+        };
         Ok(Self {
             mesh_id,
             positions,
@@ -166,6 +168,23 @@ impl PrimitiveData {
             tex_coords,
             joints,
             weights,
+            bounds,
+            vertex_accessors: Some(vertex_accessors),
+        })
+    }
+    /// the box spanned by an accessor's min and max, which gltf requires for POSITION
+    fn accessor_bounds(accessor: &gltf::Accessor) -> Option<Aabb> {
+        let corner = |value: Option<gltf::json::Value>| -> Option<cgmath::Vector3<f32>> {
+            let values: Vec<f32> = value?
+                .as_array()?
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as f32))
+                .collect::<Option<_>>()?;
+            (values.len() == 3).then(|| cgmath::Vector3::new(values[0], values[1], values[2]))
+        };
+        Some(Aabb {
+            min: corner(accessor.min())?,
+            max: corner(accessor.max())?,
         })
     }
     pub(super) fn get_vertex_data(&self, material_index: usize) -> Vec<ModelVertex> {
@@ -178,19 +197,6 @@ impl PrimitiveData {
             Some(tex_coords) => Some(bytemuck::cast_slice(tex_coords).to_vec()),
             None => None,
         };
-        let joints_u16: Option<Vec<u16>> = match &self.joints {
-            Some(joints) => Some(bytemuck::cast_slice(&joints).to_vec()),
-            None => None,
-        };
-        let weights_f32: Option<Vec<f32>> = match &self.weights {
-            Some(weights) => Some(bytemuck::cast_slice(&weights).to_vec()),
-            None => None,
-        };
-        let weights_normalized = if let Some(w) = weights_f32 {
-            Some(Self::normalize_f32_to_u8(w.to_vec()))
-        } else {
-            None
-        };
         let vertex_vec: Vec<ModelVertex> = (0..(position_f32.len() / 3))
             .map(|i| {
                 let normal = match &normals_f32 {
@@ -201,62 +207,119 @@ impl PrimitiveData {
                     Some(t) => t[i * 2..i * 2 + 2].try_into().unwrap(),
                     None => [0.0, 0.0],
                 };
-                let joints = match &joints_u16 {
-                    Some(j) => j[i * 4..i * 4 + 4].try_into().unwrap(),
-                    None => [0, 0, 0, 0],
+                let joints = match &self.joints {
+                    Some(j) => j[i],
+                    None => [0; MAX_JOINT_INFLUENCES],
                 };
-                let weights = match &weights_normalized {
-                    Some(w) => &w[i * 4..i * 4 + 4],
-                    None => match &joints_u16 {
-                        Some(_) => &[0, 0, 0, 0],
-                        None => &[1, 1, 1, 1],
-                    },
+                let weights = match &self.weights {
+                    Some(w) => Self::quantize_weights(&w[i]),
+                    None => [1, 1, 1, 1, 0, 0, 0, 0],
                 };
-                // println!("POSITION {:?}", &position_f32[i * 3..i * 3 + 3]);
-                // println!(
-                //     "JOINTS {:?}",
-                //     &[
-                //         joints[0] as u8,
-                //         joints[1] as u8,
-                //         joints[2] as u8,
-                //         joints[3] as u8,
-                //     ]
-                // );
-                if joints != [0, 0, 0, 0] {
-                    let mut sum: u32 = 0;
-                    for x in weights.iter() {
-                        sum += *x as u32;
-                    }
-                    assert!(sum <= 256 && sum >= 254);
-                    //println!("WEIGHTS {:?}\n\n", weights);
-                }
 
                 return ModelVertex {
                     base_color_index: material_index as u32,
                     position: position_f32[i * 3..i * 3 + 3].try_into().unwrap(),
                     normal: normal,
                     tex_coords: tex,
-                    joints: [
-                        joints[0] as u8,
-                        joints[1] as u8,
-                        joints[2] as u8,
-                        joints[3] as u8,
-                    ],
-                    weights: weights.try_into().unwrap(),
+                    joints,
+                    weights,
                 };
             })
             .collect();
 
         vertex_vec
     }
-    fn normalize_f32_to_u8(input: Vec<f32>) -> Vec<u8> {
-        input
-            .into_iter()
-            .map(|x| {
-                assert!(x <= 1.0 && x >= 0.0);
-                let scaled = (x * 255.0).round();
-                scaled.clamp(0.0, 255.0) as u8
-            })
-            .collect()
+    /// merge every JOINTS_n/WEIGHTS_n set of a primitive into the
+    /// MAX_JOINT_INFLUENCES strongest influences for each vertex, and renormalize
+    /// the kept weights so that they sum to 1
+    fn select_joint_influences(
+        joint_sets: &[Vec<u16>],
+        weight_sets: &[Vec<f32>],
+        vertex_count: usize,
+    ) -> (
+        Vec<[u16; MAX_JOINT_INFLUENCES]>,
+        Vec<[f32; MAX_JOINT_INFLUENCES]>,
+    ) {
+        let mut joints = Vec::with_capacity(vertex_count);
+        let mut weights = Vec::with_capacity(vertex_count);
+        let mut influences: Vec<(u16, f32)> = Vec::with_capacity(joint_sets.len() * 4);
+        for i in 0..vertex_count {
+            influences.clear();
+            for (joint_set, weight_set) in joint_sets.iter().zip(weight_sets.iter()) {
+                for j in i * 4..i * 4 + 4 {
+                    if weight_set[j] > 0.0 {
+                        influences.push((joint_set[j], weight_set[j]));
+                    }
+                }
+            }
+            // strongest first, so that truncating drops the weakest influences
+            influences.sort_by(|a, b| b.1.total_cmp(&a.1));
+            influences.truncate(MAX_JOINT_INFLUENCES);
+            let sum: f32 = influences.iter().map(|influence| influence.1).sum();
+
+            let mut vertex_joints = [0; MAX_JOINT_INFLUENCES];
+            let mut vertex_weights = [0.0; MAX_JOINT_INFLUENCES];
+            for (slot, (joint, weight)) in influences.iter().enumerate() {
+                vertex_joints[slot] = *joint;
+                vertex_weights[slot] = weight / sum;
+            }
+            joints.push(vertex_joints);
+            weights.push(vertex_weights);
+        }
+        (joints, weights)
+    }
+
+    /// convert normalized weights to unorm8, making sure the quantized
+    /// weights still add up to exactly 255
+    fn quantize_weights(weights: &[f32; MAX_JOINT_INFLUENCES]) -> [u8; MAX_JOINT_INFLUENCES] {
+        let mut quantized = weights.map(|w| (w.clamp(0.0, 1.0) * 255.0).round() as u8);
+        let sum: i32 = quantized.iter().map(|w| *w as i32).sum();
+        if sum > 0 {
+            // rounding can leave the total a few units off, so push the difference onto the
+            // largest weight where it matters least
+            let largest = (0..MAX_JOINT_INFLUENCES)
+                .max_by_key(|slot| quantized[*slot])
+                .unwrap();
+            quantized[largest] = (quantized[largest] as i32 + 255 - sum).clamp(0, 255) as u8;
+        }
+        quantized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joint_influences_keep_strongest_eight() {
+        // two sets of four influences, plus a third set that should be mostly dropped
+        let joint_sets = vec![
+            vec![0, 1, 2, 3],
+            vec![300, 301, 302, 303],
+            vec![7, 8, 9, 10],
+        ];
+        let weight_sets = vec![
+            vec![0.2, 0.1, 0.1, 0.1],
+            vec![0.1, 0.1, 0.1, 0.05],
+            vec![0.15, 0.01, 0.0, 0.0],
+        ];
+        let (joints, weights) =
+            PrimitiveData::select_joint_influences(&joint_sets, &weight_sets, 1);
+        assert_eq!(joints[0], [0, 7, 1, 2, 3, 300, 301, 302]);
+        let sum: f32 = weights[0].iter().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+        assert!(weights[0][0] > weights[0][1]);
+    }
+
+    #[test]
+    fn test_quantized_weights_sum_to_255() {
+        let third = 1.0 / 3.0;
+        let quantized =
+            PrimitiveData::quantize_weights(&[third, third, third, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(quantized.iter().map(|w| *w as u32).sum::<u32>(), 255);
+
+        let eighth = [0.125; MAX_JOINT_INFLUENCES];
+        let quantized = PrimitiveData::quantize_weights(&eighth);
+        assert_eq!(quantized.iter().map(|w| *w as u32).sum::<u32>(), 255);
     }
 }
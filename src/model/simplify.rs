@@ -0,0 +1,687 @@
+use std::collections::{HashMap, HashSet};
+
+use cgmath::InnerSpace;
+
+use crate::{
+    model::{loader::loader::GltfData, lod::default_screen_sizes},
+    scene::{culling::Aabb, scene::PrimitiveData},
+};
+
+/// how much more a border edge resists being moved than the faces around it
+const BORDER_WEIGHT: f64 = 10.0;
+
+/// How far a primitive may be simplified
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimplifySettings {
+    /// the fraction of the triangles to keep
+    pub target_ratio: f32,
+    /// The furthest the surface may move, as a fraction of the primitive's size. Simplifying
+    /// stops short of target_ratio rather than go past it
+    pub max_error: f32,
+}
+
+impl Default for SimplifySettings {
+    fn default() -> Self {
+        Self {
+            target_ratio: 0.5,
+            max_error: 0.01,
+        }
+    }
+}
+
+/// A primitive with fewer triangles, along with only the vertices they use
+pub struct SimplifiedPrimitive {
+    /// indices_offset and indices_len are left at 0, until the indices are stored somewhere
+    pub data: PrimitiveData,
+    pub indices: Vec<u16>,
+    /// the quadric error of the costliest collapse as a distance, in model units
+    pub error: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VertexKind {
+    /// surrounded by triangles, it can be collapsed into any neighbour
+    Manifold,
+    /// on an open edge of the mesh, it can only slide along that edge
+    Border,
+    /// shares its position with another vertex, at a uv or normal seam, or is where the
+    /// topology is too tangled to move it safely
+    Locked,
+}
+
+/// the squared distance to a set of planes, weighted by their area
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric {
+    /// the symmetric 3x3 part: xx, xy, xz, yy, yz, zz
+    a: [f64; 6],
+    b: [f64; 3],
+    c: f64,
+    weight: f64,
+}
+
+impl Quadric {
+    fn from_plane(normal: cgmath::Vector3<f64>, point: cgmath::Vector3<f64>, weight: f64) -> Self {
+        let d = -normal.dot(point);
+        let [x, y, z] = [normal.x, normal.y, normal.z];
+        Self {
+            a: [x * x, x * y, x * z, y * y, y * z, z * z].map(|a| a * weight),
+            b: [x * d, y * d, z * d].map(|b| b * weight),
+            c: d * d * weight,
+            weight,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (a, other_a) in self.a.iter_mut().zip(other.a) {
+            *a += other_a;
+        }
+        for (b, other_b) in self.b.iter_mut().zip(other.b) {
+            *b += other_b;
+        }
+        self.c += other.c;
+        self.weight += other.weight;
+    }
+
+    /// the mean squared distance of point to the planes
+    fn error(&self, point: cgmath::Vector3<f64>) -> f64 {
+        if self.weight <= 0.0 {
+            return 0.0;
+        }
+        let [xx, xy, xz, yy, yz, zz] = self.a;
+        let (x, y, z) = (point.x, point.y, point.z);
+        let error = xx * x * x
+            + yy * y * y
+            + zz * z * z
+            + 2.0 * (xy * x * y + xz * x * z + yz * y * z)
+            + 2.0 * (self.b[0] * x + self.b[1] * y + self.b[2] * z)
+            + self.c;
+        error.max(0.0) / self.weight
+    }
+}
+
+/// Simplify a triangle list by collapsing edges, cheapest first by the quadric error metric,
+/// until settings.target_ratio of the triangles are left or the next collapse would move the
+/// surface further than settings.max_error.
+/// A vertex is only ever collapsed into one of its neighbours, so every vertex left keeps its
+/// own normal, uv and joint influences. Vertices at uv and normal seams are never moved, open
+/// edges only collapse along themselves, and a skinned vertex only collapses into a vertex
+/// following the same strongest joint, so that the simplified mesh deforms like the original
+pub fn simplify_primitive(
+    data: &PrimitiveData,
+    indices: &[u16],
+    settings: &SimplifySettings,
+) -> SimplifiedPrimitive {
+    let positions_f32: &[f32] = bytemuck::cast_slice(&data.positions);
+    let bounds = Aabb::from_positions(positions_f32);
+    // work in a unit sized space, so that max_error doesn't depend on the model's scale
+    let (origin, extent) = match bounds {
+        Some(bounds) => {
+            let size = bounds.max - bounds.min;
+            (bounds.min, size.x.max(size.y).max(size.z).max(f32::EPSILON))
+        }
+        None => (cgmath::Vector3::new(0.0, 0.0, 0.0), 1.0),
+    };
+    let positions: Vec<cgmath::Vector3<f64>> = positions_f32
+        .chunks_exact(3)
+        .map(|p| {
+            let p = (cgmath::Vector3::new(p[0], p[1], p[2]) - origin) / extent;
+            p.cast::<f64>().expect("f32 fits in f64")
+        })
+        .collect();
+    let dominant_joints: Option<Vec<u16>> = data
+        .joints
+        .as_ref()
+        .map(|joints| joints.iter().map(|joints| joints[0]).collect());
+
+    let colocated = colocated_vertices(positions_f32);
+    let mut indices: Vec<u32> = indices.iter().map(|i| *i as u32).collect();
+    let target_index_count =
+        ((indices.len() / 3) as f32 * settings.target_ratio.clamp(0.0, 1.0)) as usize * 3;
+    let max_error = (settings.max_error as f64).powi(2);
+    let mut quadrics = vertex_quadrics(&positions, &colocated, &indices);
+    let mut worst_error: f64 = 0.0;
+
+    while indices.len() > target_index_count {
+        let border_edges = border_edges(&colocated, &indices);
+        let kinds = vertex_kinds(&colocated, &border_edges, positions.len());
+        let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); positions.len()];
+        for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+            for corner in corners {
+                vertex_triangles[*corner as usize].push(triangle);
+            }
+        }
+
+        let mut collapses: Vec<(f64, u32, u32)> = Vec::new();
+        for corners in indices.chunks_exact(3) {
+            for edge in 0..3 {
+                let (from, to) = (corners[edge], corners[(edge + 1) % 3]);
+                for (from, to) in [(from, to), (to, from)] {
+                    let can_collapse = match kinds[from as usize] {
+                        VertexKind::Manifold => true,
+                        VertexKind::Border => {
+                            kinds[to as usize] != VertexKind::Manifold
+                                && (border_edges
+                                    .contains(&(colocated[from as usize], colocated[to as usize]))
+                                    || border_edges.contains(&(
+                                        colocated[to as usize],
+                                        colocated[from as usize],
+                                    )))
+                        }
+                        VertexKind::Locked => false,
+                    };
+                    let same_joint = dominant_joints
+                        .as_ref()
+                        .is_none_or(|joints| joints[from as usize] == joints[to as usize]);
+                    if can_collapse && same_joint {
+                        let cost = quadrics[from as usize].error(positions[to as usize]);
+                        collapses.push((cost, from, to));
+                    }
+                }
+            }
+        }
+        collapses.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // every collapse removes about two triangles, and collapses touching the same
+        // triangles can't be judged independently within one pass
+        let collapse_limit = (indices.len() - target_index_count) / 6 + 1;
+        let mut collapse_into: Vec<u32> = (0..positions.len() as u32).collect();
+        let mut touched = vec![false; positions.len()];
+        let mut collapsed = 0;
+        for (cost, from, to) in collapses {
+            if cost > max_error || collapsed >= collapse_limit {
+                break;
+            }
+            let (from, to) = (from as usize, to as usize);
+            if touched[from] || touched[to] {
+                continue;
+            }
+            if flips_triangles(&positions, &indices, &vertex_triangles[from], from, to) {
+                continue;
+            }
+            collapse_into[from] = to as u32;
+            let from_quadric = quadrics[from];
+            quadrics[to].add(&from_quadric);
+            for triangle in &vertex_triangles[from] {
+                for corner in &indices[triangle * 3..triangle * 3 + 3] {
+                    touched[*corner as usize] = true;
+                }
+            }
+            worst_error = worst_error.max(cost);
+            collapsed += 1;
+        }
+        if collapsed == 0 {
+            break;
+        }
+        let mut kept = Vec::with_capacity(indices.len());
+        for corners in indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| collapse_into[corners[corner] as usize]);
+            if a != b && b != c && c != a {
+                kept.extend([a, b, c]);
+            }
+        }
+        indices = kept;
+    }
+
+    let (data, indices) = compact_vertices(data, &indices);
+    SimplifiedPrimitive {
+        data,
+        indices,
+        error: worst_error.sqrt() as f32 * extent,
+    }
+}
+
+/// for each vertex, the first vertex with exactly the same position
+fn colocated_vertices(positions: &[f32]) -> Vec<u32> {
+    let mut first_at: HashMap<[u32; 3], u32> = HashMap::new();
+    positions
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(vertex, p)| {
+            *first_at
+                .entry([p[0].to_bits(), p[1].to_bits(), p[2].to_bits()])
+                .or_insert(vertex as u32)
+        })
+        .collect()
+}
+
+/// The directed edges, between colocated vertices, that no triangle walks the other way
+fn border_edges(colocated: &[u32], indices: &[u32]) -> HashSet<(u32, u32)> {
+    let mut edges: HashSet<(u32, u32)> = HashSet::new();
+    for corners in indices.chunks_exact(3) {
+        for edge in 0..3 {
+            let from = colocated[corners[edge] as usize];
+            let to = colocated[corners[(edge + 1) % 3] as usize];
+            edges.insert((from, to));
+        }
+    }
+    edges
+        .iter()
+        .filter(|(from, to)| !edges.contains(&(*to, *from)))
+        .copied()
+        .collect()
+}
+
+fn vertex_kinds(
+    colocated: &[u32],
+    border_edges: &HashSet<(u32, u32)>,
+    vertex_count: usize,
+) -> Vec<VertexKind> {
+    let mut kinds = vec![VertexKind::Manifold; vertex_count];
+    let mut shared = vec![false; vertex_count];
+    for (vertex, first) in colocated.iter().enumerate() {
+        if *first as usize != vertex {
+            shared[vertex] = true;
+            shared[*first as usize] = true;
+        }
+    }
+    let mut border_edge_counts = vec![0; vertex_count];
+    for (from, to) in border_edges {
+        border_edge_counts[*from as usize] += 1;
+        border_edge_counts[*to as usize] += 1;
+    }
+    for (vertex, kind) in kinds.iter_mut().enumerate() {
+        let first = colocated[vertex] as usize;
+        *kind = match border_edge_counts[first] {
+            _ if shared[vertex] => VertexKind::Locked,
+            0 => VertexKind::Manifold,
+            // exactly one edge in and one out, anything else is where borders meet
+            2 => VertexKind::Border,
+            _ => VertexKind::Locked,
+        };
+    }
+    kinds
+}
+
+/// the planes of the triangles around each vertex, plus planes standing up from open edges
+/// which keep them from caving in
+fn vertex_quadrics(
+    positions: &[cgmath::Vector3<f64>],
+    colocated: &[u32],
+    indices: &[u32],
+) -> Vec<Quadric> {
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    let border_edges = border_edges(colocated, indices);
+    for corners in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|corner| corners[corner] as usize);
+        let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        let double_area = normal.magnitude();
+        if double_area <= 0.0 {
+            continue;
+        }
+        let normal = normal / double_area;
+        let quadric = Quadric::from_plane(normal, positions[a], double_area / 2.0);
+        for vertex in [a, b, c] {
+            quadrics[vertex].add(&quadric);
+        }
+        for (from, to) in [(a, b), (b, c), (c, a)] {
+            if !border_edges.contains(&(colocated[from], colocated[to])) {
+                continue;
+            }
+            let edge = positions[to] - positions[from];
+            let edge_normal = edge.cross(normal);
+            if edge_normal.magnitude2() <= 0.0 {
+                continue;
+            }
+            let quadric = Quadric::from_plane(
+                edge_normal.normalize(),
+                positions[from],
+                edge.magnitude2() * BORDER_WEIGHT,
+            );
+            quadrics[from].add(&quadric);
+            quadrics[to].add(&quadric);
+        }
+    }
+    quadrics
+}
+
+/// whether moving from onto to would turn one of the triangles around from over, the ones
+/// containing both are removed by the collapse and don't count
+fn flips_triangles(
+    positions: &[cgmath::Vector3<f64>],
+    indices: &[u32],
+    triangles: &[usize],
+    from: usize,
+    to: usize,
+) -> bool {
+    triangles.iter().any(|triangle| {
+        let corners = &indices[triangle * 3..triangle * 3 + 3];
+        if corners.contains(&(to as u32)) {
+            return false;
+        }
+        let [a, b, c] = [0, 1, 2].map(|corner| positions[corners[corner] as usize]);
+        let moved = [0, 1, 2].map(|corner| match corners[corner] as usize {
+            vertex if vertex == from => positions[to],
+            vertex => positions[vertex],
+        });
+        let before = (b - a).cross(c - a);
+        let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+        before.dot(after) <= 0.0
+    })
+}
+
+/// keep only the vertices indices refer to, in the order they are first used
+fn compact_vertices(data: &PrimitiveData, indices: &[u32]) -> (PrimitiveData, Vec<u16>) {
+    let mut new_index = vec![u32::MAX; data.positions.len() / 12];
+    let mut kept: Vec<usize> = Vec::new();
+    let indices: Vec<u16> = indices
+        .iter()
+        .map(|index| {
+            let new_index = &mut new_index[*index as usize];
+            if *new_index == u32::MAX {
+                *new_index = kept.len() as u32;
+                kept.push(*index as usize);
+            }
+            *new_index as u16
+        })
+        .collect();
+    let gather_bytes = |bytes: &Vec<u8>, size: usize| -> Vec<u8> {
+        kept.iter()
+            .flat_map(|vertex| &bytes[vertex * size..vertex * size + size])
+            .copied()
+            .collect()
+    };
+    let positions = gather_bytes(&data.positions, 12);
+    let bounds = Aabb::from_positions(bytemuck::cast_slice(&positions));
+    let data = PrimitiveData {
+        mesh_id: data.mesh_id,
+        indices_offset: 0,
+        indices_len: 0,
+        normals: data
+            .normals
+            .as_ref()
+            .map(|normals| gather_bytes(normals, 12)),
+        tex_coords: data
+            .tex_coords
+            .as_ref()
+            .map(|tex_coords| gather_bytes(tex_coords, 8)),
+        joints: data
+            .joints
+            .as_ref()
+            .map(|joints| kept.iter().map(|vertex| joints[*vertex]).collect()),
+        weights: data
+            .weights
+            .as_ref()
+            .map(|weights| kept.iter().map(|vertex| weights[*vertex]).collect()),
+        positions,
+        bounds,
+        vertex_accessors: None,
+    };
+    (data, indices)
+}
+
+/// Add simplified levels of detail to every mesh of gltf_data that has none of its own, one for
+/// each of levels, switched to at the default screen sizes. The simplified indices are appended
+/// to the binary data, and the levels get mesh ids past the ones in the file.
+/// Meshes with unindexed primitives are left alone, as are levels that would remove nothing.
+/// So are skinned meshes, their instances are never culled and always drawn in full detail
+pub fn generate_lods(gltf_data: &mut GltfData, levels: &[SimplifySettings]) {
+    let screen_sizes = default_screen_sizes(levels.len());
+    let mut next_mesh_id = gltf_data
+        .model_primitive_data
+        .iter()
+        .flat_map(|model_data| &model_data.primitive_data)
+        .map(|data| data.mesh_id + 1)
+        .max()
+        .unwrap_or(0);
+    let binary_data = &mut gltf_data.binary_data;
+    for (model, model_data) in gltf_data
+        .models
+        .iter_mut()
+        .zip(gltf_data.model_primitive_data.iter_mut())
+    {
+        let mut lod_data: Vec<PrimitiveData> = Vec::new();
+        for mesh in model.meshes_mut() {
+            if mesh.lod_count() > 0 {
+                continue;
+            }
+            let mesh_data: Vec<&PrimitiveData> = model_data
+                .primitive_data
+                .iter()
+                .filter(|data| data.mesh_id == mesh.mesh_id)
+                .collect();
+            if mesh_data
+                .iter()
+                .any(|data| data.indices_len == 0 || data.joints.is_some())
+            {
+                continue;
+            }
+            let original: Vec<Vec<u16>> = mesh_data
+                .iter()
+                .map(|data| {
+                    binary_data[data.indices_offset..data.indices_offset + data.indices_len]
+                        .chunks_exact(2)
+                        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                        .collect()
+                })
+                .collect();
+            let mut previous_index_count: usize = original.iter().map(Vec::len).sum();
+            for (settings, screen_size) in levels.iter().zip(&screen_sizes) {
+                let simplified: Vec<SimplifiedPrimitive> = mesh_data
+                    .iter()
+                    .zip(&original)
+                    .map(|(data, indices)| simplify_primitive(data, indices, settings))
+                    .collect();
+                let index_count: usize = simplified.iter().map(|s| s.indices.len()).sum();
+                if index_count >= previous_index_count {
+                    break;
+                }
+                previous_index_count = index_count;
+                for SimplifiedPrimitive {
+                    mut data, indices, ..
+                } in simplified
+                {
+                    // keep the indices readable as u16 where they are sliced out again
+                    binary_data.resize(binary_data.len().next_multiple_of(4), 0);
+                    data.mesh_id = next_mesh_id;
+                    data.indices_offset = binary_data.len();
+                    data.indices_len = indices.len() * 2;
+                    binary_data.extend_from_slice(bytemuck::cast_slice(&indices));
+                    lod_data.push(data);
+                }
+                mesh.add_simplified_lod(next_mesh_id, *screen_size);
+                next_mesh_id += 1;
+            }
+        }
+        model_data.primitive_data.extend(lod_data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a flat grid of size x size quads in the xy plane, with vertices in rows
+    fn grid(size: usize) -> (PrimitiveData, Vec<u16>) {
+        let mut positions: Vec<f32> = Vec::new();
+        for y in 0..=size {
+            for x in 0..=size {
+                positions.extend([x as f32, y as f32, 0.0]);
+            }
+        }
+        let row = size as u16 + 1;
+        let mut indices: Vec<u16> = Vec::new();
+        for y in 0..size as u16 {
+            for x in 0..size as u16 {
+                let corner = y * row + x;
+                indices.extend([corner, corner + 1, corner + row + 1]);
+                indices.extend([corner, corner + row + 1, corner + row]);
+            }
+        }
+        let data = PrimitiveData {
+            mesh_id: 0,
+            bounds: Aabb::from_positions(&positions),
+            positions: bytemuck::cast_slice(&positions).to_vec(),
+            indices_offset: 0,
+            indices_len: indices.len() * 2,
+            tex_coords: None,
+            normals: None,
+            joints: None,
+            weights: None,
+            vertex_accessors: None,
+        };
+        (data, indices)
+    }
+
+    fn kept_positions(data: &PrimitiveData) -> Vec<[f32; 3]> {
+        bytemuck::cast_slice::<u8, f32>(&data.positions)
+            .chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2]])
+            .collect()
+    }
+
+    #[test]
+    fn test_flat_grid_keeps_its_outline() {
+        let (data, indices) = grid(8);
+        let settings = SimplifySettings {
+            target_ratio: 0.1,
+            max_error: 0.01,
+        };
+        let simplified = simplify_primitive(&data, &indices, &settings);
+        assert!(simplified.indices.len() <= indices.len() / 10);
+        assert!(simplified.error < 1e-3);
+        let kept = kept_positions(&simplified.data);
+        for corner in [
+            [0.0, 0.0, 0.0],
+            [8.0, 0.0, 0.0],
+            [0.0, 8.0, 0.0],
+            [8.0, 8.0, 0.0],
+        ] {
+            assert!(kept.contains(&corner));
+        }
+        // only the vertices still used are kept
+        assert_eq!(
+            simplified.indices.iter().max().map(|max| *max as usize + 1),
+            Some(kept.len())
+        );
+        assert_eq!(simplified.data.bounds, data.bounds);
+    }
+
+    #[test]
+    fn test_error_limit_stops_simplifying_a_curved_surface() {
+        let (mut data, indices) = grid(8);
+        let mut positions: Vec<f32> = bytemuck::cast_slice(&data.positions).to_vec();
+        for p in positions.chunks_exact_mut(3) {
+            p[2] = (p[0] - 4.0).powi(2) / 4.0;
+        }
+        data.positions = bytemuck::cast_slice(&positions).to_vec();
+        let strict = SimplifySettings {
+            target_ratio: 0.0,
+            max_error: 0.001,
+        };
+        let loose = SimplifySettings {
+            max_error: 0.2,
+            ..strict
+        };
+        let strict = simplify_primitive(&data, &indices, &strict);
+        let loose = simplify_primitive(&data, &indices, &loose);
+        assert!(strict.indices.len() > loose.indices.len());
+        assert!(strict.indices.len() < indices.len());
+        assert!(strict.error <= 0.001 * 8.0);
+    }
+
+    #[test]
+    fn test_uv_seams_are_kept() {
+        // split the grid down x = 4, giving the right half its own copies of the seam vertices
+        let (mut data, mut indices) = grid(8);
+        let mut positions: Vec<f32> = bytemuck::cast_slice(&data.positions).to_vec();
+        let vertex_count = positions.len() as u16 / 3;
+        let mut tex_coords: Vec<f32> = vec![0.0; vertex_count as usize * 2];
+        let seam: Vec<u16> = (0..=8).map(|y| y * 9 + 4).collect();
+        for vertex in &seam {
+            positions.extend_from_within(*vertex as usize * 3..*vertex as usize * 3 + 3);
+            tex_coords.extend([1.0, 0.0]);
+        }
+        for triangle in indices.chunks_exact_mut(3) {
+            let right_half = triangle.iter().any(|vertex| vertex % 9 > 4);
+            for vertex in triangle.iter_mut().filter(|_| right_half) {
+                if let Some(copy) = seam.iter().position(|seam_vertex| seam_vertex == vertex) {
+                    *vertex = vertex_count + copy as u16;
+                }
+            }
+        }
+        data.positions = bytemuck::cast_slice(&positions).to_vec();
+        data.tex_coords = Some(bytemuck::cast_slice(&tex_coords).to_vec());
+
+        let settings = SimplifySettings {
+            target_ratio: 0.0,
+            max_error: 0.01,
+        };
+        let simplified = simplify_primitive(&data, &indices, &settings);
+        assert!(simplified.indices.len() < indices.len() / 2);
+        let kept = kept_positions(&simplified.data);
+        for y in 0..=8 {
+            let on_seam = kept.iter().filter(|p| **p == [4.0, y as f32, 0.0]).count();
+            assert_eq!(on_seam, 2);
+        }
+    }
+
+    #[test]
+    fn test_skinned_vertices_keep_to_their_joint() {
+        let (mut data, indices) = grid(8);
+        let joints: Vec<[u16; 8]> = (0..81)
+            .map(|vertex| [if vertex % 9 < 4 { 0 } else { 1 }, 0, 0, 0, 0, 0, 0, 0])
+            .collect();
+        data.weights = Some(vec![[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]; 81]);
+        data.joints = Some(joints);
+        let settings = SimplifySettings {
+            target_ratio: 0.0,
+            max_error: 0.01,
+        };
+        let simplified = simplify_primitive(&data, &indices, &settings);
+        let kept = kept_positions(&simplified.data);
+        let joints = simplified.data.joints.unwrap();
+        assert!(kept.len() < 81);
+        for (position, joints) in kept.iter().zip(joints) {
+            assert_eq!(joints[0], if position[0] < 4.0 { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn test_generated_lods_get_fewer_indices_with_each_level() {
+        let mut gltf_data =
+            crate::model::loader::loader::GltfLoader::load_gltf("milk-truck").unwrap();
+        let binary_len = gltf_data.binary_data.len();
+        let levels = [
+            SimplifySettings {
+                target_ratio: 0.5,
+                max_error: 0.02,
+            },
+            SimplifySettings {
+                target_ratio: 0.25,
+                max_error: 0.05,
+            },
+        ];
+        generate_lods(&mut gltf_data, &levels);
+        assert!(gltf_data.binary_data.len() > binary_len);
+        assert!(gltf_data.models.iter().all(|model| model.lod_count() > 0));
+        // the scene's index buffer is built from the new ranges, and every draw stays in it
+        let scene = crate::scene::scene::GSceneData::new(gltf_data).build_scene_uninit();
+        let index_count = |screen_size: f32| -> usize {
+            let mut draws = Vec::new();
+            let screen_sizes = vec![screen_size; scene.get_visible_instances(0).len()];
+            scene.models[0].primitive_draws(
+                scene.get_model_local_offset(0) as u32,
+                &scene.get_visible_instances(0),
+                Some(&screen_sizes),
+                &mut draws,
+            );
+            draws.iter().map(|draw| draw.indices.len()).sum()
+        };
+        let (full, half, quarter) = (index_count(1.0), index_count(0.2), index_count(0.01));
+        assert!(full > half && half > quarter && quarter > 0);
+    }
+
+    #[test]
+    fn test_skinned_meshes_get_no_lods() {
+        let mut gltf_data =
+            crate::model::loader::loader::GltfLoader::load_gltf("cesium-man").unwrap();
+        let binary_len = gltf_data.binary_data.len();
+        let levels = [SimplifySettings {
+            target_ratio: 0.5,
+            max_error: 0.02,
+        }];
+        generate_lods(&mut gltf_data, &levels);
+        assert_eq!(gltf_data.binary_data.len(), binary_len);
+        assert!(gltf_data.models.iter().all(|model| model.lod_count() == 0));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{model::model::GMesh, scene::scene::PrimitiveData};
+use crate::{
+    model::{lod::mesh_lods, model::GMesh},
+    scene::scene::PrimitiveData,
+};
 use gltf::{
     accessor::{DataType, Dimensions},
     Accessor,
@@ -13,6 +16,8 @@ pub enum GltfErrors {
     IndicesError(String),
     VericesError(String),
     NormalsError(String),
+    JointsError(String),
+    WeightsError(String),
 }
 
 #[derive(Debug)]
@@ -104,6 +109,56 @@ pub fn copy_binary_data_from_gltf(
     Ok(copy_dest)
 }
 
+/// read a JOINTS_n accessor, widening u8 joint indices to u16
+pub(super) fn get_joint_indices(
+    accessor: &Accessor,
+    buffer_offsets: &Vec<u64>,
+    binary_data: &Vec<u8>,
+) -> Result<Vec<u16>, GltfErrors> {
+    let bytes =
+        copy_binary_data_from_gltf(accessor, AttributeType::Joints, buffer_offsets, binary_data)?;
+    match accessor.data_type() {
+        DataType::U8 => Ok(bytes.into_iter().map(u16::from).collect()),
+        DataType::U16 => Ok(bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect()),
+        other => Err(GltfErrors::JointsError(format!(
+            "unsupported joint index type {:?}",
+            other
+        ))),
+    }
+}
+
+/// read a WEIGHTS_n accessor as f32, un-normalizing integer weights
+pub(super) fn get_joint_weights(
+    accessor: &Accessor,
+    buffer_offsets: &Vec<u64>,
+    binary_data: &Vec<u8>,
+) -> Result<Vec<f32>, GltfErrors> {
+    let bytes = copy_binary_data_from_gltf(
+        accessor,
+        AttributeType::Weights,
+        buffer_offsets,
+        binary_data,
+    )?;
+    match accessor.data_type() {
+        DataType::F32 => Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()),
+        DataType::U8 => Ok(bytes.into_iter().map(|w| w as f32 / 255.0).collect()),
+        DataType::U16 => Ok(bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.0)
+            .collect()),
+        other => Err(GltfErrors::WeightsError(format!(
+            "unsupported weight type {:?}",
+            other
+        ))),
+    }
+}
+
 pub(super) fn get_index_offset_len(
     maybe_accessor: Option<&Accessor>,
     buffer_offsets: &Vec<u64>,
@@ -137,6 +192,7 @@ pub(super) fn get_index_offset_len(
 pub(super) fn get_model_meshes(
     mesh_ids: &Vec<u32>,
     nodes: &Vec<gltf::Node>,
+    gltf: &gltf::Gltf,
     buffer_offsets: &Vec<u64>,
     primitive_material_map: &HashMap<usize, usize>,
     binary_data: &Vec<u8>,
@@ -144,17 +200,24 @@ pub(super) fn get_model_meshes(
     let mut mesh_primitive_data: Vec<PrimitiveData> = Vec::new();
     let mut meshes = Vec::<GMesh>::new();
     for mesh_id in mesh_ids.iter() {
-        let mesh = nodes
+        let node = nodes
             .iter()
             .find(|n| n.mesh().is_some() && n.mesh().unwrap().index() as u32 == *mesh_id)
-            .unwrap()
-            .mesh()
             .unwrap();
+        let mesh = node.mesh().unwrap();
 
-        let g_mesh = GMesh::new(&mesh, primitive_material_map)?;
+        let mut g_mesh = GMesh::new(&mesh, primitive_material_map)?;
         let primitive_data = GMesh::get_primitive_data(&mesh, buffer_offsets, binary_data)?;
-        meshes.push(g_mesh);
         mesh_primitive_data.extend(primitive_data);
+        for (lod_mesh, screen_size) in mesh_lods(node, gltf) {
+            g_mesh.add_lod(&lod_mesh, screen_size, primitive_material_map)?;
+            mesh_primitive_data.extend(GMesh::get_primitive_data(
+                &lod_mesh,
+                buffer_offsets,
+                binary_data,
+            )?);
+        }
+        meshes.push(g_mesh);
     }
 
     Ok((meshes, mesh_primitive_data))
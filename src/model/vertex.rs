@@ -1,25 +1,33 @@
 use wgpu::VertexBufferLayout;
 
+/// the most joints that can influence a single vertex. These are split across two vec4
+/// attributes in the vertex shader
+pub const MAX_JOINT_INFLUENCES: usize = 8;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
     pub tex_coords: [f32; 2],
-    pub joints: [u8; 4],
-    pub weights: [u8; 4],
+    pub joints: [u16; MAX_JOINT_INFLUENCES],
+    pub weights: [u8; MAX_JOINT_INFLUENCES],
     pub base_color_index: u32,
 }
 
 pub trait Vertex {
     fn desc() -> VertexBufferLayout<'static>;
 }
-const ATTRIBUTES: [wgpu::VertexAttribute; 6] = wgpu::vertex_attr_array![
+// locations 6 - 10 are taken by the instance buffer, so the second set of
+// joints and weights is placed after them
+const ATTRIBUTES: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
     0 => Float32x3,
     1 => Float32x3,
     2 => Float32x2,
-    3 => Uint8x4,
+    3 => Uint16x4,
+    11 => Uint16x4,
     4 => Unorm8x4,
+    12 => Unorm8x4,
     5 => Uint32,
 ];
 impl Vertex for ModelVertex {
@@ -0,0 +1,266 @@
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Matrix};
+
+use crate::{model::model::GModel, scene::instances::InstanceData};
+
+/// An axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: cgmath::Vector3<f32>,
+    pub max: cgmath::Vector3<f32>,
+}
+
+impl Aabb {
+    /// the box around a list of tightly packed f32x3 positions, None if there are none
+    pub fn from_positions(positions: &[f32]) -> Option<Self> {
+        let mut points = positions
+            .chunks_exact(3)
+            .map(|p| cgmath::Vector3::new(p[0], p[1], p[2]));
+        let first = points.next()?;
+        Some(points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, point| {
+                aabb.union(&Self {
+                    min: point,
+                    max: point,
+                })
+            },
+        ))
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: cgmath::Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: cgmath::Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    /// the box around this one after it was transformed by matrix
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let corners = (0..8).map(|corner| {
+            let point = cgmath::Vector4::new(
+                if corner & 1 == 0 {
+                    self.min.x
+                } else {
+                    self.max.x
+                },
+                if corner & 2 == 0 {
+                    self.min.y
+                } else {
+                    self.max.y
+                },
+                if corner & 4 == 0 {
+                    self.min.z
+                } else {
+                    self.max.z
+                },
+                1.0,
+            );
+            (matrix * point).truncate()
+        });
+        let positions: Vec<f32> = corners
+            .flat_map(|corner| [corner.x, corner.y, corner.z])
+            .collect();
+        Self::from_positions(&positions).expect("a box has corners")
+    }
+}
+
+/// The six planes bounding what a camera sees, pointing inwards
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    /// the plane normal in xyz, and its distance from the origin in w
+    planes: [cgmath::Vector4<f32>; 6],
+    /// the row of the view projection giving the clip space w, the distance in front of the camera
+    depth: cgmath::Vector4<f32>,
+    /// how much the projection scales the height of something one unit in front of the camera
+    vertical_scale: f32,
+}
+
+impl Frustum {
+    /// extract the planes from a view projection matrix with wgpu's 0..1 clip space depth
+    pub fn from_view_proj(view_proj: &cgmath::Matrix4<f32>) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|row| view_proj.row(row));
+        let planes = [w + x, w - x, w + y, w - y, z, w - z].map(|plane| {
+            let length = plane.truncate().magnitude();
+            if length > 0.0 {
+                plane / length
+            } else {
+                plane
+            }
+        });
+        Self {
+            planes,
+            depth: w,
+            vertical_scale: y.truncate().magnitude(),
+        }
+    }
+
+    /// The fraction of the screen height covered by the sphere around a box, more than 1 when
+    /// the camera is inside of it
+    pub fn screen_size(&self, aabb: &Aabb) -> f32 {
+        let center = (aabb.min + aabb.max) / 2.0;
+        let radius = (aabb.max - aabb.min).magnitude() / 2.0;
+        let distance = self.depth.truncate().dot(center) + self.depth.w;
+        if distance <= radius {
+            return f32::INFINITY;
+        }
+        radius * self.vertical_scale / distance
+    }
+
+    /// false only if the box lies entirely outside one of the planes
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane's normal
+            let corner = cgmath::Vector3::new(
+                if plane.x >= 0.0 {
+                    aabb.max.x
+                } else {
+                    aabb.min.x
+                },
+                if plane.y >= 0.0 {
+                    aabb.max.y
+                } else {
+                    aabb.min.y
+                },
+                if plane.z >= 0.0 {
+                    aabb.max.z
+                } else {
+                    aabb.min.z
+                },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// What survived culling against a frustum
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct CulledInstances {
+    /// for every model, the ranges of its instance indices that may be seen
+    pub visible: Vec<Vec<Range<u32>>>,
+    /// for every model, the screen size of each of its instances, see [Frustum::screen_size].
+    /// Instances without bounds cover the whole screen
+    pub screen_sizes: Vec<Vec<f32>>,
+}
+
+/// Cull the instances of every model against frustum, and measure how large they appear
+pub(super) fn cull_instances(
+    models: &[GModel],
+    instance_data: &InstanceData,
+    frustum: &Frustum,
+) -> CulledInstances {
+    let mut culled = CulledInstances::default();
+    for (model_idx, model) in models.iter().enumerate() {
+        let world_bounds: Vec<Option<Aabb>> = (0..instance_data.model_instances[model_idx])
+            .map(|instance_idx| {
+                let (offset, len) =
+                    instance_data.get_instance_local_offset(instance_idx, model_idx);
+                let bounds = model
+                    .instance_bounds(&instance_data.local_transform_data[offset..offset + len])?;
+                let global_transform = cgmath::Matrix4::from(
+                    instance_data.global_transform_data
+                        [instance_data.get_instance_global_index(instance_idx, model_idx)],
+                );
+                Some(bounds.transform(&global_transform))
+            })
+            .collect();
+        culled.visible.push(visible_ranges(
+            world_bounds
+                .iter()
+                .map(|bounds| bounds.is_none_or(|bounds| frustum.intersects(&bounds))),
+        ));
+        culled.screen_sizes.push(
+            world_bounds
+                .iter()
+                .map(|bounds| bounds.map_or(f32::INFINITY, |bounds| frustum.screen_size(&bounds)))
+                .collect(),
+        );
+    }
+    culled
+}
+
+/// Merge the indices of the visible instances into as few contiguous ranges as possible
+pub(super) fn visible_ranges(visible: impl IntoIterator<Item = bool>) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    for (index, is_visible) in visible.into_iter().enumerate() {
+        if !is_visible {
+            continue;
+        }
+        let index = index as u32;
+        match ranges.last_mut() {
+            Some(last) if last.end == index => last.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box_at(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb {
+            min: cgmath::Vector3::new(x - 0.5, y - 0.5, z - 0.5),
+            max: cgmath::Vector3::new(x + 0.5, y + 0.5, z + 0.5),
+        }
+    }
+
+    #[test]
+    fn test_frustum_keeps_only_boxes_in_view() {
+        // looking down -z from the origin
+        let proj = cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.1, 100.0);
+        let frustum = Frustum::from_view_proj(&proj);
+        assert!(frustum.intersects(&unit_box_at(0.0, 0.0, -10.0)));
+        // straddling the left plane
+        assert!(frustum.intersects(&unit_box_at(-10.0, 0.0, -10.0)));
+        assert!(!frustum.intersects(&unit_box_at(-12.0, 0.0, -10.0)));
+        assert!(!frustum.intersects(&unit_box_at(0.0, 0.0, 10.0)));
+        assert!(!frustum.intersects(&unit_box_at(0.0, 0.0, -200.0)));
+    }
+
+    #[test]
+    fn test_screen_size_shrinks_with_distance() {
+        let proj = cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.1, 100.0);
+        let frustum = Frustum::from_view_proj(&proj);
+        let near = frustum.screen_size(&unit_box_at(0.0, 0.0, -5.0));
+        let far = frustum.screen_size(&unit_box_at(0.0, 0.0, -10.0));
+        // a sphere of radius sqrt(3)/2 ten units away, with tan(fov/2) = 1
+        assert!((far - 0.75f32.sqrt() / 10.0).abs() < 1e-5);
+        assert!((near - 2.0 * far).abs() < 1e-5);
+        assert_eq!(
+            frustum.screen_size(&unit_box_at(0.0, 0.0, 0.0)),
+            f32::INFINITY
+        );
+    }
+
+    #[test]
+    fn test_transformed_box_contains_the_transformed_corners() {
+        let rotation = cgmath::Matrix4::from_angle_z(cgmath::Deg(45.0));
+        let translation = cgmath::Matrix4::from_translation(cgmath::Vector3::new(1.0, 2.0, 3.0));
+        let aabb = unit_box_at(0.0, 0.0, 0.0).transform(&(translation * rotation));
+        let half_diagonal = 0.5 * 2f32.sqrt();
+        assert!((aabb.min.x - (1.0 - half_diagonal)).abs() < 1e-5);
+        assert!((aabb.max.y - (2.0 + half_diagonal)).abs() < 1e-5);
+        assert!((aabb.min.z - 2.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_visible_ranges_skip_hidden_instances() {
+        let visible = [true, true, false, true, false, false, true, true];
+        assert_eq!(visible_ranges(visible), vec![0..2, 3..4, 6..8]);
+        assert_eq!(visible_ranges([false; 3]), vec![]);
+    }
+}
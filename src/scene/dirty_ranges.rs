@@ -0,0 +1,57 @@
+use std::ops::Range;
+
+/// The element ranges of a buffer that changed since it was last uploaded
+#[derive(Debug, Default, Clone)]
+pub(super) struct DirtyRanges {
+    ranges: Vec<Range<usize>>,
+}
+
+impl DirtyRanges {
+    pub fn mark(&mut self, range: Range<usize>) {
+        if !range.is_empty() {
+            self.ranges.push(range);
+        }
+    }
+
+    pub fn mark_one(&mut self, index: usize) {
+        self.mark(index..index + 1);
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// The marked ranges in order, with overlapping and adjacent ones merged, leaving nothing
+    /// marked
+    pub fn take(&mut self) -> Vec<Range<usize>> {
+        let mut ranges = std::mem::take(&mut self.ranges);
+        ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_merges_overlapping_and_adjacent_ranges() {
+        let mut dirty = DirtyRanges::default();
+        dirty.mark(10..12);
+        dirty.mark_one(3);
+        dirty.mark(4..6);
+        dirty.mark(11..15);
+        dirty.mark(20..20);
+        dirty.mark(12..13);
+        dirty.mark_one(30);
+        assert_eq!(dirty.take(), vec![3..6, 10..15, 30..31]);
+        assert_eq!(dirty.take(), vec![]);
+    }
+}
@@ -0,0 +1,338 @@
+use std::ops::Range;
+
+use wgpu::util::{DrawIndexedIndirectArgs, DrawIndirectArgs};
+
+use crate::{
+    model::materials::material::GMaterial,
+    scene::{
+        render_queue::{QueuedDraw, RenderQueue, RenderStats},
+        util::write_resizable_buffer,
+    },
+};
+
+/// the features the indirect draws make use of when the adapter has them
+pub const INDIRECT_DRAW_FEATURES: wgpu::Features =
+    wgpu::Features::MULTI_DRAW_INDIRECT.union(wgpu::Features::INDIRECT_FIRST_INSTANCE);
+
+const INDIRECT_BUFFER_USAGE: wgpu::BufferUsages = wgpu::BufferUsages::INDIRECT
+    .union(wgpu::BufferUsages::STORAGE)
+    .union(wgpu::BufferUsages::COPY_DST);
+
+/// How the draws in an [IndirectDraws] are issued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndirectDrawMode {
+    /// one multi_draw_indirect call per material
+    MultiDraw,
+    /// one draw_indirect call per primitive draw
+    Indirect,
+    /// the cpu copy of the args is drawn one by one. Indirect draws can't start at an instance
+    /// other than 0 without INDIRECT_FIRST_INSTANCE, and nearly every draw here does
+    Direct,
+}
+
+impl IndirectDrawMode {
+    /// the best mode the given device features allow
+    pub fn from_features(features: wgpu::Features) -> Self {
+        if !features.contains(wgpu::Features::INDIRECT_FIRST_INSTANCE) {
+            Self::Direct
+        } else if features.contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+            Self::MultiDraw
+        } else {
+            Self::Indirect
+        }
+    }
+}
+
+/// A run of draws sharing a pipeline and a material, stored one after the other in either
+/// args buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DrawBatch {
+    pipeline: usize,
+    material_index: usize,
+    indexed: bool,
+    draws: Range<usize>,
+}
+
+/// The draws of a [RenderQueue] as indirect args, grouped by pipeline and material so that each
+/// group can be issued at once. The args buffers are also STORAGE, so a compute pass can cull
+/// by zeroing instance counts in place before they are drawn
+pub struct IndirectDraws {
+    mode: IndirectDrawMode,
+    indexed_args: Vec<DrawIndexedIndirectArgs>,
+    args: Vec<DrawIndirectArgs>,
+    batches: Vec<DrawBatch>,
+    indexed_args_buffer: Option<wgpu::Buffer>,
+    args_buffer: Option<wgpu::Buffer>,
+}
+
+impl IndirectDraws {
+    pub fn new(mode: IndirectDrawMode) -> Self {
+        Self {
+            mode,
+            indexed_args: Vec::new(),
+            args: Vec::new(),
+            batches: Vec::new(),
+            indexed_args_buffer: None,
+            args_buffer: None,
+        }
+    }
+
+    pub fn mode(&self) -> IndirectDrawMode {
+        self.mode
+    }
+
+    /// the number of draws, before any of them are merged into multi draws
+    pub fn draw_count(&self) -> usize {
+        self.indexed_args.len() + self.args.len()
+    }
+
+    pub fn get_indexed_args_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.indexed_args_buffer.as_ref()
+    }
+
+    pub fn get_args_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.args_buffer.as_ref()
+    }
+
+    /// Convert the sorted draws of queue, merging neighbours with the same pipeline and material
+    pub fn build(&mut self, queue: &RenderQueue) {
+        self.indexed_args.clear();
+        self.args.clear();
+        self.batches.clear();
+        for QueuedDraw { pipeline, draw } in queue.draws() {
+            let indexed = !draw.indices.is_empty();
+            let next = if indexed {
+                self.indexed_args.len()
+            } else {
+                self.args.len()
+            };
+            match self.batches.last_mut() {
+                Some(batch)
+                    if batch.pipeline == *pipeline
+                        && batch.material_index == draw.material_index
+                        && batch.indexed == indexed
+                        && batch.draws.end == next =>
+                {
+                    batch.draws.end += 1
+                }
+                _ => self.batches.push(DrawBatch {
+                    pipeline: *pipeline,
+                    material_index: draw.material_index,
+                    indexed,
+                    draws: next..next + 1,
+                }),
+            }
+            if indexed {
+                self.indexed_args.push(DrawIndexedIndirectArgs {
+                    index_count: draw.indices.len() as u32,
+                    instance_count: draw.instances.len() as u32,
+                    first_index: draw.indices.start,
+                    base_vertex: draw.vertices.start as i32,
+                    first_instance: draw.instances.start,
+                });
+            } else {
+                self.args.push(DrawIndirectArgs {
+                    vertex_count: draw.vertices.len() as u32,
+                    instance_count: draw.instances.len() as u32,
+                    first_vertex: draw.vertices.start,
+                    first_instance: draw.instances.start,
+                });
+            }
+        }
+    }
+
+    /// Write the args to their buffers, the direct mode draws from the cpu copy instead
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.mode == IndirectDrawMode::Direct {
+            return;
+        }
+        write_resizable_buffer(
+            device,
+            queue,
+            &mut self.indexed_args_buffer,
+            bytemuck::cast_slice(&self.indexed_args),
+            std::mem::size_of::<DrawIndexedIndirectArgs>(),
+            INDIRECT_BUFFER_USAGE,
+            "Indexed indirect args buffer",
+        );
+        write_resizable_buffer(
+            device,
+            queue,
+            &mut self.args_buffer,
+            bytemuck::cast_slice(&self.args),
+            std::mem::size_of::<DrawIndirectArgs>(),
+            INDIRECT_BUFFER_USAGE,
+            "Indirect args buffer",
+        );
+    }
+}
+
+pub trait DrawIndirect {
+    /// Draw what draws was last built and uploaded with, setting each batch's pipeline and
+    /// material only when they change
+    fn draw_scene_indirect(
+        &mut self,
+        draws: &IndirectDraws,
+        pipelines: &[&wgpu::RenderPipeline],
+        materials: &[GMaterial],
+    ) -> RenderStats;
+}
+
+impl DrawIndirect for wgpu::RenderPass<'_> {
+    fn draw_scene_indirect(
+        &mut self,
+        draws: &IndirectDraws,
+        pipelines: &[&wgpu::RenderPipeline],
+        materials: &[GMaterial],
+    ) -> RenderStats {
+        const INDEXED_STRIDE: u64 = std::mem::size_of::<DrawIndexedIndirectArgs>() as u64;
+        const STRIDE: u64 = std::mem::size_of::<DrawIndirectArgs>() as u64;
+        let mut stats = RenderStats::default();
+        let mut bound_pipeline = None;
+        let mut bound_material = None;
+        for batch in draws.batches.iter() {
+            if bound_pipeline != Some(batch.pipeline) {
+                self.set_pipeline(pipelines[batch.pipeline]);
+                bound_pipeline = Some(batch.pipeline);
+                stats.pipeline_changes += 1;
+            }
+            if bound_material != Some(batch.material_index) {
+                self.set_bind_group(3, &materials[batch.material_index].bind_group, &[]);
+                bound_material = Some(batch.material_index);
+                stats.bind_group_changes += 1;
+            }
+            let count = batch.draws.len() as u32;
+            stats.draw_calls += match draws.mode {
+                IndirectDrawMode::MultiDraw => 1,
+                _ => batch.draws.len(),
+            };
+            if draws.mode == IndirectDrawMode::Direct {
+                if batch.indexed {
+                    for args in &draws.indexed_args[batch.draws.clone()] {
+                        self.draw_indexed(
+                            args.first_index..args.first_index + args.index_count,
+                            args.base_vertex,
+                            args.first_instance..args.first_instance + args.instance_count,
+                        );
+                    }
+                } else {
+                    for args in &draws.args[batch.draws.clone()] {
+                        self.draw(
+                            args.first_vertex..args.first_vertex + args.vertex_count,
+                            args.first_instance..args.first_instance + args.instance_count,
+                        );
+                    }
+                }
+                continue;
+            }
+            let (buffer, stride) = if batch.indexed {
+                (draws.indexed_args_buffer.as_ref(), INDEXED_STRIDE)
+            } else {
+                (draws.args_buffer.as_ref(), STRIDE)
+            };
+            let Some(buffer) = buffer else {
+                continue;
+            };
+            let offset = batch.draws.start as u64 * stride;
+            match (draws.mode, batch.indexed) {
+                (IndirectDrawMode::MultiDraw, true) => {
+                    self.multi_draw_indexed_indirect(buffer, offset, count)
+                }
+                (IndirectDrawMode::MultiDraw, false) => {
+                    self.multi_draw_indirect(buffer, offset, count)
+                }
+                (_, true) => {
+                    for draw in 0..count as u64 {
+                        self.draw_indexed_indirect(buffer, offset + draw * stride);
+                    }
+                }
+                (_, false) => {
+                    for draw in 0..count as u64 {
+                        self.draw_indirect(buffer, offset + draw * stride);
+                    }
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{loader::loader::GltfLoader, model::PrimitiveDraw},
+        scene::scene::GSceneData,
+        transforms,
+    };
+
+    fn draw(material_index: usize, indices: Range<u32>, instances: Range<u32>) -> PrimitiveDraw {
+        PrimitiveDraw {
+            material_index,
+            indices,
+            vertices: 10..20,
+            instances,
+        }
+    }
+
+    #[test]
+    fn test_draws_are_batched_by_pipeline_and_material() {
+        let mut queue = RenderQueue::default();
+        queue.push(0, draw(1, 0..6, 0..1));
+        queue.push(0, draw(0, 6..12, 1..3));
+        queue.push(0, draw(1, 12..18, 3..4));
+        queue.push(1, draw(1, 24..30, 0..1));
+        // culled away
+        queue.push(0, draw(0, 18..24, 4..4));
+        queue.push(0, draw(1, 0..0, 4..5));
+        queue.sort();
+        let mut draws = IndirectDraws::new(IndirectDrawMode::MultiDraw);
+        draws.build(&queue);
+
+        assert_eq!(draws.draw_count(), 5);
+        let batch = |pipeline, material_index, indexed, draws| DrawBatch {
+            pipeline,
+            material_index,
+            indexed,
+            draws,
+        };
+        assert_eq!(
+            draws.batches,
+            vec![
+                batch(0, 0, true, 0..1),
+                batch(0, 1, true, 1..3),
+                batch(0, 1, false, 0..1),
+                batch(1, 1, true, 3..4),
+            ]
+        );
+        // the order within a material is kept
+        assert_eq!(draws.indexed_args[1].first_index, 0);
+        assert_eq!(draws.indexed_args[2].first_index, 12);
+        assert_eq!(draws.indexed_args[0].instance_count, 2);
+        assert_eq!(draws.indexed_args[0].base_vertex, 10);
+        assert_eq!(draws.args[0].vertex_count, 10);
+        assert_eq!(draws.args[0].first_instance, 4);
+    }
+
+    #[test]
+    fn test_scene_draws_cover_every_instance() {
+        let gltf_data = GltfLoader::load_gltf("box-animated").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        scene.spawn_instance(0, transforms::identity()).unwrap();
+        scene.spawn_instance(0, transforms::identity()).unwrap();
+        let mut queue = RenderQueue::default();
+        queue.push_scene(&scene, 0);
+        queue.sort();
+        let mut draws = IndirectDraws::new(IndirectDrawMode::Direct);
+        draws.build(&queue);
+        let drawn_local_transforms: u32 = draws
+            .indexed_args
+            .iter()
+            .map(|args| args.instance_count)
+            .sum();
+        assert_eq!(
+            drawn_local_transforms as usize,
+            scene.get_local_transform_data().len()
+        );
+    }
+}
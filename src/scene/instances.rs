@@ -1,17 +1,104 @@
+use std::fmt::Display;
+
 use crate::{
     model::{animation::animation::AnimationFrame, model::GModel},
-    scene::{scene_scaffolds::SceneScaffold, util::calculate_model_mesh_offsets},
+    scene::{
+        scene_scaffolds::SceneScaffold,
+        util::{calculate_model_mesh_offsets, write_resizable_buffer},
+    },
     transforms,
 };
 use cgmath::SquareMatrix;
-use std::ops::Range;
 use wgpu::util::DeviceExt;
 
+use super::dirty_ranges::DirtyRanges;
 use crate::model::{
     model::{GlobalTransform, LocalTransform},
     util::InitializationError,
 };
 
+/// A model instance that stays valid while other instances are added and removed. Once the
+/// instance is removed its handle is stale, even after the slot is reused by a new instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceHandle {
+    index: u32,
+    generation: u32,
+}
+
+impl InstanceHandle {
+    /// a handle for tests that drive the animation controller without a scene
+    #[cfg(test)]
+    pub(crate) fn for_slot(index: u32) -> Self {
+        Self {
+            index,
+            generation: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceError {
+    /// the instance was removed
+    Stale(InstanceHandle),
+    /// the parent's model has no animated node with this name to attach to
+    UnknownNode(String),
+    /// the parent is attached below the instance being attached to it
+    AttachmentCycle,
+    /// a joint override refers to a node that isn't a joint of the instance's model
+    UnknownJoint(usize),
+    /// the scene has no model with this index
+    UnknownModel(usize),
+    /// another instance of the skeletal model is animated, and they share their joints
+    JointsInUse(InstanceHandle),
+}
+
+impl Display for InstanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stale(handle) => write!(
+                f,
+                "instance {} (generation {}) was removed",
+                handle.index, handle.generation
+            ),
+            Self::UnknownNode(name) => write!(f, "no animated node is named {}", name),
+            Self::AttachmentCycle => write!(f, "an instance can't be attached below itself"),
+            Self::UnknownJoint(node_id) => {
+                write!(f, "node {} is not a joint of the model", node_id)
+            }
+            Self::UnknownModel(model_id) => write!(f, "there is no model {}", model_id),
+            Self::JointsInUse(handle) => write!(
+                f,
+                "instance {} (generation {}) already animates the joints of the model",
+                handle.index, handle.generation
+            ),
+        }
+    }
+}
+
+/// Where the instance of a handle is, if it is still around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InstanceSlot {
+    generation: u32,
+    /// (model index, instance index)
+    location: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct InstanceRecord {
+    pub handle: InstanceHandle,
+    /// the slot of the instance in the global transform data
+    pub global_index: usize,
+}
+
+/// How the local transform offsets changed when an instance was added or removed, see
+/// [InstanceData::relocated_offset]
+pub(super) struct InstanceRelocation {
+    old_local_offsets: Vec<usize>,
+    /// (model index, old instance index, new instance index) of the instance moved into the
+    /// place of a removed one
+    pub moved: Option<(usize, usize, usize)>,
+}
+
 #[derive(Debug)]
 pub(super) struct InstanceData {
     pub model_instances: Vec<usize>,
@@ -20,11 +107,27 @@ pub(super) struct InstanceData {
     pub global_transform_buffer: Option<wgpu::Buffer>,
     pub global_transform_data: Vec<[[f32; 4]; 4]>,
     /// These are the offsets which correspond to the slot
-    /// of the first local transform for this model.
+    /// of the first local transform for this model, with the total at the end.
     /// All local transforms which refer to a model would be located in the range from
     /// [instance_local_offsets[model_idx] .. (instance_local_offsets[model_idx] +
-    /// model.mesh_instance_count * model_instances[model_idx])]
+    /// model.mesh_instance_count * model_instances[model_idx])], one instance after the other
     model_instances_local_offsets: Vec<usize>,
+    /// the number of local transforms each instance of a model has
+    model_mesh_counts: Vec<usize>,
+    /// the local transforms a new instance of each model starts with
+    model_mesh_templates: Vec<Vec<[[f32; 4]; 4]>>,
+    /// for every model, its instances in order
+    instance_records: Vec<Vec<InstanceRecord>>,
+    /// indexed by handle index
+    instance_slots: Vec<InstanceSlot>,
+    /// the slots of removed instances, reused before new ones are added
+    free_slots: Vec<u32>,
+    /// set when instances were added or removed since the buffers were last synced
+    layout_changed: bool,
+    /// what changed since the buffers were last uploaded
+    dirty_local_transforms: DirtyRanges,
+    dirty_global_transforms: DirtyRanges,
+    dirty_joint_transforms: DirtyRanges,
     pub joint_global_transforms: Vec<[[f32; 4]; 4]>,
     pub joint_transform_buffer: Option<wgpu::Buffer>,
 }
@@ -35,17 +138,44 @@ impl InstanceData {
         instance_idx: usize,
         model_idx: usize,
     ) -> (usize, usize) {
+        assert!(
+            instance_idx < self.model_instances[model_idx],
+            "model {} has no instance {}",
+            model_idx,
+            instance_idx
+        );
         // the location of the first instance of this model in the local transform buffer
         let model_local_offset = self.model_instances_local_offsets[model_idx];
-        let model_mesh_count = (self.model_instances_local_offsets[model_idx + 1]
-            - model_local_offset)
-            / self.model_instances[model_idx];
+        let model_mesh_count = self.model_mesh_counts[model_idx];
         return (
             model_local_offset + (instance_idx * model_mesh_count),
             model_mesh_count,
         );
     }
 
+    /// the slot of the first local transform of a model's first instance
+    pub fn get_model_local_offset(&self, model_idx: usize) -> usize {
+        self.model_instances_local_offsets[model_idx]
+    }
+
+    /// the slot of an instance in the global transform data
+    pub fn get_instance_global_index(&self, instance_idx: usize, model_idx: usize) -> usize {
+        self.instance_records[model_idx][instance_idx].global_index
+    }
+
+    /// the model index and instance index of a live instance
+    pub fn resolve(&self, handle: InstanceHandle) -> Result<(usize, usize), InstanceError> {
+        self.instance_slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.location)
+            .ok_or(InstanceError::Stale(handle))
+    }
+
+    pub fn get_instance_handle(&self, instance_idx: usize, model_idx: usize) -> InstanceHandle {
+        self.instance_records[model_idx][instance_idx].handle
+    }
+
     /// unsafe function
     /// Each animation frame contains a reference to an array x.
     /// This array contains one or more arrays of raw matrices [[f32;4];4] y_matrix.
@@ -54,6 +184,8 @@ impl InstanceData {
     pub fn apply_animation_frame_unchecked(&mut self, animation_frame: AnimationFrame) {
         for (idx, offset) in animation_frame.lt_offsets.iter().enumerate() {
             let t_slices = animation_frame.mesh_transform_slices[idx]; // y_matrix slice
+            self.dirty_local_transforms
+                .mark(*offset..*offset + t_slices.len());
             unsafe {
                 // the model index stored in the first local transform at the provided offset
                 let model_id = self.local_transform_data.get_unchecked(*offset).model_index;
@@ -71,131 +203,178 @@ impl InstanceData {
                 }
             }
         }
-        // TODO: if we want to animate multiple simultaneous instances, we will need to store
-        // separate copyies of the joint global transforms, just like we already do for local
-        // transforms
+        // move each instance by the motion extracted from its root
+        for (instance, delta) in animation_frame.root_motion.iter() {
+            let Ok((model_idx, instance_idx)) = self.resolve(*instance) else {
+                continue;
+            };
+            let global_index = self.get_instance_global_index(instance_idx, model_idx);
+            self.dirty_global_transforms.mark_one(global_index);
+            self.global_transform_data[global_index] =
+                (cgmath::Matrix4::from(self.global_transform_data[global_index])
+                    * cgmath::Matrix4::from(*delta))
+                .into();
+        }
+        // every instance of a skeletal model is skinned with these joints, which is why only
+        // one of them is animated at a time, see GScene::spawn_instance
         for (slice_index, joint_indices) in animation_frame.joint_ids.iter().enumerate() {
             for joint_index in joint_indices.iter() {
                 self.joint_global_transforms[*joint_index] =
                     animation_frame.joint_transform_slices[slice_index][*joint_index];
+                self.dirty_joint_transforms.mark_one(*joint_index);
             }
         }
     }
-    /// create Instance data with one instance of each model, each positioned at the origin
-    pub fn default_from_scene(
-        models: &Vec<GModel>,
+
+    /// One instance of every model, with the local transforms the scene was loaded with.
+    /// model_mesh_counts is the number of local transforms of each model
+    fn with_one_instance_each(
+        model_mesh_counts: Vec<usize>,
         local_transform_data: Vec<LocalTransform>,
+        global_transform_data: Vec<[[f32; 4]; 4]>,
         joint_transforms: Vec<[[f32; 4]; 4]>,
     ) -> Self {
-        // one instance of each model
-        let model_instances: Vec<usize> = (0..models.len()).into_iter().map(|_| 1).collect();
-        // every model goes at the origin
-        let global_transform_data: Vec<[[f32; 4]; 4]> = (0..models.len())
-            .into_iter()
-            .map(|_| cgmath::Matrix4::<f32>::identity().into())
-            .collect();
-
-        let model_mesh_counts: Vec<usize> = models
-            .iter()
-            .map(|model| model.mesh_instances.iter().sum::<u32>() as usize)
-            .collect();
-        let mut model_instances_local_offsets = Vec::with_capacity(models.len());
+        let model_count = model_mesh_counts.len();
+        // the offset of each model, with the total at the end
+        let mut model_instances_local_offsets = Vec::with_capacity(model_count + 1);
         model_instances_local_offsets.push(0);
-        model_mesh_counts
-            .iter()
-            .for_each(|count| model_instances_local_offsets.push(*count));
-        // leave the final value in order to calculate the last model's mesh count
+        for mesh_count in model_mesh_counts.iter() {
+            let offset = model_instances_local_offsets.last().unwrap() + mesh_count;
+            model_instances_local_offsets.push(offset);
+        }
+        let model_mesh_templates = model_instances_local_offsets
+            .windows(2)
+            .map(|offsets| {
+                local_transform_data[offsets[0]..offsets[1]]
+                    .iter()
+                    .map(|local_transform| local_transform.transform_matrix)
+                    .collect()
+            })
+            .collect();
         Self {
-            model_instances,
+            model_instances: vec![1; model_count],
             local_transform_buffer: None,
             local_transform_data,
             global_transform_buffer: None,
             global_transform_data,
             model_instances_local_offsets,
+            model_mesh_counts,
+            model_mesh_templates,
+            instance_records: (0..model_count)
+                .map(|model_idx| {
+                    vec![InstanceRecord {
+                        handle: InstanceHandle {
+                            index: model_idx as u32,
+                            generation: 0,
+                        },
+                        global_index: model_idx,
+                    }]
+                })
+                .collect(),
+            instance_slots: (0..model_count)
+                .map(|model_idx| InstanceSlot {
+                    generation: 0,
+                    location: Some((model_idx, 0)),
+                })
+                .collect(),
+            free_slots: Vec::new(),
+            dirty_local_transforms: DirtyRanges::default(),
+            dirty_global_transforms: DirtyRanges::default(),
+            dirty_joint_transforms: DirtyRanges::default(),
+            layout_changed: false,
             joint_global_transforms: joint_transforms,
             joint_transform_buffer: None,
         }
     }
 
+    /// create Instance data with one instance of each model, each positioned at the origin
+    pub fn default_from_scene(
+        models: &Vec<GModel>,
+        local_transform_data: Vec<LocalTransform>,
+        joint_transforms: Vec<[[f32; 4]; 4]>,
+    ) -> Self {
+        // every model goes at the origin
+        let global_transform_data: Vec<[[f32; 4]; 4]> = (0..models.len())
+            .into_iter()
+            .map(|_| cgmath::Matrix4::<f32>::identity().into())
+            .collect();
+        Self::with_one_instance_each(
+            mesh_counts(models),
+            local_transform_data,
+            global_transform_data,
+            joint_transforms,
+        )
+    }
+
     pub fn from_scaffold(
         scaffold: &SceneScaffold,
         local_transform_data: Vec<LocalTransform>,
         joint_transforms: Vec<[[f32; 4]; 4]>,
-        models: &Vec<GModel>,
+        models: &[GModel],
     ) -> Result<Self, InitializationError> {
-        let mut model_instances = Vec::new();
-        let mut global_transform_data = Vec::new();
-        // fill out the data for model instances and global transform
-        // data assuming that there will be one instance of each model
-        for _ in models.iter() {
-            model_instances.push(1);
-            global_transform_data.push(transforms::identity());
-        }
+        // fill out the global transform data assuming that there will be one instance of
+        // each model
+        let mut global_transform_data: Vec<[[f32; 4]; 4]> =
+            models.iter().map(|_| transforms::identity()).collect();
         // apply the transform values for the base instances, if any
         for gt_override in scaffold.global_transform_overrides {
             global_transform_data[gt_override.model_idx] = gt_override.transform;
         }
-        // build out the model instance offset vec with the proper values,
-        // with one extra value at the end to calculate the last model instances mesh count
-        let model_mesh_counts: Vec<usize> = models
-            .iter()
-            .map(|model| model.mesh_instances.iter().sum::<u32>() as usize)
-            .collect();
-        let mut model_instances_local_offsets: Vec<usize> = Vec::with_capacity(models.len() + 1);
-        model_instances_local_offsets.push(0);
-        model_mesh_counts.iter().for_each(|mesh_count| {
-            model_instances_local_offsets.push(*mesh_count);
-        });
-
-        let mut instance_data = Self {
-            model_instances,
-            local_transform_buffer: None,
+        let mut instance_data = Self::with_one_instance_each(
+            mesh_counts(models),
             local_transform_data,
-            global_transform_buffer: None,
             global_transform_data,
-            model_instances_local_offsets,
-            joint_global_transforms: joint_transforms,
-            joint_transform_buffer: None,
-        };
+            joint_transforms,
+        );
 
         // add the additional instances
         for additional_instance in scaffold.additional_instances {
-            let result = instance_data.add_model_instance(
+            instance_data.add_model_instance(
                 models,
                 additional_instance.model_index,
                 additional_instance.global_transforms.to_vec(),
-            );
-            if let Err(e) = result {
-                return Err(e);
-            }
+            )?;
         }
         Ok(instance_data)
     }
 
-    pub fn update_global_transform_x(&mut self, instance_idx: usize, new_transform: [[f32; 4]; 4]) {
+    /// apply new_transform on top of the global transform at global_index, instances are moved
+    /// by handle through GScene::update_global_transform
+    pub(super) fn update_global_transform_x(
+        &mut self,
+        global_index: usize,
+        new_transform: [[f32; 4]; 4],
+    ) {
         let t = GlobalTransform {
             transform_matrix: cgmath::Matrix4::from(new_transform),
         };
-        self.global_transform_data[instance_idx] = t * self.global_transform_data[instance_idx];
+        self.global_transform_data[global_index] = t * self.global_transform_data[global_index];
+        self.dirty_global_transforms.mark_one(global_index);
+    }
+
+    /// Overwrite the global transform at global_index
+    pub fn set_global_transform(&mut self, global_index: usize, transform: [[f32; 4]; 4]) {
+        self.global_transform_data[global_index] = transform;
+        self.dirty_global_transforms.mark_one(global_index);
     }
 
     pub fn init(&mut self, device: &wgpu::Device) {
         let local_transform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Local transform buffer"),
             contents: bytemuck::cast_slice(&self.local_transform_data),
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            usage: LOCAL_TRANSFORM_USAGE,
         });
         let global_transform_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 contents: bytemuck::cast_slice(&self.global_transform_data),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                usage: GLOBAL_TRANSFORM_USAGE,
                 label: Some("global instance buffer"),
             });
 
         let joint_buffer = if self.joint_global_transforms.len() > 0 {
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 contents: bytemuck::cast_slice(&self.joint_global_transforms),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                usage: JOINT_TRANSFORM_USAGE,
                 label: Some("Joint transform buffer"),
             })
         } else {
@@ -204,7 +383,7 @@ impl InstanceData {
                 contents: bytemuck::cast_slice::<[[f32; 4]; 4], u8>(&[
                     cgmath::Matrix4::<f32>::identity().into(),
                 ]),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                usage: JOINT_TRANSFORM_USAGE,
                 label: Some("Joint transform buffer"),
             })
         };
@@ -212,135 +391,332 @@ impl InstanceData {
         self.global_transform_buffer = Some(global_transform_buffer);
         self.local_transform_buffer = Some(local_transform_buffer);
         self.joint_transform_buffer = Some(joint_buffer);
+        self.layout_changed = false;
+        self.clear_dirty_ranges();
+    }
+
+    fn clear_dirty_ranges(&mut self) {
+        self.dirty_local_transforms.clear();
+        self.dirty_global_transforms.clear();
+        self.dirty_joint_transforms.clear();
+    }
+
+    /// Bring the local and global transform buffers up to date after instances were added or
+    /// removed. Buffers grow to the next power of two when the data outgrows them, and shrink
+    /// once it fits in a quarter of them. Returns true if a buffer was replaced, in which case
+    /// any bind group holding the old one has to be recreated
+    pub fn sync_buffers(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        if !self.layout_changed {
+            return false;
+        }
+        self.layout_changed = false;
+        // both buffers are rewritten whole
+        self.dirty_local_transforms.clear();
+        self.dirty_global_transforms.clear();
+        let local_replaced = write_resizable_buffer(
+            device,
+            queue,
+            &mut self.local_transform_buffer,
+            bytemuck::cast_slice(&self.local_transform_data),
+            std::mem::size_of::<LocalTransform>(),
+            LOCAL_TRANSFORM_USAGE,
+            "Local transform buffer",
+        );
+        let global_replaced = write_resizable_buffer(
+            device,
+            queue,
+            &mut self.global_transform_buffer,
+            bytemuck::cast_slice(&self.global_transform_data),
+            std::mem::size_of::<[[f32; 4]; 4]>(),
+            GLOBAL_TRANSFORM_USAGE,
+            "global instance buffer",
+        );
+        local_replaced || global_replaced
+    }
+
+    /// Copy the transforms that changed since the last upload into their buffers, through the
+    /// staging belt. Returns true if anything was written, in which case the belt has to be
+    /// finished before the encoder is submitted
+    pub fn upload_dirty(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        belt: &mut wgpu::util::StagingBelt,
+    ) -> bool {
+        // the buffers may not hold the data yet, they get rewritten whole once synced
+        if self.layout_changed {
+            return false;
+        }
+        let mut written = false;
+        if let Some(buffer) = &self.local_transform_buffer {
+            written |= upload_ranges(
+                device,
+                encoder,
+                belt,
+                buffer,
+                bytemuck::cast_slice(&self.local_transform_data),
+                std::mem::size_of::<LocalTransform>(),
+                self.dirty_local_transforms.take(),
+            );
+        }
+        if let Some(buffer) = &self.global_transform_buffer {
+            written |= upload_ranges(
+                device,
+                encoder,
+                belt,
+                buffer,
+                bytemuck::cast_slice(&self.global_transform_data),
+                std::mem::size_of::<[[f32; 4]; 4]>(),
+                self.dirty_global_transforms.take(),
+            );
+        }
+        if let Some(buffer) = &self.joint_transform_buffer {
+            written |= upload_ranges(
+                device,
+                encoder,
+                belt,
+                buffer,
+                bytemuck::cast_slice(&self.joint_global_transforms),
+                std::mem::size_of::<[[f32; 4]; 4]>(),
+                self.dirty_joint_transforms.take(),
+            );
+        }
+        written
     }
 
     pub fn add_model_instance(
         &mut self,
-        models: &Vec<GModel>,
+        models: &[GModel],
         model_index: usize,
         global_transforms: Vec<[[f32; 4]; 4]>,
-    ) -> Result<(), InitializationError> {
-        let count = global_transforms.len().clone();
-        self.add_new_instances_local_data(model_index, global_transforms.len(), models)?
-            .add_new_instances_global_data(model_index, global_transforms)?;
-        println!(
-            "successfully added {} model(s) at index {}",
-            count, model_index
-        );
-        Ok(())
+    ) -> Result<Vec<InstanceHandle>, InitializationError> {
+        if model_index >= models.len() {
+            return Err(InitializationError::InstanceDataInitializationError(
+                Box::new(format!(
+                    "there is no model {} to add instances of",
+                    model_index
+                )),
+            ));
+        }
+        global_transforms
+            .into_iter()
+            .map(|global_transform| {
+                self.spawn(model_index, global_transform)
+                    .map(|(handle, _)| handle)
+                    .map_err(|error| {
+                        InitializationError::InstanceDataInitializationError(Box::new(
+                            error.to_string(),
+                        ))
+                    })
+            })
+            .collect()
     }
 
-    fn add_mesh_transforms(
-        &mut self,
-        new_instance_count: usize,
-        offset: usize,
-        base_model_index: usize,
-        base_transform_index: usize,
-        mut transform_slice: Vec<LocalTransform>,
-    ) -> Vec<LocalTransform> {
-        // we want to place the new instance of this mesh right after the last instance
-        // of the mesh. That will be:
-        // offset (relative offset for this slice)
-        // plus i (the number of instances we have already added in this pass)
-        // plus model instance count (for one model, there is one instance of this
-        // particular mesh instance, and so on)
-        for i in 0..new_instance_count {
-            let mut new_transform = transform_slice[offset].clone();
-            new_transform.model_index = (i + base_model_index) as u32;
-            transform_slice.insert(offset + i + base_transform_index, new_transform);
-        }
-        transform_slice
-    }
-    // this function is hilariously costly! probably warrants a restructuring of instancedata
-    // even though it really shouldnt be in any hot loop
-    // second note: the below code gets confusing because we are working with two type of "instance" semantically.
-    // the first refers to a mesh instance for a model. There can be multiple instances of the same
-    // mesh in a single model
-    // the second refers to an instance OF THAT INSTANCE. so if there are two mesh instances (first
-    // definition) in a model, and we want to add a new model, we need to add one new instance
-    // (second definition) to each of those mesh instances (first definition)
-    fn add_new_instances_local_data(
+    /// Add an instance of a model at the end of its instances, returning its handle. The new
+    /// instance starts with the local transforms the model was loaded with.
+    /// Fails without changing anything if there is no such model
+    pub fn spawn(
         &mut self,
         model_index: usize,
-        new_instance_count: usize,
-        models: &Vec<GModel>,
-    ) -> Result<&mut Self, InitializationError> {
-        // step 1: create a new vec from all the mesh instances associated with the model
-        //
-        let model_instance_count = self.model_instances[model_index];
-        let model_mesh_count = models[model_index].mesh_instances.iter().sum::<u32>() as usize;
-        let tot_model_count = self.model_instances.iter().sum::<usize>();
-
-        // the offset for the first local transform for this model
-        let instance_offset = self.model_instances_local_offsets[model_index];
-        // the range of all the transforms that pertain to this model
-        let model_mesh_range: Range<usize> = Range {
-            start: instance_offset,
-            end: model_mesh_count * model_instance_count + instance_offset,
-        };
-        let mut transform_slice = self.local_transform_data[model_mesh_range.clone()].to_vec();
-
-        // step 2: expand the vec with the appropriate number of new transforms
-        let mut offset = 0;
-        for mesh_instance_count in models[model_index].mesh_instances.iter() {
-            for _ in 0..*mesh_instance_count {
-                transform_slice = self.add_mesh_transforms(
-                    new_instance_count,
-                    offset,
-                    tot_model_count,
-                    model_instance_count,
-                    transform_slice,
-                );
-                offset += new_instance_count + model_instance_count;
-            }
+        global_transform: [[f32; 4]; 4],
+    ) -> Result<(InstanceHandle, InstanceRelocation), InstanceError> {
+        if model_index >= self.model_mesh_counts.len() {
+            return Err(InstanceError::UnknownModel(model_index));
         }
+        let old_local_offsets = self.model_instances_local_offsets.clone();
+        let global_index = self.global_transform_data.len();
+        self.global_transform_data.push(global_transform);
 
-        // step 3: splice the local transform data vec with this new expanded vec
+        let model_end = self.model_instances_local_offsets[model_index + 1];
+        let new_transforms =
+            self.model_mesh_templates[model_index]
+                .iter()
+                .map(|matrix| LocalTransform {
+                    transform_matrix: *matrix,
+                    model_index: global_index as u32,
+                });
         self.local_transform_data
-            .splice(model_mesh_range, transform_slice);
-        // step 4: increase the offsets for all the models after this one by the number of new
-        // instances just added
-        let num_new_mesh_instances = model_mesh_count * new_instance_count;
-        for offset_val in self
-            .model_instances_local_offsets
-            .iter_mut()
-            .skip(instance_offset + 1)
-        {
-            *offset_val += num_new_mesh_instances;
+            .splice(model_end..model_end, new_transforms);
+        let mesh_count = self.model_mesh_counts[model_index];
+        for offset in self.model_instances_local_offsets[model_index + 1..].iter_mut() {
+            *offset += mesh_count;
         }
 
-        Ok(self)
+        let location = Some((model_index, self.model_instances[model_index]));
+        let handle = match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.instance_slots[index as usize];
+                slot.location = location;
+                InstanceHandle {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.instance_slots.push(InstanceSlot {
+                    generation: 0,
+                    location,
+                });
+                InstanceHandle {
+                    index: self.instance_slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        };
+        self.instance_records[model_index].push(InstanceRecord {
+            handle,
+            global_index,
+        });
+        self.model_instances[model_index] += 1;
+        self.layout_changed = true;
+        Ok((
+            handle,
+            InstanceRelocation {
+                old_local_offsets,
+                moved: None,
+            },
+        ))
     }
 
-    /// insert the appropriate number of global transform matrices into the global transform data
-    /// vector at the appropriate slot
-    fn add_new_instances_global_data(
-        &mut self,
-        model_index: usize,
-        global_transforms: Vec<[[f32; 4]; 4]>,
-    ) -> Result<(), InitializationError> {
-        let new_instance_count = global_transforms.len();
-        self.global_transform_data.extend(global_transforms);
+    /// Remove an instance. The last instance of the same model takes its place, and the last
+    /// global transform takes the place of its global transform, so the data stays packed.
+    /// Fails if the handle's instance was already removed
+    pub fn despawn(&mut self, handle: InstanceHandle) -> Result<InstanceRelocation, InstanceError> {
+        let (model_index, instance_idx) = self.resolve(handle)?;
+        let old_local_offsets = self.model_instances_local_offsets.clone();
+        let mesh_count = self.model_mesh_counts[model_index];
+        let last_idx = self.model_instances[model_index] - 1;
 
-        // increment the number of mesh instances by num new instances
-        self.model_instances[model_index] += new_instance_count;
+        // move the local transforms of the last instance into the removed one's
+        let model_offset = self.model_instances_local_offsets[model_index];
+        let last_start = model_offset + last_idx * mesh_count;
+        self.local_transform_data.copy_within(
+            last_start..last_start + mesh_count,
+            model_offset + instance_idx * mesh_count,
+        );
+        self.local_transform_data
+            .drain(last_start..last_start + mesh_count);
+        for offset in self.model_instances_local_offsets[model_index + 1..].iter_mut() {
+            *offset -= mesh_count;
+        }
+
+        let removed = self.instance_records[model_index].swap_remove(instance_idx);
+        let slot = &mut self.instance_slots[handle.index as usize];
+        slot.location = None;
+        // any copies of the handle are stale from now on
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_slots.push(handle.index);
+        self.model_instances[model_index] -= 1;
+        let moved = if instance_idx != last_idx {
+            let moved_handle = self.instance_records[model_index][instance_idx].handle;
+            self.instance_slots[moved_handle.index as usize].location =
+                Some((model_index, instance_idx));
+            Some((model_index, last_idx, instance_idx))
+        } else {
+            None
+        };
+
+        // the last global transform fills the removed one's slot
+        let last_global = self.global_transform_data.len() - 1;
+        self.global_transform_data.swap_remove(removed.global_index);
+        if removed.global_index != last_global {
+            self.set_global_index(last_global, removed.global_index);
+        }
+        self.layout_changed = true;
+        Ok(InstanceRelocation {
+            old_local_offsets,
+            moved,
+        })
+    }
+
+    /// point the instance at global slot from to global slot to
+    fn set_global_index(&mut self, from: usize, to: usize) {
+        for (model_idx, records) in self.instance_records.iter_mut().enumerate() {
+            let Some(instance_idx) = records
+                .iter()
+                .position(|record| record.global_index == from)
+            else {
+                continue;
+            };
+            records[instance_idx].global_index = to;
+            let mesh_count = self.model_mesh_counts[model_idx];
+            let start = self.model_instances_local_offsets[model_idx] + instance_idx * mesh_count;
+            for local_transform in self.local_transform_data[start..start + mesh_count].iter_mut() {
+                local_transform.model_index = to as u32;
+            }
+            return;
+        }
+    }
 
-        Ok(())
+    /// the local transform offset an instance at offset has after the relocation
+    pub fn relocated_offset(&self, relocation: &InstanceRelocation, offset: usize) -> usize {
+        let old_offsets = &relocation.old_local_offsets[..self.model_mesh_counts.len()];
+        // models without meshes share their offset with the next model, the last model
+        // starting at or before offset is the one with meshes there
+        let model_idx = old_offsets.partition_point(|model_offset| *model_offset <= offset) - 1;
+        let mesh_count = self.model_mesh_counts[model_idx];
+        if mesh_count == 0 {
+            return offset;
+        }
+        let relative = offset - old_offsets[model_idx];
+        let mut instance_idx = relative / mesh_count;
+        if let Some((moved_model, from, to)) = relocation.moved {
+            if moved_model == model_idx && instance_idx == from {
+                instance_idx = to;
+            }
+        }
+        self.model_instances_local_offsets[model_idx]
+            + instance_idx * mesh_count
+            + relative % mesh_count
     }
+
     #[allow(dead_code)]
     /// merge the instance data together
-    pub fn merge(mut self, mut other: Self, models: &Vec<GModel>) -> Self {
+    pub fn merge(mut self, other: Self, models: &Vec<GModel>) -> Self {
         let number_of_models = self.model_instances.iter().sum::<usize>();
-        for local_transform in other.local_transform_data.iter_mut() {
-            local_transform.model_index += number_of_models as u32;
-        }
+        let handle_count = self.instance_slots.len() as u32;
+        let model_count = self.model_instances.len();
+        let mut local_transform_data = self.local_transform_data;
+        local_transform_data.extend(other.local_transform_data.iter().map(|local_transform| {
+            LocalTransform {
+                transform_matrix: local_transform.transform_matrix,
+                model_index: local_transform.model_index + number_of_models as u32,
+            }
+        }));
         self.model_instances.extend(other.model_instances);
         let model_instances = self.model_instances;
-
+        self.model_mesh_counts.extend(other.model_mesh_counts);
         let model_instances_local_offsets = calculate_model_mesh_offsets(models, &model_instances);
+        self.model_mesh_templates.extend(other.model_mesh_templates);
+        self.instance_records
+            .extend(other.instance_records.into_iter().map(|records| {
+                records
+                    .into_iter()
+                    .map(|record| InstanceRecord {
+                        handle: InstanceHandle {
+                            index: record.handle.index + handle_count,
+                            generation: record.handle.generation,
+                        },
+                        global_index: record.global_index + number_of_models,
+                    })
+                    .collect()
+            }));
+        self.instance_slots
+            .extend(other.instance_slots.into_iter().map(|slot| {
+                InstanceSlot {
+                    generation: slot.generation,
+                    location: slot
+                        .location
+                        .map(|(model_idx, instance_idx)| (model_idx + model_count, instance_idx)),
+                }
+            }));
+        self.free_slots.extend(
+            other
+                .free_slots
+                .into_iter()
+                .map(|index| index + handle_count),
+        );
 
-        self.local_transform_data.extend(other.local_transform_data);
-        let local_transform_data = self.local_transform_data;
         self.global_transform_data
             .extend(other.global_transform_data);
 
@@ -351,6 +727,15 @@ impl InstanceData {
         Self {
             model_instances,
             model_instances_local_offsets,
+            model_mesh_counts: self.model_mesh_counts,
+            model_mesh_templates: self.model_mesh_templates,
+            instance_records: self.instance_records,
+            instance_slots: self.instance_slots,
+            free_slots: self.free_slots,
+            dirty_local_transforms: DirtyRanges::default(),
+            dirty_global_transforms: DirtyRanges::default(),
+            dirty_joint_transforms: DirtyRanges::default(),
+            layout_changed: true,
             local_transform_data,
             global_transform_data,
             local_transform_buffer,
@@ -361,10 +746,66 @@ impl InstanceData {
     }
 }
 
+/// the number of local transforms an instance of each model has
+fn mesh_counts(models: &[GModel]) -> Vec<usize> {
+    models
+        .iter()
+        .map(|model| model.mesh_instances.iter().sum::<u32>() as usize)
+        .collect()
+}
+
+// STORAGE so that the animation compute shader can write to it directly, COPY_SRC so that
+// what it wrote can be read back
+const LOCAL_TRANSFORM_USAGE: wgpu::BufferUsages = wgpu::BufferUsages::VERTEX
+    .union(wgpu::BufferUsages::STORAGE)
+    .union(wgpu::BufferUsages::COPY_DST)
+    .union(wgpu::BufferUsages::COPY_SRC);
+const JOINT_TRANSFORM_USAGE: wgpu::BufferUsages = wgpu::BufferUsages::STORAGE
+    .union(wgpu::BufferUsages::COPY_DST)
+    .union(wgpu::BufferUsages::COPY_SRC);
+const GLOBAL_TRANSFORM_USAGE: wgpu::BufferUsages =
+    wgpu::BufferUsages::STORAGE.union(wgpu::BufferUsages::COPY_DST);
+
+/// Write the given element ranges of data to the same place in buffer
+fn upload_ranges(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    belt: &mut wgpu::util::StagingBelt,
+    buffer: &wgpu::Buffer,
+    data: &[u8],
+    element_size: usize,
+    ranges: Vec<std::ops::Range<usize>>,
+) -> bool {
+    let mut written = false;
+    for range in ranges {
+        let bytes = &data[range.start * element_size..range.end * element_size];
+        let Some(size) = wgpu::BufferSize::new(bytes.len() as u64) else {
+            continue;
+        };
+        belt.write_buffer(
+            encoder,
+            buffer,
+            (range.start * element_size) as u64,
+            size,
+            device,
+        )
+        .copy_from_slice(bytes);
+        written = true;
+    }
+    written
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn handle(index: u32) -> InstanceHandle {
+        InstanceHandle {
+            index,
+            generation: 0,
+        }
+    }
+
     #[test]
     fn test_animation_frame_update() {
         // instance data contains a scene that has two models.
@@ -395,7 +836,37 @@ mod tests {
         // create the instance_data
         let mut instance_data = InstanceData {
             model_instances: vec![2, 1],
-            model_instances_local_offsets: vec![0, 6],
+            model_instances_local_offsets: vec![0, 6, 10],
+            model_mesh_counts: vec![3, 4],
+            model_mesh_templates: vec![vec![original_matrix_1; 3], vec![original_matrix_2; 4]],
+            instance_records: vec![
+                vec![
+                    InstanceRecord {
+                        handle: handle(0),
+                        global_index: 0,
+                    },
+                    InstanceRecord {
+                        handle: handle(2),
+                        global_index: 2,
+                    },
+                ],
+                vec![InstanceRecord {
+                    handle: handle(1),
+                    global_index: 1,
+                }],
+            ],
+            instance_slots: [(0, 0), (1, 0), (0, 1)]
+                .into_iter()
+                .map(|location| InstanceSlot {
+                    generation: 0,
+                    location: Some(location),
+                })
+                .collect(),
+            free_slots: vec![],
+            dirty_local_transforms: DirtyRanges::default(),
+            dirty_global_transforms: DirtyRanges::default(),
+            dirty_joint_transforms: DirtyRanges::default(),
+            layout_changed: false,
             local_transform_buffer: None,
             local_transform_data: instance_data_local_transforms,
             global_transform_buffer: None,
@@ -406,10 +877,13 @@ mod tests {
 
         //create the animation frame
         let animation_frame = AnimationFrame {
+            instances: vec![InstanceHandle::for_slot(0)],
             lt_offsets: vec![3],
             mesh_transform_slices: vec![&new_matrices[..]],
             joint_transform_slices: vec![],
             joint_ids: vec![],
+            events: vec![],
+            root_motion: vec![],
         };
 
         instance_data.apply_animation_frame_unchecked(animation_frame);
@@ -468,4 +942,199 @@ mod tests {
         );
         assert_eq!(instance_data.local_transform_data[9].model_index, 1,);
     }
+
+    /// one instance of each model at the origin, with every local transform of a model
+    /// filled with the model's index
+    fn one_instance_each(mesh_counts: &[usize]) -> InstanceData {
+        let local_transform_data = mesh_counts
+            .iter()
+            .enumerate()
+            .flat_map(|(model_idx, mesh_count)| {
+                (0..*mesh_count).map(move |_| LocalTransform {
+                    transform_matrix: [[model_idx as f32; 4]; 4],
+                    model_index: model_idx as u32,
+                })
+            })
+            .collect();
+        InstanceData::with_one_instance_each(
+            mesh_counts.to_vec(),
+            local_transform_data,
+            vec![transforms::identity(); mesh_counts.len()],
+            vec![],
+        )
+    }
+
+    fn translation(x: f32) -> [[f32; 4]; 4] {
+        cgmath::Matrix4::from_translation(cgmath::Vector3::new(x, 0.0, 0.0)).into()
+    }
+
+    /// every local transform points at the global transform of the instance it belongs to
+    fn assert_consistent(instance_data: &InstanceData) {
+        let total: usize = instance_data
+            .model_instances
+            .iter()
+            .zip(&instance_data.model_mesh_counts)
+            .map(|(instances, mesh_count)| instances * mesh_count)
+            .sum();
+        assert_eq!(instance_data.local_transform_data.len(), total);
+        assert_eq!(
+            instance_data.global_transform_data.len(),
+            instance_data.model_instances.iter().sum::<usize>()
+        );
+        for (model_idx, instance_count) in instance_data.model_instances.iter().enumerate() {
+            for instance_idx in 0..*instance_count {
+                let global_index = instance_data.get_instance_global_index(instance_idx, model_idx);
+                let (offset, mesh_count) =
+                    instance_data.get_instance_local_offset(instance_idx, model_idx);
+                for local_transform in
+                    &instance_data.local_transform_data[offset..offset + mesh_count]
+                {
+                    assert_eq!(local_transform.model_index as usize, global_index);
+                }
+                let handle = instance_data.get_instance_handle(instance_idx, model_idx);
+                assert_eq!(instance_data.resolve(handle), Ok((model_idx, instance_idx)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_edits_mark_only_what_they_change() {
+        let mut instance_data = one_instance_each(&[3, 2, 4]);
+        let new_matrices = [[[3f32; 4]; 4]; 2];
+        instance_data.apply_animation_frame_unchecked(AnimationFrame {
+            instances: vec![InstanceHandle::for_slot(1), InstanceHandle::for_slot(2)],
+            lt_offsets: vec![3, 5],
+            mesh_transform_slices: vec![&new_matrices[..], &new_matrices[..1]],
+            joint_transform_slices: vec![],
+            joint_ids: vec![],
+            events: vec![],
+            root_motion: vec![],
+        });
+        instance_data.update_global_transform_x(2, translation(1.0));
+        instance_data.set_global_transform(0, translation(2.0));
+
+        assert_eq!(instance_data.dirty_local_transforms.take(), vec![3..6]);
+        assert_eq!(
+            instance_data.dirty_global_transforms.take(),
+            vec![0..1, 2..3]
+        );
+        assert_eq!(instance_data.dirty_joint_transforms.take(), vec![]);
+        assert_eq!(instance_data.global_transform_data[0], translation(2.0));
+    }
+
+    #[test]
+    fn test_spawn_appends_to_the_model() {
+        let mut instance_data = one_instance_each(&[2, 0, 3]);
+        let (handle, relocation) = instance_data.spawn(0, translation(1.0)).unwrap();
+
+        assert_eq!(instance_data.model_instances, vec![2, 1, 1]);
+        assert_eq!(
+            instance_data.model_instances_local_offsets,
+            vec![0, 4, 4, 7]
+        );
+        assert_eq!(instance_data.resolve(handle), Ok((0, 1)));
+        assert_eq!(instance_data.get_instance_local_offset(1, 0), (2, 2));
+        // the new instance starts from the model's template
+        assert_eq!(
+            instance_data.local_transform_data[2].transform_matrix,
+            [[0.0; 4]; 4]
+        );
+        assert_eq!(instance_data.global_transform_data[3], translation(1.0));
+        assert_eq!(instance_data.get_instance_global_index(1, 0), 3);
+        // the last model's transforms moved up by the new instance's
+        assert_eq!(instance_data.relocated_offset(&relocation, 2), 4);
+        assert_eq!(instance_data.relocated_offset(&relocation, 4), 6);
+        assert_eq!(instance_data.relocated_offset(&relocation, 0), 0);
+        assert_consistent(&instance_data);
+
+        // unknown models are refused before anything is touched
+        let global_count = instance_data.global_transform_data.len();
+        assert!(matches!(
+            instance_data.spawn(3, translation(3.0)),
+            Err(InstanceError::UnknownModel(3))
+        ));
+        assert_eq!(instance_data.global_transform_data.len(), global_count);
+        assert_consistent(&instance_data);
+    }
+
+    #[test]
+    fn test_despawn_moves_the_last_instance() {
+        let mut instance_data = one_instance_each(&[2, 3]);
+        let first = instance_data.get_instance_handle(0, 0);
+        let second = instance_data.spawn(0, translation(1.0)).unwrap().0;
+        let third = instance_data.spawn(0, translation(2.0)).unwrap().0;
+        // tell the instances apart by their local transforms
+        instance_data.local_transform_data[4].transform_matrix = [[7.0; 4]; 4];
+
+        let relocation = instance_data.despawn(first).unwrap();
+        assert_eq!(relocation.moved, Some((0, 2, 0)));
+        assert_eq!(
+            instance_data.resolve(first),
+            Err(InstanceError::Stale(first))
+        );
+        assert_eq!(instance_data.resolve(third), Ok((0, 0)));
+        assert_eq!(instance_data.resolve(second), Ok((0, 1)));
+        assert_eq!(
+            instance_data.local_transform_data[0].transform_matrix,
+            [[7.0; 4]; 4]
+        );
+        // the third instance's transforms moved to the front, the second model's moved up
+        assert_eq!(instance_data.relocated_offset(&relocation, 4), 0);
+        assert_eq!(instance_data.relocated_offset(&relocation, 2), 2);
+        assert_eq!(instance_data.relocated_offset(&relocation, 7), 5);
+        assert_eq!(instance_data.model_instances_local_offsets, vec![0, 4, 7]);
+        assert_consistent(&instance_data);
+
+        // the removed instance's global transform slot is reused by the last one
+        assert_eq!(instance_data.global_transform_data.len(), 3);
+        let global_index = instance_data.get_instance_global_index(0, 0);
+        assert_eq!(
+            instance_data.global_transform_data[global_index],
+            translation(2.0)
+        );
+        assert!(instance_data.despawn(first).is_err());
+    }
+
+    #[test]
+    fn test_handles_survive_spawns_and_despawns() {
+        let mut instance_data = one_instance_each(&[1, 2]);
+        let handles: Vec<InstanceHandle> = (0..6)
+            .map(|i| instance_data.spawn(i % 2, translation(i as f32)).unwrap().0)
+            .collect();
+        for handle in handles.iter().step_by(2) {
+            assert!(instance_data.despawn(*handle).is_ok());
+            assert_consistent(&instance_data);
+        }
+        for (i, handle) in handles.iter().enumerate() {
+            let Ok((model_idx, instance_idx)) = instance_data.resolve(*handle) else {
+                assert_eq!(i % 2, 0);
+                continue;
+            };
+            assert_eq!(model_idx, i % 2);
+            let global_index = instance_data.get_instance_global_index(instance_idx, model_idx);
+            assert_eq!(
+                instance_data.global_transform_data[global_index],
+                translation(i as f32)
+            );
+        }
+        assert_eq!(instance_data.model_instances, vec![1, 4]);
+    }
+
+    #[test]
+    fn test_reused_slots_leave_old_handles_stale() {
+        let mut instance_data = one_instance_each(&[1]);
+        let old = instance_data.spawn(0, translation(1.0)).unwrap().0;
+        instance_data.despawn(old).unwrap();
+        let new = instance_data.spawn(0, translation(2.0)).unwrap().0;
+        // the new instance took the removed one's slot, under a new generation
+        assert_eq!(new.index, old.index);
+        assert_ne!(new, old);
+        assert_eq!(instance_data.resolve(new), Ok((0, 1)));
+        assert_eq!(instance_data.resolve(old), Err(InstanceError::Stale(old)));
+        assert!(matches!(
+            instance_data.despawn(old),
+            Err(InstanceError::Stale(_))
+        ));
+        assert_eq!(instance_data.resolve(new), Ok((0, 1)));
+    }
 }
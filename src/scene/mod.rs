@@ -1,5 +1,10 @@
 pub mod camera;
+pub mod culling;
+pub mod indirect;
 pub mod instances;
+pub mod render_queue;
 pub mod scene;
+pub mod scene_graph;
 pub mod scene_scaffolds;
+mod dirty_ranges;
 mod util;
@@ -0,0 +1,115 @@
+use crate::{model::model::PrimitiveDraw, scene::scene::GScene};
+
+/// A primitive draw, along with the index of the render pipeline it is drawn with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedDraw {
+    pub pipeline: usize,
+    pub draw: PrimitiveDraw,
+}
+
+/// What encoding a frame cost
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RenderStats {
+    /// every draw command recorded, a multi draw counts once
+    pub draw_calls: usize,
+    /// how often the material bind group was set
+    pub bind_group_changes: usize,
+    pub pipeline_changes: usize,
+}
+
+/// The draws of a frame, collected and then sorted so that draws sharing a pipeline and a
+/// material are encoded one after the other
+#[derive(Debug, Default)]
+pub struct RenderQueue {
+    draws: Vec<QueuedDraw>,
+}
+
+impl RenderQueue {
+    pub fn clear(&mut self) {
+        self.draws.clear();
+    }
+
+    /// queue a draw, draws of no instances are dropped
+    pub fn push(&mut self, pipeline: usize, draw: PrimitiveDraw) {
+        if !draw.instances.is_empty() {
+            self.draws.push(QueuedDraw { pipeline, draw });
+        }
+    }
+
+    /// queue the draws of every visible instance in the scene
+    pub fn push_scene(&mut self, scene: &GScene, pipeline: usize) {
+        let mut draws: Vec<PrimitiveDraw> = Vec::new();
+        for (model_id, model) in scene.models.iter().enumerate() {
+            model.primitive_draws(
+                scene.get_model_local_offset(model_id) as u32,
+                &scene.get_visible_instances(model_id),
+                scene.get_instance_screen_sizes(model_id),
+                &mut draws,
+            );
+        }
+        for draw in draws {
+            self.push(pipeline, draw);
+        }
+    }
+
+    /// Order the draws by pipeline, then material, then indexed before unindexed, then where
+    /// their vertices are. Equal draws keep the order they were queued in
+    pub fn sort(&mut self) {
+        self.draws.sort_by_key(|queued| {
+            (
+                queued.pipeline,
+                queued.draw.material_index,
+                queued.draw.indices.is_empty(),
+                queued.draw.vertices.start,
+                queued.draw.indices.start,
+            )
+        });
+    }
+
+    pub fn draws(&self) -> &[QueuedDraw] {
+        &self.draws
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(material_index: usize, first_vertex: u32) -> PrimitiveDraw {
+        PrimitiveDraw {
+            material_index,
+            indices: 0..6,
+            vertices: first_vertex..first_vertex + 4,
+            instances: 0..1,
+        }
+    }
+
+    #[test]
+    fn test_sort_groups_pipelines_then_materials() {
+        let mut queue = RenderQueue::default();
+        queue.push(1, draw(0, 0));
+        queue.push(0, draw(2, 8));
+        queue.push(0, draw(1, 4));
+        queue.push(0, draw(2, 0));
+        queue.push(
+            0,
+            PrimitiveDraw {
+                instances: 3..3,
+                ..draw(0, 0)
+            },
+        );
+        queue.sort();
+        let order: Vec<(usize, usize, u32)> = queue
+            .draws()
+            .iter()
+            .map(|queued| {
+                (
+                    queued.pipeline,
+                    queued.draw.material_index,
+                    queued.draw.vertices.start,
+                )
+            })
+            .collect();
+        assert_eq!(order, vec![(0, 1, 4), (0, 2, 0), (0, 2, 8), (1, 0, 0)]);
+    }
+}
@@ -1,20 +1,45 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
+use std::ops::Range;
 
+use crate::model::animation::animation::PlaybackOptions;
+use crate::model::animation::animation_compute::{AnimationComputeData, AnimationComputeJob, ComputeUnsupported};
 use crate::model::animation::animation_controller::SceneAnimationController;
+use crate::model::animation::animation_events::FiredAnimationEvent;
+use crate::model::animation::animation_layers::AnimationLayer;
+use crate::model::animation::animation_state_machine::{AnimationParameter, AnimationStateMachine, StateMachineError};
+use crate::model::animation::baked_poses::BakedPoses;
+use crate::model::animation::joint_overrides::{find_unknown_joint, JointOverride};
+use crate::model::animation::retarget::{retarget_animation, JointNameMap, RetargetError};
+use crate::model::interval_set::IntervalSet;
 use crate::model::loader::loader::GltfData;
 use crate::model::loader::loader::ModelPrimitiveData;
 use crate::model::materials::material::MaterialDefinition;
 use crate::model::model::*;
+use crate::model::optimize::{optimize_primitive, IndexOptimizationStats};
 use crate::model::util::*;
-use crate::model::vertex::ModelVertex;
+use crate::model::vertex::{ModelVertex, MAX_JOINT_INFLUENCES};
 use crate::scene::camera::get_camera_bind_group_layout;
 use crate::scene::scene_scaffolds::SceneScaffold;
+use cgmath::{SquareMatrix, Transform};
 use wgpu::util::DeviceExt;
 
 use super::camera::Camera;
 use super::camera:: get_camera_default;
-use super::instances::InstanceData;
+use super::culling::{self, Aabb, CulledInstances, Frustum};
+use super::instances::{InstanceData, InstanceError, InstanceHandle};
+use super::scene_graph::{AttachmentPoint, ResolvedPoint, SceneGraph};
+/// The gltf accessors a primitive's vertices were read from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexAccessors {
+    pub positions: usize,
+    pub normals: Option<usize>,
+    pub tex_coords: Option<usize>,
+    /// every JOINTS_n and WEIGHTS_n set
+    pub joints: Vec<usize>,
+    pub weights: Vec<usize>,
+}
+
 pub struct PrimitiveData {
     pub mesh_id: usize,
     pub positions: Vec<u8>,
@@ -22,8 +47,14 @@ pub struct PrimitiveData {
     pub tex_coords: Option<Vec<u8>>,
     pub indices_len: usize,
     pub normals: Option<Vec<u8>>,
-    pub joints: Option<Vec<u8>>,
-    pub weights: Option<Vec<u8>>,
+    /// the strongest joint influences for each vertex, with weights summing to 1
+    pub joints: Option<Vec<[u16; MAX_JOINT_INFLUENCES]>>,
+    pub weights: Option<Vec<[f32; MAX_JOINT_INFLUENCES]>>,
+    /// the bounds of the positions, from the POSITION accessor's min and max
+    pub bounds: Option<Aabb>,
+    /// where the vertices came from, primitives with the same accessors and material share
+    /// their vertices in the scene. None if they were generated
+    pub vertex_accessors: Option<VertexAccessors>,
 }
 
 pub struct GScene<'a> {
@@ -34,13 +65,31 @@ pub struct GScene<'a> {
     pub(super) instance_data: InstanceData,
     camera: Option<Camera>,
     animation_controller: SceneAnimationController,
+    scene_graph: SceneGraph,
+    /// the animation events fired by the last animation frame
+    animation_events: Vec<FiredAnimationEvent>,
+    /// the instances that survived the last culling, and how large they appeared.
+    /// None draws every instance in full detail
+    culled_instances: Option<CulledInstances>,
+    /// what reordering the indices did to the vertex cache, if they were reordered
+    index_optimization: Option<IndexOptimizationStats>,
 }
 
 impl<'a> GScene<'a> {
     pub fn get_animation_frame(&mut self, timestamp: Duration) -> bool {
         let maybe_animation_frame = self.animation_controller.do_animations(timestamp, &self.models);
+        self.animation_events.clear();
         match maybe_animation_frame {
-            Some(animation_frame) => {
+            Some(mut animation_frame) => {
+                self.animation_events.append(&mut animation_frame.events);
+                if !self.scene_graph.is_empty() {
+                    let joint_parents = self.scene_graph.joint_parents();
+                    for (instance, joint_transforms) in animation_frame.instances.iter().zip(animation_frame.joint_transform_slices.iter()) {
+                        if joint_parents.contains(instance) {
+                            self.scene_graph.set_joint_pose(*instance, joint_transforms);
+                        }
+                    }
+                }
                 self.instance_data
                     .apply_animation_frame_unchecked(animation_frame);
 
@@ -50,21 +99,214 @@ impl<'a> GScene<'a> {
         }
     }
 
+    /// advance the active animations without sampling them, producing the work for the
+    /// animation compute shader instead. Fails if an animation needs the cpu path, or an
+    /// instance is attached to the joints of another, whose pose only the cpu path keeps apart
+    pub fn get_animation_compute_jobs(&mut self, timestamp: Duration, compute_data: &AnimationComputeData) -> Result<Option<Vec<AnimationComputeJob>>, ComputeUnsupported> {
+        if let Some(parent) = self.scene_graph.joint_parents().into_iter().next() {
+            return Err(ComputeUnsupported::JointAttachments(parent));
+        }
+        self.animation_events.clear();
+        self.animation_controller.get_compute_jobs(timestamp, &self.models, compute_data, &mut self.animation_events)
+    }
+
+    /// the animation events passed during the last animation frame
+    pub fn get_animation_events(&self) -> &[FiredAnimationEvent] {
+        &self.animation_events
+    }
+
+    /// attach a named event to a point on an animation's timeline, see [ModelAnimationData::add_animation_event]
+    pub fn add_animation_event(&mut self, model_id: usize, animation_index: usize, name: &str, time: f32) {
+        self.models[model_id].animation_data.as_mut().unwrap_or_else(|| panic!("The given model {} has no animations!", model_id)).add_animation_event(animation_index, name, time);
+    }
+
+    pub fn get_animation_compute_data(&self) -> AnimationComputeData {
+        AnimationComputeData::from_models(&self.models, self.animation_controller.inverse_bind_matrices())
+    }
+
+    /// sample the joint poses of a model's animations at a fixed rate, for the vertex shader to
+    /// play back on many instances at once, see [BakedPoses]
+    pub fn bake_animation_poses(&self, model_id: usize, animation_indices: &[usize], frame_rate: f32) -> BakedPoses {
+        let animation_data = self.models[model_id].animation_data.as_ref().unwrap_or_else(|| panic!("The given model {} has no animations!", model_id));
+        let inverse_bind_matrices = self.animation_controller.inverse_bind_matrices();
+        BakedPoses::bake(animation_data, inverse_bind_matrices, animation_indices, frame_rate)
+    }
+
+    /// start an animation on an instance. Fails if another instance of a skeletal model is
+    /// animated, see [Self::spawn_instance]
     pub fn initialize_animation(
         &mut self,
-        model_id: usize,
-        instance_idx: usize,
+        instance: InstanceHandle,
         animation_index: usize,
-    ) {
+        options: PlaybackOptions,
+    ) -> Result<(), InstanceError> {
+        let (model_id, instance_idx) = self.instance_data.resolve(instance)?;
+        self.check_joints_free(model_id, instance)?;
         let animation_data = self.models[model_id].animation_data.as_ref().expect(format!("The given model {} has no animations!", model_id).as_str());
-        let offset_count = if self.models[model_id].animation_data.as_ref().unwrap().mesh_animation_data.mesh_animations.contains(&animation_index) {
-             self.instance_data.get_instance_local_offset(instance_idx, model_id)
-        } else {
-            (0 as usize,0 as usize) 
-        };
+        let offset_count = self.get_animation_local_offset(model_id, instance_idx, animation_index);
        
         self.animation_controller
-            .initialize_animation(animation_data, animation_index, offset_count.0, offset_count.1, self.models[model_id].animation_data.as_ref().unwrap().joint_animation_data.joint_count);
+            .initialize_animation(animation_data, instance, animation_index, offset_count.0, offset_count.1, options);
+        Ok(())
+    }
+
+    /// start the animation with the given name on an instance, returns false if the model has
+    /// no such animation
+    pub fn initialize_animation_by_name(&mut self, instance: InstanceHandle, name: &str, options: PlaybackOptions) -> Result<bool, InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        match self.find_animation(model_id, name) {
+            Some(animation_index) => {
+                self.initialize_animation(instance, animation_index, options)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// copy an animation of one model onto the skeleton of another, see [retarget_animation].
+    /// Returns the index of the new animation on the target model. The animation compute data
+    /// has to be rebuilt for the gpu path to see it. Fails for the same model twice, or one that
+    /// doesn't exist
+    pub fn retarget_animation(&mut self, source_model_id: usize, animation_index: usize, target_model_id: usize, joint_names: &JointNameMap, name: Option<&str>) -> Result<usize, RetargetError> {
+        if let Some(model_id) = [source_model_id, target_model_id].into_iter().find(|model_id| *model_id >= self.models.len()) {
+            return Err(RetargetError::UnknownModel(model_id));
+        }
+        if source_model_id == target_model_id {
+            return Err(RetargetError::SameModel);
+        }
+        let (source, target) = if source_model_id < target_model_id {
+            let (head, tail) = self.models.split_at_mut(target_model_id);
+            (&head[source_model_id], &mut tail[0])
+        } else {
+            let (head, tail) = self.models.split_at_mut(source_model_id);
+            (&tail[0], &mut head[target_model_id])
+        };
+        retarget_animation(source, animation_index, target, joint_names, name)
+    }
+
+    /// the index of the first model whose root node has the given name
+    pub fn find_model(&self, name: &str) -> Option<usize> {
+        self.models.iter().position(|model| model.name.as_deref() == Some(name))
+    }
+
+    /// the global index of the model's animation with the given name
+    pub fn find_animation(&self, model_id: usize, name: &str) -> Option<usize> {
+        self.models[model_id].animation_data.as_ref()?.find_animation(name)
+    }
+
+    /// the gltf index of the model's node with the given name, e.g. a joint for [JointOverride]s
+    pub fn find_node(&self, model_id: usize, name: &str) -> Option<usize> {
+        self.models[model_id].node_names.get(name).copied()
+    }
+
+    /// the gltf index of the model's mesh with the given name
+    pub fn find_mesh(&self, model_id: usize, name: &str) -> Option<usize> {
+        self.models[model_id].find_mesh(name)
+    }
+
+    /// the first material with the given name
+    pub fn find_material(&self, name: &str) -> Option<&MaterialDefinition<'a>> {
+        self.material_definitions.iter().find(|material| material.name.as_deref() == Some(name))
+    }
+
+    /// blend a layer on top of the animation playing on the given instance, returns false if
+    /// the instance isn't playing anything
+    pub fn add_animation_layer(&mut self, instance: InstanceHandle, layer: AnimationLayer) -> Result<bool, InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        Ok(self.animation_controller.add_animation_layer(model_id, instance, layer))
+    }
+
+    pub fn clear_animation_layers(&mut self, instance: InstanceHandle) -> Result<(), InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        self.animation_controller.clear_animation_layers(model_id, instance);
+        Ok(())
+    }
+
+    /// let a state machine decide what the given instance plays from the next frame on. The
+    /// inner result fails if the machine refers to animations or states that don't exist, the
+    /// outer one like [Self::initialize_animation]
+    pub fn set_animation_state_machine(&mut self, instance: InstanceHandle, state_machine: AnimationStateMachine) -> Result<Result<(), StateMachineError>, InstanceError> {
+        let (model_id, instance_idx) = self.instance_data.resolve(instance)?;
+        self.check_joints_free(model_id, instance)?;
+        let animation_data = self.models[model_id].animation_data.as_ref().unwrap_or_else(|| panic!("The given model {} has no animations!", model_id));
+        let targets: Vec<(usize, usize)> = (0..animation_data.animation_count).map(|animation_index| self.get_animation_local_offset(model_id, instance_idx, animation_index)).collect();
+        Ok(self.animation_controller.set_state_machine(animation_data, instance, state_machine, |animation_index| targets[animation_index]))
+    }
+
+    /// stop everything an instance plays, along with its state machine
+    pub fn stop_animations(&mut self, instance: InstanceHandle) -> Result<(), InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        if self.models[model_id].animation_data.is_some() {
+            self.animation_controller.stop_animations(model_id, instance);
+            self.animation_controller.remove_state_machine(model_id, instance);
+        }
+        Ok(())
+    }
+
+    /// the instances of a skeletal model write the same joint transforms, so only one of them
+    /// can be animated at a time
+    fn check_joints_free(&self, model_id: usize, instance: InstanceHandle) -> Result<(), InstanceError> {
+        if !self.models[model_id].animation_data.as_ref().is_some_and(|animation_data| animation_data.is_skeletal) {
+            return Ok(());
+        }
+        match self.animation_controller.other_animated_instance(model_id, instance) {
+            Some(other) => Err(InstanceError::JointsInUse(other)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remove_animation_state_machine(&mut self, instance: InstanceHandle) -> Result<(), InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        self.animation_controller.remove_state_machine(model_id, instance);
+        Ok(())
+    }
+
+    /// set a parameter of the state machine driving the given instance, returns false if
+    /// the instance has no state machine
+    pub fn set_animation_parameter(&mut self, instance: InstanceHandle, name: &str, value: AnimationParameter) -> Result<bool, InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        Ok(self.animation_controller.set_animation_parameter(model_id, instance, name, value))
+    }
+
+    /// the name of the state the given instance's state machine is in
+    pub fn get_animation_state(&self, instance: InstanceHandle) -> Result<Option<&str>, InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        Ok(self.animation_controller.get_animation_state(model_id, instance))
+    }
+
+    /// set the procedural joint overrides of the animation playing on the given instance,
+    /// returns false if the instance isn't playing anything. Targets are in the instance's
+    /// model space, see [Self::world_to_instance_space]. Fails if an override refers to a node
+    /// that isn't one of the model's joints
+    pub fn set_joint_overrides(&mut self, instance: InstanceHandle, joint_overrides: Vec<JointOverride>) -> Result<bool, InstanceError> {
+        let (model_id, _) = self.instance_data.resolve(instance)?;
+        let unknown_joint = match self.models[model_id].animation_data.as_ref() {
+            Some(animation_data) => find_unknown_joint(&joint_overrides, animation_data),
+            None => joint_overrides.iter().flat_map(JointOverride::joint_nodes).next(),
+        };
+        if let Some(node_id) = unknown_joint {
+            return Err(InstanceError::UnknownJoint(node_id));
+        }
+        Ok(self.animation_controller.set_joint_overrides(model_id, instance, joint_overrides))
+    }
+
+    /// transform a world space point into the model space of an instance, e.g. for ik targets
+    pub fn world_to_instance_space(&self, instance: InstanceHandle, point: cgmath::Point3<f32>) -> Result<cgmath::Point3<f32>, InstanceError> {
+        let (model_id, instance_idx) = self.instance_data.resolve(instance)?;
+        let global_idx = self.instance_data.get_instance_global_index(instance_idx, model_id);
+        let world_from_model = cgmath::Matrix4::from(self.instance_data.global_transform_data[global_idx]);
+        Ok(world_from_model.invert().expect("instance transforms should be invertible").transform_point(point))
+    }
+
+    /// the local transform offset and mesh count an animation instance of the model writes to.
+    /// animations which don't move any meshes directly don't write local transforms at all
+    fn get_animation_local_offset(&self, model_id: usize, instance_idx: usize, animation_index: usize) -> (usize, usize) {
+        let animation_data = self.models[model_id].animation_data.as_ref().unwrap_or_else(|| panic!("The given model {} has no animations!", model_id));
+        if animation_data.mesh_animation_data.mesh_animations.contains(&animation_index) {
+             self.instance_data.get_instance_local_offset(instance_idx, model_id)
+        } else {
+            (0, 0)
+        }
     }
 
 
@@ -78,8 +320,108 @@ impl<'a> GScene<'a> {
     pub fn get_camera_buf(&self) -> &wgpu::Buffer {
         &self.camera.as_ref().unwrap().camera_buffer
     }
-    pub fn get_instance_local_offset(&self, instance_idx: usize, model_id: usize) -> (usize, usize) {
-        self.instance_data.get_instance_local_offset(instance_idx, model_id)
+    /// the offset of an instance's first local transform, and how many it has
+    pub fn get_instance_local_offset(&self, instance: InstanceHandle) -> Result<(usize, usize), InstanceError> {
+        let (model_id, instance_idx) = self.instance_data.resolve(instance)?;
+        Ok(self.instance_data.get_instance_local_offset(instance_idx, model_id))
+    }
+
+    /// Add an instance of a model, playing nothing. It is drawn once the instance buffers are
+    /// synced, see [Self::sync_instance_buffers]. Fails if there is no such model.
+    /// The instances of a skeletal model share one set of joint transforms, so only one of them
+    /// is animated at a time, the others stay in the bind pose. The baked pose pipeline plays
+    /// all of them instead
+    pub fn spawn_instance(&mut self, model_id: usize, global_transform: [[f32; 4]; 4]) -> Result<InstanceHandle, InstanceError> {
+        let (handle, relocation) = self.instance_data.spawn(model_id, global_transform)?;
+        self.culled_instances = None;
+        // the instances of the following models moved up
+        self.animation_controller.relocate_instances(|offset| self.instance_data.relocated_offset(&relocation, offset));
+        Ok(handle)
+    }
+
+    /// Remove an instance along with its animations and state machine, which leaves its
+    /// handle stale. The last instance of the same model takes its instance index
+    pub fn despawn_instance(&mut self, handle: InstanceHandle) -> Result<(), InstanceError> {
+        self.stop_animations(handle)?;
+        self.scene_graph.remove_instance(handle);
+        let relocation = self.instance_data.despawn(handle)?;
+        self.culled_instances = None;
+        self.animation_controller.relocate_instances(|offset| self.instance_data.relocated_offset(&relocation, offset));
+        Ok(())
+    }
+
+    /// the model index and current instance index of a live instance
+    pub fn get_instance_location(&self, handle: InstanceHandle) -> Result<(usize, usize), InstanceError> {
+        self.instance_data.resolve(handle)
+    }
+
+    /// the handle of the instance currently at instance_idx, e.g. to reach the instances the
+    /// scene was loaded with
+    pub fn get_instance_handle(&self, instance_idx: usize, model_id: usize) -> InstanceHandle {
+        self.instance_data.get_instance_handle(instance_idx, model_id)
+    }
+
+    /// the slot of the instance currently at instance_idx in the global transform data, which
+    /// changes as instances are despawned
+    pub fn get_instance_global_index(&self, instance_idx: usize, model_id: usize) -> usize {
+        self.instance_data.get_instance_global_index(instance_idx, model_id)
+    }
+
+    /// Attach child to a point of parent, so that it follows the parent from the next
+    /// [Self::propagate_transforms] on. offset is the child's transform relative to that point.
+    /// Joints follow the parent's own pose as sampled on the cpu. The compute backend refuses
+    /// them, and the baked backend hands its poses over through [Self::set_joint_pose]
+    pub fn attach_instance(&mut self, child: InstanceHandle, parent: InstanceHandle, point: &AttachmentPoint, offset: [[f32; 4]; 4]) -> Result<(), InstanceError> {
+        let (parent_model, _) = self.instance_data.resolve(parent)?;
+        let inverse_bind_matrices = self.animation_controller.inverse_bind_matrices();
+        let point = ResolvedPoint::resolve(point, &self.models[parent_model], inverse_bind_matrices)?;
+        self.scene_graph.attach(&self.instance_data, child, parent, point, offset)
+    }
+
+    /// the instances with other instances attached to their joints
+    pub fn get_joint_parents(&self) -> HashSet<InstanceHandle> {
+        self.scene_graph.joint_parents()
+    }
+
+    /// set the joint transforms the instances attached to parent's joints follow, for poses
+    /// that weren't sampled by the animation controller
+    pub fn set_joint_pose(&mut self, parent: InstanceHandle, joint_transforms: &[[[f32; 4]; 4]]) {
+        self.scene_graph.set_joint_pose(parent, joint_transforms);
+    }
+
+    /// detach an instance from its parent, leaving it where it is. Returns false if it wasn't attached
+    pub fn detach_instance(&mut self, child: InstanceHandle) -> Result<bool, InstanceError> {
+        self.instance_data.resolve(child)?;
+        Ok(self.scene_graph.detach(child))
+    }
+
+    pub fn get_instance_parent(&self, child: InstanceHandle) -> Result<Option<InstanceHandle>, InstanceError> {
+        self.instance_data.resolve(child)?;
+        Ok(self.scene_graph.parent(child))
+    }
+
+    /// Move every attached instance to its parent's attachment point, after the parents have
+    /// been animated and before the global transforms are uploaded. Returns false if no instance
+    /// is attached, and nothing changed
+    pub fn propagate_transforms(&mut self) -> bool {
+        if self.scene_graph.is_empty() {
+            return false;
+        }
+        self.scene_graph.propagate(&mut self.instance_data);
+        true
+    }
+
+    /// Upload the instances added or removed since the last call. Returns true if the local or
+    /// global transform buffer was replaced, and bind groups using them need to be recreated
+    pub fn sync_instance_buffers(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        self.instance_data.sync_buffers(device, queue)
+    }
+
+    /// Record copies of the transforms that changed since the last upload into encoder, staged
+    /// through belt. Returns true if anything was written, the belt then has to be finished before
+    /// the encoder is submitted
+    pub fn upload_transform_changes(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, belt: &mut wgpu::util::StagingBelt) -> bool {
+        self.instance_data.upload_dirty(device, encoder, belt)
     }
    
     pub unsafe fn get_joint_buf_unchecked(&self) -> &wgpu::Buffer {
@@ -108,22 +450,22 @@ impl<'a> GScene<'a> {
             ))
         ))
     }
+    /// apply new_transform on top of the instance's global transform, or on top of its offset
+    /// from the attachment point if it is attached to another instance
     pub fn update_global_transform(
         &mut self,
-        model_number: usize,
-        model_instance_index: usize,
+        instance: InstanceHandle,
         new_transform: [[f32; 4]; 4],
-    ) {
-        let mut instance_count = 0;
-        // skip all preceding models
-        for idx in 0..model_number {
-            instance_count += self.instance_data.model_instances[idx];
+    ) -> Result<(), InstanceError> {
+        let (model_id, instance_idx) = self.instance_data.resolve(instance)?;
+        if let Some(offset) = self.scene_graph.offset_mut(instance) {
+            *offset = cgmath::Matrix4::from(new_transform) * *offset;
+            return Ok(());
         }
-        // skip all preceding instances of this model
-        instance_count += model_instance_index;
-        println!("updating model at index {}", instance_count);
+        let global_idx = self.instance_data.get_instance_global_index(instance_idx, model_id);
         self.instance_data
-            .update_global_transform_x(instance_count, new_transform);
+            .update_global_transform_x(global_idx, new_transform);
+        Ok(())
     }
     pub fn get_camera_bind_group(
         &self,
@@ -172,9 +514,52 @@ impl<'a> GScene<'a> {
     pub fn get_model_instances(&self) -> &Vec<usize> {
         &self.instance_data.model_instances
     }
-    pub fn update_global_transform_x(&mut self, instance_idx: usize, new_transform: [[f32; 4]; 4]) {
-        self.instance_data
-            .update_global_transform_x(instance_idx, new_transform);
+
+    /// Cull the instances whose bounds are outside the camera's view, they are left out of the
+    /// draws until the next culling or until instances are added or removed. The instances that
+    /// remain are drawn with the level of detail fitting their size on screen. Instances of
+    /// skinned meshes are never culled. Returns how many instances were culled
+    pub fn cull_instances(&mut self) -> usize {
+        let view_proj = cgmath::Matrix4::from(self.get_camera_uniform_data());
+        self.cull_instances_against(&view_proj)
+    }
+
+    /// [Self::cull_instances] against the frustum of any view projection
+    pub fn cull_instances_against(&mut self, view_proj: &cgmath::Matrix4<f32>) -> usize {
+        let culled_instances = culling::cull_instances(&self.models, &self.instance_data, &Frustum::from_view_proj(view_proj));
+        let visible_count: usize = culled_instances.visible.iter().flatten().map(|range| range.len()).sum();
+        self.culled_instances = Some(culled_instances);
+        self.instance_data.model_instances.iter().sum::<usize>() - visible_count
+    }
+
+    /// draw every instance again, e.g. when the cpu copy of the local transforms is stale
+    pub fn show_all_instances(&mut self) {
+        self.culled_instances = None;
+    }
+
+    /// the ranges of a model's instance indices to draw
+    pub fn get_visible_instances(&self, model_id: usize) -> Vec<Range<u32>> {
+        match &self.culled_instances {
+            Some(culled_instances) => culled_instances.visible[model_id].clone(),
+            None => std::iter::once(0..self.instance_data.model_instances[model_id] as u32).collect(),
+        }
+    }
+
+    /// the fraction of the screen height each instance of a model covered at the last culling,
+    /// which picks the level of detail it is drawn with
+    pub fn get_instance_screen_sizes(&self, model_id: usize) -> Option<&[f32]> {
+        self.culled_instances.as_ref().map(|culled_instances| culled_instances.screen_sizes[model_id].as_slice())
+    }
+
+    /// the cache misses before and after the indices were reordered at build time, None if
+    /// they were left in the order of the file
+    pub fn get_index_optimization_stats(&self) -> Option<&IndexOptimizationStats> {
+        self.index_optimization.as_ref()
+    }
+
+    /// the slot of the first local transform of a model's first instance
+    pub fn get_model_local_offset(&self, model_id: usize) -> usize {
+        self.instance_data.get_model_local_offset(model_id)
     }
 }
 
@@ -187,6 +572,8 @@ pub struct GSceneData<'a> {
     local_transforms: Vec<LocalTransform>,
     joint_transforms: Vec<[[f32;4];4]>,
     skin_ibms: HashMap<usize, Vec<cgmath::Matrix4<f32>>>,
+    /// set by [Self::optimize_indices], and handed on to the scene
+    index_optimization: Option<IndexOptimizationStats>,
 }
 
 impl<'a> GSceneData<'a> {
@@ -196,12 +583,17 @@ impl<'a> GSceneData<'a> {
         scene
     }
 
+    /// Build and initialize the scene laid out by scaffold, reordering its indices first if the
+    /// scaffold asks for it
     pub fn build_scene_from_scaffold(
-        self,
+        mut self,
         device: &wgpu::Device,
         aspect_ratio: f32,
         scaffold: &SceneScaffold,
     ) -> Result<GScene<'a>, InitializationError> {
+        if scaffold.optimize_indices {
+            self.optimize_indices();
+        }
         let instance_data =
             InstanceData::from_scaffold(scaffold, self.local_transforms,self.joint_transforms, &self.models, )?;
         let vertex_data = VertexData::from_data(self.vertex_vec);
@@ -209,6 +601,10 @@ impl<'a> GSceneData<'a> {
         let animation_controller = SceneAnimationController::new(self.models.len(), self.skin_ibms);
         let mut scene = GScene {
             animation_controller,
+            animation_events: Vec::new(),
+            scene_graph: SceneGraph::default(),
+            culled_instances: None,
+            index_optimization: self.index_optimization,
             models: self.models,
             material_definitions: self.material_definitions,
             vertex_data,
@@ -233,6 +629,10 @@ impl<'a> GSceneData<'a> {
             index_data,
             camera: None,
             animation_controller,
+            animation_events: Vec::new(),
+            scene_graph: SceneGraph::default(),
+            culled_instances: None,
+            index_optimization: self.index_optimization,
         }
     }
 
@@ -253,7 +653,33 @@ impl<'a> GSceneData<'a> {
             local_transforms: gltf_data.local_transforms,
             joint_transforms: gltf_data.joint_transforms,
             skin_ibms: gltf_data.skin_ibms,
+            index_optimization: None,
+        }
+    }
+
+    /// Reorder the indices of every primitive for the vertex cache and overdraw, and its vertices
+    /// for fetching, before the scene is built. Primitives sharing indices or vertices with a
+    /// different one are left as they are, their vertices can't be moved for both
+    pub fn optimize_indices(&mut self) -> IndexOptimizationStats {
+        let mut stats = IndexOptimizationStats::default();
+        let mut ranges: Vec<(Range<u32>, Range<u32>)> = self.models.iter().flat_map(GModel::primitive_buffer_ranges).collect();
+        // the same primitive drawn by several models is only optimized once
+        ranges.sort_by_key(|(indices, vertices)| (indices.start, indices.end, vertices.start, vertices.end));
+        ranges.dedup();
+        let overlaps = |a: &Range<u32>, b: &Range<u32>| a.start < b.end && b.start < a.end;
+        for (primitive, (indices, vertices)) in ranges.iter().enumerate() {
+            let shared = ranges.iter().enumerate().any(|(other_primitive, (other_indices, other_vertices))| {
+                other_primitive != primitive && (overlaps(indices, other_indices) || overlaps(vertices, other_vertices))
+            });
+            let indices = &mut self.index_vec[indices.start as usize..indices.end as usize];
+            let vertices = &mut self.vertex_vec[vertices.start as usize..vertices.end as usize];
+            if shared || indices.is_empty() || indices.iter().any(|index| *index as usize >= vertices.len()) {
+                continue;
+            }
+            stats.merge(&optimize_primitive(indices, vertices));
         }
+        self.index_optimization = Some(stats);
+        stats
     }
 
     fn get_scene_vertex_buffer_data(
@@ -263,26 +689,28 @@ impl<'a> GSceneData<'a> {
         let mut vertex_buffer_data = Vec::<ModelVertex>::new();
         // loop through the models -> meshes -> primitives to build out the vertex buffer
         let mut buffer_offset_val = 0;
+        // primitives reading the same accessors share their vertices, across models too
+        let mut shared_vertices = HashMap::new();
         for  model  in models.iter_mut() {
             let this_model_primitive_data = model_primitive_data.iter().find(|mpd| mpd.model_id == model.model_id).expect("There should be one primitive data vec for this model ");
             vertex_buffer_data
-                .extend(model.get_model_vertex_data(&this_model_primitive_data.primitive_data,  &mut buffer_offset_val));
+                .extend(model.get_model_vertex_data(&this_model_primitive_data.primitive_data,  &mut buffer_offset_val, &mut shared_vertices));
         }
         vertex_buffer_data
     }
     fn get_scene_index_buffer_data(
         models: &mut Vec<GModel>,
         model_primitive_data: &Vec<ModelPrimitiveData>,
-        main_buffer_data: &Vec<u8>,
+        main_buffer_data: &[u8],
     ) -> Vec<u16> {
-        let mut range_vec: Vec<std::ops::Range<usize>> = Vec::new();
+        let mut index_ranges = IntervalSet::new();
         for (model_idx, model ) in models.iter().enumerate() {
-            model.build_range_vec(&mut range_vec, &model_primitive_data[model_idx].primitive_data); // MUTATE RANGE VEC
+            model.build_index_ranges(&mut index_ranges, &model_primitive_data[model_idx].primitive_data);
         }
-        let index_vec = GModel::get_model_index_data(main_buffer_data, &range_vec);
+        let index_vec = GModel::get_model_index_data(main_buffer_data, &index_ranges);
         // add in the relative buffer offset and len based on the new composed data vec
         for (model_idx, model ) in models.iter_mut().enumerate() {
-            model.set_model_primitive_offsets(&range_vec, &model_primitive_data[model_idx].primitive_data);
+            model.set_model_primitive_offsets(&index_ranges, &model_primitive_data[model_idx].primitive_data);
         }
         index_vec
     }
@@ -336,3 +764,368 @@ impl SceneData<Vec<u16>> for IndexData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::animation::root_motion::RootMotion;
+    use crate::model::loader::loader::GltfLoader;
+    use crate::scene::scene_graph::AttachmentPoint;
+    use crate::transforms;
+
+    #[test]
+    fn test_lookup_by_name() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let scene = GSceneData::new(gltf_data).build_scene_uninit();
+        // the skeleton and the skinned mesh are separate root nodes
+        let skeleton_id = scene.find_model("root").expect("the skeleton's root node is named");
+        let fox_id = scene.find_model("fox").expect("the mesh node is named");
+        assert_eq!(scene.find_model("wolf"), None);
+
+        assert_eq!(scene.find_animation(skeleton_id, "Survey"), Some(0));
+        assert_eq!(scene.find_animation(skeleton_id, "Run"), Some(2));
+        assert_eq!(scene.find_animation(skeleton_id, "Fly"), None);
+        assert_eq!(scene.find_node(skeleton_id, "b_Hip_01"), Some(4));
+        assert_eq!(scene.find_node(skeleton_id, "fox"), None);
+        assert_eq!(scene.find_mesh(fox_id, "fox1"), Some(0));
+        assert_eq!(scene.find_mesh(skeleton_id, "fox1"), None);
+        let material = scene.find_material("fox_material").unwrap();
+        assert_eq!(material.id, 0);
+        assert!(scene.find_material("wood").is_none());
+    }
+
+    #[test]
+    fn test_retargeting_checks_the_models() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let skeleton_id = scene.find_model("root").unwrap();
+        let joint_names = JointNameMap::new();
+        assert_eq!(scene.retarget_animation(skeleton_id, 0, skeleton_id, &joint_names, None), Err(RetargetError::SameModel));
+        let unknown = scene.models.len();
+        assert_eq!(scene.retarget_animation(skeleton_id, 0, unknown, &joint_names, None), Err(RetargetError::UnknownModel(unknown)));
+        assert_eq!(scene.retarget_animation(unknown, 0, skeleton_id, &joint_names, None), Err(RetargetError::UnknownModel(unknown)));
+    }
+
+    #[test]
+    fn test_animation_follows_relocated_instance() {
+        let gltf_data = GltfLoader::load_gltf("box-animated").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        // model 0 is a static box, model 1 is animated by moving its mesh
+        let original = scene.get_instance_handle(0, 1);
+        let template = scene.get_local_transform_data()[1].transform_matrix;
+        let animated = scene.spawn_instance(1, transforms::identity()).unwrap();
+        scene.initialize_animation(animated, 0, PlaybackOptions { looping: true, ..Default::default() }).unwrap();
+
+        // spawning before it and despawning the instance it follows both move it
+        let static_box = scene.spawn_instance(0, transforms::identity()).unwrap();
+        assert!(scene.despawn_instance(original).is_ok());
+        assert_eq!(scene.despawn_instance(original), Err(InstanceError::Stale(original)));
+        assert_eq!(scene.update_global_transform(original, transforms::identity()), Err(InstanceError::Stale(original)));
+        assert!(scene.get_instance_local_offset(original).is_err());
+        assert_eq!(scene.get_instance_location(animated), Ok((1, 0)));
+        assert_eq!(scene.get_instance_location(static_box), Ok((0, 1)));
+
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(500)));
+        let (offset, mesh_count) = scene.get_instance_local_offset(animated).unwrap();
+        assert_eq!((offset, mesh_count), (2, 1));
+        let local_transforms = scene.get_local_transform_data();
+        assert_eq!(local_transforms.len(), 3);
+        assert_ne!(local_transforms[offset].transform_matrix, template);
+        // the static boxes weren't written to
+        let static_template = local_transforms[0].transform_matrix;
+        assert_eq!(local_transforms[1].transform_matrix, static_template);
+        let global_index = local_transforms[offset].model_index as usize;
+        assert_eq!(scene.get_global_transform_data().len(), 3);
+        assert_eq!(scene.instance_data.get_instance_global_index(0, 1), global_index);
+    }
+
+    #[test]
+    fn test_events_report_the_instance_that_fired_them() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let skeleton_id = scene.find_model("root").unwrap();
+        // skeletal animations all write at local transform offset 0
+        let first = scene.get_instance_handle(0, skeleton_id);
+        let second = scene.spawn_instance(skeleton_id, transforms::identity()).unwrap();
+        scene.add_animation_event(skeleton_id, 0, "step", 0.0);
+        let fired = |scene: &GScene| -> Vec<InstanceHandle> { scene.get_animation_events().iter().map(|event| event.instance).collect() };
+        scene.initialize_animation(first, 0, PlaybackOptions::default()).unwrap();
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(100)));
+        assert_eq!(fired(&scene), vec![first]);
+
+        // the two share their joints, so the second only plays once the first has stopped
+        assert_eq!(scene.initialize_animation(second, 0, PlaybackOptions::default()), Err(InstanceError::JointsInUse(first)));
+        assert_eq!(scene.set_animation_state_machine(second, AnimationStateMachine::new()).err(), Some(InstanceError::JointsInUse(first)));
+        scene.stop_animations(first).unwrap();
+        scene.initialize_animation(second, 0, PlaybackOptions::default()).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(200)));
+        assert_eq!(fired(&scene), vec![second]);
+        // each model only keeps the names and events of its own animations
+        for model in scene.models.iter() {
+            if let Some(animation_data) = model.animation_data.as_ref() {
+                let own_animations = animation_data.animation_node.animation_indices();
+                assert!(animation_data.animation_events.keys().all(|animation_index| own_animations.contains(animation_index)));
+                assert!(animation_data.animation_names.values().all(|animation_index| own_animations.contains(animation_index)));
+            }
+        }
+    }
+
+    fn translation(x: f32, y: f32, z: f32) -> [[f32; 4]; 4] {
+        cgmath::Matrix4::from_translation(cgmath::Vector3::new(x, y, z)).into()
+    }
+
+    fn global_translation(scene: &GScene, instance: InstanceHandle) -> cgmath::Vector3<f32> {
+        let (model_id, instance_idx) = scene.get_instance_location(instance).unwrap();
+        let global_idx = scene.instance_data.get_instance_global_index(instance_idx, model_id);
+        cgmath::Vector4::from(scene.get_global_transform_data()[global_idx][3]).truncate()
+    }
+
+    #[test]
+    fn test_root_motion_moves_the_instance_it_was_extracted_from() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let model_id = scene.models.iter().position(|model| model.animation_data.is_some()).unwrap();
+        // skeletal animations all write at local transform offset 0, so only the handle tells
+        // the walker apart from the first instance
+        let first = scene.get_instance_handle(0, model_id);
+        let walker = scene.spawn_instance(model_id, transforms::identity()).unwrap();
+        scene.initialize_animation(walker, 0, PlaybackOptions { looping: true, root_motion: RootMotion::Translation }).unwrap();
+        let first_start = global_translation(&scene, first);
+
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        for millis in [0, 250, 500] {
+            assert!(scene.get_animation_frame(timestamp + Duration::from_millis(millis)));
+        }
+        assert_eq!(global_translation(&scene, first), first_start);
+        assert!(cgmath::InnerSpace::magnitude(global_translation(&scene, walker)) > 1e-2);
+    }
+
+    #[test]
+    fn test_joint_overrides_only_accept_joints() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let skeleton_id = scene.find_model("root").unwrap();
+        let instance = scene.get_instance_handle(0, skeleton_id);
+        scene.initialize_animation(instance, 0, PlaybackOptions::default()).unwrap();
+        let rotate = |joint_node| vec![JointOverride::Rotate { joint_node, rotation: <cgmath::Quaternion<f32> as cgmath::Rotation3>::from_angle_y(cgmath::Deg(30.0)) }];
+        // the model's root and the mesh it skins are nodes, but not joints
+        let fox_id = scene.find_model("fox").unwrap();
+        let mesh_node = scene.find_node(fox_id, "fox").unwrap();
+        for node_id in [scene.find_node(skeleton_id, "root").unwrap(), mesh_node, 1000] {
+            assert_eq!(scene.set_joint_overrides(instance, rotate(node_id)), Err(InstanceError::UnknownJoint(node_id)));
+        }
+        let head = scene.find_node(skeleton_id, "b_Head_05").unwrap();
+        assert_eq!(scene.set_joint_overrides(instance, rotate(head)), Ok(true));
+
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_instances_follow_their_parents() {
+        let gltf_data = GltfLoader::load_gltf("box-animated").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let parent = scene.get_instance_handle(0, 0);
+        let child = scene.spawn_instance(1, transforms::identity()).unwrap();
+        let grandchild = scene.spawn_instance(1, transforms::identity()).unwrap();
+        scene.attach_instance(child, parent, &AttachmentPoint::Origin, translation(1.0, 0.0, 0.0)).unwrap();
+        scene.attach_instance(grandchild, child, &AttachmentPoint::Origin, translation(0.0, 2.0, 0.0)).unwrap();
+        assert_eq!(scene.get_instance_parent(grandchild), Ok(Some(child)));
+
+        scene.update_global_transform(parent, translation(0.0, 0.0, 3.0)).unwrap();
+        assert!(scene.propagate_transforms());
+        assert_eq!(global_translation(&scene, grandchild), cgmath::Vector3::new(1.0, 2.0, 3.0));
+        // moving an attached instance moves it relative to its parent
+        scene.update_global_transform(child, translation(0.0, 0.0, 1.0)).unwrap();
+        scene.propagate_transforms();
+        assert_eq!(global_translation(&scene, child), cgmath::Vector3::new(1.0, 0.0, 4.0));
+        assert_eq!(global_translation(&scene, grandchild), cgmath::Vector3::new(1.0, 2.0, 4.0));
+
+        assert_eq!(scene.attach_instance(parent, grandchild, &AttachmentPoint::Origin, transforms::identity()), Err(InstanceError::AttachmentCycle));
+        let unknown = AttachmentPoint::Node("hand".to_string());
+        assert_eq!(scene.attach_instance(parent, child, &unknown, transforms::identity()), Err(InstanceError::UnknownNode("hand".to_string())));
+
+        // children of a removed instance stay where they were
+        scene.despawn_instance(child).unwrap();
+        assert_eq!(scene.get_instance_parent(grandchild), Ok(None));
+        assert!(!scene.propagate_transforms());
+        assert_eq!(global_translation(&scene, grandchild), cgmath::Vector3::new(1.0, 2.0, 4.0));
+    }
+
+    #[test]
+    fn test_instance_follows_animated_joint() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let parent = scene.get_instance_handle(0, 0);
+        scene.update_global_transform(parent, translation(5.0, 0.0, 0.0)).unwrap();
+        let child = scene.spawn_instance(0, transforms::identity()).unwrap();
+        let hand = AttachmentPoint::Node("Skeleton_arm_joint_L__2_".to_string());
+        let offset = translation(0.0, 0.1, 0.0);
+        scene.attach_instance(child, parent, &hand, offset).unwrap();
+
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        scene.initialize_animation(parent, 0, PlaybackOptions::default()).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(500)));
+        assert!(scene.propagate_transforms());
+
+        // the joint's model space transform, sampled independently of the animation controller
+        let node_id = scene.find_node(0, "Skeleton_arm_joint_L__2_").unwrap();
+        let animation_node = &scene.models[0].animation_data.as_ref().unwrap().animation_node;
+        let Some((crate::model::animation::animation_node::NodeType::Joint(joint_index), _)) = animation_node.rest_pose(node_id, cgmath::Matrix4::identity()) else {
+            panic!("the hand is a joint");
+        };
+        let poses = scene.bake_animation_poses(0, &[0], 1000.0);
+        let skinning_matrix = cgmath::Matrix4::from(poses.sample(0, 0.5)[joint_index]);
+        let bind_matrix = scene.animation_controller.inverse_bind_matrices()[joint_index].invert().unwrap();
+        let expected = cgmath::Matrix4::from(translation(5.0, 0.0, 0.0)) * skinning_matrix * bind_matrix * cgmath::Matrix4::from(offset);
+        let difference = global_translation(&scene, child) - expected.w.truncate();
+        assert!(cgmath::InnerSpace::magnitude(difference) < 1e-3, "off by {:?}", difference);
+    }
+
+    #[test]
+    fn test_instance_follows_the_joints_of_its_own_parent() {
+        let gltf_data = GltfLoader::load_gltf("fox").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        let skeleton_id = scene.find_model("root").unwrap();
+        // both skeletons share the joint transforms the running one writes, only the
+        // instance tells their poses apart
+        let idle = scene.get_instance_handle(0, skeleton_id);
+        let running = scene.spawn_instance(skeleton_id, transforms::identity()).unwrap();
+        let child = scene.spawn_instance(skeleton_id, transforms::identity()).unwrap();
+        let idle_child = scene.spawn_instance(skeleton_id, transforms::identity()).unwrap();
+        let head = AttachmentPoint::Node("b_Head_05".to_string());
+        scene.attach_instance(child, running, &head, transforms::identity()).unwrap();
+        scene.attach_instance(idle_child, idle, &head, transforms::identity()).unwrap();
+        assert_eq!(scene.get_joint_parents(), HashSet::from([running, idle]));
+        let run = scene.find_animation(skeleton_id, "Run").unwrap();
+        scene.initialize_animation(running, run, PlaybackOptions::default()).unwrap();
+
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert!(scene.get_animation_frame(timestamp + Duration::from_millis(500)));
+        assert!(scene.propagate_transforms());
+
+        let node_id = scene.find_node(skeleton_id, "b_Head_05").unwrap();
+        let animation_node = &scene.models[skeleton_id].animation_data.as_ref().unwrap().animation_node;
+        let Some((crate::model::animation::animation_node::NodeType::Joint(joint_index), _)) = animation_node.rest_pose(node_id, cgmath::Matrix4::identity()) else {
+            panic!("the head is a joint");
+        };
+        // the fox is a large model, the baked poses only agree to about a hundredth
+        let poses = scene.bake_animation_poses(skeleton_id, &[run], 1000.0);
+        let bind_matrix = scene.animation_controller.inverse_bind_matrices()[joint_index].invert().unwrap();
+        let running_head = (cgmath::Matrix4::from(poses.sample(0, 0.5)[joint_index]) * bind_matrix).w.truncate();
+        let difference = global_translation(&scene, child) - running_head;
+        assert!(cgmath::InnerSpace::magnitude(difference) < 1e-2, "off by {:?}", difference);
+        // the idle skeleton's head stays in the bind pose
+        assert_eq!(global_translation(&scene, idle_child), bind_matrix.w.truncate());
+        assert!(cgmath::InnerSpace::magnitude(running_head - bind_matrix.w.truncate()) > 1e-1);
+
+        // the compute shader keeps no pose per instance to follow
+        let compute_data = AnimationComputeData::from_models(&scene.models, scene.animation_controller.inverse_bind_matrices());
+        let refused = scene.get_animation_compute_jobs(timestamp, &compute_data).err();
+        assert!(matches!(refused, Some(ComputeUnsupported::JointAttachments(parent)) if parent == running || parent == idle));
+    }
+
+    fn view_from_z(z: f32) -> cgmath::Matrix4<f32> {
+        let view = cgmath::Matrix4::look_at_rh(cgmath::Point3::new(0.0, 0.0, z), cgmath::Point3::new(0.0, 0.0, 0.0), cgmath::Vector3::unit_y());
+        cgmath::perspective(cgmath::Deg(45.0), 1.0, 0.1, 100.0) * view
+    }
+
+    #[test]
+    fn test_culled_instances_are_left_out_of_the_draws() {
+        let gltf_data = GltfLoader::load_gltf("box-animated").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        scene.spawn_instance(0, translation(0.0, 0.0, 0.0)).unwrap();
+        scene.spawn_instance(0, translation(100.0, 0.0, 0.0)).unwrap();
+        let far = scene.spawn_instance(0, translation(0.0, 0.0, -500.0)).unwrap();
+        scene.spawn_instance(0, translation(1.0, 0.0, 0.0)).unwrap();
+        assert_eq!(scene.get_visible_instances(0), vec![0..5]);
+
+        assert_eq!(scene.cull_instances_against(&view_from_z(10.0)), 2);
+        assert_eq!(scene.get_visible_instances(0), vec![0..2, 4..5]);
+        assert_eq!(scene.get_visible_instances(1), vec![0..1]);
+        // turned around, everything is behind the camera
+        let behind = cgmath::Matrix4::from_angle_y(cgmath::Deg(180.0)) * view_from_z(10.0);
+        assert_eq!(scene.cull_instances_against(&behind), 6);
+        assert_eq!(scene.get_visible_instances(0), vec![]);
+
+        // removing an instance draws everything until the next culling
+        scene.despawn_instance(far).unwrap();
+        assert_eq!(scene.get_visible_instances(0), vec![0..4]);
+    }
+
+    #[test]
+    fn test_skinned_instances_are_never_culled() {
+        let gltf_data = GltfLoader::load_gltf("cesium-man").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        for model_id in 0..scene.models.len() {
+            let instance = scene.get_instance_handle(0, model_id);
+            scene.update_global_transform(instance, translation(0.0, 0.0, 1000.0)).unwrap();
+        }
+        assert_eq!(scene.cull_instances_against(&view_from_z(10.0)), 0);
+    }
+
+    #[test]
+    fn test_primitives_reading_the_same_accessors_share_vertices() {
+        // every mesh and level of box-lod reads the same POSITION and NORMAL accessors
+        let scene_data = GSceneData::new(GltfLoader::load_gltf("box-lod").unwrap());
+        assert_eq!(scene_data.vertex_vec.len(), 24);
+        let ranges: Vec<(Range<u32>, Range<u32>)> = scene_data.models.iter().flat_map(GModel::primitive_buffer_ranges).collect();
+        assert_eq!(ranges.len(), 4);
+        assert!(ranges.iter().all(|(_, vertices)| *vertices == (0..24)));
+        // the levels still have their own indices
+        assert_eq!(ranges.iter().filter(|(indices, _)| indices.len() == 12).count(), 2);
+    }
+
+    #[test]
+    fn test_optimized_indices_draw_the_same_triangles() {
+        let triangles = |scene_data: &GSceneData| -> Vec<[[u32; 3]; 3]> {
+            let mut triangles: Vec<[[u32; 3]; 3]> = Vec::new();
+            for (indices, vertices) in scene_data.models.iter().flat_map(GModel::primitive_buffer_ranges) {
+                for corners in scene_data.index_vec[indices.start as usize..indices.end as usize].chunks_exact(3) {
+                    let mut corners = [0, 1, 2].map(|corner| {
+                        scene_data.vertex_vec[vertices.start as usize + corners[corner] as usize].position.map(f32::to_bits)
+                    });
+                    let smallest = (0..3).min_by_key(|corner| corners[*corner]).unwrap();
+                    corners.rotate_left(smallest);
+                    triangles.push(corners);
+                }
+            }
+            triangles.sort();
+            triangles
+        };
+        let mut scene_data = GSceneData::new(GltfLoader::load_gltf("cesium-man").unwrap());
+        let original = triangles(&scene_data);
+        let stats = scene_data.optimize_indices();
+        assert_eq!(stats.primitives, 1);
+        assert_eq!(stats.triangles * 3, scene_data.index_vec.len());
+        assert!(stats.acmr_after() < stats.acmr_before());
+        assert_eq!(triangles(&scene_data), original);
+        // the scene reports what was done to its indices
+        assert_eq!(scene_data.build_scene_uninit().get_index_optimization_stats(), Some(&stats));
+        let scene = GSceneData::new(GltfLoader::load_gltf("cesium-man").unwrap()).build_scene_uninit();
+        assert_eq!(scene.get_index_optimization_stats(), None);
+    }
+
+    #[test]
+    fn test_distant_instances_use_simplified_meshes() {
+        let gltf_data = GltfLoader::load_gltf("box-lod").unwrap();
+        let mut scene = GSceneData::new(gltf_data).build_scene_uninit();
+        // model 0 is named Box with a Box_LOD1, model 1 uses MSFT_lod with a coverage of 0.5
+        let index_counts = |scene: &GScene| -> Vec<usize> {
+            let mut queue = crate::scene::render_queue::RenderQueue::default();
+            queue.push_scene(scene, 0);
+            queue.draws().iter().map(|queued| queued.draw.indices.len()).collect()
+        };
+        assert_eq!(index_counts(&scene), vec![36, 36]);
+        // about 0.7 of the screen
+        scene.cull_instances_against(&view_from_z(3.0));
+        assert_eq!(index_counts(&scene), vec![36, 36]);
+        // about 0.3 of the screen, between the two thresholds
+        scene.cull_instances_against(&view_from_z(7.0));
+        assert_eq!(index_counts(&scene), vec![36, 12]);
+        scene.cull_instances_against(&view_from_z(50.0));
+        assert_eq!(index_counts(&scene), vec![12, 12]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use cgmath::SquareMatrix;
+
+use crate::{
+    model::{animation::animation_node::NodeType, model::GModel},
+    scene::instances::{InstanceData, InstanceError, InstanceHandle},
+};
+
+/// Where on its parent an instance is attached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachmentPoint {
+    /// the parent instance's own transform
+    Origin,
+    /// a named node of the parent's model, e.g. a hand joint. The model has to be animated
+    Node(String),
+}
+
+/// An attachment point, resolved to where its transform is read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum ResolvedPoint {
+    Origin,
+    /// the joint's skinning matrix, times the inverse of its inverse bind matrix
+    Joint {
+        joint_index: usize,
+        bind_matrix: cgmath::Matrix4<f32>,
+    },
+    /// the slot of a mesh node among the parent instance's local transforms
+    Mesh(usize),
+    /// a node that is neither a mesh nor a joint follows the rest pose
+    Rest(cgmath::Matrix4<f32>),
+}
+
+impl ResolvedPoint {
+    /// Find the node of model named in point. inverse_bind_matrices are those of the skin the
+    /// joint transforms are computed with
+    pub(super) fn resolve(
+        point: &AttachmentPoint,
+        model: &GModel,
+        inverse_bind_matrices: &[cgmath::Matrix4<f32>],
+    ) -> Result<Self, InstanceError> {
+        let name = match point {
+            AttachmentPoint::Origin => return Ok(Self::Origin),
+            AttachmentPoint::Node(name) => name,
+        };
+        let unknown_node = || InstanceError::UnknownNode(name.clone());
+        let node_id = *model.node_names.get(name).ok_or_else(unknown_node)?;
+        let animation_data = model.animation_data.as_ref().ok_or_else(unknown_node)?;
+        let (node_type, rest_pose) = animation_data
+            .animation_node
+            .rest_pose(node_id, cgmath::Matrix4::identity())
+            .ok_or_else(unknown_node)?;
+        Ok(match node_type {
+            NodeType::Joint(joint_index) => match inverse_bind_matrices
+                .get(joint_index)
+                .and_then(|inverse_bind_matrix| inverse_bind_matrix.invert())
+            {
+                Some(bind_matrix) => Self::Joint {
+                    joint_index,
+                    bind_matrix,
+                },
+                None => Self::Rest(rest_pose),
+            },
+            NodeType::Mesh => Self::Mesh(
+                *animation_data
+                    .mesh_animation_data
+                    .node_to_lt_index
+                    .get(&node_id)
+                    .ok_or_else(unknown_node)?,
+            ),
+            NodeType::Node => Self::Rest(rest_pose),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Attachment {
+    parent: InstanceHandle,
+    point: ResolvedPoint,
+    /// the child's transform relative to the attachment point
+    offset: cgmath::Matrix4<f32>,
+}
+
+/// Parent/child relationships between model instances. The global transform of an attached
+/// instance is overwritten with its parent's, times the attachment point, times its offset,
+/// every time the graph is propagated
+#[derive(Debug, Default)]
+pub(super) struct SceneGraph {
+    /// child -> what it is attached to
+    attachments: HashMap<InstanceHandle, Attachment>,
+    /// the joint transforms each parent with instances attached to its joints was last
+    /// animated with. Every instance of a model shares the joint transform buffer, so the
+    /// parent's own pose is kept here
+    joint_poses: HashMap<InstanceHandle, Vec<[[f32; 4]; 4]>>,
+}
+
+impl SceneGraph {
+    pub fn is_empty(&self) -> bool {
+        self.attachments.is_empty()
+    }
+
+    /// Attach child to a point of parent, replacing any previous attachment of child.
+    /// Fails if either instance was removed, or parent is attached below child
+    pub fn attach(
+        &mut self,
+        instance_data: &InstanceData,
+        child: InstanceHandle,
+        parent: InstanceHandle,
+        point: ResolvedPoint,
+        offset: [[f32; 4]; 4],
+    ) -> Result<(), InstanceError> {
+        instance_data.resolve(child)?;
+        instance_data.resolve(parent)?;
+        let mut ancestor = Some(parent);
+        while let Some(instance) = ancestor {
+            if instance == child {
+                return Err(InstanceError::AttachmentCycle);
+            }
+            ancestor = self.parent(instance);
+        }
+        self.attachments.insert(
+            child,
+            Attachment {
+                parent,
+                point,
+                offset: offset.into(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Detach child from its parent, it stays where it was last propagated to.
+    /// Returns false if it wasn't attached
+    pub fn detach(&mut self, child: InstanceHandle) -> bool {
+        self.attachments.remove(&child).is_some()
+    }
+
+    pub fn parent(&self, child: InstanceHandle) -> Option<InstanceHandle> {
+        self.attachments
+            .get(&child)
+            .map(|attachment| attachment.parent)
+    }
+
+    /// the offset of an attached instance from its attachment point
+    pub fn offset_mut(&mut self, child: InstanceHandle) -> Option<&mut cgmath::Matrix4<f32>> {
+        self.attachments
+            .get_mut(&child)
+            .map(|attachment| &mut attachment.offset)
+    }
+
+    /// forget a removed instance, its children stay where they are
+    pub fn remove_instance(&mut self, instance: InstanceHandle) {
+        self.attachments
+            .retain(|child, attachment| *child != instance && attachment.parent != instance);
+        self.joint_poses.remove(&instance);
+    }
+
+    /// the instances with other instances attached to their joints
+    pub fn joint_parents(&self) -> HashSet<InstanceHandle> {
+        self.attachments
+            .values()
+            .filter(|attachment| matches!(attachment.point, ResolvedPoint::Joint { .. }))
+            .map(|attachment| attachment.parent)
+            .collect()
+    }
+
+    /// keep the joint transforms parent was animated with, for the instances on its joints
+    pub fn set_joint_pose(&mut self, parent: InstanceHandle, joint_transforms: &[[[f32; 4]; 4]]) {
+        let pose = self.joint_poses.entry(parent).or_default();
+        pose.clear();
+        pose.extend_from_slice(joint_transforms);
+    }
+
+    /// Write the global transform of every attached instance, parents before their children
+    pub fn propagate(&self, instance_data: &mut InstanceData) {
+        let mut propagated = HashSet::with_capacity(self.attachments.len());
+        for child in self.attachments.keys() {
+            self.propagate_instance(*child, instance_data, &mut propagated);
+        }
+    }
+
+    fn propagate_instance(
+        &self,
+        child: InstanceHandle,
+        instance_data: &mut InstanceData,
+        propagated: &mut HashSet<InstanceHandle>,
+    ) {
+        let Some(attachment) = self.attachments.get(&child) else {
+            return;
+        };
+        if !propagated.insert(child) {
+            return;
+        }
+        self.propagate_instance(attachment.parent, instance_data, propagated);
+        let (Ok(parent), Ok(child_global_index)) = (
+            instance_data.resolve(attachment.parent),
+            instance_data
+                .resolve(child)
+                .map(|(model_idx, instance_idx)| {
+                    instance_data.get_instance_global_index(instance_idx, model_idx)
+                }),
+        ) else {
+            return;
+        };
+        let (parent_model, parent_instance) = parent;
+        let parent_global = cgmath::Matrix4::from(
+            instance_data.global_transform_data
+                [instance_data.get_instance_global_index(parent_instance, parent_model)],
+        );
+        let point = match attachment.point {
+            ResolvedPoint::Origin => cgmath::Matrix4::identity(),
+            ResolvedPoint::Joint {
+                joint_index,
+                bind_matrix,
+            } => match self
+                .joint_poses
+                .get(&attachment.parent)
+                .and_then(|pose| pose.get(joint_index))
+            {
+                Some(joint_transform) => cgmath::Matrix4::from(*joint_transform) * bind_matrix,
+                // not animated yet, so still in the bind pose
+                None => bind_matrix,
+            },
+            ResolvedPoint::Mesh(slot) => {
+                let offset = instance_data
+                    .get_instance_local_offset(parent_instance, parent_model)
+                    .0;
+                cgmath::Matrix4::from(
+                    instance_data.local_transform_data[offset + slot].transform_matrix,
+                )
+            }
+            ResolvedPoint::Rest(rest_pose) => rest_pose,
+        };
+        instance_data.set_global_transform(
+            child_global_index,
+            (parent_global * point * attachment.offset).into(),
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     model::{
         loader::loader::{GltfData, GltfLoader},
+        simplify::SimplifySettings,
         util::InitializationError,
     },
     scene::scene::{GScene, GSceneData},
@@ -26,7 +27,23 @@ pub struct SceneScaffold<'a> {
     file_paths: &'a [&'a str],
     pub additional_instances: &'a [AdditionalScaffoldModelInstances<'a>],
     pub global_transform_overrides: &'a [ScaffoldGTOverride],
+    /// the levels of detail to generate for meshes the file has none for
+    pub lods: &'a [SimplifySettings],
+    /// reorder every primitive's indices and vertices for the gpu caches when the scene is built
+    pub optimize_indices: bool,
 }
+
+/// two levels, at half and a quarter of the triangles
+const GENERATED_LODS: &[SimplifySettings] = &[
+    SimplifySettings {
+        target_ratio: 0.5,
+        max_error: 0.01,
+    },
+    SimplifySettings {
+        target_ratio: 0.25,
+        max_error: 0.03,
+    },
+];
 impl<'a> SceneScaffold<'a> {
     pub fn create(
         &self,
@@ -34,7 +51,7 @@ impl<'a> SceneScaffold<'a> {
         aspect_ratio: f32,
     ) -> Result<GScene, InitializationError> {
         // TODO: fix errors!!!!!
-        let gltf_data: GltfData = GltfLoader::load_gltf(self.file_paths[0])
+        let gltf_data: GltfData = GltfLoader::load_gltf_with_lods(self.file_paths[0], self.lods)
             .map_err(|_| InitializationError::SceneInitializationError)?; // onyl one file path??
         let scene_data = GSceneData::new(gltf_data);
         let scene = scene_data.build_scene_from_scaffold(device, aspect_ratio, self)?;
@@ -46,6 +63,8 @@ pub const FLEXY_BOX: SceneScaffold = SceneScaffold {
     file_paths: &["flexy-box"],
     global_transform_overrides: &[],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 
 pub const BUGGY: SceneScaffold = SceneScaffold {
@@ -55,12 +74,16 @@ pub const BUGGY: SceneScaffold = SceneScaffold {
         model_idx: 0,
     }],
     additional_instances: &[],
+    lods: GENERATED_LODS,
+    optimize_indices: true,
 };
 
 pub const CUBE: SceneScaffold = SceneScaffold {
     file_paths: &["box"],
     global_transform_overrides: &[],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 pub const FOX: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[ScaffoldGTOverride {
@@ -69,11 +92,15 @@ pub const FOX: SceneScaffold = SceneScaffold {
     }],
     file_paths: &["fox"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 pub const TRUCK: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["milk-truck"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 pub const BRAIN: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[ScaffoldGTOverride {
@@ -82,21 +109,29 @@ pub const BRAIN: SceneScaffold = SceneScaffold {
     }],
     file_paths: &["brain-stem"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: true,
 };
 pub const DRAGON: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["dragon"],
     additional_instances: &[],
+    lods: GENERATED_LODS,
+    optimize_indices: true,
 };
 pub const BOX_ANIMATED: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["box-animated"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 pub const CMAN: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["cesium-man"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 pub const TRUCK_BOX: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[ScaffoldGTOverride {
@@ -105,16 +140,22 @@ pub const TRUCK_BOX: SceneScaffold = SceneScaffold {
     }],
     file_paths: &["milk-truck", "box"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 
 pub const MONKEY: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["monkey"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
 
 pub const POLLY: SceneScaffold = SceneScaffold {
     global_transform_overrides: &[],
     file_paths: &["polly"],
     additional_instances: &[],
+    lods: &[],
+    optimize_indices: false,
 };
@@ -18,15 +18,50 @@ impl<'a> GScene<'a> {
     }
 }
 
+/// the offset of the first local transform of each model, with the total at the end
 pub(super) fn calculate_model_mesh_offsets(
-    models: &Vec<GModel>,
-    model_instances: &Vec<usize>,
+    models: &[GModel],
+    model_instances: &[usize],
 ) -> Vec<usize> {
-    let mut model_mesh_offsets = Vec::with_capacity(models.len());
+    let mut model_mesh_offsets = Vec::with_capacity(models.len() + 1);
     let mut sum = 0;
     for (idx, model) in models.iter().enumerate() {
         model_mesh_offsets.push(sum);
         sum += (model.mesh_instances.iter().sum::<u32>() as usize) * model_instances[idx];
     }
+    model_mesh_offsets.push(sum);
     model_mesh_offsets
 }
+
+/// write data to buffer, replacing the buffer if data doesn't fit or would fit in a quarter of
+/// it. Returns true if the buffer was replaced
+pub(super) fn write_resizable_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &mut Option<wgpu::Buffer>,
+    data: &[u8],
+    element_size: usize,
+    usage: wgpu::BufferUsages,
+    label: &str,
+) -> bool {
+    // bindings can't be empty, so there is always room for one element
+    let needed = data.len().max(element_size) as u64;
+    let fits = buffer.as_ref().is_some_and(|buffer| {
+        let size = buffer.size();
+        needed <= size && needed * 4 > size
+    });
+    let replaced = !fits;
+    if replaced {
+        let element_count = (needed / element_size as u64).next_power_of_two();
+        *buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: element_count * element_size as u64,
+            usage,
+            mapped_at_creation: false,
+        }));
+    }
+    if !data.is_empty() {
+        queue.write_buffer(buffer.as_ref().unwrap(), 0, data);
+    }
+    replaced
+}
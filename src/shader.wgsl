@@ -5,6 +5,8 @@ struct VertexInput {
   @location(3) joints: vec4<u32>,
   @location(4) weights: vec4<f32>,
   @location(5) base_color_index: u32,
+  @location(11) joints_1: vec4<u32>,
+  @location(12) weights_1: vec4<f32>,
 }
 
 struct InstanceInput {
@@ -40,21 +42,54 @@ var<storage, read> global_transforms: GlobalTransforms;
 @group(2) @binding(2)
 var<storage, read> joint_transforms: array<mat4x4<f32>>;
 
+// baked poses, bound in place of joint_transforms when the vertex shader plays them back
+struct BakedClip {
+  frame_offset: u32,
+  frame_count: u32,
+  duration: f32,
+  _padding: u32,
+}
+struct BakedPoseInstance {
+  clip: u32,
+  time_offset: f32,
+  speed: f32,
+  _padding: u32,
+}
+struct BakedPoseUniform {
+  time: f32,
+  joint_count: u32,
+  _padding_0: u32,
+  _padding_1: u32,
+}
+
+@group(2) @binding(3)
+var<storage, read> baked_poses: array<mat4x4<f32>>;
+@group(2) @binding(4)
+var<storage, read> baked_clips: array<BakedClip>;
+@group(2) @binding(5)
+var<storage, read> baked_instances: array<BakedPoseInstance>;
+@group(2) @binding(6)
+var<uniform> baked_pose_uniform: BakedPoseUniform;
+
 @group(3) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(3) @binding(1)
 var s_diffuse: sampler;
 
 
-fn apply_bone_transform(joints: vec4<u32>, weights: vec4<f32>, position: vec3<f32>) -> vec4<f32> {
+fn apply_bone_transform(joints: vec4<u32>, weights: vec4<f32>, joints_1: vec4<u32>, weights_1: vec4<f32>, position: vec3<f32>) -> vec4<f32> {
 	let skin_mat: mat4x4<f32> = 
 	                           weights[0] * joint_transforms[joints[0]] +
 	                           weights[1] * joint_transforms[joints[1]] +
 	                           weights[2] * joint_transforms[joints[2]] +
-                               weights[3] * joint_transforms[joints[3]];
+                               weights[3] * joint_transforms[joints[3]] +
+	                           weights_1[0] * joint_transforms[joints_1[0]] +
+	                           weights_1[1] * joint_transforms[joints_1[1]] +
+	                           weights_1[2] * joint_transforms[joints_1[2]] +
+                               weights_1[3] * joint_transforms[joints_1[3]];
 	let result: vec4<f32> = skin_mat * vec4<f32>(position, 1.0);
 	return result;
-} 
+}
 
 @vertex
 fn vs_main(obj: VertexInput, instance: InstanceInput) -> VertexOutput {
@@ -66,7 +101,60 @@ fn vs_main(obj: VertexInput, instance: InstanceInput) -> VertexOutput {
     );
 	let global_t_matrix = global_transforms.transforms[instance.model_index];
     var out: VertexOutput;
-	let new_position: vec4<f32> = apply_bone_transform(obj.joints, obj.weights, obj.position);
+	let new_position: vec4<f32> = apply_bone_transform(obj.joints, obj.weights, obj.joints_1, obj.weights_1, obj.position);
+    out.clip_position = camera_uniform.transform * global_t_matrix * obj_matrix * new_position;
+	out.tex_coords = obj.tex_coords;
+	out.base_color_index = obj.base_color_index;
+    return out;
+}
+
+// the joint matrix blended between the same joint of two baked frames
+fn baked_joint(frame_0: u32, frame_1: u32, amount: f32, joint: u32) -> mat4x4<f32> {
+	return baked_poses[frame_0 + joint] * (1.0 - amount) + baked_poses[frame_1 + joint] * amount;
+}
+
+fn apply_baked_bone_transform(frame_0: u32, frame_1: u32, amount: f32, obj: VertexInput) -> vec4<f32> {
+	let skin_mat: mat4x4<f32> =
+	                           obj.weights[0] * baked_joint(frame_0, frame_1, amount, obj.joints[0]) +
+	                           obj.weights[1] * baked_joint(frame_0, frame_1, amount, obj.joints[1]) +
+	                           obj.weights[2] * baked_joint(frame_0, frame_1, amount, obj.joints[2]) +
+	                           obj.weights[3] * baked_joint(frame_0, frame_1, amount, obj.joints[3]) +
+	                           obj.weights_1[0] * baked_joint(frame_0, frame_1, amount, obj.joints_1[0]) +
+	                           obj.weights_1[1] * baked_joint(frame_0, frame_1, amount, obj.joints_1[1]) +
+	                           obj.weights_1[2] * baked_joint(frame_0, frame_1, amount, obj.joints_1[2]) +
+	                           obj.weights_1[3] * baked_joint(frame_0, frame_1, amount, obj.joints_1[3]);
+	return skin_mat * vec4<f32>(obj.position, 1.0);
+}
+
+// vs_main, but the joints come from the baked pose of the instance's clip at the current time.
+// Mirrors BakedPoses::sample
+@vertex
+fn vs_baked(obj: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let obj_matrix = mat4x4<f32>(
+        instance.obj_matrix_0,
+        instance.obj_matrix_1,
+        instance.obj_matrix_2,
+        instance.obj_matrix_3,
+    );
+	let global_t_matrix = global_transforms.transforms[instance.model_index];
+	let baked_instance = baked_instances[instance.model_index];
+	let clip = baked_clips[baked_instance.clip];
+	var frame = 0.0;
+	if (clip.duration > 0.0 && clip.frame_count > 1u) {
+		let time = baked_pose_uniform.time * baked_instance.speed + baked_instance.time_offset;
+		let wrapped = time - floor(time / clip.duration) * clip.duration;
+		frame = wrapped / clip.duration * f32(clip.frame_count - 1u);
+	}
+	let first = min(u32(floor(frame)), clip.frame_count - 1u);
+	let second = min(first + 1u, clip.frame_count - 1u);
+	let joint_count = baked_pose_uniform.joint_count;
+	let new_position = apply_baked_bone_transform(
+		(clip.frame_offset + first) * joint_count,
+		(clip.frame_offset + second) * joint_count,
+		frame - floor(frame),
+		obj,
+	);
+    var out: VertexOutput;
     out.clip_position = camera_uniform.transform * global_t_matrix * obj_matrix * new_position;
 	out.tex_coords = obj.tex_coords;
 	out.base_color_index = obj.base_color_index;